@@ -1,14 +1,19 @@
 pub use butane_codegen::{butane_type, dataresult, model};
+pub use butane_core::backref::BackRef;
 pub use butane_core::custom;
-pub use butane_core::fkey::ForeignKey;
+pub use butane_core::fkey::{ForeignKey, ForeignKeyColumns};
 pub use butane_core::many::Many;
 pub use butane_core::migrations;
 pub use butane_core::query;
 pub use butane_core::{
-    AsPrimaryKey, DataObject, DataResult, Error, FieldType, FromSql, ObjectState, Result, SqlType,
-    SqlVal, SqlValRef, ToSql,
+    AsPrimaryKey, ConstraintDetails, DataObject, DataResult, Error, FieldType, FromSql,
+    ObjectState, PrimaryKey, PrimaryKeyColumn, Result, SoftDelete, SqlType, SqlVal, SqlValRef,
+    ToSql,
 };
 
+#[cfg(feature = "datetime")]
+pub use butane_core::now;
+
 pub mod db {
     pub use butane_core::db::*;
 }
@@ -31,6 +36,14 @@ pub mod db {
 /// To refer to values from the surrounding rust function, enclose
 /// them in braces, like `filter!(Foo, bar == {bar})`
 ///
+/// # Placeholders
+/// A value may be left to be bound when the query is run by writing
+/// `placeholder!()` in its place, like `filter!(Foo, bar ==
+/// placeholder!())`. Such queries must be run with
+/// [`Query::prepare`], which binds values to the placeholders in the
+/// order they appear. Placeholders may be compared with `==`, `!=`,
+/// `<`, `>`, `<=` and `>=`, or used as the parameter to `like`.
+///
 /// # Function-like operations
 /// Filters support some operations for which Rust does not have operators and which are instead
 /// represented syntactically as function calls.
@@ -68,6 +81,7 @@ pub mod db {
 ///
 /// [`BoolExpr`]: crate::query::BoolExpr
 /// [`Query`]: crate::query::Query
+/// [`Query::prepare`]: crate::query::Query::prepare
 pub use butane_codegen::filter;
 
 /// Constructs a filtered database query.
@@ -167,5 +181,7 @@ pub mod prelude {
     pub use crate::DataObject;
     #[doc(no_inline)]
     pub use crate::DataResult;
+    #[doc(no_inline)]
+    pub use crate::SoftDelete;
     pub use butane_core::db::BackendConnection;
 }
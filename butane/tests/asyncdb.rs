@@ -0,0 +1,71 @@
+#![cfg(feature = "async")]
+use butane::db::{AsyncConnection, ConnectionSpec};
+use butane::prelude::*;
+use butane::query;
+
+mod common;
+use common::blog::{Blog, Post, Tag};
+
+async fn save_load_delete(conn: AsyncConnection) {
+    let mut blog = Blog::new(1, "Cats");
+    blog.save_async(&conn).await.unwrap();
+    let mut tag = Tag::new("kittens");
+    tag.save_async(&conn).await.unwrap();
+    let mut post = Post::new(1, "The Tiger", "Tigers are cats", &blog);
+    post.tags.add(&tag);
+    post.save_async(&conn).await.unwrap();
+
+    post.likes = 5;
+    post.save_async(&conn).await.unwrap();
+    let post2 = Post::get_async(&conn, 1).await.unwrap();
+    assert_eq!(post2.title, "The Tiger");
+    assert_eq!(post2.likes, 5);
+
+    let posts = query!(Post, likes > 1).load_async(&conn).await.unwrap();
+    assert_eq!(posts.len(), 1);
+    assert!(query!(Post, likes > 5)
+        .load_first_async(&conn)
+        .await
+        .unwrap()
+        .is_none());
+
+    post.delete_async(&conn).await.unwrap();
+    assert!(Post::get_async(&conn, 1).await.is_err());
+    assert_eq!(Blog::query().delete_async(&conn).await.unwrap(), 1);
+}
+
+fn run(spec: ConnectionSpec) {
+    // Migrations are applied with a blocking connection before the
+    // async runtime starts.
+    let backend = butane::db::get_backend(&spec.backend_name).unwrap();
+    let mut conn = backend.connect(&spec.conn_str).unwrap();
+    common::setup_db(backend, &mut conn);
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let conn = butane::db::connect_async(&spec).await.unwrap();
+            save_load_delete(conn).await;
+        });
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn save_load_delete_sqlite() {
+    // An in-memory database is private to its connection, so use a
+    // file shared by the blocking and async connections.
+    let path = std::env::temp_dir().join(format!("butane_async_{}.db", std::process::id()));
+    run(ConnectionSpec::new(
+        butane::db::sqlite::BACKEND_NAME,
+        path.to_str().unwrap(),
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "async-pg")]
+#[test]
+fn save_load_delete_pg() {
+    let (spec, _data) = common::pg_connspec();
+    run(spec);
+}
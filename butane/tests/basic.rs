@@ -1,10 +1,9 @@
-use butane::db::Connection;
+use butane::db::{Connection, ConnectionMethods, IsolationLevel, TransactionOptions};
 use butane::prelude::*;
-use butane::{butane_type, find, model, query};
-use butane::{ForeignKey, ObjectState};
+use butane::{butane_type, filter, find, model, query};
+use butane::{ForeignKey, ObjectState, SqlValRef, ToSql};
+use chrono::{Duration, NaiveDateTime};
 use paste;
-#[cfg(feature = "pg")]
-use postgres;
 #[cfg(feature = "sqlite")]
 use rusqlite;
 
@@ -51,6 +50,36 @@ impl Bar {
     }
 }
 
+#[model]
+struct Qux {
+    id: i64,
+    #[on_delete(cascade)]
+    foo: ForeignKey<Foo>,
+}
+impl Qux {
+    fn new(id: i64, foo: &Foo) -> Self {
+        Qux {
+            id,
+            foo: foo.into(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct Counter {
+    #[auto]
+    id: i64,
+}
+impl Counter {
+    fn new() -> Self {
+        Counter {
+            id: -1, // will be set automatically when saved
+            state: ObjectState::default(),
+        }
+    }
+}
+
 #[model]
 struct Baz {
     #[auto]
@@ -80,6 +109,126 @@ impl HasOnlyPk {
     }
 }
 
+#[model]
+#[derive(PartialEq, Eq, Debug)]
+struct TenantItem {
+    #[pk]
+    tenant: i64,
+    #[pk]
+    id: i64,
+    name: String,
+}
+impl TenantItem {
+    fn new(tenant: i64, id: i64, name: &str) -> Self {
+        TenantItem {
+            tenant,
+            id,
+            name: name.to_string(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct TenantOrder {
+    id: i64,
+    #[references(tenant, id)]
+    item: ForeignKey<TenantItem>,
+    #[references(tenant, id)]
+    #[on_delete(set_null)]
+    gift: Option<ForeignKey<TenantItem>>,
+}
+impl TenantOrder {
+    fn new(id: i64, item: &TenantItem) -> Self {
+        TenantOrder {
+            id,
+            item: item.into(),
+            gift: None,
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+#[unique(fields = "tenant, code")]
+#[check("quantity >= 0")]
+struct Stock {
+    id: i64,
+    tenant: i64,
+    code: String,
+    quantity: i64,
+}
+impl Stock {
+    fn new(id: i64, tenant: i64, code: &str, quantity: i64) -> Self {
+        Stock {
+            id,
+            tenant,
+            code: code.to_string(),
+            quantity,
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model(soft_delete)]
+struct Note {
+    id: i64,
+    text: String,
+}
+impl Note {
+    fn new(id: i64, text: &str) -> Self {
+        Note {
+            id,
+            text: text.to_string(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct Entry {
+    id: i64,
+    text: String,
+    #[auto_now_add]
+    created: NaiveDateTime,
+    #[auto_now]
+    updated: Option<NaiveDateTime>,
+}
+impl Entry {
+    fn new(id: i64, text: &str) -> Self {
+        Entry {
+            id,
+            text: text.to_string(),
+            created: NaiveDateTime::from_timestamp(0, 0),
+            updated: None,
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct Document {
+    id: i64,
+    text: String,
+    #[version]
+    version: i32,
+}
+impl Document {
+    fn new(id: i64, text: &str) -> Self {
+        Document {
+            id,
+            text: text.to_string(),
+            version: 0,
+            state: ObjectState::default(),
+        }
+    }
+}
+
+// Postgres folds unquoted names to lower case
+fn lowercase(name: Option<String>) -> Option<String> {
+    name.map(|name| name.to_lowercase())
+}
+
 fn basic_crud(conn: Connection) {
     //create
     let mut foo = Foo::new(1);
@@ -107,6 +256,29 @@ fn basic_crud(conn: Connection) {
 }
 testall!(basic_crud);
 
+fn basic_save_only_changed_columns(conn: Connection) {
+    let mut foo = Foo::new(1);
+    foo.save(&conn).unwrap();
+
+    // Two copies of the same object, each changing a different field
+    let mut foo_a = Foo::get(&conn, 1).unwrap();
+    let mut foo_b = Foo::get(&conn, 1).unwrap();
+    foo_a.bar = 42;
+    foo_a.save(&conn).unwrap();
+    foo_b.baz = "hello world".to_string();
+    foo_b.save(&conn).unwrap();
+
+    // Neither save overwrote the other's change
+    let foo2 = Foo::get(&conn, 1).unwrap();
+    assert_eq!(foo2.bar, 42);
+    assert_eq!(foo2.baz, "hello world");
+
+    // Saving again with no changes is harmless
+    foo_b.save(&conn).unwrap();
+    assert_eq!(Foo::get(&conn, 1).unwrap().bar, 42);
+}
+testall!(basic_save_only_changed_columns);
+
 fn basic_find(conn: Connection) {
     //create
     let mut foo1 = Foo::new(1);
@@ -197,6 +369,33 @@ fn foreign_key(conn: Connection) {
 }
 testall!(foreign_key);
 
+fn foreign_key_prevents_delete(conn: Connection) {
+    let mut foo = Foo::new(1);
+    foo.save(&conn).unwrap();
+    let mut bar = Bar::new("tarzan", foo.clone());
+    bar.save(&conn).unwrap();
+
+    // Bar has no on_delete action, so foo cannot be deleted while referenced
+    assert!(foo.delete(&conn).is_err());
+    bar.delete(&conn).unwrap();
+    foo.delete(&conn).unwrap();
+}
+testall!(foreign_key_prevents_delete);
+
+fn foreign_key_on_delete_cascade(conn: Connection) {
+    let mut foo = Foo::new(1);
+    foo.save(&conn).unwrap();
+    let mut qux = Qux::new(1, &foo);
+    qux.save(&conn).unwrap();
+
+    foo.delete(&conn).unwrap();
+    if let Some(butane::Error::NoSuchObject) = Qux::get(&conn, 1).err() {
+    } else {
+        panic!("Expected NoSuchObject");
+    }
+}
+testall!(foreign_key_on_delete_cascade);
+
 fn auto_pk(conn: Connection) {
     let mut baz1 = Baz::new("baz1");
     baz1.save(&conn).unwrap();
@@ -209,6 +408,80 @@ fn auto_pk(conn: Connection) {
 }
 testall!(auto_pk);
 
+fn save_all(conn: Connection) {
+    // Enough objects to need several statements
+    let mut foos: Vec<Foo> = (1..=1000)
+        .map(|i| {
+            let mut foo = Foo::new(i);
+            foo.bar = i as u32;
+            foo
+        })
+        .collect();
+    Foo::save_all(&mut foos, &conn).unwrap();
+    assert!(foos.iter().all(|foo| foo.state.saved));
+    assert_eq!(Foo::query().count(&conn).unwrap(), 1000);
+    assert_eq!(Foo::get(&conn, 500).unwrap(), foos[499]);
+
+    // Saved objects are updated and new ones inserted
+    foos[0].baz = "hello world".to_string();
+    let mut foos = vec![foos.swap_remove(0), Foo::new(1001)];
+    foos[1].bar = 1001;
+    Foo::save_all(&mut foos, &conn).unwrap();
+    assert_eq!(Foo::get(&conn, 1).unwrap().baz, "hello world");
+    assert_eq!(Foo::query().count(&conn).unwrap(), 1001);
+}
+testall!(save_all);
+
+fn save_all_auto_pk(conn: Connection) {
+    let mut bazs = vec![Baz::new("baz1"), Baz::new("baz2"), Baz::new("baz3")];
+    Baz::save_all(&mut bazs, &conn).unwrap();
+    assert!(bazs[0].id < bazs[1].id);
+    assert!(bazs[1].id < bazs[2].id);
+    for baz in &bazs {
+        assert!(baz.state.saved);
+        assert_eq!(Baz::get(&conn, baz.id).unwrap().text, baz.text);
+    }
+}
+testall!(save_all_auto_pk);
+
+fn save_all_only_auto_pk(conn: Connection) {
+    let mut counters = vec![Counter::new(), Counter::new()];
+    Counter::save_all(&mut counters, &conn).unwrap();
+    assert!(counters.iter().all(|counter| counter.state.saved));
+    assert_ne!(counters[0].id, counters[1].id);
+    assert_eq!(Counter::query().count(&conn).unwrap(), 2);
+}
+testall!(save_all_only_auto_pk);
+
+fn copy_in(conn: Connection) {
+    let foos: Vec<Foo> = (1..=100)
+        .map(|i| {
+            let mut foo = Foo::new(i);
+            foo.bar = i as u32;
+            foo
+        })
+        .collect();
+    let values: Vec<SqlValRef> = foos
+        .iter()
+        .flat_map(|foo| {
+            vec![
+                foo.id.to_sql_ref(),
+                foo.bar.to_sql_ref(),
+                foo.baz.to_sql_ref(),
+                foo.blobbity.to_sql_ref(),
+            ]
+        })
+        .collect();
+    conn.copy_in(Foo::TABLE, Foo::COLUMNS, &values).unwrap();
+    assert_eq!(Foo::query().count(&conn).unwrap(), 100);
+    let mut foo = Foo::get(&conn, 42).unwrap();
+    assert_eq!(foo.bar, 42);
+    // Objects are not marked as saved by copy_in
+    foo.state = ObjectState::default();
+    assert_eq!(foo, foos[41]);
+}
+testall!(copy_in);
+
 fn only_pk(conn: Connection) {
     let mut obj = HasOnlyPk::new(1);
     obj.save(&conn).unwrap();
@@ -218,6 +491,313 @@ fn only_pk(conn: Connection) {
 }
 testall!(only_pk);
 
+fn composite_pk(conn: Connection) {
+    let mut item_a = TenantItem::new(1, 1, "a");
+    item_a.save(&conn).unwrap();
+    let mut item_b = TenantItem::new(2, 1, "b");
+    item_b.save(&conn).unwrap();
+    assert_eq!(*item_b.pk(), (2, 1));
+
+    // The same id may be used by different tenants
+    assert_eq!(TenantItem::get(&conn, (1, 1)).unwrap(), item_a);
+    assert_eq!(TenantItem::get(&conn, (2, 1)).unwrap(), item_b);
+
+    item_a.name = "c".to_string();
+    item_a.save(&conn).unwrap();
+    assert_eq!(TenantItem::get(&conn, (1, 1)).unwrap().name, "c");
+    assert_eq!(TenantItem::get(&conn, (2, 1)).unwrap().name, "b");
+
+    item_a.delete(&conn).unwrap();
+    assert!(TenantItem::get(&conn, (1, 1)).is_err());
+    assert!(TenantItem::get(&conn, (2, 1)).is_ok());
+
+    // Saving a second row with an existing key fails
+    assert!(TenantItem::new(2, 1, "d").save(&conn).is_err());
+}
+testall!(composite_pk);
+
+fn composite_foreign_key(conn: Connection) {
+    let mut item_a = TenantItem::new(1, 1, "a");
+    item_a.save(&conn).unwrap();
+    let mut item_b = TenantItem::new(2, 1, "b");
+    item_b.save(&conn).unwrap();
+    let mut order = TenantOrder::new(1, &item_b);
+    order.save(&conn).unwrap();
+    TenantOrder::new(2, &item_a).save(&conn).unwrap();
+
+    let mut order = TenantOrder::get(&conn, 1).unwrap();
+    assert_eq!(order.item.pk(), (2, 1));
+    assert_eq!(*order.item.load(&conn).unwrap(), item_b);
+    assert!(order.gift.is_none());
+
+    order.gift = Some((&item_a).into());
+    order.save(&conn).unwrap();
+    let order = TenantOrder::get(&conn, 1).unwrap();
+    assert_eq!(order.gift.unwrap().pk(), (1, 1));
+
+    let orders = TenantOrder::query()
+        .prefetch(|order| &order.item)
+        .load(&conn)
+        .unwrap();
+    let mut names: Vec<&str> = orders
+        .iter()
+        .map(|order| order.item.get().unwrap().name.as_str())
+        .collect();
+    names.sort_unstable();
+    assert_eq!(names, vec!["a", "b"]);
+
+    // Deleting the gift clears the reference to it
+    item_a.delete(&conn).unwrap_err();
+    TenantOrder::get(&conn, 2).unwrap().delete(&conn).unwrap();
+    item_a.delete(&conn).unwrap();
+    assert!(TenantOrder::get(&conn, 1).unwrap().gift.is_none());
+
+    // The referenced item must exist
+    let missing = TenantItem::new(1, 2, "c");
+    let e = TenantOrder::new(3, &missing).save(&conn).unwrap_err();
+    assert!(matches!(e, butane::Error::ForeignKeyViolation(_)));
+}
+testall!(composite_foreign_key);
+
+fn struct_unique_constraint(conn: Connection) {
+    Stock::new(1, 1, "apple", 3).save(&conn).unwrap();
+    // The same code may be used by different tenants
+    Stock::new(2, 2, "apple", 3).save(&conn).unwrap();
+    let e = Stock::new(3, 1, "apple", 3).save(&conn).unwrap_err();
+    assert!(matches!(e, butane::Error::UniqueViolation(_)));
+}
+testall!(struct_unique_constraint);
+
+fn check_constraint(conn: Connection) {
+    let mut stock = Stock::new(1, 1, "apple", 0);
+    stock.save(&conn).unwrap();
+    stock.quantity = -1;
+    let e = stock.save(&conn).unwrap_err();
+    match e {
+        butane::Error::CheckViolation(details) => assert_eq!(
+            lowercase(details.constraint),
+            Some("stock_check0".to_string())
+        ),
+        e => panic!("unexpected error {:?}", e),
+    }
+    assert_eq!(Stock::get(&conn, 1).unwrap().quantity, 0);
+}
+testall!(check_constraint);
+
+fn soft_delete(conn: Connection) {
+    for (id, text) in [(1, "a"), (2, "b"), (3, "c")].iter() {
+        Note::new(*id, text).save(&conn).unwrap();
+    }
+    let note = Note::get(&conn, 1).unwrap();
+    note.delete(&conn).unwrap();
+    assert!(matches!(
+        Note::get(&conn, 1),
+        Err(butane::Error::NoSuchObject)
+    ));
+    assert_eq!(Note::query().count(&conn).unwrap(), 2);
+    assert_eq!(Note::query().with_deleted().count(&conn).unwrap(), 3);
+
+    assert_eq!(query!(Note, id == 2).delete(&conn).unwrap(), 1);
+    let mut deleted: Vec<i64> = Note::query()
+        .only_deleted()
+        .load(&conn)
+        .unwrap()
+        .into_iter()
+        .map(|note| note.id)
+        .collect();
+    deleted.sort_unstable();
+    assert_eq!(deleted, vec![1, 2]);
+    // Objects already deleted are unaffected by deleting them again
+    assert_eq!(Note::query().with_deleted().delete(&conn).unwrap(), 1);
+    assert!(!Note::query().exists(&conn).unwrap());
+
+    note.restore(&conn).unwrap();
+    assert_eq!(Note::get(&conn, 1).unwrap().text, "a");
+    assert_eq!(Note::query().only_deleted().count(&conn).unwrap(), 2);
+}
+testall!(soft_delete);
+
+fn auto_now(conn: Connection) {
+    // Backends may store timestamps with less precision
+    let before = butane::now() - Duration::seconds(1);
+    let mut entry = Entry::new(1, "a");
+    entry.save(&conn).unwrap();
+    assert!(entry.created > before);
+    assert_eq!(entry.updated, Some(entry.created));
+
+    let created = entry.created;
+    entry.text = "b".to_string();
+    entry.save(&conn).unwrap();
+    assert_eq!(entry.created, created);
+    assert!(entry.updated.unwrap() >= created);
+
+    let entry = Entry::get(&conn, 1).unwrap();
+    assert_eq!(entry.text, "b");
+    assert!(entry.created > before);
+    assert!(entry.updated.unwrap() >= entry.created);
+
+    let mut entries = [Entry::new(2, "c")];
+    Entry::save_all(&mut entries, &conn).unwrap();
+    assert!(entries[0].created > before);
+    assert!(Entry::get(&conn, 2).unwrap().updated.is_some());
+}
+testall!(auto_now);
+
+fn optimistic_locking(conn: Connection) {
+    let mut doc = Document::new(1, "a");
+    doc.save(&conn).unwrap();
+    let mut other = Document::get(&conn, 1).unwrap();
+
+    doc.text = "b".to_string();
+    doc.save(&conn).unwrap();
+    assert_eq!(doc.version, 1);
+    // Saving without changes leaves the version alone
+    doc.save(&conn).unwrap();
+    assert_eq!(doc.version, 1);
+
+    other.text = "c".to_string();
+    assert!(matches!(other.save(&conn), Err(butane::Error::StaleObject)));
+    let mut other = Document::get(&conn, 1).unwrap();
+    assert_eq!(other.text, "b");
+    assert_eq!(other.version, 1);
+    other.text = "c".to_string();
+    other.save(&conn).unwrap();
+    assert_eq!(Document::get(&conn, 1).unwrap().version, 2);
+
+    doc.delete(&conn).unwrap();
+    doc.text = "d".to_string();
+    assert!(matches!(doc.save(&conn), Err(butane::Error::StaleObject)));
+}
+testall!(optimistic_locking);
+
+fn reload(conn: Connection) {
+    let mut foo = Foo::new(1);
+    foo.bar = 1;
+    foo.save(&conn).unwrap();
+    let mut bar = Bar::new("bar", foo.clone());
+    bar.save(&conn).unwrap();
+    bar.foo.load(&conn).unwrap();
+
+    query!(Foo, id == 1)
+        .update(&conn, vec![Foo::fields().bar().set(2)])
+        .unwrap();
+    foo.baz = "unsaved".to_string();
+    foo.reload(&conn).unwrap();
+    assert_eq!(foo.bar, 2);
+    assert_eq!(foo.baz, "");
+    // The discarded change is not saved later
+    foo.save(&conn).unwrap();
+    assert_eq!(Foo::get(&conn, 1).unwrap().baz, "");
+
+    bar.reload(&conn).unwrap();
+    assert!(bar.foo.get().is_err());
+    assert_eq!(bar.foo.load(&conn).unwrap().bar, 2);
+
+    bar.delete(&conn).unwrap();
+    assert!(matches!(
+        bar.reload(&conn),
+        Err(butane::Error::NoSuchObject)
+    ));
+}
+testall!(reload);
+
+fn upsert(conn: Connection) {
+    use butane::query::OnConflict;
+    let mut foo = Foo::new(1);
+    foo.bar = 7;
+    foo.baz = "a".to_string();
+    foo.save(&conn).unwrap();
+    let mut qux = Qux::new(1, &foo);
+    qux.save(&conn).unwrap();
+
+    let mut other = Foo::new(2);
+    other.bar = 7;
+    other.baz = "b".to_string();
+    let updated = other
+        .upsert(&conn, &["bar"], OnConflict::Update(vec!["baz"]))
+        .unwrap();
+    assert!(updated);
+    // The existing object is updated rather than replaced
+    assert_eq!(Foo::query().count(&conn).unwrap(), 1);
+    assert_eq!(Foo::get(&conn, 1).unwrap().baz, "b");
+    assert!(Qux::get(&conn, 1).is_ok());
+
+    other.baz = "c".to_string();
+    let updated = other
+        .upsert(&conn, &["bar"], OnConflict::DoNothing)
+        .unwrap();
+    assert!(!updated);
+    assert_eq!(Foo::get(&conn, 1).unwrap().baz, "b");
+
+    other.bar = 8;
+    let inserted = other
+        .upsert(&conn, &["bar"], OnConflict::DoNothing)
+        .unwrap();
+    assert!(inserted);
+    assert_eq!(Foo::get(&conn, 2).unwrap().baz, "c");
+
+    // Only DoNothing may leave out the conflict columns
+    let result = other.upsert(&conn, &[], OnConflict::Update(vec!["baz"]));
+    assert!(matches!(result, Err(butane::Error::MissingConflictTarget)));
+    assert!(!other.upsert(&conn, &[], OnConflict::DoNothing).unwrap());
+}
+testall!(upsert);
+
+fn get_or_create(mut conn: Connection) {
+    let make = |id: i64, bar: u32, baz: &str| {
+        let mut foo = Foo::new(id);
+        foo.bar = bar;
+        foo.baz = baz.to_string();
+        foo
+    };
+    let (foo, created) =
+        Foo::get_or_create(&mut conn, filter!(Foo, bar == 5), || make(1, 5, "a")).unwrap();
+    assert!(created);
+    assert_eq!(foo.baz, "a");
+    let (foo, created) =
+        Foo::get_or_create(&mut conn, filter!(Foo, bar == 5), || make(2, 5, "b")).unwrap();
+    assert!(!created);
+    assert_eq!(foo.id, 1);
+    assert_eq!(foo.baz, "a");
+
+    let (foo, created) = Foo::update_or_create(
+        &mut conn,
+        filter!(Foo, bar == 5),
+        || make(2, 5, "c"),
+        |foo| foo.baz = "d".to_string(),
+    )
+    .unwrap();
+    assert!(!created);
+    assert_eq!(foo.baz, "d");
+    assert_eq!(Foo::get(&conn, 1).unwrap().baz, "d");
+
+    let (foo, created) = Foo::update_or_create(
+        &mut conn,
+        filter!(Foo, bar == 6),
+        || make(2, 6, "e"),
+        |foo| foo.baz = "f".to_string(),
+    )
+    .unwrap();
+    assert!(created);
+    assert_eq!(foo.baz, "e");
+    assert_eq!(Foo::query().count(&conn).unwrap(), 2);
+
+    // A conflict with an object which doesn't match the filter is an error
+    let e = Foo::get_or_create(&mut conn, filter!(Foo, bar == 7), || make(1, 7, "g")).unwrap_err();
+    assert!(matches!(e, butane::Error::UniqueViolation(_)));
+    assert_eq!(Foo::get(&conn, 1).unwrap().baz, "d");
+}
+testall!(get_or_create);
+
+fn foreign_key_violation(conn: Connection) {
+    // The referenced Foo is never saved
+    let e = Bar::new("bar", Foo::new(1)).save(&conn).unwrap_err();
+    assert!(matches!(e, butane::Error::ForeignKeyViolation(_)));
+    assert!(e.constraint_details().is_some());
+    assert!(!e.is_retryable());
+}
+testall!(foreign_key_violation);
+
 fn basic_committed_transaction(mut conn: Connection) {
     let tr = conn.transaction().unwrap();
 
@@ -269,6 +849,151 @@ fn basic_rollback_transaction(mut conn: Connection) {
 }
 testall!(basic_rollback_transaction);
 
+fn savepoint(mut conn: Connection) {
+    let mut tr = conn.transaction().unwrap();
+    Foo::new(1).save(&tr).unwrap();
+
+    // A savepoint which is rolled back
+    let sp = tr.savepoint().unwrap();
+    Foo::new(2).save(&sp).unwrap();
+    sp.rollback().unwrap();
+
+    // A savepoint which is committed, containing one which is dropped
+    let mut sp = tr.savepoint().unwrap();
+    Foo::new(3).save(&sp).unwrap();
+    {
+        let sp2 = sp.savepoint().unwrap();
+        Foo::new(4).save(&sp2).unwrap();
+    }
+    sp.commit().unwrap();
+    tr.commit().unwrap();
+
+    assert!(Foo::get(&conn, 1).is_ok());
+    assert!(Foo::get(&conn, 2).is_err());
+    assert!(Foo::get(&conn, 3).is_ok());
+    assert!(Foo::get(&conn, 4).is_err());
+}
+testall!(savepoint);
+
+fn with_transaction(mut conn: Connection) {
+    let result: butane::Result<()> = conn.with_transaction(|tr| {
+        Foo::new(1).save(tr)?;
+        Err(butane::Error::NoSuchObject)
+    });
+    assert!(result.is_err());
+    assert!(Foo::get(&conn, 1).is_err());
+
+    conn.with_transaction(|tr| {
+        Foo::new(2).save(tr)?;
+        // A nested transaction which fails does not affect the outer one
+        let nested: butane::Result<()> = tr.with_transaction(|tr| {
+            Foo::new(3).save(tr)?;
+            Err(butane::Error::NoSuchObject)
+        });
+        assert!(nested.is_err());
+        Ok::<(), butane::Error>(())
+    })
+    .unwrap();
+    assert!(Foo::get(&conn, 2).is_ok());
+    assert!(Foo::get(&conn, 3).is_err());
+}
+testall!(with_transaction);
+
+fn transaction_with_options(mut conn: Connection) {
+    let options = TransactionOptions::new().isolation_level(IsolationLevel::Serializable);
+    let tr = conn.transaction_with_options(&options).unwrap();
+    Foo::new(1).save(&tr).unwrap();
+    tr.commit().unwrap();
+    assert!(Foo::get(&conn, 1).is_ok());
+
+    // Errors which are not retryable are returned immediately
+    let mut attempts = 0;
+    let result: butane::Result<()> = conn.with_transaction_retry(&options, 3, |tr| {
+        attempts += 1;
+        Foo::new(2).save(tr)?;
+        Err(butane::Error::NoSuchObject)
+    });
+    assert!(result.is_err());
+    assert_eq!(attempts, 1);
+    assert!(Foo::get(&conn, 2).is_err());
+}
+testall!(transaction_with_options);
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn transaction_retry_sqlite_busy() {
+    let mut conn = common::sqlite_connection();
+    common::setup_db(
+        Box::new(butane::db::sqlite::SQLiteBackend::new()),
+        &mut conn,
+    );
+    let mut attempts = 0;
+    conn.with_transaction_retry(&TransactionOptions::new(), 3, |tr| {
+        attempts += 1;
+        Foo::new(attempts).save(tr)?;
+        if attempts < 3 {
+            let busy = rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY);
+            Err(rusqlite::Error::SqliteFailure(busy, None).into())
+        } else {
+            Ok(())
+        }
+    })
+    .unwrap();
+    assert_eq!(attempts, 3);
+    // Only the final attempt is committed
+    assert!(Foo::get(&conn, 1).is_err());
+    assert!(Foo::get(&conn, 2).is_err());
+    assert!(Foo::get(&conn, 3).is_ok());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn transaction_retry_sqlite_busy_begin() {
+    // Each connection to an in-memory database has its own, so use a
+    // file shared by both connections.
+    let path = std::env::temp_dir().join(format!("butane_busy_{}.db", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    let backend = butane::db::get_backend("sqlite").unwrap();
+    let mut conn = backend.connect(&path).unwrap();
+    common::setup_db(
+        Box::new(butane::db::sqlite::SQLiteBackend::new()),
+        &mut conn,
+    );
+    // BEGIN IMMEDIATE fails at once while the other connection holds
+    // the lock, rather than waiting for it
+    conn.execute("PRAGMA busy_timeout = 50;").unwrap();
+    let options = TransactionOptions::new().isolation_level(IsolationLevel::RepeatableRead);
+
+    let (locked, wait_locked) = std::sync::mpsc::channel();
+    let holder = {
+        let path = path.clone();
+        let options = options.clone();
+        std::thread::spawn(move || {
+            let backend = butane::db::get_backend("sqlite").unwrap();
+            let mut conn = backend.connect(&path).unwrap();
+            let tr = conn.transaction_with_options(&options).unwrap();
+            locked.send(()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(300));
+            tr.commit().unwrap();
+        })
+    };
+    wait_locked.recv().unwrap();
+
+    let mut attempts = 0;
+    conn.with_transaction_retry(&options, 20, |tr| {
+        attempts += 1;
+        Foo::new(1).save(tr)
+    })
+    .unwrap();
+    holder.join().unwrap();
+    // The transaction could not begin until the lock was released,
+    // so the closure only ran once
+    assert_eq!(attempts, 1);
+    assert!(Foo::get(&conn, 1).is_ok());
+    drop(conn);
+    std::fs::remove_file(&path).unwrap();
+}
+
 fn basic_unique_field_error_on_non_unique(conn: Connection) {
     let mut foo1 = Foo::new(1);
     foo1.bar = 42;
@@ -278,17 +1003,11 @@ fn basic_unique_field_error_on_non_unique(conn: Connection) {
     foo2.bar = foo1.bar;
     let e = foo2.save(&conn).unwrap_err();
     // Make sure the error is one we expect
-    assert!(match e {
-        #[cfg(feature = "sqlite")]
-        butane::Error::SQLite(rusqlite::Error::SqliteFailure(
-            rusqlite::ffi::Error { code, .. },
-            _,
-        )) if code == rusqlite::ffi::ErrorCode::ConstraintViolation => true,
-        #[cfg(feature = "pg")]
-        butane::Error::Postgres(e)
-            if e.code() == Some(&postgres::error::SqlState::UNIQUE_VIOLATION) =>
-            true,
-        _ => false,
-    });
+    match e {
+        butane::Error::UniqueViolation(details) => {
+            assert_eq!(lowercase(details.table), Some("foo".to_string()))
+        }
+        e => panic!("unexpected error {:?}", e),
+    }
 }
 testall!(basic_unique_field_error_on_non_unique);
@@ -1,19 +1,21 @@
 use chrono::{naive::NaiveDateTime, offset::Utc};
 use butane::prelude::*;
 use butane::{dataresult, model};
-use butane::{db::Connection, ForeignKey, Many, ObjectState};
+use butane::{db::Connection, BackRef, ForeignKey, Many, ObjectState};
 
 #[model]
 #[derive(Debug, Eq, PartialEq)]
 pub struct Blog {
     pub id: i64,
     pub name: String,
+    pub posts: BackRef<Post>,
 }
 impl Blog {
     pub fn new(id: i64, name: &str) -> Self {
         Blog {
             id,
             name: name.to_string(),
+            posts: BackRef::new(),
             state: ObjectState::default(),
         }
     }
@@ -60,11 +62,14 @@ pub struct PostMetadata {
 pub struct Tag {
     #[pk]
     pub tag: String,
+    #[backref = "tags"]
+    pub posts: BackRef<Post>,
 }
 impl Tag {
     pub fn new(tag: &str) -> Self {
         Tag {
             tag: tag.to_string(),
+            posts: BackRef::new(),
             state: ObjectState::default(),
         }
     }
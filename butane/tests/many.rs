@@ -0,0 +1,397 @@
+use butane::db::Connection;
+use butane::prelude::*;
+use butane::{model, BackRef, ForeignKey, Many, ObjectState};
+use paste;
+
+mod common;
+use common::blog::{Blog, Post, Tag};
+
+#[model]
+struct Article {
+    id: i64,
+    tags: Many<Tag, ArticleTag>,
+}
+impl Article {
+    fn new(id: i64) -> Self {
+        Article {
+            id,
+            tags: Many::new(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct ArticleTag {
+    #[auto]
+    id: i64,
+    owner: ForeignKey<Article>,
+    has: ForeignKey<Tag>,
+    note: Option<String>,
+}
+impl ArticleTag {
+    fn new(owner: &Article, has: &Tag, note: &str) -> Self {
+        ArticleTag {
+            id: -1,
+            owner: owner.into(),
+            has: has.into(),
+            note: Some(note.to_string()),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+#[table = "labels"]
+struct Label {
+    #[pk]
+    name: String,
+    #[backref = "labels"]
+    notes: BackRef<Note>,
+    #[backref = "linked_labels"]
+    linked_notes: BackRef<Note>,
+}
+impl Label {
+    fn new(name: &str) -> Self {
+        Label {
+            name: name.to_string(),
+            notes: BackRef::new(),
+            linked_notes: BackRef::new(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+#[table = "notes"]
+struct Note {
+    id: i64,
+    labels: Many<Label>,
+    linked_labels: Many<Label, NoteLabel>,
+}
+impl Note {
+    fn new(id: i64) -> Self {
+        Note {
+            id,
+            labels: Many::new(),
+            linked_labels: Many::new(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct NoteLabel {
+    #[auto]
+    id: i64,
+    owner: ForeignKey<Note>,
+    has: ForeignKey<Label>,
+}
+
+#[model(soft_delete)]
+struct Topic {
+    #[pk]
+    name: String,
+}
+impl Topic {
+    fn new(name: &str) -> Self {
+        Topic {
+            name: name.to_string(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct Thread {
+    id: i64,
+    pinned: ForeignKey<Topic>,
+    topics: Many<Topic>,
+}
+impl Thread {
+    fn new(id: i64, pinned: &Topic) -> Self {
+        Thread {
+            id,
+            pinned: pinned.into(),
+            topics: Many::new(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+#[model]
+struct Shelf {
+    #[pk]
+    tenant: i64,
+    #[pk]
+    id: i64,
+    tags: Many<Tag>,
+    #[references(tenant, id)]
+    neighbours: Many<Shelf>,
+}
+impl Shelf {
+    fn new(tenant: i64, id: i64) -> Self {
+        Shelf {
+            tenant,
+            id,
+            tags: Many::new(),
+            neighbours: Many::new(),
+            state: ObjectState::default(),
+        }
+    }
+}
+
+fn setup_post(conn: &Connection) -> (Post, Tag, Tag, Tag) {
+    let mut blog = Blog::new(1, "Cats");
+    blog.save(conn).unwrap();
+    let mut tag_a = Tag::new("a");
+    tag_a.save(conn).unwrap();
+    let mut tag_b = Tag::new("b");
+    tag_b.save(conn).unwrap();
+    let mut tag_c = Tag::new("c");
+    tag_c.save(conn).unwrap();
+
+    let mut post = Post::new(1, "The Tiger", "Tigers are cats", &blog);
+    post.tags.add(&tag_a);
+    post.tags.add(&tag_b);
+    post.save(conn).unwrap();
+    (post, tag_a, tag_b, tag_c)
+}
+
+fn tag_names(post: &Post, conn: &Connection) -> Vec<String> {
+    let mut tags: Vec<String> = post
+        .tags
+        .load(conn)
+        .unwrap()
+        .map(|tag| tag.tag.clone())
+        .collect();
+    tags.sort_unstable();
+    tags
+}
+
+fn remove(conn: Connection) {
+    let (mut post, tag_a, _, _) = setup_post(&conn);
+    post.tags.load(&conn).unwrap();
+    post.tags.remove(&tag_a);
+    // The loaded values reflect the removal before it is saved
+    assert_eq!(tag_names(&post, &conn), vec!["b"]);
+    assert_eq!(post.tags.count(&conn).unwrap(), 2);
+    post.save(&conn).unwrap();
+
+    let post = Post::get(&conn, 1).unwrap();
+    assert_eq!(tag_names(&post, &conn), vec!["b"]);
+    assert!(!post.tags.contains(&conn, &tag_a).unwrap());
+}
+testall!(remove);
+
+fn remove_then_add(conn: Connection) {
+    let (mut post, tag_a, _, tag_c) = setup_post(&conn);
+    post.tags.remove(&tag_a);
+    post.tags.add(&tag_a);
+    post.tags.add(&tag_c);
+    post.tags.remove(&tag_c);
+    post.save(&conn).unwrap();
+
+    let post = Post::get(&conn, 1).unwrap();
+    assert_eq!(tag_names(&post, &conn), vec!["a", "b"]);
+}
+testall!(remove_then_add);
+
+fn clear(conn: Connection) {
+    let (mut post, _, _, _) = setup_post(&conn);
+    post.tags.clear();
+    assert_eq!(post.tags.get().unwrap().count(), 0);
+    post.save(&conn).unwrap();
+
+    let post = Post::get(&conn, 1).unwrap();
+    assert_eq!(post.tags.count(&conn).unwrap(), 0);
+    assert!(tag_names(&post, &conn).is_empty());
+}
+testall!(clear);
+
+fn set(conn: Connection) {
+    let (mut post, _, tag_b, tag_c) = setup_post(&conn);
+    post.tags.set(vec![&tag_b, &tag_c]);
+    assert_eq!(tag_names(&post, &conn), vec!["b", "c"]);
+    post.save(&conn).unwrap();
+
+    let post = Post::get(&conn, 1).unwrap();
+    assert_eq!(tag_names(&post, &conn), vec!["b", "c"]);
+    assert_eq!(post.tags.count(&conn).unwrap(), 2);
+}
+testall!(set);
+
+fn reload(conn: Connection) {
+    let (mut post, _, _, tag_c) = setup_post(&conn);
+    assert_eq!(tag_names(&post, &conn), vec!["a", "b"]);
+    let mut other = Post::get(&conn, 1).unwrap();
+    other.tags.add(&tag_c);
+    other.save(&conn).unwrap();
+
+    post.reload(&conn).unwrap();
+    assert_eq!(tag_names(&post, &conn), vec!["a", "b", "c"]);
+}
+testall!(reload);
+
+fn contains(conn: Connection) {
+    let (post, tag_a, tag_b, tag_c) = setup_post(&conn);
+    assert!(post.tags.contains(&conn, &tag_a).unwrap());
+    assert!(post.tags.contains(&conn, &tag_b).unwrap());
+    assert!(!post.tags.contains(&conn, &tag_c).unwrap());
+}
+testall!(contains);
+
+fn through(conn: Connection) {
+    let (_, tag_a, tag_b, tag_c) = setup_post(&conn);
+    let mut article = Article::new(1);
+    article.tags.add(&tag_a);
+    article.save(&conn).unwrap();
+    ArticleTag::new(&article, &tag_b, "second")
+        .save(&conn)
+        .unwrap();
+
+    let article = Article::get(&conn, 1).unwrap();
+    let mut tags: Vec<String> = article
+        .tags
+        .load(&conn)
+        .unwrap()
+        .map(|tag| tag.tag.clone())
+        .collect();
+    tags.sort_unstable();
+    assert_eq!(tags, vec!["a", "b"]);
+    assert!(!article.tags.contains(&conn, &tag_c).unwrap());
+
+    let mut links: Vec<(Option<String>, String)> = article
+        .tags
+        .load_links(&conn)
+        .unwrap()
+        .into_iter()
+        .map(|(link, tag)| (link.note, tag.tag))
+        .collect();
+    links.sort_unstable();
+    assert_eq!(
+        links,
+        vec![
+            (None, "a".to_string()),
+            (Some("second".to_string()), "b".to_string())
+        ]
+    );
+}
+testall!(through);
+
+fn backref(conn: Connection) {
+    let mut label = Label::new("urgent");
+    label.save(&conn).unwrap();
+    let mut note1 = Note::new(1);
+    note1.labels.add(&label);
+    note1.save(&conn).unwrap();
+    let mut note2 = Note::new(2);
+    note2.linked_labels.add(&label);
+    note2.save(&conn).unwrap();
+
+    // The Many table of a model with a custom table name
+    let label = Label::get(&conn, "urgent".to_string()).unwrap();
+    let notes: Vec<i64> = label.notes.load(&conn).unwrap().map(|n| n.id).collect();
+    assert_eq!(notes, vec![1]);
+    // The table of a through model
+    let notes: Vec<i64> = label
+        .linked_notes
+        .load(&conn)
+        .unwrap()
+        .map(|n| n.id)
+        .collect();
+    assert_eq!(notes, vec![2]);
+}
+testall!(backref);
+
+fn soft_deleted_values(conn: Connection) {
+    let mut cats = Topic::new("cats");
+    cats.save(&conn).unwrap();
+    let mut dogs = Topic::new("dogs");
+    dogs.save(&conn).unwrap();
+    let mut thread = Thread::new(1, &cats);
+    thread.topics.add(&cats);
+    thread.topics.add(&dogs);
+    thread.save(&conn).unwrap();
+    cats.delete(&conn).unwrap();
+
+    let thread = Thread::get(&conn, 1).unwrap();
+    assert_eq!(thread.topics.count(&conn).unwrap(), 1);
+    assert!(!thread.topics.contains(&conn, &cats).unwrap());
+    assert!(thread.topics.contains(&conn, &dogs).unwrap());
+
+    let threads = Thread::query()
+        .prefetch(|thread| &thread.pinned)
+        .prefetch(|thread| &thread.topics)
+        .load(&conn)
+        .unwrap();
+    assert!(threads[0].pinned.get().is_err());
+    let topics: Vec<&str> = threads[0]
+        .topics
+        .get()
+        .unwrap()
+        .map(|topic| topic.name.as_str())
+        .collect();
+    assert_eq!(topics, vec!["dogs"]);
+}
+testall!(soft_deleted_values);
+
+fn composite_keys(conn: Connection) {
+    let (_, tag, _, _) = setup_post(&conn);
+    let mut shelf_a = Shelf::new(1, 1);
+    shelf_a.save(&conn).unwrap();
+    let mut shelf_b = Shelf::new(2, 1);
+    shelf_b.save(&conn).unwrap();
+    let mut shelf_c = Shelf::new(1, 2);
+    shelf_c.tags.add(&tag);
+    shelf_c.neighbours.add(&shelf_a);
+    shelf_c.neighbours.add(&shelf_b);
+    shelf_c.save(&conn).unwrap();
+
+    let shelf = Shelf::get(&conn, (1, 2)).unwrap();
+    let tags: Vec<&str> = shelf
+        .tags
+        .load(&conn)
+        .unwrap()
+        .map(|tag| tag.tag.as_str())
+        .collect();
+    assert_eq!(tags, vec![tag.tag.as_str()]);
+    let mut neighbours: Vec<(i64, i64)> = shelf
+        .neighbours
+        .load(&conn)
+        .unwrap()
+        .map(|shelf| (shelf.tenant, shelf.id))
+        .collect();
+    neighbours.sort_unstable();
+    assert_eq!(neighbours, vec![(1, 1), (2, 1)]);
+    assert_eq!(shelf.neighbours.count(&conn).unwrap(), 2);
+    assert!(shelf.neighbours.contains(&conn, &shelf_b).unwrap());
+    // Only the pair of key columns together identify a neighbour
+    assert!(!shelf.neighbours.contains(&conn, &Shelf::new(2, 2)).unwrap());
+    // Links are kept apart by the whole of the owner's key
+    assert_eq!(
+        Shelf::get(&conn, (1, 1))
+            .unwrap()
+            .neighbours
+            .count(&conn)
+            .unwrap(),
+        0
+    );
+
+    let mut shelf = Shelf::get(&conn, (1, 2)).unwrap();
+    shelf.neighbours.remove(&shelf_a);
+    shelf.save(&conn).unwrap();
+    let shelves = Shelf::query()
+        .prefetch(|shelf| &shelf.neighbours)
+        .load(&conn)
+        .unwrap();
+    let shelf = shelves.iter().find(|shelf| shelf.id == 2).unwrap();
+    let neighbours: Vec<(i64, i64)> = shelf
+        .neighbours
+        .get()
+        .unwrap()
+        .map(|shelf| (shelf.tenant, shelf.id))
+        .collect();
+    assert_eq!(neighbours, vec![(2, 1)]);
+}
+testall!(composite_keys);
@@ -1,9 +1,12 @@
 use butane::migrations::{
-    adb::DeferredSqlType, adb::TypeIdentifier, adb::TypeKey, MemMigrations, Migration,
+    adb::AConstraintKind, adb::AForeignKey, adb::ARef, adb::DeferredSqlType,
+    adb::ReferentialAction, adb::TypeIdentifier, adb::TypeKey, MemMigrations, Migration,
     MigrationMut, Migrations, MigrationsMut,
 };
 use butane::{db::Connection, prelude::*, SqlType, SqlVal};
-use butane_core::codegen::{butane_type_with_migrations, model_with_migrations};
+use butane_core::codegen::{
+    butane_type_with_migrations, model_with_args_and_migrations, model_with_migrations,
+};
 use proc_macro2::TokenStream;
 use quote::quote;
 
@@ -65,6 +68,31 @@ fn current_migration_pk_attribute() {
     assert_eq!(table.pk(), Some(pkcol))
 }
 
+#[test]
+fn current_migration_composite_pk() {
+    let tokens = quote! {
+        #[derive(PartialEq, Eq, Debug, Clone)]
+        struct Foo {
+            #[pk]
+            tenant: i64,
+            #[pk]
+            name: String,
+            bar: String,
+        }
+    };
+
+    let mut ms = MemMigrations::new();
+    model_with_migrations(tokens, &mut ms);
+    let m = ms.current();
+    let db = m.db().unwrap();
+    let table = db.get_table("Foo").expect("No Foo table");
+    let tenantcol = table.column("tenant").unwrap();
+    let namecol = table.column("name").unwrap();
+    assert!(!table.column("bar").unwrap().is_pk());
+
+    assert_eq!(table.pk_columns(), vec![tenantcol, namecol]);
+}
+
 #[test]
 fn current_migration_default_attribute() {
     let tokens = quote! {
@@ -162,6 +190,210 @@ fn current_migration_custom_type() {
     assert_eq!(col.typeid().unwrap(), TypeIdentifier::Ty(SqlType::Text));
 }
 
+#[test]
+fn current_migration_foreign_key() {
+    let tokens = quote! {
+        struct Foo {
+            id: i64,
+        }
+    };
+    let mut ms = MemMigrations::new();
+    model_with_migrations(tokens, &mut ms);
+
+    let tokens = quote! {
+        struct Bar {
+            id: i64,
+            #[on_delete(cascade)]
+            foo: ForeignKey<Foo>,
+            other: Option<ForeignKey<Foo>>,
+        }
+    };
+    model_with_migrations(tokens, &mut ms);
+
+    let m = ms.current();
+    let db = m.db().unwrap();
+    let table = db.get_table("Bar").expect("No Bar table");
+    let expected_ref = ARef::Literal {
+        table: "Foo".to_string(),
+        column: "id".to_string(),
+    };
+
+    let col = table.column("foo").unwrap();
+    let fk = col.foreign_key().expect("No foreign key on foo");
+    assert_eq!(fk.reference(), &expected_ref);
+    assert_eq!(fk.on_delete(), Some(ReferentialAction::Cascade));
+    assert_eq!(fk.on_update(), None);
+
+    let col = table.column("other").unwrap();
+    let fk = col.foreign_key().expect("No foreign key on other");
+    assert_eq!(fk.reference(), &expected_ref);
+    assert_eq!(fk.on_delete(), None);
+}
+
+#[test]
+fn current_migration_composite_foreign_key() {
+    let tokens = quote! {
+        struct Foo {
+            #[pk]
+            tenant: i64,
+            #[pk]
+            name: String,
+        }
+    };
+    let mut ms = MemMigrations::new();
+    model_with_migrations(tokens, &mut ms);
+
+    let tokens = quote! {
+        struct Bar {
+            id: i64,
+            #[references(tenant, name)]
+            #[on_delete(cascade)]
+            foo: ForeignKey<Foo>,
+            #[references(tenant, name)]
+            foos: Many<Foo>,
+        }
+    };
+    model_with_migrations(tokens, &mut ms);
+
+    let m = ms.current();
+    let db = m.db().unwrap();
+    let table = db.get_table("Bar").expect("No Bar table");
+    assert!(table.column("foo").is_none());
+    let col = table.column("foo_tenant").unwrap();
+    assert_eq!(col.typeid().unwrap(), TypeIdentifier::Ty(SqlType::BigInt));
+    let col = table.column("foo_name").unwrap();
+    assert_eq!(col.typeid().unwrap(), TypeIdentifier::Ty(SqlType::Text));
+    assert!(col.foreign_key().is_none());
+
+    let columns = vec!["foo_tenant".to_string(), "foo_name".to_string()];
+    let fk = AForeignKey::new(
+        ARef::Columns {
+            table: "Foo".to_string(),
+            columns: vec!["tenant".to_string(), "name".to_string()],
+        },
+        Some(ReferentialAction::Cascade),
+        None,
+    );
+    let constraint = table
+        .constraint("Bar_foo_tenant_foo_name_fkey")
+        .expect("No foreign key constraint");
+    assert_eq!(constraint.kind(), &AConstraintKind::ForeignKey(columns, fk));
+
+    let table = db.get_table("Bar_foos_Many").expect("No Many table");
+    let col = table.column("has_tenant").unwrap();
+    assert_eq!(col.typeid().unwrap(), TypeIdentifier::Ty(SqlType::BigInt));
+    let col = table.column("has_name").unwrap();
+    assert_eq!(col.typeid().unwrap(), TypeIdentifier::Ty(SqlType::Text));
+}
+
+#[test]
+fn current_migration_index() {
+    let tokens = quote! {
+        #[index(fields = "bar, baz")]
+        struct Foo {
+            id: i64,
+            #[index]
+            bar: String,
+            baz: i32,
+        }
+    };
+
+    let mut ms = MemMigrations::new();
+    model_with_migrations(tokens, &mut ms);
+    let m = ms.current();
+    let db = m.db().unwrap();
+    let table = db.get_table("Foo").expect("No Foo table");
+    assert_eq!(table.indexes.len(), 2);
+    let index = table.index("Foo_bar_idx").expect("No bar index");
+    assert_eq!(index.columns(), &["bar".to_string()]);
+    let index = table.index("Foo_bar_baz_idx").expect("No bar, baz index");
+    assert_eq!(index.columns(), &["bar".to_string(), "baz".to_string()]);
+}
+
+#[test]
+fn current_migration_soft_delete() {
+    let tokens = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+        }
+    };
+
+    let mut ms = MemMigrations::new();
+    model_with_args_and_migrations(quote!(soft_delete), tokens, &mut ms);
+    let m = ms.current();
+    let db = m.db().unwrap();
+    let table = db.get_table("Foo").expect("No Foo table");
+    let col = table.column("deleted_at").expect("No deleted_at column");
+    assert_eq!(
+        col.typeid().unwrap(),
+        TypeIdentifier::Ty(SqlType::Timestamp)
+    );
+    assert!(col.nullable());
+    assert!(col.default().is_none());
+}
+
+#[test]
+fn current_migration_auto_now() {
+    let tokens = quote! {
+        struct Foo {
+            id: i64,
+            #[auto_now_add]
+            created: NaiveDateTime,
+            #[auto_now]
+            updated: NaiveDateTime,
+            bar: String,
+        }
+    };
+
+    let mut ms = MemMigrations::new();
+    model_with_migrations(tokens, &mut ms);
+    let m = ms.current();
+    let db = m.db().unwrap();
+    let table = db.get_table("Foo").expect("No Foo table");
+    for name in &["created", "updated"] {
+        let col = table.column(name).expect("No timestamp column");
+        assert_eq!(
+            col.typeid().unwrap(),
+            TypeIdentifier::Ty(SqlType::Timestamp)
+        );
+        assert!(col.default_now());
+    }
+    assert!(!table.column("bar").unwrap().default_now());
+}
+
+#[test]
+fn current_migration_constraints() {
+    let tokens = quote! {
+        #[unique(fields = "bar, baz")]
+        #[check("baz >= 0")]
+        struct Foo {
+            id: i64,
+            bar: String,
+            baz: i32,
+        }
+    };
+
+    let mut ms = MemMigrations::new();
+    model_with_migrations(tokens, &mut ms);
+    let m = ms.current();
+    let db = m.db().unwrap();
+    let table = db.get_table("Foo").expect("No Foo table");
+    assert_eq!(table.constraints.len(), 2);
+    let constraint = table
+        .constraint("Foo_bar_baz_key")
+        .expect("No bar, baz unique constraint");
+    assert_eq!(
+        constraint.kind(),
+        &AConstraintKind::Unique(vec!["bar".to_string(), "baz".to_string()])
+    );
+    let constraint = table.constraint("Foo_check0").expect("No check constraint");
+    assert_eq!(
+        constraint.kind(),
+        &AConstraintKind::Check("baz >= 0".to_string())
+    );
+}
+
 #[cfg(feature = "sqlite")]
 #[test]
 fn migration_add_field_sqlite() {
@@ -259,6 +491,263 @@ fn migration_delete_table_pg() {
     );
 }
 
+#[cfg(feature = "sqlite")]
+#[test]
+fn migration_add_table_with_foreign_key_sqlite() {
+    migration_add_table_with_foreign_key(
+        &mut common::sqlite_connection(),
+        "CREATE TABLE Baz (id INTEGER NOT NULL PRIMARY KEY,foo INTEGER NOT NULL REFERENCES Foo(id) ON DELETE CASCADE);",
+        "DROP TABLE Baz;",
+    );
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_add_table_with_foreign_key_pg() {
+    let (mut conn, _data) = common::pg_connection();
+    migration_add_table_with_foreign_key(
+        &mut conn,
+        "CREATE TABLE Baz (id BIGINT NOT NULL PRIMARY KEY,foo BIGINT NOT NULL CONSTRAINT Baz_foo_fkey REFERENCES Foo(id) ON DELETE CASCADE);",
+        "DROP TABLE Baz;",
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn migration_add_index_sqlite() {
+    migration_add_index(
+        &mut common::sqlite_connection(),
+        "CREATE INDEX Foo_bar_idx ON Foo (bar);",
+        "DROP INDEX Foo_bar_idx;",
+    );
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_add_index_pg() {
+    let (mut conn, _data) = common::pg_connection();
+    migration_add_index(
+        &mut conn,
+        "CREATE INDEX Foo_bar_idx ON Foo (bar);",
+        "DROP INDEX Foo_bar_idx;",
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn migration_add_constraints_sqlite() {
+    migration_add_constraints(
+        &mut common::sqlite_connection(),
+        // See comments on migration_add_field_sqlite
+        "CREATE TABLE Foo__butane_tmp (id INTEGER NOT NULL PRIMARY KEY,bar TEXT NOT NULL,baz INTEGER NOT NULL,CONSTRAINT Foo_bar_baz_key UNIQUE (bar, baz));INSERT INTO Foo__butane_tmp SELECT id, bar, baz FROM Foo;DROP TABLE Foo;ALTER TABLE Foo__butane_tmp RENAME TO Foo;CREATE TABLE Foo__butane_tmp (id INTEGER NOT NULL PRIMARY KEY,bar TEXT NOT NULL,baz INTEGER NOT NULL,CONSTRAINT Foo_bar_baz_key UNIQUE (bar, baz),CONSTRAINT Foo_check0 CHECK (baz >= 0));INSERT INTO Foo__butane_tmp SELECT id, bar, baz FROM Foo;DROP TABLE Foo;ALTER TABLE Foo__butane_tmp RENAME TO Foo;",
+        "CREATE TABLE Foo__butane_tmp (id INTEGER NOT NULL PRIMARY KEY,bar TEXT NOT NULL,baz INTEGER NOT NULL,CONSTRAINT Foo_check0 CHECK (baz >= 0));INSERT INTO Foo__butane_tmp SELECT id, bar, baz FROM Foo;DROP TABLE Foo;ALTER TABLE Foo__butane_tmp RENAME TO Foo;CREATE TABLE Foo__butane_tmp (id INTEGER NOT NULL PRIMARY KEY,bar TEXT NOT NULL,baz INTEGER NOT NULL);INSERT INTO Foo__butane_tmp SELECT id, bar, baz FROM Foo;DROP TABLE Foo;ALTER TABLE Foo__butane_tmp RENAME TO Foo;",
+    );
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_add_constraints_pg() {
+    let (mut conn, _data) = common::pg_connection();
+    migration_add_constraints(
+        &mut conn,
+        "ALTER TABLE Foo ADD CONSTRAINT Foo_bar_baz_key UNIQUE (bar, baz);ALTER TABLE Foo ADD CONSTRAINT Foo_check0 CHECK (baz >= 0);",
+        "ALTER TABLE Foo DROP CONSTRAINT Foo_bar_baz_key;ALTER TABLE Foo DROP CONSTRAINT Foo_check0;",
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn migration_remove_indexed_field_sqlite() {
+    migration_remove_indexed_field(
+        &mut common::sqlite_connection(),
+        // See comments on migration_add_field_sqlite
+        "DROP INDEX Foo_baz_idx;CREATE TABLE Foo__butane_tmp (id INTEGER NOT NULL PRIMARY KEY,bar TEXT NOT NULL);INSERT INTO Foo__butane_tmp SELECT id, bar FROM Foo;DROP TABLE Foo;ALTER TABLE Foo__butane_tmp RENAME TO Foo;CREATE INDEX Foo_bar_idx ON Foo (bar);",
+        "ALTER TABLE Foo ADD COLUMN baz INTEGER NOT NULL DEFAULT 0;CREATE INDEX Foo_baz_idx ON Foo (baz);",
+    );
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_remove_indexed_field_pg() {
+    let (mut conn, _data) = common::pg_connection();
+    migration_remove_indexed_field(
+        &mut conn,
+        "DROP INDEX Foo_baz_idx;ALTER TABLE Foo DROP COLUMN baz;",
+        "ALTER TABLE Foo ADD COLUMN baz BIGINT NOT NULL DEFAULT 0;CREATE INDEX Foo_baz_idx ON Foo (baz);",
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn migration_rename_field_sqlite() {
+    migration_rename_field(
+        &mut common::sqlite_connection(),
+        "ALTER TABLE Foo RENAME COLUMN bar TO baz;",
+        "ALTER TABLE Foo RENAME COLUMN baz TO bar;",
+    );
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_rename_field_pg() {
+    let (mut conn, _data) = common::pg_connection();
+    migration_rename_field(
+        &mut conn,
+        "ALTER TABLE Foo RENAME COLUMN bar TO baz;",
+        "ALTER TABLE Foo RENAME COLUMN baz TO bar;",
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn migration_rename_table_sqlite() {
+    migration_rename_table(
+        &mut common::sqlite_connection(),
+        "ALTER TABLE Foo RENAME TO Bar;",
+        "ALTER TABLE Bar RENAME TO Foo;",
+    );
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_rename_table_pg() {
+    let (mut conn, _data) = common::pg_connection();
+    migration_rename_table(
+        &mut conn,
+        "ALTER TABLE Foo RENAME TO Bar;",
+        "ALTER TABLE Bar RENAME TO Foo;",
+    );
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn migration_rebuild_keeps_referencing_rows_sqlite() {
+    let mut conn = common::sqlite_connection();
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+        }
+    };
+    let mut ms = MemMigrations::new();
+    let backend = conn.backend();
+    model_with_migrations(init, &mut ms);
+    let baz = quote! {
+        struct Baz {
+            id: i64,
+            #[on_delete(cascade)]
+            foo: ForeignKey<Foo>,
+        }
+    };
+    model_with_migrations(baz, &mut ms);
+    assert!(ms.create_migration(&backend, "init", None).unwrap());
+    for m in ms.unapplied_migrations(&conn).unwrap() {
+        m.apply(&mut conn).unwrap();
+    }
+    conn.execute("INSERT INTO Foo (id, bar) VALUES (1, 'a');")
+        .unwrap();
+    conn.execute("INSERT INTO Baz (id, foo) VALUES (1, 1);")
+        .unwrap();
+
+    // Removing a column rebuilds Foo, dropping the old table
+    let v2 = quote! {
+        struct Foo {
+            id: i64,
+        }
+    };
+    model_with_migrations(v2, &mut ms);
+    assert!(ms
+        .create_migration(&backend, "v2", ms.latest().as_ref())
+        .unwrap());
+    for m in ms.unapplied_migrations(&conn).unwrap() {
+        m.apply(&mut conn).unwrap();
+    }
+    assert_eq!(count_rows(&conn, "Foo"), 1);
+    assert_eq!(count_rows(&conn, "Baz"), 1);
+
+    // Foreign keys are enforced again once the migration is done
+    let e = conn
+        .execute("INSERT INTO Baz (id, foo) VALUES (2, 2);")
+        .unwrap_err();
+    assert!(matches!(e, butane::Error::ForeignKeyViolation(_)));
+}
+
+#[cfg(feature = "sqlite")]
+fn count_rows(conn: &Connection, table: &str) -> usize {
+    use butane::db::{BackendRows, Column, ConnectionMethods};
+    let columns = [Column::new("id", SqlType::BigInt)];
+    let mut rows = conn.query(table, &columns, None, None, None, None).unwrap();
+    let mut count = 0;
+    while rows.next().unwrap().is_some() {
+        count += 1;
+    }
+    count
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_rename_table_and_change_column_pg() {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            bar: i32,
+        }
+    };
+    let v2 = quote! {
+        #[renamed_from("Foo")]
+        struct Bar {
+            id: i64,
+            bar: i64,
+        }
+    };
+    let up_sql = pg_up_sql(init, v2);
+    // The column is changed in the renamed table
+    assert!(up_sql.starts_with("ALTER TABLE Foo RENAME TO Bar;"));
+    assert!(up_sql.contains("CREATE TABLE Bar__butane_tmp"));
+    assert!(up_sql.contains("bar BIGINT NOT NULL"));
+    assert!(up_sql.contains("ALTER TABLE Bar__butane_tmp RENAME TO Bar;"));
+}
+
+#[cfg(feature = "pg")]
+#[test]
+fn migration_remove_index_and_change_column_pg() {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            #[index]
+            bar: String,
+            baz: i32,
+        }
+    };
+    let v2 = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+            baz: i64,
+        }
+    };
+    let up_sql = pg_up_sql(init, v2);
+    // Rebuilding Foo must not recreate the index just removed
+    assert!(up_sql.contains("DROP INDEX Foo_bar_idx;"));
+    assert!(up_sql.contains("CREATE TABLE Foo__butane_tmp"));
+    assert!(!up_sql.contains("CREATE INDEX Foo_bar_idx"));
+}
+
+/// Generates the SQL to migrate from `init` to `v2` on Postgres,
+/// without applying it.
+#[cfg(feature = "pg")]
+fn pg_up_sql(init: TokenStream, v2: TokenStream) -> String {
+    let backend = butane::db::get_backend("pg").unwrap();
+    let mut ms = MemMigrations::new();
+    model_with_migrations(init, &mut ms);
+    assert!(ms.create_migration(&backend, "init", None).unwrap());
+    model_with_migrations(v2, &mut ms);
+    assert!(ms
+        .create_migration(&backend, "v2", ms.latest().as_ref())
+        .unwrap());
+    ms.latest().unwrap().up_sql("pg").unwrap().unwrap()
+}
+
 fn test_migrate(
     conn: &mut Connection,
     init_tokens: TokenStream,
@@ -391,3 +880,118 @@ fn migration_delete_table(conn: &mut Connection, expected_up_sql: &str, expected
         m.downgrade(conn).unwrap();
     }
 }
+
+fn migration_add_table_with_foreign_key(conn: &mut Connection, up_sql: &str, down_sql: &str) {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+        }
+    };
+
+    // Foo remains in the current migration, so only Baz is added
+    let v2 = quote! {
+        struct Baz {
+            id: i64,
+            #[on_delete(cascade)]
+            foo: ForeignKey<Foo>,
+        }
+    };
+    test_migrate(conn, init, v2, up_sql, down_sql);
+}
+
+fn migration_add_index(conn: &mut Connection, up_sql: &str, down_sql: &str) {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+        }
+    };
+
+    let v2 = quote! {
+        struct Foo {
+            id: i64,
+            #[index]
+            bar: String,
+        }
+    };
+    test_migrate(conn, init, v2, up_sql, down_sql);
+}
+
+fn migration_add_constraints(conn: &mut Connection, up_sql: &str, down_sql: &str) {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+            baz: u32,
+        }
+    };
+
+    let v2 = quote! {
+        #[unique(fields = "bar, baz")]
+        #[check("baz >= 0")]
+        struct Foo {
+            id: i64,
+            bar: String,
+            baz: u32,
+        }
+    };
+    test_migrate(conn, init, v2, up_sql, down_sql);
+}
+
+fn migration_remove_indexed_field(conn: &mut Connection, up_sql: &str, down_sql: &str) {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            #[index]
+            bar: String,
+            #[index]
+            baz: u32,
+        }
+    };
+
+    let v2 = quote! {
+        struct Foo {
+            id: i64,
+            #[index]
+            bar: String,
+        }
+    };
+    test_migrate(conn, init, v2, up_sql, down_sql);
+}
+
+fn migration_rename_field(conn: &mut Connection, up_sql: &str, down_sql: &str) {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+        }
+    };
+
+    let v2 = quote! {
+        struct Foo {
+            id: i64,
+            #[renamed_from("bar")]
+            baz: String,
+        }
+    };
+    test_migrate(conn, init, v2, up_sql, down_sql);
+}
+
+fn migration_rename_table(conn: &mut Connection, up_sql: &str, down_sql: &str) {
+    let init = quote! {
+        struct Foo {
+            id: i64,
+            bar: String,
+        }
+    };
+
+    let v2 = quote! {
+        #[renamed_from("Foo")]
+        struct Bar {
+            id: i64,
+            bar: String,
+        }
+    };
+    test_migrate(conn, init, v2, up_sql, down_sql);
+}
@@ -1,7 +1,7 @@
 use butane::db::Connection;
 use butane::prelude::*;
-use butane::query::BoolExpr;
-use butane::{colname, filter, find, query, Many};
+use butane::query::{BoolExpr, FallibleIterator};
+use butane::{colname, filter, find, query, Error, Many, ToSql};
 use chrono::{TimeZone, Utc};
 use paste;
 use serde_json;
@@ -166,6 +166,250 @@ fn many_objects_with_tag_explicit(conn: Connection) {
 }
 testall!(many_objects_with_tag_explicit);
 
+fn backref_foreign_key(conn: Connection) {
+    blog::setup_blog(&conn);
+    let blog: Blog = find!(Blog, name == "Cats", &conn).unwrap();
+    assert!(blog.posts.get().is_err());
+    let mut posts: Vec<&Post> = blog.posts.load(&conn).unwrap().collect();
+    posts.sort_by(|p1, p2| p1.id.partial_cmp(&p2.id).unwrap());
+    assert_eq!(posts.len(), 2);
+    assert_eq!(posts[0].title, "The Tiger");
+    assert_eq!(posts[1].title, "Sir Charles");
+    assert_eq!(blog.posts.get().unwrap().count(), 2);
+}
+testall!(backref_foreign_key);
+
+fn backref_many(conn: Connection) {
+    blog::setup_blog(&conn);
+    let tag: Tag = find!(Tag, tag == "danger", &conn).unwrap();
+    let mut posts: Vec<&Post> = tag.posts.load(&conn).unwrap().collect();
+    posts.sort_by(|p1, p2| p1.id.partial_cmp(&p2.id).unwrap());
+    assert_eq!(posts.len(), 3);
+    assert_eq!(posts[0].title, "The Tiger");
+    assert_eq!(posts[1].title, "Mount Doom");
+    assert_eq!(posts[2].title, "Mt. Everest");
+}
+testall!(backref_many);
+
+fn backref_after_save(conn: Connection) {
+    let mut blog = Blog::new(1, "Birds");
+    blog.save(&conn).unwrap();
+    let mut post = Post::new(1, "Puffins", "Puffins are cute.", &blog);
+    post.save(&conn).unwrap();
+    let posts: Vec<&Post> = blog.posts.load(&conn).unwrap().collect();
+    assert_eq!(posts.len(), 1);
+    assert_eq!(posts[0].title, "Puffins");
+}
+testall!(backref_after_save);
+
+fn count_and_exists(conn: Connection) {
+    blog::setup_blog(&conn);
+    assert_eq!(Post::query().count(&conn).unwrap(), 4);
+    assert_eq!(query!(Post, published == true).count(&conn).unwrap(), 3);
+    assert!(query!(Post, title == "Mount Doom").exists(&conn).unwrap());
+    assert!(!query!(Post, title == "Mount Kilimanjaro")
+        .exists(&conn)
+        .unwrap());
+    assert_eq!(query!(Post, likes > 100).count(&conn).unwrap(), 0);
+    // The count is of the objects the query would load
+    assert_eq!(Post::query().limit(2).count(&conn).unwrap(), 2);
+    assert_eq!(Post::query().offset(3).count(&conn).unwrap(), 1);
+    assert_eq!(Post::query().offset(3).limit(2).count(&conn).unwrap(), 1);
+    assert_eq!(Post::query().offset(5).count(&conn).unwrap(), 0);
+}
+testall!(count_and_exists);
+
+fn aggregates(conn: Connection) {
+    blog::setup_blog(&conn);
+    let likes = Post::fields().likes();
+    assert_eq!(Post::query().sum(likes, &conn).unwrap(), Some(34));
+    let avg = query!(Post, published == true)
+        .avg(Post::fields().likes(), &conn)
+        .unwrap()
+        .unwrap();
+    assert!((avg - 34.0 / 3.0).abs() < 1e-9);
+    assert_eq!(
+        Post::query().min(Post::fields().likes(), &conn).unwrap(),
+        Some(0)
+    );
+    assert_eq!(
+        Post::query().max(Post::fields().title(), &conn).unwrap(),
+        Some("The Tiger".to_string())
+    );
+    // Aggregates over no rows have no value
+    assert_eq!(
+        query!(Post, likes > 100)
+            .sum(Post::fields().likes(), &conn)
+            .unwrap(),
+        None
+    );
+    let e = Post::query()
+        .limit(2)
+        .sum(Post::fields().likes(), &conn)
+        .unwrap_err();
+    assert!(matches!(e, Error::LimitUnsupported(_)));
+}
+testall!(aggregates);
+
+fn sum_does_not_overflow(conn: Connection) {
+    blog::setup_blog(&conn);
+    let cnt = Post::query()
+        .update(&conn, vec![Post::fields().likes().set(i32::MAX)])
+        .unwrap();
+    assert_eq!(cnt, 4);
+    let sum: Option<i64> = Post::query().sum(Post::fields().likes(), &conn).unwrap();
+    assert_eq!(sum, Some(4 * i32::MAX as i64));
+}
+testall!(sum_does_not_overflow);
+
+fn group_by(conn: Connection) {
+    blog::setup_blog(&conn);
+    let counts = Post::query()
+        .group_by(Post::fields().blog())
+        .count(&conn)
+        .unwrap();
+    let counts: Vec<(i64, i64)> = counts
+        .into_iter()
+        .map(|(blog, count)| (blog.pk(), count))
+        .collect();
+    assert_eq!(counts, vec![(1, 2), (2, 2)]);
+
+    let likes = query!(Post, published == true)
+        .group_by(Post::fields().blog())
+        .sum(Post::fields().likes(), &conn)
+        .unwrap();
+    let likes: Vec<(i64, Option<i64>)> = likes
+        .into_iter()
+        .map(|(blog, likes)| (blog.pk(), likes))
+        .collect();
+    assert_eq!(likes, vec![(1, Some(24)), (2, Some(10))]);
+}
+testall!(group_by);
+
+fn update(conn: Connection) {
+    blog::setup_blog(&conn);
+    let cnt = query!(Post, published == true)
+        .update(
+            &conn,
+            vec![
+                Post::fields().likes().set(0),
+                Post::fields().title().set("Redacted"),
+            ],
+        )
+        .unwrap();
+    assert_eq!(cnt, 3);
+    let posts = query!(Post, title == "Redacted").load(&conn).unwrap();
+    assert_eq!(posts.len(), 3);
+    assert!(posts.iter().all(|post| post.likes == 0 && post.published));
+
+    // The unpublished post is unchanged
+    let post = find!(Post, published == false, &conn).unwrap();
+    assert_eq!(post.title, "Mt. Everest");
+}
+testall!(update);
+
+fn iter(conn: Connection) {
+    blog::setup_blog(&conn);
+    let mut posts = query!(Post, published == true)
+        .order_asc(colname!(Post, id))
+        .iter(&conn)
+        .unwrap();
+    assert_eq!(posts.next().unwrap().unwrap().title, "The Tiger");
+    let titles: Vec<String> = posts.map(|post| Ok(post.title)).collect().unwrap();
+    assert_eq!(titles, vec!["Sir Charles", "Mount Doom"]);
+}
+testall!(iter);
+
+fn query_while_reading_rows(conn: Connection) {
+    use butane::db::{BackendRows, ConnectionMethods};
+    blog::setup_blog(&conn);
+    let mut rows = conn
+        .query(Post::TABLE, Post::COLUMNS, None, None, None, None)
+        .unwrap();
+    let mut count = 0;
+    // The rows of a plain query don't hold on to the connection
+    while rows.next().unwrap().is_some() {
+        count += 1;
+        let published = query!(Post, published == true).load(&conn).unwrap();
+        assert_eq!(published.len(), 3);
+    }
+    assert_eq!(count, 4);
+}
+testall!(query_while_reading_rows);
+
+fn prepared(conn: Connection) {
+    blog::setup_blog(&conn);
+    let prepared = query!(Post, published == true && title == placeholder!())
+        .prepare(&conn)
+        .unwrap();
+    // Run more than once to exercise any cached statement
+    for title in &["The Tiger", "Mount Doom"] {
+        let posts = prepared.load(&conn, &[title.to_sql()]).unwrap();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].title, *title);
+    }
+    let post = prepared
+        .load_first(&conn, &["Nonexistent".to_sql()])
+        .unwrap();
+    assert!(post.is_none());
+
+    let prepared = query!(Post, likes > placeholder!() && title.like(placeholder!()))
+        .prepare(&conn)
+        .unwrap();
+    let posts = prepared
+        .load(&conn, &[0.to_sql(), "%Tiger".to_sql()])
+        .unwrap();
+    assert_eq!(posts.len(), 1);
+    assert_eq!(posts[0].title, "The Tiger");
+
+    match prepared.load(&conn, &[0.to_sql()]) {
+        Err(Error::WrongParameterCount { expected, found }) => {
+            assert_eq!(expected, 2);
+            assert_eq!(found, 1);
+        }
+        _ => panic!("expected WrongParameterCount"),
+    }
+}
+testall!(prepared);
+
+fn prefetch(conn: Connection) {
+    blog::setup_blog(&conn);
+    let posts = Post::query()
+        .order_asc(colname!(Post, id))
+        .prefetch(|post| &post.blog)
+        .prefetch(|post| &post.tags)
+        .load(&conn)
+        .unwrap();
+    assert_eq!(posts.len(), 4);
+    let blogs: Vec<&str> = posts
+        .iter()
+        .map(|post| post.blog.get().unwrap().name.as_str())
+        .collect();
+    assert_eq!(blogs, vec!["Cats", "Cats", "Mountains", "Mountains"]);
+    let mut tags: Vec<&str> = posts[0]
+        .tags
+        .get()
+        .unwrap()
+        .map(|tag| tag.tag.as_str())
+        .collect();
+    tags.sort_unstable();
+    assert_eq!(tags, vec!["asia", "danger"]);
+    assert_eq!(posts[1].tags.get().unwrap().count(), 0);
+    assert_eq!(posts[3].tags.get().unwrap().count(), 1);
+
+    let post = query!(Post, title == "Mount Doom")
+        .prefetch(|post| &post.blog)
+        .load_first(&conn)
+        .unwrap()
+        .unwrap();
+    assert_eq!(post.blog.get().unwrap().name, "Mountains");
+
+    // Prefetching is not supported while iterating
+    let result = Post::query().prefetch(|post| &post.blog).iter(&conn);
+    assert!(matches!(result, Err(Error::PrefetchUnsupported(_))));
+}
+testall!(prefetch);
+
 fn by_timestamp(conn: Connection) {
     blog::setup_blog(&conn);
     let mut post = find!(Post, title == "Sir Charles", &conn).unwrap();
@@ -39,8 +39,18 @@ fn handle_block(block: &syn::Block) -> TokenStream2 {
     quote!(#(#stmts)*)
 }
 
+fn is_placeholder(expr: &Expr) -> bool {
+    match expr {
+        Expr::Macro(mac) => mac.mac.path.is_ident("placeholder"),
+        _ => false,
+    }
+}
+
 fn handle_bin_op(fields: &impl ToTokens, binop: &ExprBinary) -> TokenStream2 {
     let left = handle_expr(fields, &binop.left);
+    if is_placeholder(&binop.right) {
+        return handle_placeholder_op(&left, &binop.op);
+    }
     let right = handle_expr(fields, &binop.right);
     match binop.op {
         BinOp::Eq(_) => quote!(#left.eq(&#right)),
@@ -55,6 +65,20 @@ fn handle_bin_op(fields: &impl ToTokens, binop: &ExprBinary) -> TokenStream2 {
     }
 }
 
+fn handle_placeholder_op(left: &TokenStream2, op: &BinOp) -> TokenStream2 {
+    // The placeholder has no value to typecheck against the field
+    let variant = match op {
+        BinOp::Eq(_) => ident("Eq"),
+        BinOp::Ne(_) => ident("Ne"),
+        BinOp::Lt(_) => ident("Lt"),
+        BinOp::Gt(_) => ident("Gt"),
+        BinOp::Le(_) => ident("Le"),
+        BinOp::Ge(_) => ident("Ge"),
+        _ => return quote!(compile_error!("Unsupported binary operator")),
+    };
+    quote!(butane::query::BoolExpr::#variant(#left.name(), butane::query::Expr::Placeholder))
+}
+
 fn handle_call(fields: &impl ToTokens, mcall: &ExprMethodCall) -> TokenStream2 {
     let method = mcall.method.to_string();
     match method.as_str() {
@@ -110,6 +134,9 @@ fn handle_like(fields: &impl ToTokens, receiver: &Expr, expr: &Expr) -> TokenStr
     match expr {
         Expr::Binary(_) => make_compile_error!("Unexpected binary expression as parameter to like"),
         Expr::Call(_) => make_compile_error!("Unexpected call expression as parameter to like"),
+        _ if is_placeholder(expr) => {
+            quote!(butane::query::BoolExpr::Like(#fex.name(), butane::query::Expr::Placeholder))
+        }
         _ => {
             // Arbitrary expression
             let q = handle_expr(fields, expr);
@@ -19,20 +19,59 @@ mod filter;
 /// generate migrations
 ///
 /// ## Restrictions on model types:
-/// 1. The type of each field must implement [`FieldType`] or be [`Many`].
+/// 1. The type of each field must implement [`FieldType`] or be [`Many`] or [`BackRef`].
 /// 2. There must be a primary key field. This must be either annotated with a `#[pk]` attribute or named `id`.
 ///
+/// ## Options
+/// * `#[model(soft_delete)]` adds a nullable `deleted_at` timestamp column to the table (requires the
+///    `datetime` feature). Deleting an object, or objects matched by a query, sets `deleted_at`
+///    rather than removing them. Queries do not match soft deleted objects unless
+///    [`with_deleted`](butane_core::query::Query::with_deleted) or
+///    [`only_deleted`](butane_core::query::Query::only_deleted) is used, and
+///    [`SoftDelete::restore`](butane_core::SoftDelete::restore) undoes the deletion.
+///
 /// ## Helper Attributes
 /// * `#[table = "NAME"]` used on the struct to specify the name of the table (defaults to struct name)
-/// * `#[pk]` on a field to specify that it is the primary key.
+/// * `#[index(fields = "a, b")]` used on the struct to create an index on multiple fields. May be repeated.
+/// * `#[unique(fields = "a, b")]` used on the struct to require that no two objects have the same values
+///    for all of the fields. May be repeated.
+/// * `#[check("EXPR")]` used on the struct to add an SQL check constraint, such as `#[check("likes >= 0")]`,
+///    which every row must satisfy. May be repeated.
+/// * `#[renamed_from("OLD")]` used on the struct when the table was previously named `OLD`, so that
+///    migrations rename the table rather than dropping it and creating a new one.
+/// * `#[pk]` on a field to specify that it is the primary key. If
+///    several fields have it, together they form a composite primary key
+///    whose type is a tuple of their types, in order. A model with a
+///    composite primary key may not have `BackRef` fields.
 /// * `#[auto]` on a field indicates that the field's value is
 ///    initialized based on serial/autoincrement. Currently supported
 ///    only on the primary key and only if the primary key is an integer
 ///    type
 /// * `#[unique]` on a field indicates that the field's value must be unique
 ///    (perhaps implemented as the SQL UNIQUE constraint by some backends).
+/// * `#[index]` on a field creates an index on its column, to speed up queries filtering or ordering by it.
+/// * `#[renamed_from("old")]` on a field whose name was previously `old`, so that migrations rename the
+///    column and preserve its data.
 /// * `[default]` should be used on fields added by later migrations to avoid errors on existing objects.
 ///     Unnecessary if the new field is an `Option<>`
+/// * `#[auto_now_add]` on a `NaiveDateTime` (or `Option<NaiveDateTime>`) field sets it to the current
+///    time when the object is first saved, and `#[auto_now]` sets it every time the object is saved.
+///    When such a field is added by a later migration, existing objects are given the time the
+///    migration was created. Requires the `datetime` feature.
+/// * `#[version]` on an integer field enables optimistic locking. Saving an object which is already in
+///    the database only updates it if its version is unchanged in the database, and increments the
+///    version. If another save has incremented it (or the object has been deleted) since this object
+///    was loaded, `save` fails with [`Error::StaleObject`](butane_core::Error::StaleObject).
+/// * `#[on_delete(ACTION)]` and `#[on_update(ACTION)]` on a `ForeignKey` field specify what happens to
+///    this object when the object it references is deleted or has its primary key changed. `ACTION`
+///    is one of `cascade`, `restrict`, `set_null` (only for `Option<ForeignKey>`), or `no_action`.
+///    Without these, the backend's default (no action) applies.
+/// * `#[references(a, b)]` on a `ForeignKey`, `Option<ForeignKey>` or `Many` field referring to a model
+///    with a composite primary key names the columns of that key, in order. The field is stored in a
+///    column for each, such as `order_a` and `order_b` for a field `order`, which together form a
+///    foreign key. Such fields cannot be used in `filter!`.
+/// * `#[backref = "field"]` on a `BackRef<T>` field names the `ForeignKey` or `Many` field of `T` which
+///    refers to this model. Defaults to the snake_case name of this model.
 ///
 /// For example
 /// ```ignore
@@ -52,9 +91,11 @@ mod filter;
 ///
 /// [`FieldType`]: crate::FieldType
 /// [`Many`]: butane_core::many::Many
+/// [`BackRef`]: butane_core::backref::BackRef
 #[proc_macro_attribute]
-pub fn model(_args: TokenStream, input: TokenStream) -> TokenStream {
-    codegen::model_with_migrations(input.into(), &mut migrations_for_dir()).into()
+pub fn model(args: TokenStream, input: TokenStream) -> TokenStream {
+    codegen::model_with_args_and_migrations(args.into(), input.into(), &mut migrations_for_dir())
+        .into()
 }
 
 /// Attribute macro which generates an implementation of
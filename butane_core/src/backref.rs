@@ -0,0 +1,141 @@
+use crate::db::ConnectionMethods;
+use crate::query::{BoolExpr, Expr};
+use crate::{DataObject, Error, Result, SqlVal};
+use once_cell::unsync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+fn default_oc<T>() -> OnceCell<Vec<T>> {
+    OnceCell::default()
+}
+
+/// Used to implement the reverse side of a relationship between models.
+///
+/// A `BackRef<T>` field holds the objects of type T which refer to
+/// this object, either through a [`ForeignKey`] field or by including
+/// it in a [`Many`] field. It does not correspond to any column or
+/// table of its own. The field of T which refers to this object is
+/// given with the `#[backref = "field"]` attribute, or defaults to
+/// the snake_case name of this model.
+///
+/// # Examples
+/// ```ignore
+/// #[model]
+/// struct Blog {
+///   ...
+///   #[backref = "blog"]
+///   posts: BackRef<Post>,
+/// }
+/// #[model]
+/// struct Post {
+///   blog: ForeignKey<Blog>,
+///   tags: Many<Tag>,
+///   ...
+/// }
+/// #[model]
+/// struct Tag {
+///   ...
+///   #[backref = "tags"]
+///   posts: BackRef<Post>,
+/// }
+/// ```
+///
+/// [`ForeignKey`]: crate::fkey::ForeignKey
+/// [`Many`]: crate::many::Many
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackRef<T>
+where
+    T: DataObject,
+{
+    field: Cow<'static, str>,
+    owner: Option<SqlVal>,
+    #[serde(skip)]
+    #[serde(default = "default_oc")]
+    all_values: OnceCell<Vec<T>>,
+}
+impl<T> BackRef<T>
+where
+    T: DataObject,
+{
+    /// Constructs a new BackRef. It is initialized automatically
+    /// when the [`DataObject`] containing it is loaded or saved.
+    /// Until then it refers to no values.
+    ///
+    /// [`DataObject`]: super::DataObject
+    pub fn new() -> Self {
+        BackRef {
+            field: Cow::Borrowed("not_initialized"),
+            owner: None,
+            all_values: OnceCell::new(),
+        }
+    }
+
+    /// Used by macro-generated code. You do not need to call this directly.
+    pub fn ensure_init(&mut self, field: &'static str, owner: SqlVal) {
+        if self.owner.is_some() {
+            return;
+        }
+        self.field = Cow::Borrowed(field);
+        self.owner = Some(owner);
+        self.all_values = OnceCell::new();
+    }
+
+    /// Returns a reference to the values. They must have already been
+    /// loaded. If not, returns Error::ValueNotLoaded
+    pub fn get(&self) -> Result<impl Iterator<Item = &T>> {
+        self.all_values
+            .get()
+            .ok_or(Error::ValueNotLoaded)
+            .map(|v| v.iter())
+    }
+
+    /// Loads the objects referring to this one from the database if
+    /// necessary and returns a reference to them.
+    pub fn load(&self, conn: &impl ConnectionMethods) -> Result<impl Iterator<Item = &T>> {
+        let vals: Result<&Vec<T>> = self.all_values.get_or_try_init(|| {
+            //if we don't have an owner then nothing can refer to it
+            let owner: &SqlVal = match &self.owner {
+                Some(o) => o,
+                None => return Ok(Vec::new()),
+            };
+            let filter = match T::COLUMNS.iter().find(|col| col.name() == self.field) {
+                // A ForeignKey field
+                Some(col) => BoolExpr::Eq(col.name(), Expr::Val(owner.clone())),
+                // Otherwise the field must be a Many, stored in its own table
+                None => match T::many_table(&self.field) {
+                    Some(many_table) => BoolExpr::Subquery {
+                        col: T::PKCOL,
+                        tbl2: Cow::Borrowed(many_table),
+                        tbl2_col: "owner",
+                        expr: Box::new(BoolExpr::Eq("has", Expr::Val(owner.clone()))),
+                    },
+                    None => {
+                        return Err(Error::Internal(format!(
+                            "{} has no ForeignKey or Many field {}",
+                            T::TABLE,
+                            self.field
+                        )))
+                    }
+                },
+            };
+            T::query().filter(filter).load(conn)
+        });
+        vals.map(|v| v.iter())
+    }
+
+    /// Clears any loaded values, so they will be reloaded by the next call to `load`.
+    pub fn reset(&mut self) {
+        self.all_values = OnceCell::new();
+    }
+}
+impl<T: DataObject> PartialEq<BackRef<T>> for BackRef<T> {
+    fn eq(&self, other: &BackRef<T>) -> bool {
+        (self.owner == other.owner) && (self.field == other.field)
+    }
+}
+impl<T: DataObject> Eq for BackRef<T> {}
+impl<T: DataObject> Default for BackRef<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
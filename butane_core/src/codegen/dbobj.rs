@@ -10,8 +10,13 @@ use syn::{spanned::Spanned, Field, ItemStruct};
 #[derive(Default)]
 pub struct Config {
     pub table_name: Option<String>,
+    // Set by #[model(soft_delete)]
+    pub soft_delete: bool,
 }
 
+// The column recording when an object was soft deleted
+pub const SOFT_DELETE_COL: &str = "deleted_at";
+
 // implement the DataObject trait
 pub fn impl_dbobject(ast_struct: &ItemStruct, config: &Config) -> TokenStream2 {
     let tyname = &ast_struct.ident;
@@ -22,35 +27,100 @@ pub fn impl_dbobject(ast_struct: &ItemStruct, config: &Config) -> TokenStream2 {
     if let Some(err) = err {
         return err;
     }
+    if let Some(err) = verify_soft_delete(ast_struct, config) {
+        return err;
+    }
 
-    let pk_field = pk_field(&ast_struct).unwrap();
-    let pktype = &pk_field.ty;
+    let pk_fields = pk_fields(&ast_struct);
+    let pk_field = &pk_fields[0];
     let pkident = pk_field.ident.clone().unwrap();
     let pklit = make_ident_literal_str(&pkident);
+    let (pktype, pk, pkcols) = if pk_fields.len() > 1 {
+        let types = pk_fields.iter().map(|f| &f.ty);
+        let idents: Vec<Ident> = pk_fields.iter().map(|f| f.ident.clone().unwrap()).collect();
+        let lits = idents.iter().map(make_ident_literal_str);
+        (
+            quote!((#(#types),*)),
+            quote!(std::borrow::Cow::Owned((#(self.#idents.clone()),*))),
+            quote!(const PKCOLS: &'static [&'static str] = &[#(#lits),*];),
+        )
+    } else {
+        let pktype = &pk_field.ty;
+        (
+            quote!(#pktype),
+            quote!(std::borrow::Cow::Borrowed(&self.#pkident)),
+            TokenStream2::new(),
+        )
+    };
+    let single_pk_impls = if pk_fields.len() > 1 {
+        // A composite key has no single SQL value, and so cannot be
+        // referred to by a ForeignKey
+        TokenStream2::new()
+    } else {
+        single_pk_impls(tyname, &pkident)
+    };
 
-    let insert_cols = columns(ast_struct, |f| !is_auto(f));
-    let save_cols = columns(ast_struct, |f| !is_auto(f) && f != &pk_field);
-
-    let mut post_insert: Vec<TokenStream2> = Vec::new();
-    add_post_insert_for_auto(&pk_field, &mut post_insert);
-    post_insert.push(quote!(self.state.saved = true;));
-
-    let numdbfields = fields(&ast_struct).filter(|f| is_row_field(f)).count();
-    let many_save: TokenStream2 = fields(&ast_struct).filter(|f| is_many_to_many(f)).map(|f| {
-        let ident = f.ident.clone().expect("Fields must be named for butane");
-        let many_table_lit = many_table_lit(&ast_struct, f);
-        let pksqltype =
-            quote!(<<Self as butane::DataObject>::PKType as butane::FieldType>::SQLTYPE);
-        // Save  needs to ensure_initialized
+    let save = save_body(ast_struct, &pk_fields, false);
+    #[cfg(feature = "async")]
+    let save_async = {
+        let body = save_body(ast_struct, &pk_fields, true);
         quote!(
-            self.#ident.ensure_init(#many_table_lit, butane::ToSql::to_sql(self.pk()), #pksqltype);
-            self.#ident.save(conn)?;
+            fn save_async<'a>(
+                &'a mut self,
+                conn: &'a impl butane::db::AsyncConnectionMethods,
+            ) -> butane::db::BoxFuture<'a, butane::Result<()>> {
+                Box::pin(async move { #body })
+            }
+        )
+    };
+    #[cfg(not(feature = "async"))]
+    let save_async = TokenStream2::new();
+    let save_all = save_all(ast_struct, pk_field);
+    let reload = reload_body(ast_struct, false);
+    #[cfg(feature = "async")]
+    let reload_async = {
+        let body = reload_body(ast_struct, true);
+        quote!(
+            fn reload_async<'a>(
+                &'a mut self,
+                conn: &'a impl butane::db::AsyncConnectionMethods,
+            ) -> butane::db::BoxFuture<'a, butane::Result<()>> {
+                Box::pin(async move { #body })
+            }
         )
-    }).collect();
+    };
+    #[cfg(not(feature = "async"))]
+    let reload_async = TokenStream2::new();
+    let many_table = many_table_fn(ast_struct);
+    let upsert = upsert_body(ast_struct, false);
+    #[cfg(feature = "async")]
+    let upsert_async = {
+        let body = upsert_body(ast_struct, true);
+        quote!(
+            fn upsert_async<'a>(
+                &'a mut self,
+                conn: &'a impl butane::db::AsyncConnectionMethods,
+                conflict: &'a [&'a str],
+                on_conflict: butane::query::OnConflict,
+            ) -> butane::db::BoxFuture<'a, butane::Result<bool>> {
+                Box::pin(async move { #body })
+            }
+        )
+    };
+    #[cfg(not(feature = "async"))]
+    let upsert_async = TokenStream2::new();
 
-    let values: Vec<TokenStream2> = push_values(&ast_struct, |_| true);
-    let values_no_pk: Vec<TokenStream2> = push_values(&ast_struct, |f: &Field| f != &pk_field);
+    let (soft_delete_col, soft_delete_impl) = if config.soft_delete {
+        let collit = make_lit(SOFT_DELETE_COL);
+        (
+            quote!(const SOFT_DELETE_COL: Option<&'static str> = Some(#collit);),
+            quote!(impl butane::SoftDelete for #tyname {}),
+        )
+    } else {
+        (TokenStream2::new(), TokenStream2::new())
+    };
 
+    let references_checks = references_checks(ast_struct);
     let dataresult = impl_dataresult(ast_struct, &tyname);
     quote!(
                 #dataresult
@@ -58,56 +128,118 @@ pub fn impl_dbobject(ast_struct: &ItemStruct, config: &Config) -> TokenStream2 {
             type PKType = #pktype;
                         type Fields = #fields_type;
             const PKCOL: &'static str = #pklit;
+            #pkcols
             const TABLE: &'static str = #tablelit;
-            fn pk(&self) -> &Self::PKType {
-                &self.#pkident
+            #soft_delete_col
+            fn pk(&self) -> std::borrow::Cow<'_, Self::PKType> {
+                #pk
             }
             fn save(&mut self, conn: &impl butane::db::ConnectionMethods) -> butane::Result<()> {
-                #many_save
-                //future perf improvement use an array on the stack
-                let mut values: Vec<butane::SqlValRef> = Vec::with_capacity(#numdbfields);
-                let pkcol = butane::db::Column::new(
-                    #pklit,
-                    <#pktype as butane::FieldType>::SQLTYPE);
-                if self.state.saved {
-                    #(#values_no_pk)*
-                    if values.len() > 0 {
-                        conn.update(Self::TABLE,
-                                    pkcol,
-                                    butane::ToSql::to_sql_ref(self.pk()),
-                                    &[#save_cols], &values)?;
-                    }
-                } else {
-                    #(#values)*
-                    let pk = conn.insert_returning_pk(Self::TABLE, &[#insert_cols], &pkcol, &values)?;
-                    #(#post_insert)*
-                }
-                Ok(())
+                #save
             }
+            #save_async
+            #save_all
             fn delete(&self, conn: &impl butane::db::ConnectionMethods) -> butane::Result<()> {
-                use butane::ToSql;
                 use butane::prelude::DataObject;
-                conn.delete(Self::TABLE, Self::PKCOL, self.pk().to_sql())
+                let expr = butane::query::pk_expr::<Self>(&self.pk());
+                butane::query::delete_where::<Self>(conn, Self::TABLE, expr)?;
+                Ok(())
             }
+            fn reload(&mut self, conn: &impl butane::db::ConnectionMethods) -> butane::Result<()> {
+                #reload
+            }
+            #reload_async
+            fn upsert(
+                &mut self,
+                conn: &impl butane::db::ConnectionMethods,
+                conflict: &[&str],
+                on_conflict: butane::query::OnConflict,
+            ) -> butane::Result<bool> {
+                #upsert
+            }
+            #upsert_async
+            #many_table
         }
+        #soft_delete_impl
+        #single_pk_impls
+        #references_checks
+        impl butane::AsPrimaryKey<#tyname> for #tyname {
+            fn as_pk(&self) -> std::borrow::Cow<<Self as butane::DataObject>::PKType> {
+                use butane::DataObject;
+                self.pk()
+            }
+        }
+        impl butane::AsPrimaryKey<#tyname> for &#tyname {
+            fn as_pk(&self) -> std::borrow::Cow<<#tyname as butane::DataObject>::PKType> {
+                use butane::DataObject;
+                self.pk()
+            }
+        }
+    )
+}
+
+/// Builds compile-time checks that each `#[references]` attribute
+/// names the primary key columns of the model it refers to, in order,
+/// as the columns of the field are named after them.
+fn references_checks(ast_struct: &ItemStruct) -> TokenStream2 {
+    fields(ast_struct)
+        .filter_map(|f| {
+            let ident = f.ident.as_ref().unwrap();
+            if is_many_to_many(f) && !has_references(f) && get_many_through_type(f).is_none() {
+                let path = get_foreign_type_argument(&f.ty, "Many")?;
+                let msg = make_lit(&format!(
+                    "Many field {} refers to a model with a composite primary key, so needs #[references(...)]",
+                    ident
+                ));
+                return Some(quote!(
+                    const _: () = assert!(<#path as butane::DataObject>::PKCOLS.len() == 1, #msg);
+                ));
+            }
+            // Malformed attributes are reported by verify_fields
+            let references = get_references(f).ok()??;
+            let model = if is_many_to_many(f) {
+                let path = get_foreign_type_argument(&f.ty, "Many")?;
+                quote!(#path)
+            } else {
+                let fty = &f.ty;
+                quote!(<#fty as butane::ForeignKeyColumns>::Model)
+            };
+            let lits = references.iter().map(|col| make_lit(col));
+            let msg = make_lit(&format!(
+                "references on {} must name the primary key columns of the model it refers to, in order",
+                ident
+            ));
+            Some(quote!(
+                const _: () = assert!(
+                    butane::query::columns_match(
+                        <#model as butane::DataObject>::PKCOLS,
+                        &[#(#lits),*]
+                    ),
+                    #msg
+                );
+            ))
+        })
+        .collect()
+}
+
+/// Builds the impls for a model with a single-column primary key
+/// which allow it to be used as the value of that key.
+fn single_pk_impls(tyname: &Ident, pkident: &Ident) -> TokenStream2 {
+    quote!(
         impl butane::ToSql for #tyname {
             fn to_sql(&self) -> butane::SqlVal {
-                use butane::DataObject;
-                butane::ToSql::to_sql(self.pk())
+                butane::ToSql::to_sql(&self.#pkident)
             }
             fn to_sql_ref(&self) -> butane::SqlValRef<'_> {
-                use butane::DataObject;
-                butane::ToSql::to_sql_ref(self.pk())
+                butane::ToSql::to_sql_ref(&self.#pkident)
             }
         }
         impl butane::ToSql for &#tyname {
             fn to_sql(&self) -> butane::SqlVal {
-                use butane::DataObject;
-                butane::ToSql::to_sql(self.pk())
+                butane::ToSql::to_sql(&self.#pkident)
             }
             fn to_sql_ref(&self) -> butane::SqlValRef<'_> {
-                use butane::DataObject;
-                butane::ToSql::to_sql_ref(self.pk())
+                butane::ToSql::to_sql_ref(&self.#pkident)
             }
         }
         impl PartialEq<butane::ForeignKey<#tyname>> for #tyname {
@@ -120,48 +252,36 @@ pub fn impl_dbobject(ast_struct: &ItemStruct, config: &Config) -> TokenStream2 {
                 other.eq(self)
             }
         }
-        impl butane::AsPrimaryKey<#tyname> for #tyname {
-            fn as_pk(&self) -> std::borrow::Cow<<Self as butane::DataObject>::PKType> {
-                use butane::DataObject;
-                std::borrow::Cow::Borrowed(self.pk())
-            }
-        }
-        impl butane::AsPrimaryKey<#tyname> for &#tyname {
-            fn as_pk(&self) -> std::borrow::Cow<<#tyname as butane::DataObject>::PKType> {
-                use butane::DataObject;
-                std::borrow::Cow::Borrowed(self.pk())
-            }
-        }
     )
 }
 
 pub fn impl_dataresult(ast_struct: &ItemStruct, dbo: &Ident) -> TokenStream2 {
     let tyname = &ast_struct.ident;
-    let numdbfields = fields(&ast_struct).filter(|f| is_row_field(f)).count();
+    let numdbfields = num_columns(ast_struct);
     let rows = rows_for_from(&ast_struct);
     let cols = columns(ast_struct, |_| true);
 
-    let many_init: TokenStream2 =
-        fields(&ast_struct)
+    let many_init: TokenStream2 = fields(&ast_struct)
         .filter(|f| is_many_to_many(f))
         .map(|f| {
-            let ident = f
-                .ident
-                .clone()
-                .expect("Fields must be named for butane");
-            let many_table_lit = many_table_lit(&ast_struct, f);
-            let pksqltype = quote!(<<Self as butane::DataObject>::PKType as butane::FieldType>::SQLTYPE);
-            quote!(obj.#ident.ensure_init(#many_table_lit, butane::ToSql::to_sql(obj.pk()), #pksqltype);)
-        }).collect();
+            let ident = f.ident.clone().expect("Fields must be named for butane");
+            let many_table = many_table(&ast_struct, f);
+            let owner = many_owner(&ast_struct, f, quote!(obj));
+            quote!(obj.#ident.ensure_init(#many_table, #owner);)
+        })
+        .collect();
+    let backref_init = backref_init(ast_struct, quote!(obj));
 
     let dbo_is_self = dbo == tyname;
     let ctor = if dbo_is_self {
+        let snapshot = snapshot_values(&ast_struct, quote!(obj));
         quote!(
             let mut obj = #tyname {
                                 state: butane::ObjectState::default(),
                                 #(#rows),*
                         };
                         obj.state.saved = true;
+                        obj.state.set_snapshot(#snapshot);
         )
     } else {
         quote!(
@@ -184,6 +304,7 @@ pub fn impl_dataresult(ast_struct: &ItemStruct, dbo: &Ident) -> TokenStream2 {
                                 }
                                 #ctor
                                 #many_init
+                                #backref_init
                                 Ok(obj)
                         }
                     fn query() -> butane::query::Query<Self> {
@@ -204,7 +325,12 @@ fn make_tablelit(config: &Config, tyname: &Ident) -> LitStr {
 pub fn add_fieldexprs(ast_struct: &ItemStruct) -> TokenStream2 {
     let tyname = &ast_struct.ident;
     let vis = &ast_struct.vis;
+    let composite_pk = pk_fields(ast_struct).len() > 1;
+    // Fields whose values span several columns cannot be compared in
+    // a filter, nor can Many fields linking a composite key
     let fieldexprs: Vec<TokenStream2> = fields(ast_struct)
+        .filter(|f| !is_backref(f) && !is_composite_fk(f))
+        .filter(|f| !(is_many_to_many(f) && (composite_pk || has_references(f))))
         .map(|f| {
             if is_many_to_many(f) {
                 fieldexpr_func_many(f, ast_struct)
@@ -248,12 +374,12 @@ fn fieldexpr_func_regular(f: &Field, ast_struct: &ItemStruct) -> TokenStream2 {
 fn fieldexpr_func_many(f: &Field, ast_struct: &ItemStruct) -> TokenStream2 {
     let tyname = &ast_struct.ident;
     let fty = get_foreign_type_argument(&f.ty, "Many").expect("Many field misdetected");
-    let many_table_lit = many_table_lit(ast_struct, f);
+    let many_table = many_table(ast_struct, f);
     fieldexpr_func(
         f,
         ast_struct,
         quote!(butane::query::ManyFieldExpr<#tyname, #fty>),
-        quote!(butane::query::ManyFieldExpr::<#tyname, #fty>::new(#many_table_lit)),
+        quote!(butane::query::ManyFieldExpr::<#tyname, #fty>::new(#many_table)),
     )
 }
 
@@ -303,7 +429,20 @@ fn rows_for_from(ast_struct: &ItemStruct) -> Vec<TokenStream2> {
     fields(&ast_struct)
         .map(|f| {
             let ident = f.ident.clone().unwrap();
-            if is_row_field(f) {
+            if is_composite_fk(f) {
+                let fty = &f.ty;
+                let vals: Vec<TokenStream2> = (0..field_columns(f).len())
+                    .map(|j| {
+                        let sqltype = fk_column_sqltype(fty, j);
+                        let idx = i + j;
+                        quote!(butane::SqlVal::from(row.get(#idx, #sqltype)?))
+                    })
+                    .collect();
+                i += vals.len();
+                quote!(
+                    #ident: butane::ForeignKeyColumns::from_sql_values(vec![#(#vals),*])?
+                )
+            } else if is_row_field(f) {
                 let fty = &f.ty;
                 let ret = quote!(
                         #ident: butane::FromSql::from_sql_ref(
@@ -313,6 +452,8 @@ fn rows_for_from(ast_struct: &ItemStruct) -> Vec<TokenStream2> {
                 ret
             } else if is_many_to_many(f) {
                 quote!(#ident: butane::Many::new())
+            } else if is_backref(f) {
+                quote!(#ident: butane::BackRef::new())
             } else {
                 make_compile_error!(f.span()=> "Unexpected struct field")
             }
@@ -327,6 +468,7 @@ where
     fields(&ast_struct)
         .filter(|f| is_row_field(f) && predicate(f))
         .map(|f| match f.ident.clone() {
+            Some(_) if is_composite_fk(f) => field_column_defs(f),
             Some(fname) => {
                 let ident = make_ident_literal_str(&fname);
                 let fty = &f.ty;
@@ -340,22 +482,174 @@ where
         .collect()
 }
 
-fn many_table_lit(ast_struct: &ItemStruct, field: &Field) -> LitStr {
-    let tyname = &ast_struct.ident;
+/// Builds the definitions of the columns of a foreign key to a
+/// composite primary key, each followed by a comma.
+fn field_column_defs(f: &Field) -> TokenStream2 {
+    field_columns(f)
+        .iter()
+        .enumerate()
+        .map(|(j, col)| {
+            let lit = make_lit(col);
+            let sqltype = fk_column_sqltype(&f.ty, j);
+            quote!(butane::db::Column::new(#lit, #sqltype),)
+        })
+        .collect()
+}
+
+/// Builds an expression for the SqlType of the `j`th column of a
+/// foreign key of type `fty` to a composite primary key.
+fn fk_column_sqltype(fty: &syn::Type, j: usize) -> TokenStream2 {
+    let j = syn::Index::from(j);
+    quote!(
+        <<<<#fty as butane::ForeignKeyColumns>::Model as butane::DataObject>::PKType
+            as butane::PrimaryKeyColumn<#j>>::Type as butane::FieldType>::SQLTYPE
+    )
+}
+
+/// The number of columns of the model.
+fn num_columns(ast_struct: &ItemStruct) -> usize {
+    fields(ast_struct)
+        .filter(|f| is_row_field(f))
+        .map(|f| field_columns(f).len())
+        .sum()
+}
+
+/// Builds an expression for the name of the table holding the links
+/// of a Many field: the table of its through model if it has one, or
+/// else the table generated for it.
+fn many_table(ast_struct: &ItemStruct, field: &Field) -> TokenStream2 {
+    if let Some(through) = get_many_through_type(field) {
+        return quote!(<#through as butane::DataObject>::TABLE);
+    }
+    let table = config_from_attributes(ast_struct)
+        .table_name
+        .unwrap_or_else(|| ast_struct.ident.to_string());
     let ident = field
         .ident
         .clone()
         .expect("Fields must be named for butane");
-    make_lit(&format!("{}_{}_Many", &tyname, &ident))
+    let lit = make_lit(&format!("{}_{}_Many", &table, &ident));
+    quote!(#lit)
+}
+
+/// Builds the `many_table` method, which finds the table of a `Many`
+/// field by name for the `BackRef` fields of other models.
+fn many_table_fn(ast_struct: &ItemStruct) -> TokenStream2 {
+    let arms: Vec<TokenStream2> = fields(&ast_struct)
+        .filter(|f| is_many_to_many(f))
+        .map(|f| {
+            let fieldlit = make_ident_literal_str(f.ident.as_ref().unwrap());
+            let many_table = many_table(ast_struct, f);
+            quote!(#fieldlit => Some(#many_table),)
+        })
+        .collect();
+    if arms.is_empty() {
+        return TokenStream2::new();
+    }
+    quote!(
+        fn many_table(field: &str) -> Option<&'static str> {
+            match field {
+                #(#arms)*
+                _ => None,
+            }
+        }
+    )
+}
+
+/// Builds code to initialize each BackRef field of `obj` once its
+/// primary key is known
+fn backref_init(ast_struct: &ItemStruct, obj: TokenStream2) -> TokenStream2 {
+    fields(&ast_struct)
+        .filter(|f| is_backref(f))
+        .map(|f| {
+            let ident = f.ident.clone().expect("Fields must be named for butane");
+            // Malformed attributes are reported by verify_fields
+            let field = get_backref_field(f, &ast_struct.ident).unwrap_or_default();
+            let fieldlit = make_lit(&field);
+            quote!(#obj.#ident.ensure_init(#fieldlit, butane::ToSql::to_sql(&*#obj.pk()));)
+        })
+        .collect()
+}
+
+fn verify_soft_delete(ast_struct: &ItemStruct, config: &Config) -> Option<TokenStream2> {
+    if !config.soft_delete {
+        return None;
+    }
+    if !cfg!(feature = "datetime") {
+        return Some(make_compile_error!(ast_struct.span()=>
+            "soft_delete requires the datetime feature"));
+    }
+    if let Some(f) = fields(ast_struct).find(|f| f.ident.as_ref().unwrap() == SOFT_DELETE_COL) {
+        return Some(make_compile_error!(f.span()=>
+            "A soft_delete model may not have a field named {}", SOFT_DELETE_COL));
+    }
+    None
 }
 
 fn verify_fields(ast_struct: &ItemStruct) -> Option<TokenStream2> {
-    let pk_field = pk_field(ast_struct);
-    if pk_field.is_none() {
+    let pk_fields = pk_fields(ast_struct);
+    if pk_fields.is_empty() {
         return Some(make_compile_error!(ast_struct.span() => "No pk field found"));
     };
-    let pk_field = pk_field.unwrap();
+    let composite_pk = pk_fields.len() > 1;
+    if let Err(err) = get_renamed_from(&ast_struct.attrs) {
+        return Some(err.ts);
+    }
+    match get_struct_indexes(ast_struct) {
+        Ok(indexes) => {
+            for name in indexes.iter().flatten() {
+                if !fields(ast_struct).any(|f| is_row_field(f) && f.ident.as_ref().unwrap() == name)
+                {
+                    return Some(make_compile_error!(ast_struct.span()=>
+                        "Index field {} is not a column of this model", name));
+                }
+            }
+        }
+        Err(err) => return Some(err.ts),
+    }
+    match get_struct_unique_constraints(ast_struct) {
+        Ok(constraints) => {
+            for name in constraints.iter().flatten() {
+                if !fields(ast_struct).any(|f| is_row_field(f) && f.ident.as_ref().unwrap() == name)
+                {
+                    return Some(make_compile_error!(ast_struct.span()=>
+                        "Unique field {} is not a column of this model", name));
+                }
+            }
+        }
+        Err(err) => return Some(err.ts),
+    }
+    if let Err(err) = get_struct_checks(ast_struct) {
+        return Some(err.ts);
+    }
+    if fields(ast_struct).filter(|f| is_version(f)).count() > 1 {
+        return Some(make_compile_error!(ast_struct.span()=>
+            "Only one field may have the version attribute"));
+    }
     for f in fields(ast_struct) {
+        if let Err(err) = get_renamed_from(&f.attrs) {
+            return Some(err.ts);
+        }
+        if is_indexed(f) && !is_row_field(f) {
+            return Some(
+                make_compile_error!(f.span()=> "Index is not supported on Many or BackRef fields"),
+            );
+        }
+        if composite_pk && is_backref(f) {
+            return Some(make_compile_error!(f.span()=>
+                "BackRef fields are not supported with a composite primary key"));
+        }
+        if let Some(err) = verify_references(f, &pk_fields) {
+            return Some(err);
+        }
+        if is_backref(f) {
+            if let Err(err) = get_backref_field(f, &ast_struct.ident) {
+                return Some(err.ts);
+            }
+        } else if f.attrs.iter().any(|attr| attr.path.is_ident("backref")) {
+            return Some(make_compile_error!(f.span()=>
+                "backref is only supported for BackRef fields"));
+        }
         if is_auto(f) {
             match get_primitive_sql_type(&f.ty) {
                 Some(DeferredSqlType::KnownId(TypeIdentifier::Ty(SqlType::Int))) => (),
@@ -367,26 +661,494 @@ fn verify_fields(ast_struct: &ItemStruct) -> Option<TokenStream2> {
                     ))
                 }
             }
-            if &pk_field != f {
+            if composite_pk {
+                return Some(make_compile_error!(f.span()=>
+                    "Auto is not supported with a composite primary key"));
+            }
+            if &pk_fields[0] != f {
                 return Some(
                     quote_spanned!(f.span() => compile_error!("Auto is currently only supported for the primary key")),
                 );
             }
         }
+        if is_auto_now(f) || is_auto_now_add(f) {
+            if let Some(err) = verify_auto_now(f, &pk_fields) {
+                return Some(err);
+            }
+        }
+        if is_version(f) {
+            match get_primitive_sql_type(&f.ty) {
+                Some(DeferredSqlType::KnownId(TypeIdentifier::Ty(SqlType::Int))) => (),
+                Some(DeferredSqlType::KnownId(TypeIdentifier::Ty(SqlType::BigInt))) => (),
+                _ => {
+                    return Some(make_compile_error!(f.span()=>
+                        "Version is only supported for integer types"))
+                }
+            }
+            if pk_fields.contains(f) {
+                return Some(make_compile_error!(f.span()=>
+                    "The primary key may not be a version"));
+            }
+        }
+        for attrname in &["on_delete", "on_update"] {
+            let action = match get_referential_action(f, attrname) {
+                Ok(Some(action)) => action,
+                Ok(None) => continue,
+                Err(err) => return Some(err.ts),
+            };
+            if get_foreign_key_type_name(f).is_none() {
+                return Some(make_compile_error!(f.span()=>
+                    "{} is only supported for ForeignKey fields", attrname));
+            }
+            if action == ReferentialAction::SetNull && !is_option(f) {
+                return Some(make_compile_error!(f.span()=>
+                    "{}(set_null) requires an Option<ForeignKey> field", attrname));
+            }
+        }
+    }
+    None
+}
+
+fn verify_references(f: &Field, pk_fields: &[Field]) -> Option<TokenStream2> {
+    match get_references(f) {
+        Ok(Some(_)) => (),
+        Ok(None) => return None,
+        Err(err) => return Some(err.ts),
+    }
+    if get_foreign_key_type_name(f).is_none() && !is_many_to_many(f) {
+        return Some(make_compile_error!(f.span()=>
+            "references is only supported for ForeignKey and Many fields"));
+    }
+    if is_many_to_many(f) && get_many_through_type(f).is_some() {
+        return Some(make_compile_error!(f.span()=>
+            "references is not needed with a through model, whose fields name its columns"));
+    }
+    if !is_row_field(f) {
+        return None;
+    }
+    // These apply to a single column. A foreign key spanning several
+    // may be covered by the struct's unique attribute instead.
+    if pk_fields.contains(f) {
+        return Some(make_compile_error!(f.span()=>
+            "A ForeignKey to a composite primary key may not be a primary key"));
+    }
+    if is_unique(f) {
+        return Some(make_compile_error!(f.span()=>
+            "A ForeignKey to a composite primary key may not be unique; use #[unique(fields = ...)] on the struct"));
+    }
+    if f.attrs.iter().any(|attr| attr.path.is_ident("default")) {
+        return Some(make_compile_error!(f.span()=>
+            "A ForeignKey to a composite primary key may not have a default"));
     }
     None
 }
 
-fn add_post_insert_for_auto(pk_field: &Field, post_insert: &mut Vec<TokenStream2>) {
+fn verify_auto_now(f: &Field, pk_fields: &[Field]) -> Option<TokenStream2> {
+    let attrname = if is_auto_now(f) {
+        "auto_now"
+    } else {
+        "auto_now_add"
+    };
+    if !cfg!(feature = "datetime") {
+        return Some(make_compile_error!(f.span()=>
+            "{} requires the datetime feature", attrname));
+    }
+    if is_auto_now(f) && is_auto_now_add(f) {
+        return Some(make_compile_error!(f.span()=>
+            "auto_now and auto_now_add may not be used together"));
+    }
+    match get_deferred_sql_type(&f.ty) {
+        DeferredSqlType::KnownId(TypeIdentifier::Ty(SqlType::Timestamp)) => (),
+        _ => {
+            return Some(make_compile_error!(f.span()=>
+                "{} is only supported for timestamp fields", attrname))
+        }
+    }
+    if pk_fields.contains(f) {
+        return Some(make_compile_error!(f.span()=>
+            "{} is not supported for the primary key", attrname));
+    }
+    if f.attrs.iter().any(|attr| attr.path.is_ident("default")) {
+        return Some(make_compile_error!(f.span()=>
+            "{} may not be combined with a default", attrname));
+    }
+    None
+}
+
+/// Builds code to set the `#[auto_now]` fields of `obj`, and also its
+/// `#[auto_now_add]` fields if it is about to be inserted, to the
+/// current time.
+fn auto_now_values(ast_struct: &ItemStruct, obj: TokenStream2, insert: bool) -> TokenStream2 {
+    let idents: Vec<Ident> = fields(&ast_struct)
+        .filter(|f| is_auto_now(f) || (insert && is_auto_now_add(f)))
+        .map(|f| f.ident.clone().expect("Fields must be named for butane"))
+        .collect();
+    if idents.is_empty() {
+        return TokenStream2::new();
+    }
+    quote!(
+        let now = butane::now();
+        #(#obj.#idents = now.into();)*
+    )
+}
+
+/// Builds the body of `save`, or of the future returned by
+/// `save_async` if `is_async`.
+fn save_body(ast_struct: &ItemStruct, pk_fields: &[Field], is_async: bool) -> TokenStream2 {
+    let pk_field = &pk_fields[0];
+    let pktype = &pk_field.ty;
+    let pkident = pk_field.ident.clone().unwrap();
+    let pklit = make_ident_literal_str(&pkident);
+    let insert_cols = columns(ast_struct, |f| !is_auto(f));
+    let post_insert = post_insert(ast_struct, pk_field, quote!(self));
+    let numdbfields = num_columns(ast_struct);
+    let many_save = many_save(ast_struct, quote!(self), is_async);
+    let values: Vec<TokenStream2> = push_values(&ast_struct, quote!(self), |_| true);
+    let dirty_values: Vec<TokenStream2> = push_dirty_values(&ast_struct, pk_fields);
+    let snapshot = snapshot_values(&ast_struct, quote!(self));
+    let stamp_update = auto_now_values(ast_struct, quote!(self), false);
+    let stamp_insert = auto_now_values(ast_struct, quote!(self), true);
+    let awaited = if is_async { quote!(.await) } else { quote!() };
+    let assignments = quote!(columns
+        .iter()
+        .zip(values.iter())
+        .map(|(col, val)| butane::query::Assignment {
+            column: col.name(),
+            value: val.clone().into(),
+        })
+        .collect::<Vec<butane::query::Assignment>>());
+    let update = if let Some(version_field) = fields(ast_struct).find(|f| is_version(f)) {
+        // The row is only updated if no one else has updated it since
+        // it was loaded, which would have incremented its version
+        let vident = version_field.ident.clone().unwrap();
+        let vlit = make_ident_literal_str(&vident);
+        quote!(
+            let version = self.#vident;
+            let mut assignments = #assignments;
+            assignments.push(butane::query::Assignment {
+                column: #vlit,
+                value: butane::ToSql::to_sql(&(version + 1)),
+            });
+            let expr = butane::query::BoolExpr::And(
+                Box::new(butane::query::pk_expr::<Self>(&self.pk())),
+                Box::new(butane::query::BoolExpr::Eq(
+                    #vlit,
+                    butane::query::Expr::Val(butane::ToSql::to_sql(&version)),
+                )),
+            );
+            if conn.update_where(Self::TABLE, assignments, expr)#awaited? == 0 {
+                return Err(butane::Error::StaleObject);
+            }
+            self.#vident = version + 1;
+        )
+    } else if pk_fields.len() > 1 {
+        // A composite key is matched column by column
+        quote!(
+            let assignments = #assignments;
+            let expr = butane::query::pk_expr::<Self>(&self.pk());
+            conn.update_where(Self::TABLE, assignments, expr)#awaited?;
+        )
+    } else {
+        quote!(
+            let pkcol = butane::db::Column::new(
+                #pklit,
+                <#pktype as butane::FieldType>::SQLTYPE);
+            conn.update(Self::TABLE,
+                        pkcol,
+                        butane::ToSql::to_sql_ref(&self.#pkident),
+                        &columns, &values)#awaited?;
+        )
+    };
+    let insert = if pk_fields.len() > 1 {
+        // A composite key is never auto
+        quote!(conn.insert_only(Self::TABLE, &[#insert_cols], &values)#awaited?;)
+    } else {
+        quote!(
+            let pkcol = butane::db::Column::new(
+                #pklit,
+                <#pktype as butane::FieldType>::SQLTYPE);
+            let pk = conn.insert_returning_pk(Self::TABLE, &[#insert_cols], &pkcol, &values)#awaited?;
+        )
+    };
+    quote!(
+        //future perf improvement use an array on the stack
+        let mut values: Vec<butane::SqlValRef> = Vec::with_capacity(#numdbfields);
+        if self.state.saved {
+            // Only columns changed since the last load or save are written
+            let mut columns: Vec<butane::db::Column> = Vec::with_capacity(#numdbfields);
+            #stamp_update
+            #(#dirty_values)*
+            if values.len() > 0 {
+                #update
+            }
+        } else {
+            #stamp_insert
+            #(#values)*
+            #insert
+            #(#post_insert)*
+        }
+        self.state.set_snapshot(#snapshot);
+        // Links refer to the row, so are saved once it exists
+        #many_save
+        Ok(())
+    )
+}
+
+/// Builds the body of `reload`, or of the future returned by
+/// `reload_async` if `is_async`.
+fn reload_body(ast_struct: &ItemStruct, is_async: bool) -> TokenStream2 {
+    let get = if is_async {
+        quote!(Self::get_async(conn, self.pk().into_owned()).await?)
+    } else {
+        quote!(Self::get(conn, self.pk().into_owned())?)
+    };
+    let row_idents: Vec<Ident> = fields(ast_struct)
+        .filter(|f| is_row_field(f))
+        .map(|f| f.ident.clone().unwrap())
+        .collect();
+    // Many and BackRef fields keep their initialization, but forget
+    // any values they have loaded
+    let resets: Vec<Ident> = fields(ast_struct)
+        .filter(|f| is_many_to_many(f) || is_backref(f))
+        .map(|f| f.ident.clone().unwrap())
+        .collect();
+    quote!(
+        use butane::prelude::DataObject;
+        let fresh = #get;
+        #(self.#row_idents = fresh.#row_idents;)*
+        #(self.#resets.reset();)*
+        self.state = fresh.state;
+        Ok(())
+    )
+}
+
+/// Builds the body of `upsert`, or of the future returned by
+/// `upsert_async` if `is_async`.
+fn upsert_body(ast_struct: &ItemStruct, is_async: bool) -> TokenStream2 {
+    let insert_cols = columns(ast_struct, |f| !is_auto(f));
+    let numdbfields = num_columns(ast_struct);
+    let values: Vec<TokenStream2> = push_values(&ast_struct, quote!(self), |_| true);
+    let stamp_insert = auto_now_values(ast_struct, quote!(self), true);
+    let awaited = if is_async { quote!(.await) } else { quote!() };
+    quote!(
+        #stamp_insert
+        let mut values: Vec<butane::SqlValRef> = Vec::with_capacity(#numdbfields);
+        #(#values)*
+        let cnt = conn.upsert(Self::TABLE, &[#insert_cols], conflict, &on_conflict, &values)#awaited?;
+        Ok(cnt > 0)
+    )
+}
+
+/// Builds code to update `obj` after it has been inserted with primary key `pk`
+fn post_insert(ast_struct: &ItemStruct, pk_field: &Field, obj: TokenStream2) -> Vec<TokenStream2> {
+    let mut post_insert: Vec<TokenStream2> = Vec::new();
+    add_post_insert_for_auto(pk_field, &obj, &mut post_insert);
+    post_insert.push(quote!(#obj.state.saved = true;));
+    post_insert.push(backref_init(ast_struct, obj));
+    post_insert
+}
+
+fn add_post_insert_for_auto(
+    pk_field: &Field,
+    obj: &TokenStream2,
+    post_insert: &mut Vec<TokenStream2>,
+) {
     if !is_auto(&pk_field) {
         return;
     }
     let pkident = pk_field.ident.clone().unwrap();
-    post_insert.push(quote!(self.#pkident = butane::FromSql::from_sql(pk)?;));
+    post_insert.push(quote!(#obj.#pkident = butane::FromSql::from_sql(pk)?;));
+}
+
+/// Builds code to save the Many fields of `obj`, asynchronously if `is_async`
+fn many_save(ast_struct: &ItemStruct, obj: TokenStream2, is_async: bool) -> TokenStream2 {
+    fields(&ast_struct)
+        .filter(|f| is_many_to_many(f))
+        .map(|f| {
+            let ident = f.ident.clone().expect("Fields must be named for butane");
+            let many_table = many_table(&ast_struct, f);
+            let owner = many_owner(&ast_struct, f, obj.clone());
+            let save = if is_async {
+                quote!(#obj.#ident.save_async(conn).await?)
+            } else {
+                quote!(#obj.#ident.save(conn)?)
+            };
+            // Save  needs to ensure_initialized
+            quote!(
+                #obj.#ident.ensure_init(#many_table, #owner);
+                #save;
+            )
+        })
+        .collect()
+}
+
+/// Builds the arguments describing `obj` as the owner of a Many field
+/// for `Many::ensure_init`, following the table.
+fn many_owner(ast_struct: &ItemStruct, field: &Field, obj: TokenStream2) -> TokenStream2 {
+    let owner_columns = many_link_columns(
+        "owner",
+        Some(
+            pk_fields(ast_struct)
+                .iter()
+                .map(|f| f.ident.as_ref().unwrap().to_string())
+                .collect(),
+        ),
+    );
+    // Malformed attributes are reported by verify_fields
+    let has_columns = many_link_columns("has", get_references(field).ok().flatten());
+    quote!(
+        butane::PrimaryKey::to_sql_values(&*#obj.pk()),
+        &[#(#owner_columns),*],
+        &[#(#has_columns),*],
+        <<Self as butane::DataObject>::PKType as butane::PrimaryKey>::sql_types()
+    )
+}
+
+/// The names of the columns of a many table holding a key with the
+/// columns `pkcols`: just `prefix` for a single column, or else
+/// `{prefix}_{column}` for each column, as the migration names them.
+fn many_link_columns(prefix: &str, pkcols: Option<Vec<String>>) -> Vec<LitStr> {
+    match pkcols {
+        Some(pkcols) if pkcols.len() > 1 => pkcols
+            .iter()
+            .map(|col| make_lit(&format!("{}_{}", prefix, col)))
+            .collect(),
+        _ => vec![make_lit(prefix)],
+    }
+}
+
+/// Builds the `save_all` method, which inserts all new objects with a
+/// single call to the connection.
+fn save_all(ast_struct: &ItemStruct, pk_field: &Field) -> TokenStream2 {
+    let pktype = &pk_field.ty;
+    let pklit = make_ident_literal_str(pk_field.ident.as_ref().unwrap());
+    let insert_cols = columns(ast_struct, |f| !is_auto(f));
+    if insert_cols.is_empty() {
+        // Rows with no columns to insert can't be inserted together,
+        // so the default implementation saves each object in turn.
+        return TokenStream2::new();
+    }
+    let values: Vec<TokenStream2> = push_values(&ast_struct, quote!(obj), |_| true);
+    let post_insert = post_insert(ast_struct, pk_field, quote!(obj));
+    let many_save = many_save(ast_struct, quote!(obj), false);
+    let snapshot = snapshot_values(&ast_struct, quote!(obj));
+    let stamp_insert = auto_now_values(ast_struct, quote!(obj), true);
+    let stamp = if stamp_insert.is_empty() {
+        TokenStream2::new()
+    } else {
+        quote!(
+            for obj in objects.iter_mut().filter(|obj| !obj.state.saved) {
+                #stamp_insert
+            }
+        )
+    };
+    let (insert, inserted) = if is_auto(pk_field) {
+        (
+            quote!(
+                let pkcol = butane::db::Column::new(
+                    #pklit,
+                    <#pktype as butane::FieldType>::SQLTYPE);
+                let pks = conn.insert_many_returning_pk(Self::TABLE, &[#insert_cols], &pkcol, &values)?;
+            ),
+            quote!((obj, pk) in objects.iter_mut().filter(|obj| !obj.state.saved).zip(pks)),
+        )
+    } else {
+        // Primary keys are already known
+        (
+            quote!(conn.insert_many(Self::TABLE, &[#insert_cols], &values)?;),
+            quote!(obj in objects.iter_mut().filter(|obj| !obj.state.saved)),
+        )
+    };
+    quote!(
+        fn save_all(objects: &mut [Self], conn: &impl butane::db::ConnectionMethods) -> butane::Result<()> {
+            use butane::prelude::DataObject;
+            // Objects already in the database are updated one at a time
+            for obj in objects.iter_mut().filter(|obj| obj.state.saved) {
+                obj.save(conn)?;
+            }
+            #stamp
+            let mut values: Vec<butane::SqlValRef> = Vec::new();
+            for obj in objects.iter().filter(|obj| !obj.state.saved) {
+                #(#values)*
+            }
+            #insert
+            drop(values);
+            for #inserted {
+                #(#post_insert)*
+                #many_save
+                obj.state.set_snapshot(#snapshot);
+            }
+            Ok(())
+        }
+    )
+}
+
+/// Builds code for pushing SqlVals and Columns for each non-pk column
+/// which has changed since the object was loaded or saved into vecs
+/// called `values` and `columns`
+fn push_dirty_values(ast_struct: &ItemStruct, pk_fields: &[Field]) -> Vec<TokenStream2> {
+    let mut i: usize = 0;
+    fields(&ast_struct)
+        .filter(|f| is_row_field(f))
+        .map(|f| {
+            // The index of the field's first column
+            let idx = i;
+            i += field_columns(f).len();
+            (idx, f)
+        })
+        .filter(|(_, f)| !is_auto(f) && !is_version(f) && !pk_fields.contains(f))
+        .map(|(i, f)| {
+            let ident = f.ident.clone().unwrap();
+            if is_composite_fk(f) {
+                let defs = field_column_defs(f);
+                return quote!(
+                    let vals = butane::ForeignKeyColumns::to_sql_refs(&self.#ident);
+                    if vals.iter().enumerate().any(|(j, val)| self.state.is_column_dirty(#i + j, val)) {
+                        columns.extend_from_slice(&[#defs]);
+                        values.extend(vals);
+                    }
+                );
+            }
+            let lit = make_ident_literal_str(&ident);
+            let fty = &f.ty;
+            quote!(
+                let val = butane::ToSql::to_sql_ref(&self.#ident);
+                if self.state.is_column_dirty(#i, &val) {
+                    columns.push(butane::db::Column::new(#lit, <#fty as butane::FieldType>::SQLTYPE));
+                    values.push(val);
+                }
+            )
+        })
+        .collect()
+}
+
+/// Builds an expression for a vec of the SqlVals of each column of `obj`
+fn snapshot_values(ast_struct: &ItemStruct, obj: TokenStream2) -> TokenStream2 {
+    let numdbfields = num_columns(ast_struct);
+    let pushes: Vec<TokenStream2> = fields(&ast_struct)
+        .filter(|f| is_row_field(f))
+        .map(|f| {
+            let ident = f.ident.clone().unwrap();
+            if is_composite_fk(f) {
+                quote!(snapshot.extend(
+                    butane::ForeignKeyColumns::to_sql_refs(&#obj.#ident)
+                        .into_iter()
+                        .map(butane::SqlVal::from)
+                );)
+            } else {
+                quote!(snapshot.push(butane::ToSql::to_sql(&#obj.#ident));)
+            }
+        })
+        .collect();
+    quote!({
+        let mut snapshot: Vec<butane::SqlVal> = Vec::with_capacity(#numdbfields);
+        #(#pushes)*
+        snapshot
+    })
 }
 
 /// Builds code for pushing SqlVals for each column satisfying predicate into a vec called `values`
-fn push_values<P>(ast_struct: &ItemStruct, mut predicate: P) -> Vec<TokenStream2>
+fn push_values<P>(ast_struct: &ItemStruct, obj: TokenStream2, mut predicate: P) -> Vec<TokenStream2>
 where
     P: FnMut(&Field) -> bool,
 {
@@ -394,9 +1156,11 @@ where
         .filter(|f| is_row_field(f) && predicate(f))
         .map(|f| {
             let ident = f.ident.clone().unwrap();
-            if is_row_field(f) {
+            if is_composite_fk(f) {
+                quote!(values.extend(butane::ForeignKeyColumns::to_sql_refs(&#obj.#ident));)
+            } else if is_row_field(f) {
                 if !is_auto(f) {
-                    quote!(values.push(butane::ToSql::to_sql_ref(&self.#ident));)
+                    quote!(values.push(butane::ToSql::to_sql_ref(&#obj.#ident));)
                 } else {
                     quote!()
                 }
@@ -1,5 +1,5 @@
 use super::*;
-use crate::migrations::adb::{AColumn, ATable};
+use crate::migrations::adb::{AColumn, AConstraint, AForeignKey, AIndex, ARef, ATable};
 use crate::migrations::{MigrationMut, MigrationsMut};
 use crate::Result;
 use syn::{Field, ItemStruct};
@@ -14,6 +14,10 @@ where
 {
     let current_migration = ms.current();
     for table in create_atables(ast_struct, config) {
+        if let Some(old_name) = &table.renamed_from {
+            // The table now lives under its new name
+            current_migration.delete_table(old_name)?;
+        }
         current_migration.write_table(&table)?;
     }
     if let Some(name) = &config.table_name {
@@ -33,8 +37,12 @@ fn create_atables(ast_struct: &ItemStruct, config: &dbobj::Config) -> Vec<ATable
         None => ast_struct.ident.to_string(),
     };
     let mut table = ATable::new(name);
-    let pk = pk_field(ast_struct)
-        .expect("No primary key found. Expected 'id' field or field with #[pk] attribute.");
+    // Malformed attributes are reported by verify_fields
+    table.renamed_from = get_renamed_from(&ast_struct.attrs).unwrap_or(None);
+    let pk = pk_fields(ast_struct);
+    if pk.is_empty() {
+        panic!("No primary key found. Expected 'id' field or field with #[pk] attribute.");
+    }
     let mut result: Vec<ATable> = Vec::new();
     for f in fields(ast_struct) {
         let name = f
@@ -42,43 +50,162 @@ fn create_atables(ast_struct: &ItemStruct, config: &dbobj::Config) -> Vec<ATable
             .clone()
             .expect("db object fields must be named")
             .to_string();
-        if is_row_field(f) {
-            let col = AColumn::new(
+        if is_composite_fk(f) {
+            add_composite_fk_columns(&mut table, f);
+        } else if is_row_field(f) {
+            let mut col = AColumn::new(
                 name,
                 get_deferred_sql_type(&f.ty),
                 is_nullable(&f),
-                f == &pk,
+                pk.contains(f),
                 is_auto(&f),
                 is_unique(&f),
                 get_default(&f).expect("Malformed default attribute"),
             );
+            col.set_renamed_from(get_renamed_from(&f.attrs).unwrap_or(None));
+            col.set_default_now(is_auto_now(f) || is_auto_now_add(f));
+            if let Some(tyname) = get_foreign_key_type_name(f) {
+                col.set_foreign_key(Some(AForeignKey::new(
+                    ARef::Deferred(TypeKey::PK(tyname)),
+                    get_referential_action(f, "on_delete").unwrap_or(None),
+                    get_referential_action(f, "on_update").unwrap_or(None),
+                )));
+            }
+            if is_indexed(f) {
+                let index = AIndex::new_for_columns(&table.name, vec![col.name().to_string()]);
+                table.add_index(index);
+            }
             table.add_column(col);
-        } else if is_many_to_many(f) {
-            result.push(many_table(&table.name, f, &pk));
+        } else if is_many_to_many(f) && get_many_through_type(f).is_none() {
+            // A through model's table is created for the model itself
+            let mut many = many_table(&table.name, f, &pk);
+            let old_field_name = get_renamed_from(&f.attrs).unwrap_or(None);
+            if table.renamed_from.is_some() || old_field_name.is_some() {
+                many.renamed_from = Some(many_table_name(
+                    table.renamed_from.as_ref().unwrap_or(&table.name),
+                    old_field_name.as_ref().unwrap_or(&name),
+                ));
+            }
+            result.push(many);
         }
     }
+    for columns in get_struct_indexes(ast_struct).unwrap_or_default() {
+        let index = AIndex::new_for_columns(&table.name, fields_to_columns(ast_struct, columns));
+        table.add_index(index);
+    }
+    #[cfg(feature = "datetime")]
+    if config.soft_delete {
+        table.add_column(AColumn::new(
+            dbobj::SOFT_DELETE_COL,
+            DeferredSqlType::Known(TypeIdentifier::Ty(SqlType::Timestamp)),
+            true,
+            false,
+            false,
+            false,
+            None,
+        ));
+    }
+    for columns in get_struct_unique_constraints(ast_struct).unwrap_or_default() {
+        let constraint =
+            AConstraint::new_unique(&table.name, fields_to_columns(ast_struct, columns));
+        table.add_constraint(constraint);
+    }
+    for (n, expr) in get_struct_checks(ast_struct)
+        .unwrap_or_default()
+        .into_iter()
+        .enumerate()
+    {
+        table.add_constraint(AConstraint::new_check(&table.name, n, expr));
+    }
     result.push(table);
     result
 }
 
-fn many_table(main_table_name: &str, many_field: &Field, pk_field: &Field) -> ATable {
+/// Adds the columns of a foreign key to a composite primary key,
+/// named `{field}_{column}` for each column of the key, along with
+/// the table constraint which makes them a foreign key.
+fn add_composite_fk_columns(table: &mut ATable, f: &Field) {
+    let tyname = get_foreign_key_type_name(f).expect("references is only supported on ForeignKey");
+    // Malformed attributes are reported by verify_fields
+    let references = get_references(f).unwrap_or(None).unwrap_or_default();
+    let old_name = get_renamed_from(&f.attrs).unwrap_or(None);
+    let columns = field_columns(f);
+    for (name, pkcol) in columns.iter().zip(&references) {
+        let mut col = AColumn::new(
+            name,
+            DeferredSqlType::Deferred(TypeKey::PKColumn(tyname.clone(), pkcol.clone())),
+            is_nullable(f),
+            false,
+            false,
+            false,
+            None,
+        );
+        col.set_renamed_from(old_name.as_ref().map(|old| format!("{}_{}", old, pkcol)));
+        table.add_column(col);
+    }
+    if is_indexed(f) {
+        let index = AIndex::new_for_columns(&table.name, columns.clone());
+        table.add_index(index);
+    }
+    let fk = AForeignKey::new(
+        ARef::Deferred(TypeKey::PK(tyname)),
+        get_referential_action(f, "on_delete").unwrap_or(None),
+        get_referential_action(f, "on_update").unwrap_or(None),
+    );
+    table.add_constraint(AConstraint::new_foreign_key(&table.name, columns, fk));
+}
+
+fn many_table(main_table_name: &str, many_field: &Field, pk_fields: &[Field]) -> ATable {
     let field_name = many_field
         .ident
         .clone()
         .expect("fields must be named")
         .to_string();
-    let mut table = ATable::new(format!("{}_{}_Many", main_table_name, field_name));
-    let col = AColumn::new_simple("owner", get_deferred_sql_type(&pk_field.ty));
-    table.add_column(col);
-    let col = AColumn::new_simple(
-        "has",
-        get_many_sql_type(many_field)
-            .unwrap_or_else(|| panic!("Mis-identified Many field {}", field_name)),
-    );
-    table.add_column(col);
+    let mut table = ATable::new(many_table_name(main_table_name, &field_name));
+    // A composite key has a column for each of its columns, in the
+    // way Many names them
+    if let [pk_field] = pk_fields {
+        table.add_column(AColumn::new_simple(
+            "owner",
+            get_deferred_sql_type(&pk_field.ty),
+        ));
+    } else {
+        for pk_field in pk_fields {
+            let name = format!("owner_{}", pk_field.ident.as_ref().unwrap());
+            table.add_column(AColumn::new_simple(
+                name,
+                get_deferred_sql_type(&pk_field.ty),
+            ));
+        }
+    }
+    match get_references(many_field).unwrap_or(None) {
+        Some(references) => {
+            let tyname = get_foreign_type_argument(&many_field.ty, "Many")
+                .and_then(|path| path.segments.last())
+                .map(|seg| seg.ident.to_string())
+                .unwrap_or_else(|| panic!("Mis-identified Many field {}", field_name));
+            for pkcol in references {
+                let sqltype =
+                    DeferredSqlType::Deferred(TypeKey::PKColumn(tyname.clone(), pkcol.clone()));
+                table.add_column(AColumn::new_simple(format!("has_{}", pkcol), sqltype));
+            }
+        }
+        None => {
+            let col = AColumn::new_simple(
+                "has",
+                get_many_sql_type(many_field)
+                    .unwrap_or_else(|| panic!("Mis-identified Many field {}", field_name)),
+            );
+            table.add_column(col);
+        }
+    }
     table
 }
 
+fn many_table_name(main_table_name: &str, field_name: &str) -> String {
+    format!("{}_{}_Many", main_table_name, field_name)
+}
+
 fn is_nullable(field: &Field) -> bool {
     is_option(field)
 }
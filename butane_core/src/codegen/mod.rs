@@ -1,10 +1,11 @@
-use crate::migrations::adb::{DeferredSqlType, TypeIdentifier, TypeKey};
+use crate::migrations::adb::{DeferredSqlType, ReferentialAction, TypeIdentifier, TypeKey};
 use crate::migrations::{MigrationMut, MigrationsMut};
 use crate::{SqlType, SqlVal};
 use proc_macro2::TokenStream as TokenStream2;
 use proc_macro2::{Ident, Span, TokenTree};
 use quote::{quote, ToTokens};
 use regex::Regex;
+use syn::parse::Parser;
 use syn::parse_quote;
 use syn::{
     punctuated::Punctuated, Attribute, Field, ItemEnum, ItemStruct, ItemType, Lit, LitStr, Meta,
@@ -30,6 +31,19 @@ pub fn model_with_migrations<M>(
     input: TokenStream2,
     ms: &mut impl MigrationsMut<M = M>,
 ) -> TokenStream2
+where
+    M: MigrationMut,
+{
+    model_with_args_and_migrations(TokenStream2::new(), input, ms)
+}
+
+/// Like `model_with_migrations`, with the arguments given to the
+/// `model` attribute, e.g. `soft_delete`.
+pub fn model_with_args_and_migrations<M>(
+    args: TokenStream2,
+    input: TokenStream2,
+    ms: &mut impl MigrationsMut<M = M>,
+) -> TokenStream2
 where
     M: MigrationMut,
 {
@@ -37,7 +51,10 @@ where
     // attributes but proc macro attributes can't yet (nor can they
     // create field attributes)
     let mut ast_struct: ItemStruct = syn::parse2(input).unwrap();
-    let config: dbobj::Config = config_from_attributes(&ast_struct);
+    let mut config: dbobj::Config = config_from_attributes(&ast_struct);
+    if let Err(err) = config_from_args(args, &mut config) {
+        return err.ts;
+    }
 
     // Filter out our helper attributes
     let attrs: Vec<Attribute> = filter_helper_attributes(&ast_struct);
@@ -210,7 +227,13 @@ fn filter_helper_attributes(ast_struct: &ItemStruct) -> Vec<Attribute> {
         .attrs
         .clone()
         .into_iter()
-        .filter(|a| !a.path.is_ident("table"))
+        .filter(|a| {
+            !a.path.is_ident("table")
+                && !a.path.is_ident("index")
+                && !a.path.is_ident("unique")
+                && !a.path.is_ident("check")
+                && !a.path.is_ident("renamed_from")
+        })
         .collect()
 }
 
@@ -231,6 +254,33 @@ fn config_from_attributes(ast_struct: &ItemStruct) -> dbobj::Config {
     config
 }
 
+/// Applies the options given as arguments to the `model` attribute.
+/// Example
+/// #[model(soft_delete)]
+fn config_from_args(
+    args: TokenStream2,
+    config: &mut dbobj::Config,
+) -> std::result::Result<(), CompilerErrorMsg> {
+    let parser = Punctuated::<Ident, syn::Token![,]>::parse_terminated;
+    let options = match parser.parse2(args) {
+        Ok(options) => options,
+        Err(_) => {
+            return Err(make_compile_error!(
+                "malformed model attribute, expected #[model(soft_delete)]"
+            )
+            .into())
+        }
+    };
+    for option in options {
+        if option == "soft_delete" {
+            config.soft_delete = true;
+        } else {
+            return Err(make_compile_error!("unknown model option {}", option).into());
+        }
+    }
+    Ok(())
+}
+
 fn remove_helper_field_attributes(
     fields: &mut syn::Fields,
 ) -> std::result::Result<&syn::FieldsNamed, TokenStream2> {
@@ -243,6 +293,15 @@ fn remove_helper_field_attributes(
                         && !a.path.is_ident("sqltype")
                         && !a.path.is_ident("default")
                         && !a.path.is_ident("unique")
+                        && !a.path.is_ident("on_delete")
+                        && !a.path.is_ident("on_update")
+                        && !a.path.is_ident("index")
+                        && !a.path.is_ident("renamed_from")
+                        && !a.path.is_ident("backref")
+                        && !a.path.is_ident("auto_now")
+                        && !a.path.is_ident("auto_now_add")
+                        && !a.path.is_ident("version")
+                        && !a.path.is_ident("references")
                 });
             }
             Ok(fields)
@@ -273,17 +332,22 @@ fn remove_existing_state_field(
         .collect()
 }
 
-fn pk_field(ast_struct: &ItemStruct) -> Option<Field> {
-    let pk_by_attribute =
-        fields(ast_struct).find(|f| f.attrs.iter().any(|attr| attr.path.is_ident("pk")));
-    if let Some(id_field) = pk_by_attribute {
-        return Some(id_field.clone());
+/// The primary key fields: those with the `#[pk]` attribute, of
+/// which there are several for a composite primary key, or else the
+/// field named `id`.
+fn pk_fields(ast_struct: &ItemStruct) -> Vec<Field> {
+    let pk_by_attribute: Vec<Field> = fields(ast_struct)
+        .filter(|f| f.attrs.iter().any(|attr| attr.path.is_ident("pk")))
+        .cloned()
+        .collect();
+    if !pk_by_attribute.is_empty() {
+        return pk_by_attribute;
     }
     let pk_by_name = ast_struct.fields.iter().find(|f| match &f.ident {
         Some(ident) => *ident == "id",
         None => false,
     });
-    pk_by_name.cloned()
+    pk_by_name.into_iter().cloned().collect()
 }
 
 fn is_auto(field: &Field) -> bool {
@@ -294,6 +358,150 @@ fn is_unique(field: &Field) -> bool {
     field.attrs.iter().any(|attr| attr.path.is_ident("unique"))
 }
 
+/// Timestamps set to the current time whenever the object is saved
+fn is_auto_now(field: &Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .any(|attr| attr.path.is_ident("auto_now"))
+}
+
+/// Timestamps set to the current time when the object is first saved
+fn is_auto_now_add(field: &Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .any(|attr| attr.path.is_ident("auto_now_add"))
+}
+
+/// The version number of the object, for optimistic locking
+fn is_version(field: &Field) -> bool {
+    field.attrs.iter().any(|attr| attr.path.is_ident("version"))
+}
+
+fn is_indexed(field: &Field) -> bool {
+    field.attrs.iter().any(|attr| attr.path.is_ident("index"))
+}
+
+/// Multi-column indexes declared on the struct, each given as the
+/// names of the fields it covers.
+/// Example
+/// #[index(fields = "title, pub_time")]
+fn get_struct_indexes(
+    ast_struct: &ItemStruct,
+) -> std::result::Result<Vec<Vec<String>>, CompilerErrorMsg> {
+    get_struct_field_lists(ast_struct, "index")
+}
+
+/// Multi-column unique constraints declared on the struct, each given
+/// as the names of the fields it covers.
+/// Example
+/// #[unique(fields = "blog, slug")]
+fn get_struct_unique_constraints(
+    ast_struct: &ItemStruct,
+) -> std::result::Result<Vec<Vec<String>>, CompilerErrorMsg> {
+    get_struct_field_lists(ast_struct, "unique")
+}
+
+/// Parses each `#[<attr_name>(fields = "a, b")]` attribute on the
+/// struct into the list of field names it gives.
+fn get_struct_field_lists(
+    ast_struct: &ItemStruct,
+    attr_name: &str,
+) -> std::result::Result<Vec<Vec<String>>, CompilerErrorMsg> {
+    let mut lists: Vec<Vec<String>> = Vec::new();
+    for attr in ast_struct
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident(attr_name))
+    {
+        let malformed = || -> CompilerErrorMsg {
+            make_compile_error!(
+                "malformed {0} attribute, expected #[{0}(fields = \"a, b\")]",
+                attr_name
+            )
+            .into()
+        };
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list,
+            _ => return Err(malformed()),
+        };
+        let fields: Option<String> = list.nested.iter().find_map(|nested| match nested {
+            NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                path,
+                lit: Lit::Str(s),
+                ..
+            })) if path.is_ident("fields") => Some(s.value()),
+            _ => None,
+        });
+        let columns: Vec<String> = match fields {
+            Some(fields) => fields
+                .split(',')
+                .map(|f| f.trim().to_string())
+                .filter(|f| !f.is_empty())
+                .collect(),
+            None => return Err(malformed()),
+        };
+        if columns.is_empty() {
+            return Err(malformed());
+        }
+        lists.push(columns);
+    }
+    Ok(lists)
+}
+
+/// SQL check constraints declared on the struct, each given as a
+/// boolean expression over the model's columns.
+/// Example
+/// #[check("likes >= 0")]
+fn get_struct_checks(
+    ast_struct: &ItemStruct,
+) -> std::result::Result<Vec<String>, CompilerErrorMsg> {
+    let mut checks: Vec<String> = Vec::new();
+    for attr in ast_struct
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("check"))
+    {
+        let malformed = || -> CompilerErrorMsg {
+            make_compile_error!("malformed check attribute, expected #[check(\"expression\")]")
+                .into()
+        };
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list,
+            _ => return Err(malformed()),
+        };
+        match (list.nested.len(), list.nested.first()) {
+            (1, Some(NestedMeta::Lit(Lit::Str(s)))) if !s.value().trim().is_empty() => {
+                checks.push(s.value())
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    Ok(checks)
+}
+
+/// The previous name of a renamed field or struct, allowing
+/// migrations to rename rather than drop and recreate.
+/// Example
+/// #[renamed_from("old_name")]
+fn get_renamed_from(attrs: &[Attribute]) -> std::result::Result<Option<String>, CompilerErrorMsg> {
+    let attr: Option<&Attribute> = attrs.iter().find(|attr| attr.path.is_ident("renamed_from"));
+    let attr = match attr {
+        None => return Ok(None),
+        Some(attr) => attr,
+    };
+    if let Ok(Meta::List(list)) = attr.parse_meta() {
+        if let (1, Some(NestedMeta::Lit(Lit::Str(s)))) = (list.nested.len(), list.nested.first()) {
+            return Ok(Some(s.value()));
+        }
+    }
+    Err(
+        make_compile_error!("malformed renamed_from attribute, expected #[renamed_from(\"name\")]")
+            .into(),
+    )
+}
+
 fn fields(ast_struct: &ItemStruct) -> impl Iterator<Item = &Field> {
     ast_struct
         .fields
@@ -321,14 +529,74 @@ fn is_many_to_many(field: &Field) -> bool {
     get_many_sql_type(field).is_some()
 }
 
+/// The through model of a `Many<T, Through>` field, if it has one.
+fn get_many_through_type(field: &Field) -> Option<&syn::Path> {
+    let path = match &field.ty {
+        syn::Type::Path(path) => &path.path,
+        _ => return None,
+    };
+    match &path.segments.last()?.arguments {
+        syn::PathArguments::AngleBracketed(args) if args.args.len() == 2 => {
+            match args.args.last().unwrap() {
+                syn::GenericArgument::Type(syn::Type::Path(typath)) => Some(&typath.path),
+                _ => panic!("Many through argument should be a type."),
+            }
+        }
+        _ => None,
+    }
+}
+
 fn is_option(field: &Field) -> bool {
     get_foreign_type_argument(&field.ty, "Option").is_some()
 }
 
+fn is_backref(field: &Field) -> bool {
+    get_foreign_type_argument(&field.ty, "BackRef").is_some()
+}
+
+/// The field of the referring model which a `BackRef` field follows,
+/// defaulting to the snake_case name of the model owning the `BackRef`.
+/// Example
+/// #[backref = "blog"]
+fn get_backref_field(
+    field: &Field,
+    owner: &Ident,
+) -> std::result::Result<String, CompilerErrorMsg> {
+    let attr: Option<&Attribute> = field
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("backref"));
+    match attr.map(|attr| attr.parse_meta()) {
+        None => Ok(snake_case(&owner.to_string())),
+        Some(Ok(Meta::NameValue(MetaNameValue {
+            lit: Lit::Str(s), ..
+        }))) => Ok(s.value()),
+        Some(_) => Err(make_compile_error!(
+            "malformed backref attribute, expected #[backref = \"field\"]"
+        )
+        .into()),
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                result.push('_');
+            }
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
 /// Check for special fields which won't correspond to rows and don't
 /// implement FieldType
 fn is_row_field(f: &Field) -> bool {
-    !is_many_to_many(f)
+    !is_many_to_many(f) && !is_backref(f)
 }
 
 fn get_foreign_type_argument<'a>(ty: &'a syn::Type, tyname: &'static str) -> Option<&'a syn::Path> {
@@ -348,10 +616,11 @@ fn get_foreign_type_argument<'a>(ty: &'a syn::Type, tyname: &'static str) -> Opt
         syn::PathArguments::AngleBracketed(args) => &args.args,
         _ => return None,
     };
-    if args.len() != 1 {
+    // Many may also name a through model as a second argument
+    if args.len() != 1 && !(tyname == "Many" && args.len() == 2) {
         panic!("{} should have a single type argument", tyname)
     }
-    match args.last().unwrap() {
+    match args.first().unwrap() {
         syn::GenericArgument::Type(syn::Type::Path(typath)) => Some(&typath.path),
         _ => panic!("{} argument should be a type.", tyname),
     }
@@ -382,6 +651,146 @@ pub fn get_deferred_sql_type(ty: &syn::Type) -> DeferredSqlType {
         })
 }
 
+/// If the field is a `ForeignKey<T>` or `Option<ForeignKey<T>>`,
+/// returns the name of `T`.
+fn get_foreign_key_type_name(field: &Field) -> Option<String> {
+    let path = match get_foreign_type_argument(&field.ty, "Option") {
+        Some(path) => {
+            let inner_ty: syn::Type = syn::TypePath {
+                qself: None,
+                path: path.clone(),
+            }
+            .into();
+            get_foreign_type_argument(&inner_ty, "ForeignKey")?.clone()
+        }
+        None => get_foreign_type_argument(&field.ty, "ForeignKey")?.clone(),
+    };
+    path.segments.last().map(|seg| seg.ident.to_string())
+}
+
+/// The primary key columns of the model referred to by a
+/// `ForeignKey` or `Many` field, if it has a composite primary key.
+/// These must be given since they name the field's columns.
+/// Example
+/// #[references(tenant_id, id)]
+fn get_references(field: &Field) -> std::result::Result<Option<Vec<String>>, CompilerErrorMsg> {
+    let attr: Option<&Attribute> = field
+        .attrs
+        .iter()
+        .find(|attr| attr.path.is_ident("references"));
+    let attr = match attr {
+        None => return Ok(None),
+        Some(attr) => attr,
+    };
+    let malformed = || -> CompilerErrorMsg {
+        make_compile_error!("malformed references attribute, expected #[references(a, b)]").into()
+    };
+    let list = match attr.parse_meta() {
+        Ok(Meta::List(list)) if !list.nested.is_empty() => list,
+        _ => return Err(malformed()),
+    };
+    list.nested
+        .iter()
+        .map(|nested| match nested {
+            NestedMeta::Meta(Meta::Path(path)) => path
+                .get_ident()
+                .map(|ident| ident.to_string())
+                .ok_or_else(malformed),
+            _ => Err(malformed()),
+        })
+        .collect::<std::result::Result<Vec<String>, CompilerErrorMsg>>()
+        .map(Some)
+}
+
+/// Whether a row field is a foreign key to a model with a composite
+/// primary key, and so has a column for each column of the key.
+fn is_composite_fk(field: &Field) -> bool {
+    is_row_field(field) && has_references(field)
+}
+
+fn has_references(field: &Field) -> bool {
+    field
+        .attrs
+        .iter()
+        .any(|attr| attr.path.is_ident("references"))
+}
+
+/// The names of the columns holding a row field: just the field's
+/// name, or `{field}_{column}` for each column of the key referred to
+/// by a foreign key to a composite primary key.
+fn field_columns(field: &Field) -> Vec<String> {
+    let name = field
+        .ident
+        .clone()
+        .expect("db object fields must be named")
+        .to_string();
+    // Malformed attributes are reported by verify_fields
+    match get_references(field) {
+        Ok(Some(references)) if is_row_field(field) => references
+            .iter()
+            .map(|col| format!("{}_{}", name, col))
+            .collect(),
+        _ => vec![name],
+    }
+}
+
+/// Replaces the name of each field in `names` by the names of its
+/// columns, for the multi-column indexes and constraints declared on
+/// the struct.
+fn fields_to_columns(ast_struct: &ItemStruct, names: Vec<String>) -> Vec<String> {
+    names
+        .into_iter()
+        .flat_map(
+            |name| match fields(ast_struct).find(|f| f.ident.as_ref().unwrap() == &name) {
+                Some(f) => field_columns(f),
+                None => vec![name],
+            },
+        )
+        .collect()
+}
+
+/// Referential actions apply to foreign keys when the referenced
+/// object is deleted or its primary key updated.
+/// Example
+/// #[on_delete(cascade)]
+fn get_referential_action(
+    field: &Field,
+    attrname: &'static str,
+) -> std::result::Result<Option<ReferentialAction>, CompilerErrorMsg> {
+    let attr: Option<&Attribute> = field.attrs.iter().find(|attr| attr.path.is_ident(attrname));
+    let attr = match attr {
+        None => return Ok(None),
+        Some(attr) => attr,
+    };
+    let malformed = || -> CompilerErrorMsg {
+        make_compile_error!(
+            "malformed {} attribute, expected one of cascade, restrict, set_null or no_action",
+            attrname
+        )
+        .into()
+    };
+    let nested = match attr.parse_meta() {
+        Ok(Meta::List(list)) if list.nested.len() == 1 => list.nested.into_iter().next().unwrap(),
+        _ => return Err(malformed()),
+    };
+    let action = match nested {
+        NestedMeta::Meta(Meta::Path(path)) if path.is_ident("cascade") => {
+            ReferentialAction::Cascade
+        }
+        NestedMeta::Meta(Meta::Path(path)) if path.is_ident("restrict") => {
+            ReferentialAction::Restrict
+        }
+        NestedMeta::Meta(Meta::Path(path)) if path.is_ident("set_null") => {
+            ReferentialAction::SetNull
+        }
+        NestedMeta::Meta(Meta::Path(path)) if path.is_ident("no_action") => {
+            ReferentialAction::NoAction
+        }
+        _ => return Err(malformed()),
+    };
+    Ok(Some(action))
+}
+
 /// Defaults are used for fields added by later migrations
 /// Example
 /// #[default = 42]
@@ -0,0 +1,393 @@
+//! Async counterparts of the connection types. Available with the
+//! `async` feature.
+//!
+//! * `AsyncConnectionMethods` is the async equivalent of
+//!   [ConnectionMethods][crate::db::ConnectionMethods]. Methods such as
+//!   [Query::load_async][crate::query::Query::load_async] and
+//!   [DataObject::save_async][crate::DataObject::save_async] require an
+//!   implementation of it.
+//! * `AsyncConnection` is a boxed async connection to any backend. It
+//!   is returned by [connect_async][crate::db::connect_async].
+//!
+//! Postgres connections (with the `async-pg` feature) use
+//! tokio-postgres directly. SQLite has no async driver, so SQLite
+//! connections are run on a dedicated worker thread.
+
+use super::connmethods::{owned_row, Column, ConnectionMethods};
+use crate::query::{Assignment, BoolExpr, Expr, OnConflict, Order};
+use crate::{Error, Result, SqlVal, SqlValRef};
+use async_trait::async_trait;
+use fallible_iterator::FallibleIterator;
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::{mpsc, oneshot};
+
+/// A boxed future which can be sent between threads.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A row returned from an async query. Rows are read in full before
+/// the query completes, so unlike [BackendRow][crate::db::BackendRow]
+/// they own their values.
+pub type AsyncRow = Vec<SqlVal>;
+
+/// Methods available on an async database connection. Most users do
+/// not need to call these methods directly and will instead use the
+/// async methods on [DataObject][crate::DataObject] or
+/// [Query][crate::query::Query]. See
+/// [ConnectionMethods][crate::db::ConnectionMethods] for the
+/// description of each method.
+#[async_trait]
+pub trait AsyncConnectionMethods: Send + Sync {
+    async fn execute(&self, sql: &str) -> Result<()>;
+    async fn query(
+        &self,
+        table: &str,
+        columns: &[Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        sort: Option<&[Order]>,
+    ) -> Result<Vec<AsyncRow>>;
+    async fn insert_returning_pk(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<SqlVal>;
+    /// Like `insert_returning_pk` but with no return value
+    async fn insert_only(
+        &self,
+        table: &str,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<()>;
+    /// Insert unless there's a conflict on the primary key column, in which case update
+    async fn insert_or_replace(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<()>;
+    /// Like [ConnectionMethods::upsert].
+    async fn upsert(
+        &self,
+        table: &str,
+        columns: &[Column],
+        conflict: &[&str],
+        on_conflict: &OnConflict,
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize>;
+    /// Updates `columns` of the row whose primary key is `pk`,
+    /// returning the number of rows updated.
+    async fn update(
+        &self,
+        table: &str,
+        pkcol: Column,
+        pk: SqlValRef<'_>,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize>;
+    async fn delete(&self, table: &str, pkcol: &'static str, pk: SqlVal) -> Result<()> {
+        self.delete_where(table, BoolExpr::Eq(pkcol, Expr::Val(pk)))
+            .await?;
+        Ok(())
+    }
+    async fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize>;
+    async fn update_where(
+        &self,
+        table: &str,
+        assignments: Vec<Assignment>,
+        expr: BoolExpr,
+    ) -> Result<usize>;
+    /// Tests if a table exists in the database.
+    async fn has_table(&self, table: &str) -> Result<bool>;
+}
+
+/// Async database connection. May be a connection to any type of
+/// database as it is a boxed abstraction over a specific connection.
+pub struct AsyncConnection {
+    conn: Box<dyn AsyncConnectionMethods>,
+    backend_name: &'static str,
+}
+impl AsyncConnection {
+    // unused may occur if no backends are selected
+    #[allow(unused)]
+    pub(crate) fn new(conn: Box<dyn AsyncConnectionMethods>, backend_name: &'static str) -> Self {
+        AsyncConnection { conn, backend_name }
+    }
+    pub fn backend_name(&self) -> &'static str {
+        self.backend_name
+    }
+}
+#[async_trait]
+impl AsyncConnectionMethods for AsyncConnection {
+    async fn execute(&self, sql: &str) -> Result<()> {
+        self.conn.execute(sql).await
+    }
+    async fn query(
+        &self,
+        table: &str,
+        columns: &[Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        sort: Option<&[Order]>,
+    ) -> Result<Vec<AsyncRow>> {
+        self.conn
+            .query(table, columns, expr, limit, offset, sort)
+            .await
+    }
+    async fn insert_returning_pk(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<SqlVal> {
+        self.conn
+            .insert_returning_pk(table, columns, pkcol, values)
+            .await
+    }
+    async fn insert_only(
+        &self,
+        table: &str,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<()> {
+        self.conn.insert_only(table, columns, values).await
+    }
+    async fn insert_or_replace(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<()> {
+        self.conn
+            .insert_or_replace(table, columns, pkcol, values)
+            .await
+    }
+    async fn upsert(
+        &self,
+        table: &str,
+        columns: &[Column],
+        conflict: &[&str],
+        on_conflict: &OnConflict,
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
+        self.conn
+            .upsert(table, columns, conflict, on_conflict, values)
+            .await
+    }
+    async fn update(
+        &self,
+        table: &str,
+        pkcol: Column,
+        pk: SqlValRef<'_>,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
+        self.conn.update(table, pkcol, pk, columns, values).await
+    }
+    async fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize> {
+        self.conn.delete_where(table, expr).await
+    }
+    async fn update_where(
+        &self,
+        table: &str,
+        assignments: Vec<Assignment>,
+        expr: BoolExpr,
+    ) -> Result<usize> {
+        self.conn.update_where(table, assignments, expr).await
+    }
+    async fn has_table(&self, table: &str) -> Result<bool> {
+        self.conn.has_table(table).await
+    }
+}
+
+type Job<C> = Box<dyn FnOnce(&C) + Send>;
+
+/// Provides async access to a blocking connection by running it on a
+/// dedicated worker thread. The thread exits when the
+/// `BlockingAsyncConnection` is dropped.
+pub struct BlockingAsyncConnection<C>
+where
+    C: ConnectionMethods + Send + 'static,
+{
+    sender: mpsc::UnboundedSender<Job<C>>,
+}
+impl<C> BlockingAsyncConnection<C>
+where
+    C: ConnectionMethods + Send + 'static,
+{
+    pub fn new(conn: C) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Job<C>>();
+        std::thread::spawn(move || {
+            while let Some(job) = receiver.blocking_recv() {
+                job(&conn);
+            }
+        });
+        BlockingAsyncConnection { sender }
+    }
+
+    /// Runs `f` against the connection on the worker thread.
+    async fn call<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&C) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(Box::new(move |conn: &C| {
+                // The receiver is only gone if the caller stopped waiting
+                let _ = tx.send(f(conn));
+            }))
+            .map_err(|_| worker_stopped())?;
+        rx.await.map_err(|_| worker_stopped())?
+    }
+}
+
+fn worker_stopped() -> Error {
+    Error::Internal("connection worker thread has stopped".to_string())
+}
+
+fn owned_values(values: &[SqlValRef<'_>]) -> Vec<SqlVal> {
+    values.iter().map(|v| v.clone().into()).collect()
+}
+
+#[async_trait]
+impl<C> AsyncConnectionMethods for BlockingAsyncConnection<C>
+where
+    C: ConnectionMethods + Send + 'static,
+{
+    async fn execute(&self, sql: &str) -> Result<()> {
+        let sql = sql.to_string();
+        self.call(move |conn| conn.execute(&sql)).await
+    }
+    async fn query(
+        &self,
+        table: &str,
+        columns: &[Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        sort: Option<&[Order]>,
+    ) -> Result<Vec<AsyncRow>> {
+        let table = table.to_string();
+        let columns = columns.to_vec();
+        let sort: Option<Vec<Order>> = sort.map(|s| s.to_vec());
+        self.call(move |conn| {
+            conn.query(&table, &columns, expr, limit, offset, sort.as_deref())?
+                .mapped(|row| owned_row(row, &columns))
+                .collect()
+        })
+        .await
+    }
+    async fn insert_returning_pk(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<SqlVal> {
+        let table = table.to_string();
+        let columns = columns.to_vec();
+        let pkcol = pkcol.clone();
+        let values = owned_values(values);
+        self.call(move |conn| {
+            let values: Vec<SqlValRef> = values.iter().map(SqlVal::as_ref).collect();
+            conn.insert_returning_pk(&table, &columns, &pkcol, &values)
+        })
+        .await
+    }
+    async fn insert_only(
+        &self,
+        table: &str,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<()> {
+        let table = table.to_string();
+        let columns = columns.to_vec();
+        let values = owned_values(values);
+        self.call(move |conn| {
+            let values: Vec<SqlValRef> = values.iter().map(SqlVal::as_ref).collect();
+            conn.insert_only(&table, &columns, &values)
+        })
+        .await
+    }
+    async fn insert_or_replace(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<()> {
+        let table = table.to_string();
+        let columns = columns.to_vec();
+        let pkcol = pkcol.clone();
+        let values = owned_values(values);
+        self.call(move |conn| {
+            let values: Vec<SqlValRef> = values.iter().map(SqlVal::as_ref).collect();
+            conn.insert_or_replace(&table, &columns, &pkcol, &values)
+        })
+        .await
+    }
+    async fn upsert(
+        &self,
+        table: &str,
+        columns: &[Column],
+        conflict: &[&str],
+        on_conflict: &OnConflict,
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
+        let table = table.to_string();
+        let columns = columns.to_vec();
+        let conflict: Vec<String> = conflict.iter().map(|c| c.to_string()).collect();
+        let on_conflict = on_conflict.clone();
+        let values = owned_values(values);
+        self.call(move |conn| {
+            let conflict: Vec<&str> = conflict.iter().map(String::as_str).collect();
+            let values: Vec<SqlValRef> = values.iter().map(SqlVal::as_ref).collect();
+            conn.upsert(&table, &columns, &conflict, &on_conflict, &values)
+        })
+        .await
+    }
+    async fn update(
+        &self,
+        table: &str,
+        pkcol: Column,
+        pk: SqlValRef<'_>,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
+        let table = table.to_string();
+        let pk: SqlVal = pk.into();
+        let columns = columns.to_vec();
+        let values = owned_values(values);
+        self.call(move |conn| {
+            let values: Vec<SqlValRef> = values.iter().map(SqlVal::as_ref).collect();
+            conn.update(&table, pkcol, pk.as_ref(), &columns, &values)
+        })
+        .await
+    }
+    async fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize> {
+        let table = table.to_string();
+        self.call(move |conn| conn.delete_where(&table, expr)).await
+    }
+    async fn update_where(
+        &self,
+        table: &str,
+        assignments: Vec<Assignment>,
+        expr: BoolExpr,
+    ) -> Result<usize> {
+        let table = table.to_string();
+        self.call(move |conn| conn.update_where(&table, assignments, expr))
+            .await
+    }
+    async fn has_table(&self, table: &str) -> Result<bool> {
+        let table = table.to_string();
+        self.call(move |conn| conn.has_table(&table)).await
+    }
+}
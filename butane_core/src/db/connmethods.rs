@@ -1,8 +1,8 @@
 //! Not expected to be called directly by most users. Used by code
 //! generated by `#[model]`, `query!`, and other macros.
 
-use crate::query::{BoolExpr, Expr, Order};
-use crate::{Result, SqlType, SqlVal, SqlValRef};
+use crate::query::{Aggregate, Assignment, BoolExpr, Expr, OnConflict, Order};
+use crate::{Error, Result, SqlType, SqlVal, SqlValRef};
 use std::ops::{Deref, DerefMut};
 use std::vec::Vec;
 
@@ -21,6 +21,54 @@ pub trait ConnectionMethods {
         offset: Option<i32>,
         sort: Option<&[Order]>,
     ) -> Result<RawQueryResult<'a>>;
+    /// Like `query`, but the rows may be read from the database as
+    /// they are requested rather than all at once, in which case the
+    /// connection cannot be used for anything else until the rows
+    /// have been dropped. By default this is `query`, for backends
+    /// whose `query` already streams rows or which cannot stream them.
+    fn query_streaming<'a, 'b, 'c: 'a>(
+        &'c self,
+        table: &str,
+        columns: &'b [Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        sort: Option<&[Order]>,
+    ) -> Result<RawQueryResult<'a>> {
+        self.query(table, columns, expr, limit, offset, sort)
+    }
+    /// Generates the SQL for a query like `query` would, without
+    /// running it. Any [Expr::Placeholder] in `expr` becomes a
+    /// parameter which is bound when the query is run with
+    /// `query_prepared`.
+    fn prepare_query(
+        &self,
+        table: &str,
+        columns: &[Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        sort: Option<&[Order]>,
+    ) -> Result<PreparedSql>;
+    /// Runs a query previously generated by `prepare_query`, binding
+    /// `params` to its placeholders in order. The backend may cache
+    /// the prepared statement for reuse.
+    fn query_prepared<'a, 'c: 'a>(
+        &'c self,
+        prepared: &PreparedSql,
+        params: &[SqlVal],
+    ) -> Result<RawQueryResult<'a>>;
+    /// Computes `aggregates` over the rows of `table` matching
+    /// `expr`. Rows are grouped by the `group_by` columns, if any, and
+    /// each result row contains the `group_by` columns followed by the
+    /// aggregates. Groups are ordered by the `group_by` columns.
+    fn query_aggregate<'a, 'c: 'a>(
+        &'c self,
+        table: &str,
+        group_by: &[Column],
+        aggregates: &[Aggregate],
+        expr: Option<BoolExpr>,
+    ) -> Result<RawQueryResult<'a>>;
     fn insert_returning_pk(
         &self,
         table: &str,
@@ -30,6 +78,23 @@ pub trait ConnectionMethods {
     ) -> Result<SqlVal>;
     /// Like `insert_returning_pk` but with no return value
     fn insert_only(&self, table: &str, columns: &[Column], values: &[SqlValRef<'_>]) -> Result<()>;
+    /// Inserts many rows at once. `values` holds the values of each
+    /// row in turn, `columns.len()` values per row. Returns the
+    /// primary key of each row, in the same order.
+    fn insert_many_returning_pk(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<Vec<SqlVal>>;
+    /// Like `insert_many_returning_pk` but with no return value
+    fn insert_many(&self, table: &str, columns: &[Column], values: &[SqlValRef<'_>]) -> Result<()>;
+    /// Like `insert_many`, but uses the backend's bulk loading
+    /// mechanism if it has one (`COPY ... FROM STDIN BINARY` on
+    /// Postgres), which may be faster for very large numbers of
+    /// rows. Backends without one use `insert_many`.
+    fn copy_in(&self, table: &str, columns: &[Column], values: &[SqlValRef<'_>]) -> Result<()>;
     /// Insert unless there's a conflict on the primary key column, in which case update
     fn insert_or_replace(
         &self,
@@ -38,6 +103,22 @@ pub trait ConnectionMethods {
         pkcol: &Column,
         values: &[SqlValRef<'_>],
     ) -> Result<()>;
+    /// Inserts a row unless it conflicts with an existing row on the
+    /// `conflict` columns, which must have a unique constraint, in
+    /// which case `on_conflict` is applied to the existing row
+    /// instead. Returns the number of rows inserted or updated. Fails
+    /// with [`Error::MissingConflictTarget`](crate::Error::MissingConflictTarget)
+    /// if `on_conflict` updates the row but `conflict` is empty.
+    fn upsert(
+        &self,
+        table: &str,
+        columns: &[Column],
+        conflict: &[&str],
+        on_conflict: &OnConflict,
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize>;
+    /// Updates `columns` of the row whose primary key is `pk`,
+    /// returning the number of rows updated.
     fn update(
         &self,
         table: &str,
@@ -45,18 +126,27 @@ pub trait ConnectionMethods {
         pk: SqlValRef,
         columns: &[Column],
         values: &[SqlValRef<'_>],
-    ) -> Result<()>;
+    ) -> Result<usize>;
     fn delete(&self, table: &str, pkcol: &'static str, pk: SqlVal) -> Result<()> {
         self.delete_where(table, BoolExpr::Eq(pkcol, Expr::Val(pk)))?;
         Ok(())
     }
     fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize>;
+    /// Applies `assignments` to each row of `table` matching `expr`,
+    /// returning the number of rows updated.
+    fn update_where(
+        &self,
+        table: &str,
+        assignments: Vec<Assignment>,
+        expr: BoolExpr,
+    ) -> Result<usize>;
     /// Tests if a table exists in the database.
     fn has_table(&self, table: &str) -> Result<bool>;
 }
 
 /// Represents a database column. Most users do not need to use this
 /// directly.
+#[derive(Clone)]
 pub struct Column {
     name: &'static str,
     ty: SqlType,
@@ -73,6 +163,95 @@ impl Column {
     }
 }
 
+/// The SQL for a query generated by
+/// [prepare_query][ConnectionMethods::prepare_query], along with the
+/// values it requires. Most users do not need to use this directly and
+/// should use [PreparedQuery](crate::query::PreparedQuery) instead.
+#[derive(Clone, Debug)]
+pub struct PreparedSql {
+    backend_name: &'static str,
+    sql: String,
+    params: Vec<PreparedParam>,
+    num_bound: usize,
+    num_columns: usize,
+}
+
+#[derive(Clone, Debug)]
+enum PreparedParam {
+    Value(SqlVal),
+    // Index into the parameters bound when the query is run
+    Bound(usize),
+}
+
+impl PreparedSql {
+    /// Creates a `PreparedSql` for the query `sql`, which selects
+    /// `num_columns` columns. `values` holds the values of the
+    /// placeholders in `sql` other than those at the (zero-based)
+    /// positions in `bound`, which are bound when the query is run.
+    pub fn new(
+        backend_name: &'static str,
+        sql: String,
+        values: Vec<SqlVal>,
+        bound: &[usize],
+        num_columns: usize,
+    ) -> Self {
+        let num_params = values.len() + bound.len();
+        let mut values = values.into_iter();
+        let params = (0..num_params)
+            .map(|i| match bound.iter().position(|b| *b == i) {
+                Some(n) => PreparedParam::Bound(n),
+                None => PreparedParam::Value(values.next().unwrap()),
+            })
+            .collect();
+        PreparedSql {
+            backend_name,
+            sql,
+            params,
+            num_bound: bound.len(),
+            num_columns,
+        }
+    }
+    pub fn backend_name(&self) -> &'static str {
+        self.backend_name
+    }
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+    pub fn num_columns(&self) -> usize {
+        self.num_columns
+    }
+    /// Returns the values of all the placeholders in the SQL, in
+    /// order, taking those which are bound at run time from `bound`.
+    /// Fails if the query was not prepared by `backend_name` or if
+    /// `bound` has the wrong number of values.
+    pub fn params<'a>(
+        &'a self,
+        backend_name: &'static str,
+        bound: &'a [SqlVal],
+    ) -> Result<Vec<SqlValRef<'a>>> {
+        if backend_name != self.backend_name {
+            return Err(Error::IncompatiblePreparedQuery(
+                self.backend_name,
+                backend_name,
+            ));
+        }
+        if bound.len() != self.num_bound {
+            return Err(Error::WrongParameterCount {
+                expected: self.num_bound,
+                found: bound.len(),
+            });
+        }
+        Ok(self
+            .params
+            .iter()
+            .map(|param| match param {
+                PreparedParam::Value(val) => val.as_ref(),
+                PreparedParam::Bound(n) => bound[*n].as_ref(),
+            })
+            .collect())
+    }
+}
+
 /// Backend-specific row abstraction. Only implementors of new
 /// backends need use this trait directly.
 pub trait BackendRow {
@@ -126,6 +305,29 @@ where
     }
 }
 
+/// A row which owns its values, such as one read in full by
+/// [owned_row].
+impl BackendRow for Vec<SqlVal> {
+    fn get(&self, idx: usize, _ty: SqlType) -> Result<SqlValRef> {
+        self.as_slice()
+            .get(idx)
+            .map(SqlVal::as_ref)
+            .ok_or_else(|| Error::BoundsError(format!("no column {} in row", idx)))
+    }
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+}
+
+/// Copies the values of `columns` out of `row`.
+pub(crate) fn owned_row(row: &dyn BackendRow, columns: &[Column]) -> Result<Vec<SqlVal>> {
+    columns
+        .iter()
+        .enumerate()
+        .map(|(i, col)| row.get(i, col.ty().clone()).map(SqlVal::from))
+        .collect()
+}
+
 pub type RawQueryResult<'a> = Box<dyn BackendRows + 'a>;
 pub type QueryResult<T> = Vec<T>;
 
@@ -4,9 +4,14 @@
 #![allow(unused)]
 
 use super::Column;
-use crate::migrations::adb::{AColumn, TypeIdentifier};
+use crate::migrations::adb::{
+    AColumn, AConstraint, AConstraintKind, AForeignKey, AIndex, ARef, ATable, TypeIdentifier,
+};
 use crate::query::Expr::{Condition, Placeholder, Val};
-use crate::query::{BoolExpr::*, Expr, Join, Order, OrderDirection};
+use crate::query::{
+    Aggregate, AggregateFunc, Assignment, BoolExpr, BoolExpr::*, Expr, Join, OnConflict, Order,
+    OrderDirection,
+};
 use crate::Error;
 use crate::{query, Result, SqlType, SqlVal};
 use std::borrow::Cow;
@@ -17,6 +22,47 @@ use chrono::naive::NaiveDateTime;
 
 pub trait PlaceholderSource {
     fn next_placeholder(&mut self) -> Cow<str>;
+    /// Like `next_placeholder`, but for an [Expr::Placeholder], the
+    /// value of which is bound when the query is run.
+    fn next_bound_placeholder(&mut self) -> Cow<str> {
+        self.next_placeholder()
+    }
+}
+
+/// Wraps a [PlaceholderSource], recording which of the placeholders
+/// it issues are for [Expr::Placeholder]s. Used to prepare queries
+/// whose parameters are bound when the query is run.
+pub struct BoundPlaceholders<P> {
+    inner: P,
+    count: usize,
+    bound: Vec<usize>,
+}
+impl<P> BoundPlaceholders<P> {
+    pub fn new(inner: P) -> Self {
+        BoundPlaceholders {
+            inner,
+            count: 0,
+            bound: Vec::new(),
+        }
+    }
+    /// Zero-based positions, among all the placeholders issued, of
+    /// those issued for [Expr::Placeholder]s.
+    pub fn bound(&self) -> &[usize] {
+        &self.bound
+    }
+}
+impl<P> PlaceholderSource for BoundPlaceholders<P>
+where
+    P: PlaceholderSource,
+{
+    fn next_placeholder(&mut self) -> Cow<str> {
+        self.count += 1;
+        self.inner.next_placeholder()
+    }
+    fn next_bound_placeholder(&mut self) -> Cow<str> {
+        self.bound.push(self.count);
+        self.next_placeholder()
+    }
 }
 
 /// Writes to `w` the SQL to express the expression given in `expr`. Values contained in `expr` are rendered
@@ -40,7 +86,7 @@ where
                 w.write_str(&pls.next_placeholder())
             }
         },
-        Placeholder => w.write_str(&pls.next_placeholder()),
+        Placeholder => w.write_str(&pls.next_bound_placeholder()),
         Condition(c) => match *c {
             True => write!(w, "TRUE"),
             Eq(col, ex) => match ex {
@@ -130,12 +176,64 @@ pub fn sql_select(columns: &[Column], table: &str, w: &mut impl Write) {
     write!(w, " FROM {}", table).unwrap();
 }
 
+/// Writes a SELECT of the `group_by` columns followed by the
+/// `aggregates`, each of which is rendered by `f`.
+pub fn sql_select_aggregates<F, W>(
+    group_by: &[Column],
+    aggregates: &[Aggregate],
+    table: &str,
+    f: F,
+    w: &mut W,
+) where
+    F: Fn(&Aggregate, &mut W),
+    W: Write,
+{
+    write!(w, "SELECT ").unwrap();
+    list_columns(group_by, w);
+    aggregates.iter().fold(
+        if group_by.is_empty() { "" } else { "," },
+        |sep, aggregate| {
+            write!(w, "{}", sep).unwrap();
+            f(aggregate, w);
+            ","
+        },
+    );
+    write!(w, " FROM {}", table).unwrap();
+}
+
+pub fn sql_aggregate(aggregate: &Aggregate, w: &mut impl Write) {
+    let func = match aggregate.func {
+        AggregateFunc::Count => "COUNT",
+        AggregateFunc::Sum => "SUM",
+        AggregateFunc::Avg => "AVG",
+        AggregateFunc::Min => "MIN",
+        AggregateFunc::Max => "MAX",
+    };
+    write!(w, "{}({})", func, aggregate.column.unwrap_or("*")).unwrap();
+}
+
+pub fn sql_group_by(group_by: &[Column], w: &mut impl Write) {
+    if group_by.is_empty() {
+        return;
+    }
+    write!(w, " GROUP BY ").unwrap();
+    list_columns(group_by, w);
+    write!(w, " ORDER BY ").unwrap();
+    list_columns(group_by, w);
+}
+
 pub fn sql_insert_with_placeholders(
     table: &str,
     columns: &[Column],
     pls: &mut impl PlaceholderSource,
     w: &mut impl Write,
 ) {
+    if columns.is_empty() {
+        // Every column takes its default, such as an autoincrementing
+        // primary key.
+        write!(w, "INSERT INTO {} DEFAULT VALUES", table).unwrap();
+        return;
+    }
     write!(w, "INSERT INTO {} (", table).unwrap();
     list_columns(columns, w);
     write!(w, ") VALUES (").unwrap();
@@ -146,6 +244,86 @@ pub fn sql_insert_with_placeholders(
     write!(w, ")").unwrap();
 }
 
+/// Like `sql_insert_with_placeholders`, but a conflict with an
+/// existing row on the `conflict` columns is resolved as
+/// `on_conflict` says. Updating the existing row requires
+/// `conflict` columns, as neither backend can tell which row to
+/// update otherwise.
+pub fn sql_upsert_with_placeholders(
+    table: &str,
+    columns: &[Column],
+    conflict: &[&str],
+    on_conflict: &OnConflict,
+    pls: &mut impl PlaceholderSource,
+    w: &mut impl Write,
+) -> Result<()> {
+    if conflict.is_empty()
+        && matches!(on_conflict, OnConflict::Update(update) if !update.is_empty())
+    {
+        return Err(Error::MissingConflictTarget);
+    }
+    sql_insert_with_placeholders(table, columns, pls, w);
+    write!(w, " ON CONFLICT").unwrap();
+    if !conflict.is_empty() {
+        write!(w, " ({})", conflict.join(", ")).unwrap();
+    }
+    match on_conflict {
+        OnConflict::Update(update) if !update.is_empty() => {
+            write!(w, " DO UPDATE SET ").unwrap();
+            update.iter().fold("", |sep, col| {
+                write!(w, "{}{} = excluded.{}", sep, col, col).unwrap();
+                ", "
+            });
+        }
+        _ => write!(w, " DO NOTHING").unwrap(),
+    }
+    Ok(())
+}
+
+/// The action for an upsert which replaces all of the columns of an
+/// existing row with the same primary key.
+pub fn on_conflict_replace(columns: &[Column], pkcol: &Column) -> OnConflict {
+    OnConflict::Update(
+        columns
+            .iter()
+            .map(Column::name)
+            .filter(|name| *name != pkcol.name())
+            .collect(),
+    )
+}
+
+/// Like `sql_insert_with_placeholders` but inserts `rows` rows.
+pub fn sql_insert_many_with_placeholders(
+    table: &str,
+    columns: &[Column],
+    rows: usize,
+    pls: &mut impl PlaceholderSource,
+    w: &mut impl Write,
+) {
+    write!(w, "INSERT INTO {} (", table).unwrap();
+    list_columns(columns, w);
+    write!(w, ") VALUES ").unwrap();
+    for row in 0..rows {
+        if row > 0 {
+            write!(w, ", ").unwrap();
+        }
+        write!(w, "(").unwrap();
+        columns.iter().fold("", |sep, _| {
+            write!(w, "{}{}", sep, pls.next_placeholder()).unwrap();
+            ", "
+        });
+        write!(w, ")").unwrap();
+    }
+}
+
+/// The number of values to insert with each statement when
+/// inserting many rows, so that no statement has more than
+/// `max_params` parameters.
+pub fn insert_chunk_len(num_columns: usize, max_params: usize) -> usize {
+    let num_columns = num_columns.max(1);
+    (max_params / num_columns).max(1) * num_columns
+}
+
 pub fn sql_update_with_placeholders(
     table: &str,
     pkcol: Column,
@@ -161,6 +339,32 @@ pub fn sql_update_with_placeholders(
     write!(w, " WHERE {} = {}", pkcol.name(), pls.next_placeholder()).unwrap();
 }
 
+/// Writes an UPDATE of the rows of `table` matching `expr`. Values are
+/// rendered as placeholders by `f` (as with `sql_for_expr`) and added
+/// to `values`.
+pub fn sql_update_where<F, P, W>(
+    table: &str,
+    assignments: Vec<Assignment>,
+    expr: BoolExpr,
+    f: F,
+    values: &mut Vec<SqlVal>,
+    pls: &mut P,
+    w: &mut W,
+) where
+    F: Fn(Expr, &mut Vec<SqlVal>, &mut P, &mut W),
+    P: PlaceholderSource,
+    W: Write,
+{
+    write!(w, "UPDATE {} SET ", table).unwrap();
+    assignments.into_iter().fold("", |sep, assignment| {
+        write!(w, "{}{} = ", sep, assignment.column).unwrap();
+        f(Expr::Val(assignment.value), values, pls, w);
+        ", "
+    });
+    write!(w, " WHERE ").unwrap();
+    f(Expr::Condition(Box::new(expr)), values, pls, w);
+}
+
 pub fn sql_limit(limit: i32, w: &mut impl Write) {
     write!(w, " LIMIT {}", limit).unwrap();
 }
@@ -185,6 +389,11 @@ pub fn column_default(col: &AColumn) -> Result<SqlVal> {
     if let Some(val) = col.default() {
         return Ok(val.clone());
     }
+    #[cfg(feature = "datetime")]
+    if col.default_now() {
+        // Existing rows are stamped with the time the migration is created
+        return Ok(SqlVal::Timestamp(crate::now()));
+    }
     if col.nullable() {
         return Ok(SqlVal::Null);
     }
@@ -204,6 +413,109 @@ pub fn column_default(col: &AColumn) -> Result<SqlVal> {
     })
 }
 
+/// SQL for the `REFERENCES` clause of a foreign key, including any
+/// `ON DELETE`/`ON UPDATE` actions. Returns None if the reference
+/// has not been resolved.
+pub fn sql_references(fk: &AForeignKey) -> Option<String> {
+    let mut sql = match fk.reference() {
+        ARef::Literal { table, column } => format!("REFERENCES {}({})", table, column),
+        ARef::Columns { table, columns } => {
+            format!("REFERENCES {}({})", table, columns.join(", "))
+        }
+        ARef::Deferred(key) => {
+            crate::warn!("Cannot create foreign key for unresolved reference {}", key);
+            return None;
+        }
+    };
+    if let Some(action) = fk.on_delete() {
+        write!(sql, " ON DELETE {}", action.sql()).unwrap();
+    }
+    if let Some(action) = fk.on_update() {
+        write!(sql, " ON UPDATE {}", action.sql()).unwrap();
+    }
+    Some(sql)
+}
+
+pub fn sql_create_index(tbl_name: &str, index: &AIndex) -> String {
+    format!(
+        "CREATE INDEX {} ON {} ({});",
+        index.name(),
+        tbl_name,
+        index.columns().join(", ")
+    )
+}
+
+pub fn sql_drop_index(name: &str) -> String {
+    format!("DROP INDEX {};", name)
+}
+
+/// The table constraint declaring the primary key of `table` if it
+/// is a composite key. A single-column key is instead declared along
+/// with its column.
+pub fn sql_composite_pk(table: &ATable) -> Option<String> {
+    let pks = table.pk_columns();
+    if pks.len() < 2 {
+        return None;
+    }
+    let names: Vec<&str> = pks.iter().map(|col| col.name()).collect();
+    Some(format!("PRIMARY KEY ({})", names.join(", ")))
+}
+
+/// The table constraint defining `constraint`, for use in CREATE
+/// TABLE or ALTER TABLE ADD. Returns None for a foreign key whose
+/// reference has not been resolved.
+pub fn sql_constraint(constraint: &AConstraint) -> Option<String> {
+    Some(match constraint.kind() {
+        AConstraintKind::Unique(columns) => format!(
+            "CONSTRAINT {} UNIQUE ({})",
+            constraint.name(),
+            columns.join(", ")
+        ),
+        AConstraintKind::Check(expr) => {
+            format!("CONSTRAINT {} CHECK ({})", constraint.name(), expr)
+        }
+        AConstraintKind::ForeignKey(columns, fk) => format!(
+            "CONSTRAINT {} FOREIGN KEY ({}) {}",
+            constraint.name(),
+            columns.join(", "),
+            sql_references(fk)?
+        ),
+    })
+}
+
+/// Defines each of the table-level constraints on `table` whose
+/// columns still exist.
+pub fn sql_constraints(table: &ATable) -> Vec<String> {
+    table
+        .constraints
+        .iter()
+        .filter(|constraint| {
+            constraint
+                .columns()
+                .unwrap_or_default()
+                .iter()
+                .all(|col| table.column(col).is_some())
+        })
+        .filter_map(sql_constraint)
+        .collect()
+}
+
+/// Creates each of the indexes on `table` whose columns still exist,
+/// for use after the table has been created or rebuilt.
+pub fn sql_create_indexes(tbl_name: &str, table: &ATable) -> Vec<String> {
+    table
+        .indexes
+        .iter()
+        .filter(|index| {
+            index
+                .columns()
+                .iter()
+                .all(|col| table.column(col).is_some())
+        })
+        .map(|index| sql_create_index(tbl_name, index))
+        .collect()
+}
+
 pub fn list_columns(columns: &[Column], w: &mut impl Write) {
     let mut colnames: Vec<&'static str> = Vec::new();
     columns.iter().for_each(|c| colnames.push(c.name()));
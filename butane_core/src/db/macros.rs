@@ -17,6 +17,48 @@ macro_rules! connection_method_wrapper {
                 self.wrapped_connection_methods()?
                     .query(table, columns, expr, limit, offset, sort)
             }
+            fn query_streaming<'a, 'b, 'c: 'a>(
+                &'c self,
+                table: &str,
+                columns: &'b [Column],
+                expr: Option<BoolExpr>,
+                limit: Option<i32>,
+                offset: Option<i32>,
+                sort: Option<&[crate::query::Order]>,
+            ) -> Result<RawQueryResult<'a>> {
+                self.wrapped_connection_methods()?
+                    .query_streaming(table, columns, expr, limit, offset, sort)
+            }
+            fn prepare_query(
+                &self,
+                table: &str,
+                columns: &[Column],
+                expr: Option<BoolExpr>,
+                limit: Option<i32>,
+                offset: Option<i32>,
+                sort: Option<&[crate::query::Order]>,
+            ) -> Result<crate::db::PreparedSql> {
+                self.wrapped_connection_methods()?
+                    .prepare_query(table, columns, expr, limit, offset, sort)
+            }
+            fn query_prepared<'a, 'c: 'a>(
+                &'c self,
+                prepared: &crate::db::PreparedSql,
+                params: &[SqlVal],
+            ) -> Result<RawQueryResult<'a>> {
+                self.wrapped_connection_methods()?
+                    .query_prepared(prepared, params)
+            }
+            fn query_aggregate<'a, 'c: 'a>(
+                &'c self,
+                table: &str,
+                group_by: &[Column],
+                aggregates: &[crate::query::Aggregate],
+                expr: Option<BoolExpr>,
+            ) -> Result<RawQueryResult<'a>> {
+                self.wrapped_connection_methods()?
+                    .query_aggregate(table, group_by, aggregates, expr)
+            }
             fn insert_returning_pk(
                 &self,
                 table: &str,
@@ -36,6 +78,34 @@ macro_rules! connection_method_wrapper {
                 self.wrapped_connection_methods()?
                     .insert_only(table, columns, values)
             }
+            fn insert_many_returning_pk(
+                &self,
+                table: &str,
+                columns: &[Column],
+                pkcol: &Column,
+                values: &[SqlValRef<'_>],
+            ) -> Result<Vec<SqlVal>> {
+                self.wrapped_connection_methods()?
+                    .insert_many_returning_pk(table, columns, pkcol, values)
+            }
+            fn insert_many(
+                &self,
+                table: &str,
+                columns: &[Column],
+                values: &[SqlValRef<'_>],
+            ) -> Result<()> {
+                self.wrapped_connection_methods()?
+                    .insert_many(table, columns, values)
+            }
+            fn copy_in(
+                &self,
+                table: &str,
+                columns: &[Column],
+                values: &[SqlValRef<'_>],
+            ) -> Result<()> {
+                self.wrapped_connection_methods()?
+                    .copy_in(table, columns, values)
+            }
             fn insert_or_replace(
                 &self,
                 table: &str,
@@ -46,6 +116,22 @@ macro_rules! connection_method_wrapper {
                 self.wrapped_connection_methods()?
                     .insert_or_replace(table, columns, pkcol, values)
             }
+            fn upsert(
+                &self,
+                table: &str,
+                columns: &[Column],
+                conflict: &[&str],
+                on_conflict: &crate::query::OnConflict,
+                values: &[SqlValRef<'_>],
+            ) -> Result<usize> {
+                self.wrapped_connection_methods()?.upsert(
+                    table,
+                    columns,
+                    conflict,
+                    on_conflict,
+                    values,
+                )
+            }
             fn update(
                 &self,
                 table: &str,
@@ -53,13 +139,22 @@ macro_rules! connection_method_wrapper {
                 pk: SqlValRef,
                 columns: &[Column],
                 values: &[SqlValRef<'_>],
-            ) -> Result<()> {
+            ) -> Result<usize> {
                 self.wrapped_connection_methods()?
                     .update(table, pkcol, pk, columns, values)
             }
             fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize> {
                 self.wrapped_connection_methods()?.delete_where(table, expr)
             }
+            fn update_where(
+                &self,
+                table: &str,
+                assignments: Vec<crate::query::Assignment>,
+                expr: BoolExpr,
+            ) -> Result<usize> {
+                self.wrapped_connection_methods()?
+                    .update_where(table, assignments, expr)
+            }
             fn has_table(&self, table: &str) -> Result<bool> {
                 self.wrapped_connection_methods()?.has_table(table)
             }
@@ -11,6 +11,9 @@
 //! * `Connection` is a convience struct containing a boxed `BackendConnection`. It cannot do anything other than
 //!    what a `BackendConnection` can do, but allows using a single concrete type that is not tied to a particular
 //!    database backend. It is returned by the `connect` method.
+//! * With the `async` feature, [`AsyncConnectionMethods`] and [`AsyncConnection`] are the async
+//!   counterparts of `ConnectionMethods` and `Connection`. An `AsyncConnection` is returned by the
+//!   `connect_async` method.
 
 use crate::query::BoolExpr;
 use crate::{migrations::adb, Error, Result, SqlVal, SqlValRef};
@@ -21,6 +24,8 @@ use std::io::Write;
 use std::ops::{Deref, DerefMut};
 use std::path::Path;
 
+#[cfg(feature = "async")]
+mod asyncconn;
 mod connmethods;
 mod helper;
 mod macros;
@@ -37,8 +42,13 @@ pub use r2::ConnectionManager;
 // Macros are always exported at the root of the crate
 use crate::connection_method_wrapper;
 
+#[cfg(feature = "async")]
+pub use asyncconn::{
+    AsyncConnection, AsyncConnectionMethods, AsyncRow, BlockingAsyncConnection, BoxFuture,
+};
+pub(crate) use connmethods::owned_row;
 pub use connmethods::{
-    BackendRow, BackendRows, Column, ConnectionMethods, QueryResult, RawQueryResult,
+    BackendRow, BackendRows, Column, ConnectionMethods, PreparedSql, QueryResult, RawQueryResult,
 };
 
 /// Database connection.
@@ -46,12 +56,85 @@ pub trait BackendConnection: ConnectionMethods + Send + 'static {
     /// Begin a database transaction. The transaction object must be
     /// used in place of this connection until it is committed and aborted.
     fn transaction(&mut self) -> Result<Transaction>;
+    /// Like [`transaction`](BackendConnection::transaction), but with
+    /// the given options rather than the backend's defaults.
+    fn transaction_with_options(&mut self, options: &TransactionOptions) -> Result<Transaction>;
+    /// Begin a transaction in which to apply a migration. Backends
+    /// which rebuild tables to alter them suspend foreign key
+    /// enforcement until it is finished, so that rebuilding a table
+    /// does not trigger the referential actions of rows referring to
+    /// it, and instead check the foreign keys before committing.
+    fn migration_transaction(&mut self) -> Result<Transaction> {
+        self.transaction()
+    }
     /// Retrieve the backend backend this connection
     fn backend(&self) -> Box<dyn Backend>;
     fn backend_name(&self) -> &'static str;
     /// Tests if the connection has been closed. Backends which do not
     /// support this check should return false.
     fn is_closed(&self) -> bool;
+    /// Run `f` within a transaction. The transaction is committed if
+    /// `f` returns `Ok` and rolled back if it returns `Err`.
+    fn with_transaction<F, T, E>(&mut self, f: F) -> std::result::Result<T, E>
+    where
+        Self: Sized,
+        F: FnOnce(&mut Transaction) -> std::result::Result<T, E>,
+        E: From<Error>,
+    {
+        let mut trans = self.transaction()?;
+        finish_transaction(&mut trans, f)
+    }
+    /// Run `f` within a transaction begun with `options`, committing
+    /// it if `f` returns `Ok` and rolling it back if it returns
+    /// `Err`. If the transaction fails with an error for which
+    /// [`is_retryable`](crate::Error::is_retryable) is true, it is
+    /// rolled back and `f` is run again in a new transaction, up to
+    /// `max_retries` times.
+    fn with_transaction_retry<F, T>(
+        &mut self,
+        options: &TransactionOptions,
+        max_retries: u32,
+        mut f: F,
+    ) -> Result<T>
+    where
+        Self: Sized,
+        F: FnMut(&mut Transaction) -> Result<T>,
+    {
+        let mut retries = 0;
+        loop {
+            // Beginning the transaction may itself fail with a
+            // retryable error, e.g. when it must take a lock at once
+            let result = self
+                .transaction_with_options(options)
+                .and_then(|mut trans| finish_transaction(&mut trans, &mut f));
+            match result {
+                Err(e) if e.is_retryable() && retries < max_retries => retries += 1,
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Runs `f` within `trans`, committing it on success and rolling it back otherwise.
+/// If `f` fails, its error is returned even if the rollback also fails.
+fn finish_transaction<F, T, E>(trans: &mut Transaction, f: F) -> std::result::Result<T, E>
+where
+    F: FnOnce(&mut Transaction) -> std::result::Result<T, E>,
+    E: From<Error>,
+{
+    match f(trans) {
+        Ok(val) => {
+            trans.trans.deref_mut().commit()?;
+            Ok(val)
+        }
+        Err(e) => {
+            // _rollback_err is unused if logging is disabled
+            if let Err(_rollback_err) = trans.trans.deref_mut().rollback() {
+                crate::warn!("failed to roll back transaction: {}", _rollback_err);
+            }
+            Err(e)
+        }
+    }
 }
 
 /// Database connection. May be a connection to any type of database
@@ -73,6 +156,12 @@ impl BackendConnection for Connection {
     fn transaction(&mut self) -> Result<Transaction> {
         self.conn.transaction()
     }
+    fn transaction_with_options(&mut self, options: &TransactionOptions) -> Result<Transaction> {
+        self.conn.transaction_with_options(options)
+    }
+    fn migration_transaction(&mut self) -> Result<Transaction> {
+        self.conn.migration_transaction()
+    }
     fn backend(&self) -> Box<dyn Backend> {
         self.conn.backend()
     }
@@ -160,6 +249,26 @@ pub fn connect(spec: &ConnectionSpec) -> Result<Connection> {
         .connect(&spec.conn_str)
 }
 
+/// Connect to a database asynchronously. SQLite connections are run
+/// on a dedicated worker thread. Postgres connections require the
+/// `async-pg` feature and must be made from within a tokio runtime.
+#[cfg(feature = "async")]
+pub async fn connect_async(spec: &ConnectionSpec) -> Result<AsyncConnection> {
+    match spec.backend_name.as_str() {
+        #[cfg(feature = "sqlite")]
+        sqlite::BACKEND_NAME => Ok(AsyncConnection::new(
+            Box::new(sqlite::SQLiteBackend::new().connect_async(&spec.conn_str)?),
+            sqlite::BACKEND_NAME,
+        )),
+        #[cfg(feature = "async-pg")]
+        pg::BACKEND_NAME => Ok(AsyncConnection::new(
+            Box::new(pg::PgBackend::new().connect_async(&spec.conn_str).await?),
+            pg::BACKEND_NAME,
+        )),
+        _ => Err(Error::UnknownBackend(spec.backend_name.clone())),
+    }
+}
+
 trait BackendTransaction<'c>: ConnectionMethods {
     /// Commit the transaction Unfortunately because we use this as a
     /// trait object, we can't consume self. It should be understood
@@ -168,6 +277,8 @@ trait BackendTransaction<'c>: ConnectionMethods {
     fn commit(&mut self) -> Result<()>;
     /// Roll back the transaction. Same comment about consuming self as above.
     fn rollback(&mut self) -> Result<()>;
+    /// Begin a transaction nested within this one, using a savepoint.
+    fn savepoint(&mut self) -> Result<Transaction<'_>>;
 
     // Workaround for https://github.com/rust-lang/rfcs/issues/2765
     fn connection_methods(&self) -> &dyn ConnectionMethods;
@@ -195,6 +306,26 @@ impl<'c> Transaction<'c> {
     pub fn rollback(mut self) -> Result<()> {
         self.trans.deref_mut().rollback()
     }
+    /// Begin a transaction nested within this one. It is implemented
+    /// with a savepoint: committing it releases the savepoint and
+    /// rolling it back (or dropping it) rolls back only the changes
+    /// made since it began. This transaction cannot be used until the
+    /// nested one is committed or rolled back.
+    pub fn savepoint(&mut self) -> Result<Transaction<'_>> {
+        self.trans.deref_mut().savepoint()
+    }
+    /// Run `f` within a transaction nested within this one (see
+    /// [`savepoint`](Transaction::savepoint)). The nested transaction
+    /// is committed if `f` returns `Ok` and rolled back if it returns
+    /// `Err`.
+    pub fn with_transaction<F, T, E>(&mut self, f: F) -> std::result::Result<T, E>
+    where
+        F: FnOnce(&mut Transaction) -> std::result::Result<T, E>,
+        E: From<Error>,
+    {
+        let mut trans = self.savepoint()?;
+        finish_transaction(&mut trans, f)
+    }
     // For use with connection_method_wrapper macro
     #[allow(clippy::unnecessary_wraps)]
     fn wrapped_connection_methods(&self) -> Result<&dyn ConnectionMethods> {
@@ -204,3 +335,61 @@ impl<'c> Transaction<'c> {
 }
 
 connection_method_wrapper!(Transaction<'_>);
+
+/// Transaction isolation level. See
+/// [`TransactionOptions::isolation_level`] for how these apply to each
+/// backend.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// Options for beginning a transaction with
+/// [`transaction_with_options`](BackendConnection::transaction_with_options).
+#[derive(Clone, Debug, Default)]
+pub struct TransactionOptions {
+    isolation_level: Option<IsolationLevel>,
+    read_only: bool,
+    deferrable: bool,
+}
+impl TransactionOptions {
+    /// Options for a transaction which uses the backend's defaults.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the isolation level of the transaction.
+    ///
+    /// SQLite transactions are always serializable, so for SQLite the
+    /// level instead determines when the transaction takes its locks:
+    /// `Serializable` begins the transaction with `BEGIN EXCLUSIVE`,
+    /// `RepeatableRead` and `ReadCommitted` with `BEGIN IMMEDIATE` and
+    /// `ReadUncommitted` with `BEGIN DEFERRED` (SQLite's default).
+    pub fn isolation_level(mut self, level: IsolationLevel) -> Self {
+        self.isolation_level = Some(level);
+        self
+    }
+    /// Sets whether the transaction is read-only. A read-only SQLite
+    /// transaction always begins with `BEGIN DEFERRED`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+    /// Sets whether the transaction is deferrable. Only has an effect
+    /// for Postgres transactions which are serializable and read-only.
+    pub fn deferrable(mut self, deferrable: bool) -> Self {
+        self.deferrable = deferrable;
+        self
+    }
+    pub fn get_isolation_level(&self) -> Option<IsolationLevel> {
+        self.isolation_level
+    }
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    pub fn is_deferrable(&self) -> bool {
+        self.deferrable
+    }
+}
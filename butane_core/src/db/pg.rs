@@ -3,20 +3,28 @@ use super::connmethods::VecRows;
 use super::helper;
 use super::*;
 use crate::custom::{SqlTypeCustom, SqlValRefCustom};
-use crate::migrations::adb::{AColumn, ATable, Operation, TypeIdentifier, ADB};
+use crate::migrations::adb::{
+    AColumn, AConstraint, AConstraintKind, ARef, ATable, Operation, TypeIdentifier, ADB,
+};
 use crate::{debug, query};
-use crate::{Result, SqlType, SqlVal, SqlValRef};
+use crate::{ConstraintDetails, Result, SqlType, SqlVal, SqlValRef};
 use bytes::BufMut;
 #[cfg(feature = "datetime")]
 use chrono::NaiveDateTime;
+use pin_project::pin_project;
 use postgres::fallible_iterator::FallibleIterator;
 use postgres::GenericClient;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::pin::Pin;
 
 /// The name of the postgres backend.
 pub const BACKEND_NAME: &str = "pg";
 
+// Postgres limits the number of parameters to a statement
+const MAX_PARAMS: usize = 65535;
+
 /// Pg [Backend][crate::db::Backend] implementation.
 #[derive(Default)]
 pub struct PgBackend {}
@@ -29,6 +37,12 @@ impl PgBackend {
     fn connect(&self, params: &str) -> Result<PgConnection> {
         PgConnection::open(params)
     }
+    /// Open an async connection. Must be called from within a tokio
+    /// runtime. See also [connect_async][crate::db::connect_async].
+    #[cfg(feature = "async-pg")]
+    pub async fn connect_async(&self, params: &str) -> Result<PgAsyncConnection> {
+        PgAsyncConnection::open(params).await
+    }
 }
 impl Backend for PgBackend {
     fn name(&self) -> &'static str {
@@ -38,8 +52,12 @@ impl Backend for PgBackend {
     fn create_migration_sql(&self, current: &ADB, ops: Vec<Operation>) -> Result<String> {
         let mut current: ADB = (*current).clone();
         Ok(ops
-            .iter()
-            .map(|o| sql_for_op(&mut current, &o))
+            .into_iter()
+            .map(|o| {
+                let sql = sql_for_op(&mut current, &o);
+                current.transform_with(o);
+                sql
+            })
             .collect::<Result<Vec<String>>>()?
             .join("\n"))
     }
@@ -54,11 +72,13 @@ impl Backend for PgBackend {
 /// Pg database connection.
 pub struct PgConnection {
     conn: RefCell<postgres::Client>,
+    statements: PgStatementCache,
 }
 impl PgConnection {
     fn open(params: &str) -> Result<Self> {
         Ok(PgConnection {
             conn: RefCell::new(Self::connect(params)?),
+            statements: PgStatementCache::default(),
         })
     }
     fn connect(params: &str) -> Result<postgres::Client> {
@@ -78,11 +98,35 @@ impl PgConnectionLike for PgConnection {
     fn cell(&self) -> Result<&RefCell<Self::Client>> {
         Ok(&self.conn)
     }
+    fn statements(&self) -> &PgStatementCache {
+        &self.statements
+    }
 }
 impl BackendConnection for PgConnection {
     fn transaction<'c>(&'c mut self) -> Result<Transaction<'c>> {
         let trans: postgres::Transaction<'_> = self.conn.get_mut().transaction()?;
-        let trans = Box::new(PgTransaction::new(trans));
+        let trans = Box::new(PgTransaction::new(trans, &self.statements));
+        Ok(Transaction::new(trans))
+    }
+    fn transaction_with_options<'c>(
+        &'c mut self,
+        options: &TransactionOptions,
+    ) -> Result<Transaction<'c>> {
+        let mut builder = self
+            .conn
+            .get_mut()
+            .build_transaction()
+            .read_only(options.is_read_only())
+            .deferrable(options.is_deferrable());
+        if let Some(level) = options.get_isolation_level() {
+            builder = builder.isolation_level(match level {
+                IsolationLevel::ReadUncommitted => postgres::IsolationLevel::ReadUncommitted,
+                IsolationLevel::ReadCommitted => postgres::IsolationLevel::ReadCommitted,
+                IsolationLevel::RepeatableRead => postgres::IsolationLevel::RepeatableRead,
+                IsolationLevel::Serializable => postgres::IsolationLevel::Serializable,
+            });
+        }
+        let trans = Box::new(PgTransaction::new(builder.start()?, &self.statements));
         Ok(Transaction::new(trans))
     }
     fn backend(&self) -> Box<dyn Backend> {
@@ -111,6 +155,63 @@ fn sqlvalref_for_pg_query<'a>(v: &'a SqlValRef<'a>) -> &'a dyn postgres::types::
 pub trait PgConnectionLike {
     type Client: postgres::GenericClient;
     fn cell(&self) -> Result<&RefCell<Self::Client>>;
+    fn statements(&self) -> &PgStatementCache;
+}
+
+// The number of statements kept by a PgStatementCache
+const STATEMENT_CACHE_CAPACITY: usize = 128;
+
+/// Statements prepared on a connection for use by
+/// [query_prepared][ConnectionMethods::query_prepared], keyed by their
+/// SQL. Once full, the least recently used statement is dropped
+/// (which closes it on the server) to make room for another.
+/// Implementation detail. Semver exempt.
+#[derive(Default)]
+pub struct PgStatementCache {
+    statements: RefCell<HashMap<String, CachedStatement>>,
+    // Counts uses of the cache, to find the least recently used statement
+    uses: Cell<u64>,
+}
+struct CachedStatement {
+    stmt: postgres::Statement,
+    last_used: u64,
+}
+impl PgStatementCache {
+    fn prepare(
+        &self,
+        client: &mut impl postgres::GenericClient,
+        sql: &str,
+        types: &[postgres::types::Type],
+    ) -> Result<postgres::Statement> {
+        let mut statements = self.statements.try_borrow_mut()?;
+        let now = self.uses.get() + 1;
+        self.uses.set(now);
+        if let Some(cached) = statements.get_mut(sql) {
+            // The same SQL may be run with parameters of different types
+            if cached.stmt.params() == types {
+                cached.last_used = now;
+                return Ok(cached.stmt.clone());
+            }
+        }
+        let stmt = client.prepare_typed(sql, types)?;
+        if statements.len() >= STATEMENT_CACHE_CAPACITY && !statements.contains_key(sql) {
+            let oldest = statements
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(sql, _)| sql.clone());
+            if let Some(oldest) = oldest {
+                statements.remove(&oldest);
+            }
+        }
+        statements.insert(
+            sql.to_string(),
+            CachedStatement {
+                stmt: stmt.clone(),
+                last_used: now,
+            },
+        );
+        Ok(stmt)
+    }
 }
 
 impl<T> ConnectionMethods for T
@@ -133,9 +234,106 @@ where
         limit: Option<i32>,
         offset: Option<i32>,
         order: Option<&[query::Order]>,
+    ) -> Result<RawQueryResult<'a>> {
+        let (sqlquery, values) = sql_for_query(
+            table,
+            columns,
+            expr,
+            limit,
+            offset,
+            order,
+            &mut PgPlaceholderSource::new(),
+        );
+
+        if cfg!(feature = "log") {
+            debug!("query sql {}", sqlquery);
+        }
+        eprintln!("query sql {}", sqlquery);
+
+        let types: Vec<postgres::types::Type> = values.iter().map(pgtype_for_val).collect();
+        let mut client = self.cell()?.try_borrow_mut()?;
+        let stmt = client.prepare_typed(&sqlquery, types.as_ref())?;
+        let rows = query_rows(&mut *client, &stmt, &values, columns.len())?;
+        Ok(Box::new(VecRows::new(rows)))
+    }
+    fn query_streaming<'a, 'b, 'c: 'a>(
+        &'c self,
+        table: &str,
+        columns: &'b [Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        order: Option<&[query::Order]>,
+    ) -> Result<RawQueryResult<'a>> {
+        let (sqlquery, values) = sql_for_query(
+            table,
+            columns,
+            expr,
+            limit,
+            offset,
+            order,
+            &mut PgPlaceholderSource::new(),
+        );
+
+        if cfg!(feature = "log") {
+            debug!("query sql {}", sqlquery);
+        }
+
+        let types: Vec<postgres::types::Type> = values.iter().map(pgtype_for_val).collect();
+        let mut client = self.cell()?.try_borrow_mut()?;
+        let stmt = client.prepare_typed(&sqlquery, types.as_ref())?;
+        let rows = PgRows::new(client, &stmt, &values, columns.len())?;
+        Ok(Box::new(rows))
+    }
+    fn prepare_query(
+        &self,
+        table: &str,
+        columns: &[Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        order: Option<&[query::Order]>,
+    ) -> Result<PreparedSql> {
+        let mut pls = helper::BoundPlaceholders::new(PgPlaceholderSource::new());
+        let (sqlquery, values) =
+            sql_for_query(table, columns, expr, limit, offset, order, &mut pls);
+        Ok(PreparedSql::new(
+            BACKEND_NAME,
+            sqlquery,
+            values,
+            pls.bound(),
+            columns.len(),
+        ))
+    }
+    fn query_prepared<'a, 'c: 'a>(
+        &'c self,
+        prepared: &PreparedSql,
+        params: &[SqlVal],
+    ) -> Result<RawQueryResult<'a>> {
+        let values = prepared.params(BACKEND_NAME, params)?;
+        if cfg!(feature = "log") {
+            debug!("query sql {}", prepared.sql());
+        }
+
+        // The statement is prepared for the types of the values it is
+        // run with, as their sizes matter to the binary protocol.
+        let types: Vec<postgres::types::Type> = values.iter().map(pgtype_for_valref).collect();
+        let mut client = self.cell()?.try_borrow_mut()?;
+        let stmt = self
+            .statements()
+            .prepare(&mut *client, prepared.sql(), &types)?;
+        let rows = query_rows(&mut *client, &stmt, &values, prepared.num_columns())?;
+        Ok(Box::new(VecRows::new(rows)))
+    }
+    fn query_aggregate<'a, 'c: 'a>(
+        &'c self,
+        table: &str,
+        group_by: &[Column],
+        aggregates: &[query::Aggregate],
+        expr: Option<BoolExpr>,
     ) -> Result<RawQueryResult<'a>> {
         let mut sqlquery = String::new();
-        helper::sql_select(columns, table, &mut sqlquery);
+        helper::sql_select_aggregates(group_by, aggregates, table, sql_aggregate, &mut sqlquery);
         let mut values: Vec<SqlVal> = Vec::new();
         if let Some(expr) = expr {
             sqlquery.write_str(" WHERE ").unwrap();
@@ -146,39 +344,22 @@ where
                 &mut sqlquery,
             );
         }
-
-        if let Some(order) = order {
-            helper::sql_order(order, &mut sqlquery)
-        }
-
-        if let Some(limit) = limit {
-            helper::sql_limit(limit, &mut sqlquery)
-        }
-
-        if let Some(offset) = offset {
-            helper::sql_offset(offset, &mut sqlquery)
-        }
+        helper::sql_group_by(group_by, &mut sqlquery);
 
         if cfg!(feature = "log") {
             debug!("query sql {}", sqlquery);
         }
-        eprintln!("query sql {}", sqlquery);
 
         let types: Vec<postgres::types::Type> = values.iter().map(pgtype_for_val).collect();
         let stmt = self
             .cell()?
             .try_borrow_mut()?
             .prepare_typed(&sqlquery, types.as_ref())?;
-        // todo avoid intermediate vec?
         let rowvec: Vec<postgres::Row> = self
             .cell()?
             .try_borrow_mut()?
             .query_raw(&stmt, values.iter().map(sqlval_for_pg_query))?
-            .map_err(Error::Postgres)
-            .map(|r| {
-                check_columns(&r, columns)?;
-                Ok(r)
-            })
+            .map_err(Error::from)
             .collect()?;
         Ok(Box::new(VecRows::new(rowvec)))
     }
@@ -206,7 +387,7 @@ where
             .cell()?
             .try_borrow_mut()?
             .query_raw(sql.as_str(), values.iter().map(sqlvalref_for_pg_query))?
-            .map_err(Error::Postgres)
+            .map_err(Error::from)
             .map(|r| sql_val_from_postgres(&r, 0, pkcol))
             .nth(0)?;
         pk.ok_or_else(|| Error::Internal("could not get pk".to_string()))
@@ -225,6 +406,82 @@ where
             .execute(sql.as_str(), params.as_slice())?;
         Ok(())
     }
+    fn insert_many_returning_pk(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<Vec<SqlVal>> {
+        let mut pks: Vec<SqlVal> = Vec::with_capacity(values.len() / columns.len().max(1));
+        for chunk in values.chunks(helper::insert_chunk_len(columns.len(), MAX_PARAMS)) {
+            let mut sql = String::new();
+            sql_insert_many_returning_pk(
+                table,
+                columns,
+                pkcol,
+                chunk.len() / columns.len(),
+                &mut sql,
+            );
+            if cfg!(feature = "log") {
+                debug!("insert sql {}", sql);
+            }
+            let chunk_pks: Vec<SqlVal> = self
+                .cell()?
+                .try_borrow_mut()?
+                .query_raw(sql.as_str(), chunk.iter().map(sqlvalref_for_pg_query))?
+                .map_err(Error::from)
+                .map(|r| sql_val_from_postgres(&r, 0, pkcol))
+                .collect()?;
+            pks.extend(chunk_pks);
+        }
+        Ok(pks)
+    }
+    fn insert_many(&self, table: &str, columns: &[Column], values: &[SqlValRef<'_>]) -> Result<()> {
+        for chunk in values.chunks(helper::insert_chunk_len(columns.len(), MAX_PARAMS)) {
+            let mut sql = String::new();
+            helper::sql_insert_many_with_placeholders(
+                table,
+                columns,
+                chunk.len() / columns.len(),
+                &mut PgPlaceholderSource::new(),
+                &mut sql,
+            );
+            if cfg!(feature = "log") {
+                debug!("insert sql {}", sql);
+            }
+            let params: Vec<&DynToSqlPg> = chunk.iter().map(|v| v as &DynToSqlPg).collect();
+            self.cell()?
+                .try_borrow_mut()?
+                .execute(sql.as_str(), params.as_slice())?;
+        }
+        Ok(())
+    }
+    /// Uses `COPY ... FROM STDIN BINARY`, which is considerably faster
+    /// than `INSERT` for large numbers of rows.
+    fn copy_in(&self, table: &str, columns: &[Column], values: &[SqlValRef<'_>]) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let mut sql = String::new();
+        write!(&mut sql, "COPY {} (", table).unwrap();
+        helper::list_columns(columns, &mut sql);
+        write!(&mut sql, ") FROM STDIN BINARY").unwrap();
+        if cfg!(feature = "log") {
+            debug!("copy sql {}", sql);
+        }
+        let types: Vec<postgres::types::Type> =
+            columns.iter().map(|c| pgtype_for_sqltype(c.ty())).collect();
+        let mut client = self.cell()?.try_borrow_mut()?;
+        let mut writer =
+            postgres::binary_copy::BinaryCopyInWriter::new(client.copy_in(sql.as_str())?, &types);
+        for row in values.chunks(columns.len()) {
+            let params: Vec<&DynToSqlPg> = row.iter().map(|v| v as &DynToSqlPg).collect();
+            writer.write(&params)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
     fn insert_or_replace<'a>(
         &self,
         table: &str,
@@ -232,13 +489,36 @@ where
         pkcol: &Column,
         values: &[SqlValRef<'a>],
     ) -> Result<()> {
+        let on_conflict = helper::on_conflict_replace(columns, pkcol);
+        self.upsert(table, columns, &[pkcol.name()], &on_conflict, values)?;
+        Ok(())
+    }
+    fn upsert(
+        &self,
+        table: &str,
+        columns: &[Column],
+        conflict: &[&str],
+        on_conflict: &query::OnConflict,
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
         let mut sql = String::new();
-        sql_insert_or_replace_with_placeholders(table, columns, pkcol, &mut sql);
+        helper::sql_upsert_with_placeholders(
+            table,
+            columns,
+            conflict,
+            on_conflict,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        )?;
+        if cfg!(feature = "log") {
+            debug!("upsert sql {}", sql);
+        }
         let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
-        self.cell()?
+        let cnt = self
+            .cell()?
             .try_borrow_mut()?
             .execute(sql.as_str(), params.as_slice())?;
-        Ok(())
+        Ok(cnt as usize)
     }
     fn update(
         &self,
@@ -247,7 +527,7 @@ where
         pk: SqlValRef,
         columns: &[Column],
         values: &[SqlValRef<'_>],
-    ) -> Result<()> {
+    ) -> Result<usize> {
         let mut sql = String::new();
         helper::sql_update_with_placeholders(
             table,
@@ -264,10 +544,11 @@ where
         if cfg!(feature = "log") {
             debug!("update sql {}", sql);
         }
-        self.cell()?
+        let cnt = self
+            .cell()?
             .try_borrow_mut()?
             .execute(sql.as_str(), params.as_slice())?;
-        Ok(())
+        Ok(cnt as usize)
     }
     fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize> {
         let mut sql = String::new();
@@ -286,6 +567,33 @@ where
             .execute(sql.as_str(), params.as_slice())?;
         Ok(cnt as usize)
     }
+    fn update_where(
+        &self,
+        table: &str,
+        assignments: Vec<query::Assignment>,
+        expr: BoolExpr,
+    ) -> Result<usize> {
+        let mut sql = String::new();
+        let mut values: Vec<SqlVal> = Vec::new();
+        helper::sql_update_where(
+            table,
+            assignments,
+            expr,
+            sql_for_expr,
+            &mut values,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        );
+        if cfg!(feature = "log") {
+            debug!("update sql {}", sql);
+        }
+        let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
+        let cnt = self
+            .cell()?
+            .try_borrow_mut()?
+            .execute(sql.as_str(), params.as_slice())?;
+        Ok(cnt as usize)
+    }
     fn has_table(&self, table: &str) -> Result<bool> {
         // future improvement, should be schema-aware
         let stmt = self
@@ -297,13 +605,244 @@ where
     }
 }
 
+/// Async Pg database connection, using tokio-postgres.
+#[cfg(feature = "async-pg")]
+pub struct PgAsyncConnection {
+    client: tokio_postgres::Client,
+}
+#[cfg(feature = "async-pg")]
+impl PgAsyncConnection {
+    async fn open(params: &str) -> Result<Self> {
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "tls")] {
+                let connector = native_tls::TlsConnector::new()?;
+                let connector = postgres_native_tls::MakeTlsConnector::new(connector);
+            } else {
+                let connector = tokio_postgres::NoTls;
+            }
+        }
+        let (client, connection) = tokio_postgres::connect(params, connector).await?;
+        // The connection performs the actual communication with the
+        // database and must be polled until it is closed.
+        tokio::spawn(async move {
+            // _e is unused if logging is disabled
+            if let Err(_e) = connection.await {
+                crate::warn!("postgres connection error {}", _e);
+            }
+        });
+        Ok(PgAsyncConnection { client })
+    }
+}
+#[cfg(feature = "async-pg")]
+#[async_trait::async_trait]
+impl AsyncConnectionMethods for PgAsyncConnection {
+    async fn execute(&self, sql: &str) -> Result<()> {
+        if cfg!(feature = "log") {
+            debug!("execute sql {}", sql);
+        }
+        self.client.batch_execute(sql).await?;
+        Ok(())
+    }
+    async fn query(
+        &self,
+        table: &str,
+        columns: &[Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        order: Option<&[query::Order]>,
+    ) -> Result<Vec<AsyncRow>> {
+        let (sqlquery, values) = sql_for_query(
+            table,
+            columns,
+            expr,
+            limit,
+            offset,
+            order,
+            &mut PgPlaceholderSource::new(),
+        );
+        if cfg!(feature = "log") {
+            debug!("query sql {}", sqlquery);
+        }
+        let types: Vec<postgres::types::Type> = values.iter().map(pgtype_for_val).collect();
+        let stmt = self.client.prepare_typed(&sqlquery, &types).await?;
+        let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
+        self.client
+            .query(&stmt, &params)
+            .await?
+            .iter()
+            .map(|r| -> Result<AsyncRow> {
+                check_columns(r, columns.len())?;
+                columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, col)| sql_val_from_postgres(r, i, col))
+                    .collect()
+            })
+            .collect()
+    }
+    async fn insert_returning_pk(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<SqlVal> {
+        let mut sql = String::new();
+        helper::sql_insert_with_placeholders(
+            table,
+            columns,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        );
+        write!(&mut sql, " RETURNING {}", pkcol.name()).unwrap();
+        if cfg!(feature = "log") {
+            debug!("insert sql {}", sql);
+        }
+        let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
+        let row = self.client.query_one(sql.as_str(), &params).await?;
+        sql_val_from_postgres(&row, 0, pkcol)
+    }
+    async fn insert_only(
+        &self,
+        table: &str,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<()> {
+        let mut sql = String::new();
+        helper::sql_insert_with_placeholders(
+            table,
+            columns,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        );
+        let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
+        self.client.execute(sql.as_str(), &params).await?;
+        Ok(())
+    }
+    async fn insert_or_replace(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<()> {
+        let on_conflict = helper::on_conflict_replace(columns, pkcol);
+        self.upsert(table, columns, &[pkcol.name()], &on_conflict, values)
+            .await?;
+        Ok(())
+    }
+    async fn upsert(
+        &self,
+        table: &str,
+        columns: &[Column],
+        conflict: &[&str],
+        on_conflict: &query::OnConflict,
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
+        let mut sql = String::new();
+        helper::sql_upsert_with_placeholders(
+            table,
+            columns,
+            conflict,
+            on_conflict,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        )?;
+        if cfg!(feature = "log") {
+            debug!("upsert sql {}", sql);
+        }
+        let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
+        let cnt = self.client.execute(sql.as_str(), &params).await?;
+        Ok(cnt as usize)
+    }
+    async fn update(
+        &self,
+        table: &str,
+        pkcol: Column,
+        pk: SqlValRef<'_>,
+        columns: &[Column],
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
+        let mut sql = String::new();
+        helper::sql_update_with_placeholders(
+            table,
+            pkcol,
+            columns,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        );
+        if cfg!(feature = "log") {
+            debug!("update sql {}", sql);
+        }
+        let params: Vec<&DynToSqlPg> = values
+            .iter()
+            .chain(std::iter::once(&pk))
+            .map(|v| v as &DynToSqlPg)
+            .collect();
+        let cnt = self.client.execute(sql.as_str(), &params).await?;
+        Ok(cnt as usize)
+    }
+    async fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize> {
+        let mut sql = String::new();
+        let mut values: Vec<SqlVal> = Vec::new();
+        write!(&mut sql, "DELETE FROM {} WHERE ", table).unwrap();
+        sql_for_expr(
+            query::Expr::Condition(Box::new(expr)),
+            &mut values,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        );
+        let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
+        let cnt = self.client.execute(sql.as_str(), &params).await?;
+        Ok(cnt as usize)
+    }
+    async fn update_where(
+        &self,
+        table: &str,
+        assignments: Vec<query::Assignment>,
+        expr: BoolExpr,
+    ) -> Result<usize> {
+        let mut sql = String::new();
+        let mut values: Vec<SqlVal> = Vec::new();
+        helper::sql_update_where(
+            table,
+            assignments,
+            expr,
+            sql_for_expr,
+            &mut values,
+            &mut PgPlaceholderSource::new(),
+            &mut sql,
+        );
+        if cfg!(feature = "log") {
+            debug!("update sql {}", sql);
+        }
+        let params: Vec<&DynToSqlPg> = values.iter().map(|v| v as &DynToSqlPg).collect();
+        let cnt = self.client.execute(sql.as_str(), &params).await?;
+        Ok(cnt as usize)
+    }
+    async fn has_table(&self, table: &str) -> Result<bool> {
+        // future improvement, should be schema-aware
+        let rows = self
+            .client
+            .query(
+                "SELECT table_name FROM information_schema.tables WHERE table_name=$1;",
+                &[&table],
+            )
+            .await?;
+        Ok(!rows.is_empty())
+    }
+}
+
 struct PgTransaction<'c> {
     trans: Option<RefCell<postgres::Transaction<'c>>>,
+    statements: &'c PgStatementCache,
 }
 impl<'c> PgTransaction<'c> {
-    fn new(trans: postgres::Transaction<'c>) -> Self {
+    fn new(trans: postgres::Transaction<'c>, statements: &'c PgStatementCache) -> Self {
         PgTransaction {
             trans: Some(RefCell::new(trans)),
+            statements,
         }
     }
     fn get(&self) -> Result<&RefCell<postgres::Transaction<'c>>> {
@@ -321,6 +860,9 @@ impl<'c> PgConnectionLike for PgTransaction<'c> {
     fn cell(&self) -> Result<&RefCell<Self::Client>> {
         self.get()
     }
+    fn statements(&self) -> &PgStatementCache {
+        self.statements
+    }
 }
 
 impl<'c> BackendTransaction<'c> for PgTransaction<'c> {
@@ -336,6 +878,18 @@ impl<'c> BackendTransaction<'c> for PgTransaction<'c> {
             Some(trans) => Ok(trans.into_inner().rollback()?),
         }
     }
+    fn savepoint(&mut self) -> Result<Transaction<'_>> {
+        // A transaction within a transaction is implemented by
+        // postgres with a savepoint
+        let trans = match self.trans.as_mut() {
+            None => return Err(Self::already_consumed()),
+            Some(trans) => trans.get_mut().transaction()?,
+        };
+        Ok(Transaction::new(Box::new(PgTransaction::new(
+            trans,
+            self.statements,
+        ))))
+    }
     // Workaround for https://github.com/rust-lang/rfcs/issues/2765
     fn connection_methods(&self) -> &dyn ConnectionMethods {
         self
@@ -458,18 +1012,125 @@ impl<'a> postgres::types::FromSql<'a> for SqlValRef<'a> {
     }
 }
 
-fn check_columns(row: &postgres::Row, cols: &[Column]) -> Result<()> {
-    if cols.len() != row.len() {
+fn check_columns(row: &postgres::Row, num_cols: usize) -> Result<()> {
+    if num_cols != row.len() {
         Err(Error::Internal(format!(
             "postgres returns columns {} doesn't match requested columns {}",
             row.len(),
-            cols.len()
+            num_cols
         )))
     } else {
         Ok(())
     }
 }
 
+/// Runs `stmt` with `values`, reading all of the rows it returns.
+fn query_rows<T>(
+    client: &mut impl postgres::GenericClient,
+    stmt: &postgres::Statement,
+    values: &[T],
+    num_columns: usize,
+) -> Result<Vec<postgres::Row>>
+where
+    T: postgres::types::ToSql,
+{
+    client
+        .query_raw(
+            stmt,
+            values.iter().map(|v| v as &dyn postgres::types::ToSql),
+        )?
+        .map_err(Error::from)
+        .map(|row| {
+            check_columns(&row, num_columns)?;
+            Ok(row)
+        })
+        .collect()
+}
+
+#[pin_project]
+struct PgRowsInner<'a, C> {
+    // will always be Some when the constructor has finished. We use an
+    // option only to get the client in place before we can reference
+    // it. Declared before the client so that it is dropped first.
+    rows: Option<postgres::RowIter<'a>>,
+    // Keeps the client borrowed for as long as the rows are read
+    client: RefMut<'a, C>,
+}
+impl<'a, C> PgRowsInner<'a, C>
+where
+    C: postgres::GenericClient,
+{
+    fn new<T>(
+        client: RefMut<'a, C>,
+        stmt: &postgres::Statement,
+        values: &[T],
+    ) -> Result<Pin<Box<Self>>>
+    where
+        T: postgres::types::ToSql,
+    {
+        let mut q = Box::pin(PgRowsInner { rows: None, client });
+        unsafe {
+            //Soundness: we pin a PgRowsInner value containing both the
+            //  borrowed client and the rows referencing it together. It
+            //  is not possible to drop/move the client without bringing
+            //  the referencing rows along with it.
+            let q_ref = Pin::get_unchecked_mut(Pin::as_mut(&mut q));
+            let client_ref: *mut C = &mut *q_ref.client;
+            q_ref.rows = Some((&mut *client_ref).query_raw(
+                stmt,
+                values.iter().map(|v| v as &dyn postgres::types::ToSql),
+            )?)
+        }
+        Ok(q)
+    }
+
+    fn next(self: Pin<&mut Self>) -> Result<Option<postgres::Row>> {
+        let this = self.project();
+        let rows: &mut postgres::RowIter<'a> = this.rows.as_mut().unwrap();
+        Ok(rows.next()?)
+    }
+}
+
+/// Rows of a query, read from the server as they are requested
+/// rather than all at once.
+struct PgRows<'a, C> {
+    inner: Pin<Box<PgRowsInner<'a, C>>>,
+    current: Option<postgres::Row>,
+    num_columns: usize,
+}
+impl<'a, C> PgRows<'a, C>
+where
+    C: postgres::GenericClient,
+{
+    fn new<T>(
+        client: RefMut<'a, C>,
+        stmt: &postgres::Statement,
+        values: &[T],
+        num_columns: usize,
+    ) -> Result<Self>
+    where
+        T: postgres::types::ToSql,
+    {
+        Ok(PgRows {
+            inner: PgRowsInner::new(client, stmt, values)?,
+            current: None,
+            num_columns,
+        })
+    }
+}
+impl<'a, C> BackendRows for PgRows<'a, C> {
+    fn next<'b>(&'b mut self) -> Result<Option<&'b (dyn BackendRow + 'b)>> {
+        self.current = self.inner.as_mut().next()?;
+        if let Some(row) = &self.current {
+            check_columns(row, self.num_columns)?;
+        }
+        Ok(self.current.as_ref().map(|row| row as &dyn BackendRow))
+    }
+    fn current<'b>(&'b self) -> Option<&'b (dyn BackendRow + 'b)> {
+        self.current.as_ref().map(|row| row as &dyn BackendRow)
+    }
+}
+
 impl BackendRow for postgres::Row {
     fn get(&self, idx: usize, _ty: SqlType) -> Result<SqlValRef> {
         Ok(self.try_get(idx)?)
@@ -479,17 +1140,128 @@ impl BackendRow for postgres::Row {
     }
 }
 
-fn sql_for_expr<W>(
-    expr: query::Expr,
-    values: &mut Vec<SqlVal>,
-    pls: &mut PgPlaceholderSource,
-    w: &mut W,
-) where
+fn sql_for_query(
+    table: &str,
+    columns: &[Column],
+    expr: Option<BoolExpr>,
+    limit: Option<i32>,
+    offset: Option<i32>,
+    order: Option<&[query::Order]>,
+    pls: &mut impl helper::PlaceholderSource,
+) -> (String, Vec<SqlVal>) {
+    let mut sqlquery = String::new();
+    helper::sql_select(columns, table, &mut sqlquery);
+    let mut values: Vec<SqlVal> = Vec::new();
+    if let Some(expr) = expr {
+        sqlquery.write_str(" WHERE ").unwrap();
+        sql_for_expr(
+            query::Expr::Condition(Box::new(expr)),
+            &mut values,
+            pls,
+            &mut sqlquery,
+        );
+    }
+
+    if let Some(order) = order {
+        helper::sql_order(order, &mut sqlquery)
+    }
+
+    if let Some(limit) = limit {
+        helper::sql_limit(limit, &mut sqlquery)
+    }
+
+    if let Some(offset) = offset {
+        helper::sql_offset(offset, &mut sqlquery)
+    }
+    (sqlquery, values)
+}
+
+fn sql_for_expr<P, W>(expr: query::Expr, values: &mut Vec<SqlVal>, pls: &mut P, w: &mut W)
+where
+    P: helper::PlaceholderSource,
     W: Write,
 {
     helper::sql_for_expr(expr, &sql_for_expr, values, pls, w)
 }
 
+/// Inserts `rows` rows, returning their primary keys in the order of
+/// the rows. Postgres does not return the rows of a multi-row insert
+/// in any particular order, so each row's key is taken from the
+/// sequence up front and the keys are returned by row number.
+fn sql_insert_many_returning_pk(
+    table: &str,
+    columns: &[Column],
+    pkcol: &Column,
+    rows: usize,
+    w: &mut String,
+) {
+    use helper::PlaceholderSource;
+    let mut pls = PgPlaceholderSource::new();
+    w.push_str("WITH butane_rows (");
+    columns
+        .iter()
+        .for_each(|c| write!(w, "{}, ", c.name()).unwrap());
+    w.push_str("butane_ord) AS (VALUES ");
+    for row in 0..rows {
+        if row > 0 {
+            w.push_str(", ");
+        }
+        w.push('(');
+        for col in columns {
+            write!(
+                w,
+                "CAST({} AS {}), ",
+                pls.next_placeholder(),
+                sqltype(col.ty())
+            )
+            .unwrap();
+        }
+        write!(w, "{})", row).unwrap();
+    }
+    write!(
+        w,
+        "), butane_pks AS (SELECT butane_ord, CAST(nextval(pg_get_serial_sequence('{table}', '{pk}')) AS {pktype}) AS {pk} FROM butane_rows)",
+        table = table,
+        pk = pkcol.name(),
+        pktype = sqltype(pkcol.ty()),
+    )
+    .unwrap();
+    write!(
+        w,
+        ", butane_inserted AS (INSERT INTO {} ({}",
+        table,
+        pkcol.name()
+    )
+    .unwrap();
+    columns
+        .iter()
+        .for_each(|c| write!(w, ", {}", c.name()).unwrap());
+    write!(w, ") SELECT butane_pks.{}", pkcol.name()).unwrap();
+    columns
+        .iter()
+        .for_each(|c| write!(w, ", butane_rows.{}", c.name()).unwrap());
+    write!(
+        w,
+        " FROM butane_rows JOIN butane_pks USING (butane_ord)) SELECT {} FROM butane_pks ORDER BY butane_ord",
+        pkcol.name()
+    )
+    .unwrap();
+}
+
+/// Postgres widens the results of SUM and AVG (e.g. to NUMERIC), so
+/// they are cast to the type the caller expects: BIGINT for sums of
+/// integers and DOUBLE PRECISION otherwise.
+fn sql_aggregate(aggregate: &query::Aggregate, w: &mut String) {
+    match aggregate.func {
+        query::AggregateFunc::Sum | query::AggregateFunc::Avg => {
+            w.push_str("CAST(");
+            helper::sql_aggregate(aggregate, w);
+            write!(w, " AS {})", sqltype(&aggregate.ty)).unwrap();
+        }
+        _ => helper::sql_aggregate(aggregate, w),
+    }
+}
+
 fn sql_val_from_postgres<I>(row: &postgres::Row, idx: I, col: &Column) -> Result<SqlVal>
 where
     I: postgres::row::RowIndex + std::fmt::Display,
@@ -510,37 +1282,112 @@ where
     }
 }
 
+/// Translates Postgres SQLSTATEs into the backend-neutral error
+/// variants where possible.
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Self {
+        use postgres::error::SqlState;
+        let db = match e.as_db_error() {
+            Some(db) => db,
+            None => return Error::Postgres(e),
+        };
+        let code = db.code();
+        if *code == SqlState::T_R_SERIALIZATION_FAILURE {
+            return Error::SerializationFailure(db.message().to_string());
+        }
+        if *code == SqlState::T_R_DEADLOCK_DETECTED {
+            return Error::Deadlock(db.message().to_string());
+        }
+        // Class 23 is integrity constraint violations
+        if !code.code().starts_with("23") {
+            return Error::Postgres(e);
+        }
+        let details = ConstraintDetails {
+            table: db.table().map(str::to_string),
+            column: db.column().map(str::to_string),
+            constraint: db.constraint().map(str::to_string),
+            message: db.message().to_string(),
+        };
+        if *code == SqlState::UNIQUE_VIOLATION {
+            Error::UniqueViolation(details)
+        } else if *code == SqlState::FOREIGN_KEY_VIOLATION {
+            Error::ForeignKeyViolation(details)
+        } else if *code == SqlState::NOT_NULL_VIOLATION {
+            Error::NotNullViolation(details)
+        } else if *code == SqlState::CHECK_VIOLATION {
+            Error::CheckViolation(details)
+        } else {
+            Error::ConstraintViolation(details)
+        }
+    }
+}
+
 fn sql_for_op(current: &mut ADB, op: &Operation) -> Result<String> {
     match op {
-        Operation::AddTable(table) => Ok(create_table(&table)?),
+        Operation::AddTable(table) => Ok(create_table_and_indexes(&table)?),
         Operation::RemoveTable(name) => Ok(drop_table(&name)),
         Operation::AddColumn(tbl, col) => add_column(&tbl, &col),
         Operation::RemoveColumn(tbl, name) => Ok(remove_column(&tbl, &name)),
         Operation::ChangeColumn(tbl, old, new) => change_column(current, &tbl, &old, Some(new)),
+        Operation::AddIndex(tbl, index) => Ok(helper::sql_create_index(&tbl, &index)),
+        Operation::RemoveIndex(_, name) => Ok(helper::sql_drop_index(&name)),
+        Operation::AddConstraint(tbl, constraint) => {
+            Ok(add_constraint(&tbl, &constraint).unwrap_or_default())
+        }
+        Operation::RemoveConstraint(tbl, name) => Ok(drop_constraint(&tbl, &name)),
+        Operation::RenameTable(old, new) => Ok(rename_table(current, &old, &new)),
+        Operation::RenameColumn(tbl, old, new) => Ok(rename_column(current, &tbl, &old, &new)),
     }
 }
 
 fn create_table(table: &ATable) -> Result<String> {
-    let coldefs = table
+    create_table_with_constraints_of(table, &table.name)
+}
+
+fn create_table_and_indexes(table: &ATable) -> Result<String> {
+    let mut stmts = vec![create_table(table)?];
+    stmts.extend(helper::sql_create_indexes(&table.name, table));
+    Ok(stmts.join("\n"))
+}
+
+/// Creates `table`, naming its constraints as if the table were
+/// called `constraint_tbl_name`.
+fn create_table_with_constraints_of(table: &ATable, constraint_tbl_name: &str) -> Result<String> {
+    let composite_pk = helper::sql_composite_pk(table);
+    let mut coldefs = table
         .columns
         .iter()
-        .map(define_column)
-        .collect::<Result<Vec<String>>>()?
-        .join(",\n");
-    Ok(format!("CREATE TABLE {} (\n{}\n);", table.name, coldefs))
+        .map(|col| define_column(constraint_tbl_name, col, composite_pk.is_none()))
+        .collect::<Result<Vec<String>>>()?;
+    coldefs.extend(composite_pk);
+    coldefs.extend(helper::sql_constraints(table));
+    Ok(format!(
+        "CREATE TABLE {} (\n{}\n);",
+        table.name,
+        coldefs.join(",\n")
+    ))
 }
 
-fn define_column(col: &AColumn) -> Result<String> {
+/// Defines `col`, declaring it the primary key if it is one and
+/// `inline_pk` is set.
+fn define_column(tbl_name: &str, col: &AColumn, inline_pk: bool) -> Result<String> {
     let mut constraints: Vec<String> = Vec::new();
     if !col.nullable() {
         constraints.push("NOT NULL".to_string());
     }
-    if col.is_pk() {
+    if col.is_pk() && inline_pk {
         constraints.push("PRIMARY KEY".to_string());
     }
     if col.unique() {
         constraints.push("UNIQUE".to_string());
     }
+    if let Some(references) = col.foreign_key().and_then(helper::sql_references) {
+        constraints.push(format!(
+            "CONSTRAINT {} {}",
+            fk_constraint_name(tbl_name, col.name()),
+            references
+        ));
+    }
     Ok(format!(
         "{} {} {}",
         &col.name(),
@@ -549,6 +1396,12 @@ fn define_column(col: &AColumn) -> Result<String> {
     ))
 }
 
+/// The name of the foreign key constraint on a column. This matches
+/// the name Postgres would pick by default.
+fn fk_constraint_name(tbl_name: &str, col_name: &str) -> String {
+    format!("{}_{}_fkey", tbl_name, col_name)
+}
+
 fn col_sqltype(col: &AColumn) -> Result<Cow<str>> {
     match col.typeid()? {
         TypeIdentifier::Name(name) => Ok(Cow::Owned(name)),
@@ -560,34 +1413,87 @@ fn col_sqltype(col: &AColumn) -> Result<Cow<str>> {
                     _ => Err(Error::InvalidAuto(col.name().to_string())),
                 }
             } else {
-                Ok(match ty {
-                    SqlType::Bool => Cow::Borrowed("BOOLEAN"),
-                    SqlType::Int => Cow::Borrowed("INTEGER"),
-                    SqlType::BigInt => Cow::Borrowed("BIGINT"),
-                    SqlType::Real => Cow::Borrowed("DOUBLE PRECISION"),
-                    SqlType::Text => Cow::Borrowed("TEXT"),
-                    #[cfg(feature = "datetime")]
-                    SqlType::Timestamp => Cow::Borrowed("TIMESTAMP"),
-                    SqlType::Blob => Cow::Borrowed("BYTEA"),
-                    SqlType::Custom(c) => match c {
-                        SqlTypeCustom::Pg(ref ty) => Cow::Owned(ty.name().to_string()),
-                    },
-                })
+                Ok(sqltype(&ty))
             }
         }
     }
 }
 
+fn sqltype(ty: &SqlType) -> Cow<'static, str> {
+    match ty {
+        SqlType::Bool => Cow::Borrowed("BOOLEAN"),
+        SqlType::Int => Cow::Borrowed("INTEGER"),
+        SqlType::BigInt => Cow::Borrowed("BIGINT"),
+        SqlType::Real => Cow::Borrowed("DOUBLE PRECISION"),
+        SqlType::Text => Cow::Borrowed("TEXT"),
+        #[cfg(feature = "datetime")]
+        SqlType::Timestamp => Cow::Borrowed("TIMESTAMP"),
+        SqlType::Blob => Cow::Borrowed("BYTEA"),
+        SqlType::Custom(c) => match c {
+            SqlTypeCustom::Pg(ty) => Cow::Owned(ty.name().to_string()),
+        },
+    }
+}
+
 fn drop_table(name: &str) -> String {
     format!("DROP TABLE {};", name)
 }
 
+fn rename_table(current: &ADB, old: &str, new: &str) -> String {
+    let mut stmts = vec![format!("ALTER TABLE {} RENAME TO {};", old, new)];
+    // Keep foreign key constraint names in line with the table name
+    if let Some(table) = current.get_table(old) {
+        for col in table.columns.iter().filter(|c| c.foreign_key().is_some()) {
+            stmts.push(rename_constraint(
+                new,
+                &fk_constraint_name(old, col.name()),
+                &fk_constraint_name(new, col.name()),
+            ));
+        }
+    }
+    stmts.join("\n")
+}
+
+fn rename_column(current: &ADB, tbl_name: &str, old: &str, new: &str) -> String {
+    let mut stmts = vec![format!(
+        "ALTER TABLE {} RENAME COLUMN {} TO {};",
+        tbl_name, old, new
+    )];
+    let has_fk = current
+        .get_table(tbl_name)
+        .and_then(|table| table.column(old))
+        .map_or(false, |col| col.foreign_key().is_some());
+    if has_fk {
+        stmts.push(rename_constraint(
+            tbl_name,
+            &fk_constraint_name(tbl_name, old),
+            &fk_constraint_name(tbl_name, new),
+        ));
+    }
+    stmts.join("\n")
+}
+
+fn rename_constraint(tbl_name: &str, old: &str, new: &str) -> String {
+    format!(
+        "ALTER TABLE {} RENAME CONSTRAINT {} TO {};",
+        tbl_name, old, new
+    )
+}
+
+fn add_constraint(tbl_name: &str, constraint: &AConstraint) -> Option<String> {
+    helper::sql_constraint(constraint).map(|sql| format!("ALTER TABLE {} ADD {};", tbl_name, sql))
+}
+
+fn drop_constraint(tbl_name: &str, name: &str) -> String {
+    format!("ALTER TABLE {} DROP CONSTRAINT {};", tbl_name, name)
+}
+
 fn add_column(tbl_name: &str, col: &AColumn) -> Result<String> {
     let default: SqlVal = helper::column_default(col)?;
     Ok(format!(
         "ALTER TABLE {} ADD COLUMN {} DEFAULT {};",
         tbl_name,
-        define_column(col)?,
+        define_column(tbl_name, col, true)?,
         helper::sql_literal_value(default)?
     ))
 }
@@ -629,71 +1535,153 @@ fn change_column(
         return Ok(String::new());
     }
     let old_table = table.unwrap();
+    if let Some(new) = new {
+        let mut unconstrained = new.clone();
+        unconstrained.set_foreign_key(old.foreign_key().cloned());
+        if &unconstrained == old {
+            // Only the foreign key differs, which doesn't require
+            // rebuilding the table.
+            let mut stmts = vec![drop_foreign_key(tbl_name, old.name())];
+            stmts.extend(add_foreign_key(tbl_name, new));
+            let result = stmts.join("\n");
+            let mut new_table = old_table.clone();
+            new_table.replace_column(new.clone());
+            current.replace_table(new_table);
+            return Ok(result);
+        }
+    }
     let mut new_table = old_table.clone();
     new_table.name = tmp_table_name(&new_table.name);
     match new {
         Some(col) => new_table.replace_column(col.clone()),
         None => new_table.remove_column(old.name()),
     }
-    let stmts: [&str; 4] = [
-        &create_table(&new_table)?,
-        &copy_table(&old_table, &new_table),
-        &drop_table(&old_table.name),
-        &format!("ALTER TABLE {} RENAME TO {};", &new_table.name, tbl_name),
+    // A unique constraint creates an index of the same name, which
+    // cannot coexist with the old table's, so table constraints are
+    // only added once the old table is gone.
+    let constraints = std::mem::take(&mut new_table.constraints);
+    // Foreign keys from other tables must be dropped along with the
+    // old table and then recreated against the new one.
+    let referencing_constraints: Vec<(&str, &AConstraint)> = current
+        .tables()
+        .filter(|other| other.name != tbl_name)
+        .flat_map(|other| {
+            other
+                .constraints
+                .iter()
+                .filter(|constraint| match constraint.kind() {
+                    AConstraintKind::ForeignKey(_, fk) => match fk.reference() {
+                        ARef::Columns { table, .. } => table == tbl_name,
+                        _ => false,
+                    },
+                    _ => false,
+                })
+                .map(move |constraint| (other.name.as_str(), constraint))
+        })
+        .collect();
+    let referencing: Vec<(&str, &AColumn)> = current
+        .tables()
+        .filter(|other| other.name != tbl_name)
+        .flat_map(|other| {
+            other
+                .columns
+                .iter()
+                .filter(|col| match col.reference() {
+                    Some(ARef::Literal { table, .. }) => table == tbl_name,
+                    _ => false,
+                })
+                .map(move |col| (other.name.as_str(), col))
+        })
+        .collect();
+    let mut stmts: Vec<String> = vec![
+        create_table_with_constraints_of(&new_table, tbl_name)?,
+        copy_table(&old_table, &new_table),
     ];
+    if referencing.is_empty() && referencing_constraints.is_empty() {
+        stmts.push(drop_table(&old_table.name));
+    } else {
+        stmts.push(format!("DROP TABLE {} CASCADE;", &old_table.name));
+    }
+    stmts.push(format!(
+        "ALTER TABLE {} RENAME TO {};",
+        &new_table.name, tbl_name
+    ));
+    // Indexes are dropped along with the old table
+    stmts.extend(helper::sql_create_indexes(tbl_name, &new_table));
+    stmts.extend(
+        constraints
+            .iter()
+            .filter_map(|constraint| add_constraint(tbl_name, constraint)),
+    );
+    new_table.constraints = constraints;
+    stmts.extend(
+        referencing
+            .into_iter()
+            .filter_map(|(other, col)| add_foreign_key(other, col)),
+    );
+    stmts.extend(
+        referencing_constraints
+            .into_iter()
+            .filter_map(|(other, constraint)| add_constraint(other, constraint)),
+    );
     let result = stmts.join("\n");
     new_table.name = old_table.name.clone();
     current.replace_table(new_table);
     Ok(result)
 }
 
-pub fn sql_insert_or_replace_with_placeholders(
-    table: &str,
-    columns: &[Column],
-    pkcol: &Column,
-    w: &mut impl Write,
-) {
-    write!(w, "INSERT ").unwrap();
-    write!(w, "INTO {} (", table).unwrap();
-    helper::list_columns(columns, w);
-    write!(w, ") VALUES (").unwrap();
-    columns.iter().fold(1, |n, _| {
-        let sep = if n == 1 { "" } else { ", " };
-        write!(w, "{}${}", sep, n).unwrap();
-        n + 1
-    });
-    write!(w, ")").unwrap();
-    write!(w, " ON CONFLICT ({}) DO UPDATE SET (", pkcol.name()).unwrap();
-    helper::list_columns(columns, w);
-    write!(w, ") = (").unwrap();
-    columns.iter().fold("", |sep, c| {
-        write!(w, "{}excluded.{}", sep, c.name()).unwrap();
-        ", "
-    });
-    write!(w, ")").unwrap();
+fn add_foreign_key(tbl_name: &str, col: &AColumn) -> Option<String> {
+    col.foreign_key()
+        .and_then(helper::sql_references)
+        .map(|references| {
+            format!(
+                "ALTER TABLE {} ADD CONSTRAINT {} FOREIGN KEY ({}) {};",
+                tbl_name,
+                fk_constraint_name(tbl_name, col.name()),
+                col.name(),
+                references
+            )
+        })
+}
+
+fn drop_foreign_key(tbl_name: &str, col_name: &str) -> String {
+    format!(
+        "ALTER TABLE {} DROP CONSTRAINT IF EXISTS {};",
+        tbl_name,
+        fk_constraint_name(tbl_name, col_name)
+    )
 }
 
 fn pgtype_for_val(val: &SqlVal) -> postgres::types::Type {
-    use postgres::types::Type;
+    pgtype_for_valref(&val.as_ref())
+}
+
+fn pgtype_for_valref(val: &SqlValRef) -> postgres::types::Type {
     match val.sqltype() {
-        None => Type::UNKNOWN,
-        Some(SqlType::Bool) => postgres::types::Type::BOOL,
-        Some(SqlType::Int) => postgres::types::Type::INT4,
-        Some(SqlType::BigInt) => postgres::types::Type::INT8,
-        Some(SqlType::Real) => postgres::types::Type::FLOAT8,
-        Some(SqlType::Text) => postgres::types::Type::TEXT,
-        Some(SqlType::Blob) => postgres::types::Type::BYTEA,
+        None => postgres::types::Type::UNKNOWN,
+        Some(ty) => pgtype_for_sqltype(&ty),
+    }
+}
+
+fn pgtype_for_sqltype(ty: &SqlType) -> postgres::types::Type {
+    match ty {
+        SqlType::Bool => postgres::types::Type::BOOL,
+        SqlType::Int => postgres::types::Type::INT4,
+        SqlType::BigInt => postgres::types::Type::INT8,
+        SqlType::Real => postgres::types::Type::FLOAT8,
+        SqlType::Text => postgres::types::Type::TEXT,
+        SqlType::Blob => postgres::types::Type::BYTEA,
         #[cfg(feature = "datetime")]
-        Some(SqlType::Timestamp) => postgres::types::Type::TIMESTAMP,
-        Some(SqlType::Custom(inner)) => match inner {
+        SqlType::Timestamp => postgres::types::Type::TIMESTAMP,
+        SqlType::Custom(inner) => match inner {
             #[cfg(feature = "pg")]
-            SqlTypeCustom::Pg(ty, ..) => ty,
+            SqlTypeCustom::Pg(ty, ..) => ty.clone(),
         },
     }
 }
 
 struct PgPlaceholderSource {
-    n: i8,
+    n: u32,
 }
 impl PgPlaceholderSource {
     fn new() -> Self {
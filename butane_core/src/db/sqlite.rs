@@ -6,7 +6,7 @@ use crate::debug;
 use crate::migrations::adb::{AColumn, ATable, Operation, TypeIdentifier, ADB};
 use crate::query;
 use crate::query::Order;
-use crate::{Result, SqlType, SqlVal, SqlValRef};
+use crate::{ConstraintDetails, Result, SqlType, SqlVal, SqlValRef};
 #[cfg(feature = "datetime")]
 use chrono::naive::NaiveDateTime;
 use fallible_streaming_iterator::FallibleStreamingIterator;
@@ -21,6 +21,9 @@ const SQLITE_DT_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
 /// The name of the sqlite backend.
 pub const BACKEND_NAME: &str = "sqlite";
 
+// The default value of SQLITE_MAX_VARIABLE_NUMBER in older SQLite versions
+const MAX_PARAMS: usize = 999;
+
 /// SQLite [Backend][crate::db::Backend] implementation.
 #[derive(Default)]
 pub struct SQLiteBackend {}
@@ -33,6 +36,12 @@ impl SQLiteBackend {
     fn connect(&self, path: &str) -> Result<SQLiteConnection> {
         SQLiteConnection::open(Path::new(path))
     }
+    /// Open an async connection, which runs on a dedicated worker
+    /// thread. See also [connect_async][crate::db::connect_async].
+    #[cfg(feature = "async")]
+    pub fn connect_async(&self, path: &str) -> Result<SQLiteAsyncConnection> {
+        Ok(BlockingAsyncConnection::new(self.connect(path)?))
+    }
 }
 impl Backend for SQLiteBackend {
     fn name(&self) -> &'static str {
@@ -59,15 +68,20 @@ impl Backend for SQLiteBackend {
     }
 }
 
+/// Async SQLite database connection.
+#[cfg(feature = "async")]
+pub type SQLiteAsyncConnection = BlockingAsyncConnection<SQLiteConnection>;
+
 /// SQLite database connection.
 pub struct SQLiteConnection {
     conn: rusqlite::Connection,
 }
 impl SQLiteConnection {
     fn open(path: impl AsRef<Path>) -> Result<Self> {
-        rusqlite::Connection::open(path)
-            .map(|conn| SQLiteConnection { conn })
-            .map_err(|e| e.into())
+        let conn = rusqlite::Connection::open(path)?;
+        // SQLite does not enforce foreign key constraints unless asked to
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        Ok(SQLiteConnection { conn })
     }
 
     // For use with connection_method_wrapper macro
@@ -84,6 +98,46 @@ impl BackendConnection for SQLiteConnection {
         let trans = Box::new(SqliteTransaction::new(trans));
         Ok(Transaction::new(trans))
     }
+    fn transaction_with_options<'c>(
+        &'c mut self,
+        options: &TransactionOptions,
+    ) -> Result<Transaction<'c>> {
+        use rusqlite::TransactionBehavior;
+        let behavior = match options.get_isolation_level() {
+            _ if options.is_read_only() => TransactionBehavior::Deferred,
+            None | Some(IsolationLevel::ReadUncommitted) => TransactionBehavior::Deferred,
+            Some(IsolationLevel::ReadCommitted) | Some(IsolationLevel::RepeatableRead) => {
+                TransactionBehavior::Immediate
+            }
+            Some(IsolationLevel::Serializable) => TransactionBehavior::Exclusive,
+        };
+        let trans = self.conn.transaction_with_behavior(behavior)?;
+        let trans = Box::new(SqliteTransaction::new(trans));
+        Ok(Transaction::new(trans))
+    }
+    fn migration_transaction<'c>(&'c mut self) -> Result<Transaction<'c>> {
+        // Rebuilding a table drops it, which with foreign keys enforced
+        // would delete or update the rows referring to it. Enforcement
+        // cannot be changed within a transaction, so it is turned off
+        // first and the foreign keys are checked before committing
+        // instead, as in SQLite's procedure for altering tables.
+        self.conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+        let conn: &'c rusqlite::Connection = &self.conn;
+        let trans = match rusqlite::Transaction::new_unchecked(
+            conn,
+            rusqlite::TransactionBehavior::Deferred,
+        ) {
+            Ok(trans) => trans,
+            Err(e) => {
+                conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+                return Err(e.into());
+            }
+        };
+        Ok(Transaction::new(Box::new(SqliteTransaction {
+            trans: Some(SqliteTransactionKind::Transaction(trans)),
+            foreign_keys_off: Some(conn),
+        })))
+    }
     fn backend(&self) -> Box<dyn Backend> {
         Box::new(SQLiteBackend {})
     }
@@ -112,9 +166,70 @@ impl ConnectionMethods for rusqlite::Connection {
         limit: Option<i32>,
         offset: Option<i32>,
         order: Option<&[Order]>,
+    ) -> Result<RawQueryResult<'a>> {
+        let (sqlquery, values) = sql_for_query(
+            table,
+            columns,
+            expr,
+            limit,
+            offset,
+            order,
+            &mut SQLitePlaceholderSource::new(),
+        );
+
+        debug!("query sql {}", sqlquery);
+
+        let stmt = self.prepare(&sqlquery)?;
+        let adapter = QueryAdapter::new(stmt, rusqlite::params_from_iter(values))?;
+        Ok(Box::new(adapter))
+    }
+    fn prepare_query(
+        &self,
+        table: &str,
+        columns: &[Column],
+        expr: Option<BoolExpr>,
+        limit: Option<i32>,
+        offset: Option<i32>,
+        order: Option<&[Order]>,
+    ) -> Result<PreparedSql> {
+        let mut pls = helper::BoundPlaceholders::new(SQLitePlaceholderSource::new());
+        let (sqlquery, values) =
+            sql_for_query(table, columns, expr, limit, offset, order, &mut pls);
+        Ok(PreparedSql::new(
+            BACKEND_NAME,
+            sqlquery,
+            values,
+            pls.bound(),
+            columns.len(),
+        ))
+    }
+    fn query_prepared<'a, 'c: 'a>(
+        &'c self,
+        prepared: &PreparedSql,
+        params: &[SqlVal],
+    ) -> Result<RawQueryResult<'a>> {
+        let values = prepared.params(BACKEND_NAME, params)?;
+        debug!("query sql {}", prepared.sql());
+
+        let stmt = self.prepare_cached(prepared.sql())?;
+        let adapter = QueryAdapter::new(stmt, rusqlite::params_from_iter(values))?;
+        Ok(Box::new(adapter))
+    }
+    fn query_aggregate<'a, 'c: 'a>(
+        &'c self,
+        table: &str,
+        group_by: &[Column],
+        aggregates: &[query::Aggregate],
+        expr: Option<BoolExpr>,
     ) -> Result<RawQueryResult<'a>> {
         let mut sqlquery = String::new();
-        helper::sql_select(columns, table, &mut sqlquery);
+        helper::sql_select_aggregates(
+            group_by,
+            aggregates,
+            table,
+            helper::sql_aggregate,
+            &mut sqlquery,
+        );
         let mut values: Vec<SqlVal> = Vec::new();
         if let Some(expr) = expr {
             sqlquery.write_str(" WHERE ").unwrap();
@@ -125,18 +240,7 @@ impl ConnectionMethods for rusqlite::Connection {
                 &mut sqlquery,
             );
         }
-
-        if let Some(order) = order {
-            helper::sql_order(order, &mut sqlquery)
-        }
-
-        if let Some(limit) = limit {
-            helper::sql_limit(limit, &mut sqlquery)
-        }
-
-        if let Some(offset) = offset {
-            helper::sql_offset(offset, &mut sqlquery)
-        }
+        helper::sql_group_by(group_by, &mut sqlquery);
 
         debug!("query sql {}", sqlquery);
 
@@ -187,18 +291,98 @@ impl ConnectionMethods for rusqlite::Connection {
         self.execute(&sql, rusqlite::params_from_iter(values))?;
         Ok(())
     }
+    fn insert_many_returning_pk(
+        &self,
+        table: &str,
+        columns: &[Column],
+        pkcol: &Column,
+        values: &[SqlValRef<'_>],
+    ) -> Result<Vec<SqlVal>> {
+        // Rows are inserted one at a time so that each primary key
+        // can be read back. With prepared statements and no network
+        // round trip this is still fast.
+        let mut sql = String::new();
+        helper::sql_insert_with_placeholders(
+            table,
+            columns,
+            &mut SQLitePlaceholderSource::new(),
+            &mut sql,
+        );
+        if cfg!(feature = "log") {
+            debug!("insert sql {}", sql);
+        }
+        let mut insert = self.prepare_cached(&sql)?;
+        let mut select_pk = self.prepare_cached(&format!(
+            "SELECT {} FROM {} WHERE ROWID = last_insert_rowid()",
+            pkcol.name(),
+            table
+        ))?;
+        let mut pks: Vec<SqlVal> = Vec::with_capacity(values.len() / columns.len().max(1));
+        for row in values.chunks(columns.len().max(1)) {
+            insert.execute(rusqlite::params_from_iter(row))?;
+            let pk: Option<Result<SqlVal>> = select_pk
+                .query_and_then([], |r| sql_val_from_rusqlite(r.get_ref_unwrap(0), pkcol))?
+                .next();
+            pks.push(pk.ok_or_else(|| Error::Internal("could not get pk".to_string()))??);
+        }
+        Ok(pks)
+    }
+    fn insert_many(&self, table: &str, columns: &[Column], values: &[SqlValRef<'_>]) -> Result<()> {
+        for chunk in values.chunks(helper::insert_chunk_len(columns.len(), MAX_PARAMS)) {
+            let mut sql = String::new();
+            helper::sql_insert_many_with_placeholders(
+                table,
+                columns,
+                chunk.len() / columns.len(),
+                &mut SQLitePlaceholderSource::new(),
+                &mut sql,
+            );
+            if cfg!(feature = "log") {
+                debug!("insert sql {}", sql);
+            }
+            self.execute(&sql, rusqlite::params_from_iter(chunk))?;
+        }
+        Ok(())
+    }
+    fn copy_in(&self, table: &str, columns: &[Column], values: &[SqlValRef<'_>]) -> Result<()> {
+        self.insert_many(table, columns, values)
+    }
     fn insert_or_replace(
         &self,
         table: &str,
         columns: &[Column],
-        _pkcol: &Column,
+        pkcol: &Column,
         values: &[SqlValRef],
     ) -> Result<()> {
-        let mut sql = String::new();
-        sql_insert_or_update(table, columns, &mut sql);
-        self.execute(&sql, rusqlite::params_from_iter(values))?;
+        // Unlike INSERT OR REPLACE, an upsert updates the existing row
+        // rather than deleting it, which would fire cascades
+        let on_conflict = helper::on_conflict_replace(columns, pkcol);
+        self.upsert(table, columns, &[pkcol.name()], &on_conflict, values)?;
         Ok(())
     }
+    fn upsert(
+        &self,
+        table: &str,
+        columns: &[Column],
+        conflict: &[&str],
+        on_conflict: &query::OnConflict,
+        values: &[SqlValRef<'_>],
+    ) -> Result<usize> {
+        let mut sql = String::new();
+        helper::sql_upsert_with_placeholders(
+            table,
+            columns,
+            conflict,
+            on_conflict,
+            &mut SQLitePlaceholderSource::new(),
+            &mut sql,
+        )?;
+        if cfg!(feature = "log") {
+            debug!("upsert sql {}", sql);
+        }
+        let cnt = self.execute(&sql, rusqlite::params_from_iter(values))?;
+        Ok(cnt)
+    }
     fn update(
         &self,
         table: &str,
@@ -206,7 +390,7 @@ impl ConnectionMethods for rusqlite::Connection {
         pk: SqlValRef,
         columns: &[Column],
         values: &[SqlValRef<'_>],
-    ) -> Result<()> {
+    ) -> Result<usize> {
         let mut sql = String::new();
         helper::sql_update_with_placeholders(
             table,
@@ -219,8 +403,8 @@ impl ConnectionMethods for rusqlite::Connection {
         if cfg!(feature = "log") {
             debug!("update sql {}", sql);
         }
-        self.execute(&sql, rusqlite::params_from_iter(placeholder_values))?;
-        Ok(())
+        let cnt = self.execute(&sql, rusqlite::params_from_iter(placeholder_values))?;
+        Ok(cnt)
     }
     fn delete_where(&self, table: &str, expr: BoolExpr) -> Result<usize> {
         let mut sql = String::new();
@@ -235,6 +419,27 @@ impl ConnectionMethods for rusqlite::Connection {
         let cnt = self.execute(&sql, rusqlite::params_from_iter(values))?;
         Ok(cnt)
     }
+    fn update_where(
+        &self,
+        table: &str,
+        assignments: Vec<query::Assignment>,
+        expr: BoolExpr,
+    ) -> Result<usize> {
+        let mut sql = String::new();
+        let mut values: Vec<SqlVal> = Vec::new();
+        helper::sql_update_where(
+            table,
+            assignments,
+            expr,
+            sql_for_expr,
+            &mut values,
+            &mut SQLitePlaceholderSource::new(),
+            &mut sql,
+        );
+        debug!("update sql {}", sql);
+        let cnt = self.execute(&sql, rusqlite::params_from_iter(values))?;
+        Ok(cnt)
+    }
     fn has_table(&self, table: &str) -> Result<bool> {
         let mut stmt =
             self.prepare("SELECT name FROM sqlite_master WHERE type='table' AND name=?;")?;
@@ -243,39 +448,113 @@ impl ConnectionMethods for rusqlite::Connection {
     }
 }
 
+/// Either a top-level transaction or a savepoint nested within one.
+enum SqliteTransactionKind<'c> {
+    Transaction(rusqlite::Transaction<'c>),
+    Savepoint(rusqlite::Savepoint<'c>),
+}
+
 struct SqliteTransaction<'c> {
-    trans: Option<rusqlite::Transaction<'c>>,
+    trans: Option<SqliteTransactionKind<'c>>,
+    // The connection, if foreign key enforcement was turned off for
+    // this transaction and must be turned back on once it finishes
+    foreign_keys_off: Option<&'c rusqlite::Connection>,
 }
 impl<'c> SqliteTransaction<'c> {
     fn new(trans: rusqlite::Transaction<'c>) -> Self {
-        SqliteTransaction { trans: Some(trans) }
+        SqliteTransaction {
+            trans: Some(SqliteTransactionKind::Transaction(trans)),
+            foreign_keys_off: None,
+        }
     }
-    fn get(&self) -> Result<&rusqlite::Transaction<'c>> {
+    fn get(&self) -> Result<&rusqlite::Connection> {
         match &self.trans {
             None => Err(Self::already_consumed()),
-            Some(trans) => Ok(trans),
+            Some(SqliteTransactionKind::Transaction(trans)) => Ok(trans.deref()),
+            Some(SqliteTransactionKind::Savepoint(sp)) => Ok(sp.deref()),
         }
     }
     fn wrapped_connection_methods(&self) -> Result<&rusqlite::Connection> {
-        Ok(self.get()?.deref())
+        self.get()
     }
     fn already_consumed() -> Error {
         Error::Internal("transaction has already been consumed".to_string())
     }
+    /// Fails if any row refers to a row which does not exist, as
+    /// foreign keys are not checked as changes are made while
+    /// enforcement is off.
+    fn check_foreign_keys(&self) -> Result<()> {
+        let conn = self.get()?;
+        let mut stmt = conn.prepare("PRAGMA foreign_key_check;")?;
+        let mut rows = stmt.query([])?;
+        match rows.next()? {
+            None => Ok(()),
+            Some(row) => {
+                let table: String = row.get(0)?;
+                let parent: String = row.get(2)?;
+                Err(Error::ForeignKeyViolation(ConstraintDetails {
+                    message: format!(
+                        "FOREIGN KEY constraint failed: {} refers to a missing row of {}",
+                        table, parent
+                    ),
+                    table: Some(table),
+                    ..Default::default()
+                }))
+            }
+        }
+    }
+    fn enable_foreign_keys(&mut self) -> Result<()> {
+        if let Some(conn) = self.foreign_keys_off.take() {
+            conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        }
+        Ok(())
+    }
+}
+impl Drop for SqliteTransaction<'_> {
+    fn drop(&mut self) {
+        // The transaction must be finished (by rolling it back, if it
+        // has not been committed) before enforcement can be changed
+        self.trans.take();
+        // _err is unused if logging is disabled
+        if let Err(_err) = self.enable_foreign_keys() {
+            crate::warn!("failed to turn foreign key enforcement back on: {}", _err);
+        }
+    }
 }
 connection_method_wrapper!(SqliteTransaction<'_>);
 impl<'c> BackendTransaction<'c> for SqliteTransaction<'c> {
     fn commit(&mut self) -> Result<()> {
+        if self.foreign_keys_off.is_some() {
+            // On failure the transaction is rolled back when dropped
+            self.check_foreign_keys()?;
+        }
         match self.trans.take() {
-            None => Err(Self::already_consumed()),
-            Some(trans) => Ok(trans.commit()?),
+            None => return Err(Self::already_consumed()),
+            Some(SqliteTransactionKind::Transaction(trans)) => trans.commit()?,
+            Some(SqliteTransactionKind::Savepoint(sp)) => sp.commit()?,
         }
+        self.enable_foreign_keys()
     }
     fn rollback(&mut self) -> Result<()> {
         match self.trans.take() {
-            None => Err(Self::already_consumed()),
-            Some(trans) => Ok(trans.rollback()?),
+            None => return Err(Self::already_consumed()),
+            Some(SqliteTransactionKind::Transaction(trans)) => trans.rollback()?,
+            // The default drop behavior of a savepoint rolls back to
+            // and then releases it.
+            Some(SqliteTransactionKind::Savepoint(sp)) => sp.finish()?,
         }
+        self.enable_foreign_keys()
+    }
+    fn savepoint(&mut self) -> Result<Transaction<'_>> {
+        let sp = match self.trans.as_mut() {
+            None => return Err(Self::already_consumed()),
+            Some(SqliteTransactionKind::Transaction(trans)) => trans.savepoint()?,
+            Some(SqliteTransactionKind::Savepoint(sp)) => sp.savepoint()?,
+        };
+        Ok(Transaction::new(Box::new(SqliteTransaction {
+            trans: Some(SqliteTransactionKind::Savepoint(sp)),
+            foreign_keys_off: None,
+        })))
     }
     // Workaround for https://github.com/rust-lang/rfcs/issues/2765
     fn connection_methods(&self) -> &dyn ConnectionMethods {
@@ -318,16 +597,42 @@ fn sqlvalref_to_sqlite<'a, 'b>(valref: &'b SqlValRef<'a>) -> rusqlite::types::To
     }
 }
 
+/// A statement which is either owned or borrowed from the connection's
+/// statement cache.
+enum QueryStatement<'a> {
+    Plain(rusqlite::Statement<'a>),
+    Cached(rusqlite::CachedStatement<'a>),
+}
+impl<'a> QueryStatement<'a> {
+    fn get_mut(&mut self) -> &mut rusqlite::Statement<'a> {
+        match self {
+            QueryStatement::Plain(stmt) => stmt,
+            QueryStatement::Cached(stmt) => &mut **stmt,
+        }
+    }
+}
+impl<'a> From<rusqlite::Statement<'a>> for QueryStatement<'a> {
+    fn from(stmt: rusqlite::Statement<'a>) -> Self {
+        QueryStatement::Plain(stmt)
+    }
+}
+impl<'a> From<rusqlite::CachedStatement<'a>> for QueryStatement<'a> {
+    fn from(stmt: rusqlite::CachedStatement<'a>) -> Self {
+        QueryStatement::Cached(stmt)
+    }
+}
+
 #[pin_project]
 struct QueryAdapterInner<'a> {
-    stmt: rusqlite::Statement<'a>,
     // will always be Some when the constructor has finished. We use an option only to get the
-    // stmt in place before we can reference it.
+    // stmt in place before we can reference it. Declared before the stmt so that it is
+    // dropped first.
     rows: Option<rusqlite::Rows<'a>>,
+    stmt: QueryStatement<'a>,
 }
 
 impl<'a> QueryAdapterInner<'a> {
-    fn new(stmt: rusqlite::Statement<'a>, params: impl rusqlite::Params) -> Result<Pin<Box<Self>>> {
+    fn new(stmt: QueryStatement<'a>, params: impl rusqlite::Params) -> Result<Pin<Box<Self>>> {
         let mut q = Box::pin(QueryAdapterInner { stmt, rows: None });
         unsafe {
             //Soundness: we pin a QueryAdapterInner value containing
@@ -335,7 +640,7 @@ impl<'a> QueryAdapterInner<'a> {
             //  together. It is not possible to drop/move the stmt without
             //  bringing the referencing rows along with it.
             let q_ref = Pin::get_unchecked_mut(Pin::as_mut(&mut q));
-            let stmt_ref: *mut rusqlite::Statement<'a> = &mut q_ref.stmt;
+            let stmt_ref: *mut rusqlite::Statement<'a> = q_ref.stmt.get_mut();
             q_ref.rows = Some((&mut *stmt_ref).query(params)?)
         }
         Ok(q)
@@ -357,9 +662,9 @@ struct QueryAdapter<'a> {
     inner: Pin<Box<QueryAdapterInner<'a>>>,
 }
 impl<'a> QueryAdapter<'a> {
-    fn new(stmt: rusqlite::Statement<'a>, params: impl rusqlite::Params) -> Result<Self> {
+    fn new(stmt: impl Into<QueryStatement<'a>>, params: impl rusqlite::Params) -> Result<Self> {
         Ok(QueryAdapter {
-            inner: QueryAdapterInner::new(stmt, params)?,
+            inner: QueryAdapterInner::new(stmt.into(), params)?,
         })
     }
 }
@@ -389,12 +694,45 @@ impl BackendRow for rusqlite::Row<'_> {
     }
 }
 
-fn sql_for_expr<W>(
-    expr: query::Expr,
-    values: &mut Vec<SqlVal>,
-    pls: &mut SQLitePlaceholderSource,
-    w: &mut W,
-) where
+fn sql_for_query(
+    table: &str,
+    columns: &[Column],
+    expr: Option<BoolExpr>,
+    limit: Option<i32>,
+    offset: Option<i32>,
+    order: Option<&[Order]>,
+    pls: &mut impl helper::PlaceholderSource,
+) -> (String, Vec<SqlVal>) {
+    let mut sqlquery = String::new();
+    helper::sql_select(columns, table, &mut sqlquery);
+    let mut values: Vec<SqlVal> = Vec::new();
+    if let Some(expr) = expr {
+        sqlquery.write_str(" WHERE ").unwrap();
+        sql_for_expr(
+            query::Expr::Condition(Box::new(expr)),
+            &mut values,
+            pls,
+            &mut sqlquery,
+        );
+    }
+
+    if let Some(order) = order {
+        helper::sql_order(order, &mut sqlquery)
+    }
+
+    if let Some(limit) = limit {
+        helper::sql_limit(limit, &mut sqlquery)
+    }
+
+    if let Some(offset) = offset {
+        helper::sql_offset(offset, &mut sqlquery)
+    }
+    (sqlquery, values)
+}
+
+fn sql_for_expr<P, W>(expr: query::Expr, values: &mut Vec<SqlVal>, pls: &mut P, w: &mut W)
+where
+    P: helper::PlaceholderSource,
     W: Write,
 {
     helper::sql_for_expr(expr, &sql_for_expr, values, pls, w)
@@ -429,32 +767,106 @@ fn sql_valref_from_rusqlite<'a>(
     })
 }
 
+/// Translates SQLite's extended result codes into the backend-neutral
+/// error variants where possible.
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        use rusqlite::ffi;
+        let (err, msg) = match &e {
+            rusqlite::Error::SqliteFailure(err, msg) => (err, msg),
+            _ => return Error::SQLite(e),
+        };
+        let message = msg.clone().unwrap_or_else(|| err.to_string());
+        if err.extended_code == ffi::SQLITE_BUSY_SNAPSHOT {
+            return Error::SerializationFailure(message);
+        }
+        if err.code != rusqlite::ErrorCode::ConstraintViolation {
+            return Error::SQLite(e);
+        }
+        let details = constraint_details(message, err.extended_code);
+        match err.extended_code {
+            ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY => {
+                Error::UniqueViolation(details)
+            }
+            ffi::SQLITE_CONSTRAINT_FOREIGNKEY => Error::ForeignKeyViolation(details),
+            ffi::SQLITE_CONSTRAINT_NOTNULL => Error::NotNullViolation(details),
+            ffi::SQLITE_CONSTRAINT_CHECK => Error::CheckViolation(details),
+            _ => Error::ConstraintViolation(details),
+        }
+    }
+}
+
+/// Extracts what it can from the message SQLite gives for a constraint
+/// violation, such as "UNIQUE constraint failed: Foo.bar" or "CHECK
+/// constraint failed: Foo_check0". SQLite does not report the names of
+/// unique constraints, nor anything about foreign key violations.
+fn constraint_details(message: String, extended_code: std::os::raw::c_int) -> ConstraintDetails {
+    let mut details = ConstraintDetails::default();
+    if let Some(pos) = message.find(": ") {
+        let subject = &message[pos + 2..];
+        if extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_CHECK {
+            details.constraint = Some(subject.to_string());
+        } else {
+            let columns: Vec<(&str, &str)> = subject
+                .split(", ")
+                .filter_map(|col| col.find('.').map(|dot| (&col[..dot], &col[dot + 1..])))
+                .collect();
+            details.table = columns.first().map(|(table, _)| table.to_string());
+            if let [(_, column)] = columns.as_slice() {
+                details.column = Some(column.to_string());
+            }
+        }
+    }
+    details.message = message;
+    details
+}
+
 fn sql_for_op(current: &mut ADB, op: &Operation) -> Result<String> {
     match op {
-        Operation::AddTable(table) => Ok(create_table(&table)),
+        Operation::AddTable(table) => Ok(create_table_and_indexes(&table)),
         Operation::RemoveTable(name) => Ok(drop_table(&name)),
-        Operation::AddColumn(tbl, col) => add_column(&tbl, &col),
-        Operation::RemoveColumn(tbl, name) => Ok(remove_column(current, &tbl, &name)),
-        Operation::ChangeColumn(tbl, old, new) => Ok(change_column(current, &tbl, &old, Some(new))),
+        Operation::AddColumn(tbl, col) => add_column(current, &tbl, &col),
+        Operation::RemoveColumn(tbl, name) => remove_column(current, &tbl, &name),
+        Operation::ChangeColumn(tbl, old, new) => change_column(current, &tbl, &old, Some(new)),
+        Operation::AddIndex(tbl, index) => Ok(helper::sql_create_index(&tbl, &index)),
+        Operation::RemoveIndex(_, name) => Ok(helper::sql_drop_index(&name)),
+        Operation::AddConstraint(tbl, constraint) => rebuild_table(current, &tbl, |table| {
+            table.add_constraint(constraint.clone())
+        }),
+        Operation::RemoveConstraint(tbl, name) => {
+            rebuild_table(current, &tbl, |table| table.remove_constraint(&name))
+        }
+        Operation::RenameTable(old, new) => Ok(rename_table(&old, &new)),
+        Operation::RenameColumn(tbl, old, new) => Ok(rename_column(&tbl, &old, &new)),
     }
 }
 
 fn create_table(table: &ATable) -> String {
-    let coldefs = table
+    let composite_pk = helper::sql_composite_pk(table);
+    let mut coldefs = table
         .columns
         .iter()
-        .map(define_column)
-        .collect::<Vec<String>>()
-        .join(",\n");
-    format!("CREATE TABLE {} (\n{}\n);", table.name, coldefs)
+        .map(|col| define_column(col, composite_pk.is_none()))
+        .collect::<Vec<String>>();
+    coldefs.extend(composite_pk);
+    coldefs.extend(helper::sql_constraints(table));
+    format!("CREATE TABLE {} (\n{}\n);", table.name, coldefs.join(",\n"))
+}
+
+fn create_table_and_indexes(table: &ATable) -> String {
+    let mut stmts = vec![create_table(table)];
+    stmts.extend(helper::sql_create_indexes(&table.name, table));
+    stmts.join("\n")
 }
 
-fn define_column(col: &AColumn) -> String {
+/// Defines `col`, declaring it the primary key if it is one and
+/// `inline_pk` is set.
+fn define_column(col: &AColumn, inline_pk: bool) -> String {
     let mut constraints: Vec<String> = Vec::new();
     if !col.nullable() {
         constraints.push("NOT NULL".to_string());
     }
-    if col.is_pk() {
+    if col.is_pk() && inline_pk {
         constraints.push("PRIMARY KEY".to_string());
     }
     if col.is_auto() && !col.is_pk() {
@@ -465,6 +877,9 @@ fn define_column(col: &AColumn) -> String {
     if col.unique() {
         constraints.push("UNIQUE".to_string());
     }
+    if let Some(references) = col.foreign_key().and_then(helper::sql_references) {
+        constraints.push(references);
+    }
     format!(
         "{} {} {}",
         &col.name(),
@@ -501,17 +916,30 @@ fn drop_table(name: &str) -> String {
     format!("DROP TABLE {};", name)
 }
 
-fn add_column(tbl_name: &str, col: &AColumn) -> Result<String> {
+fn rename_table(old: &str, new: &str) -> String {
+    format!("ALTER TABLE {} RENAME TO {};", old, new)
+}
+
+fn rename_column(tbl_name: &str, old: &str, new: &str) -> String {
+    format!("ALTER TABLE {} RENAME COLUMN {} TO {};", tbl_name, old, new)
+}
+
+fn add_column(current: &mut ADB, tbl_name: &str, col: &AColumn) -> Result<String> {
+    if col.foreign_key().is_some() {
+        // SQLite refuses to add a column with a foreign key and a
+        // non-null default, so rebuild the table instead.
+        return rebuild_table(current, tbl_name, |table| table.add_column(col.clone()));
+    }
     let default: SqlVal = helper::column_default(col)?;
     Ok(format!(
         "ALTER TABLE {} ADD COLUMN {} DEFAULT {};",
         tbl_name,
-        define_column(col),
+        define_column(col, true),
         helper::sql_literal_value(default)?
     ))
 }
 
-fn remove_column(current: &mut ADB, tbl_name: &str, name: &str) -> String {
+fn remove_column(current: &mut ADB, tbl_name: &str, name: &str) -> Result<String> {
     let old = current
         .get_table(tbl_name)
         .and_then(|table| table.column(name))
@@ -524,22 +952,28 @@ fn remove_column(current: &mut ADB, tbl_name: &str, name: &str) -> String {
                 name,
                 tbl_name
             );
-            "".to_string()
+            Ok("".to_string())
         }
     }
 }
 
-fn copy_table(old: &ATable, new: &ATable) -> String {
+fn copy_table(old: &ATable, new: &ATable) -> Result<String> {
     let column_names = new
         .columns
         .iter()
-        .map(|col| col.name())
-        .collect::<Vec<&str>>()
+        .map(|col| match old.column(col.name()) {
+            Some(_) => Ok(Cow::Borrowed(col.name())),
+            // The column is new, so fill it in with its default
+            None => helper::column_default(col)
+                .and_then(helper::sql_literal_value)
+                .map(Cow::Owned),
+        })
+        .collect::<Result<Vec<Cow<str>>>>()?
         .join(", ");
-    format!(
+    Ok(format!(
         "INSERT INTO {} SELECT {} FROM {};",
         &new.name, column_names, &old.name
-    )
+    ))
 }
 
 fn tmp_table_name(name: &str) -> String {
@@ -551,45 +985,54 @@ fn change_column(
     tbl_name: &str,
     old: &AColumn,
     new: Option<&AColumn>,
-) -> String {
-    let table = current.get_table(tbl_name);
-    if table.is_none() {
+) -> Result<String> {
+    if current.get_table(tbl_name).is_none() {
         crate::warn!(
             "Cannot alter column {} from table {} that does not exist",
             &old.name(),
             tbl_name
         );
-        return "".to_string();
+        return Ok("".to_string());
     }
-    let old_table = table.unwrap();
+    rebuild_table(current, tbl_name, |table| match new {
+        Some(col) => table.replace_column(col.clone()),
+        None => table.remove_column(old.name()),
+    })
+}
+
+/// Recreates the table `tbl_name` with the modifications made by
+/// `modify`, preserving its data. SQLite's ALTER TABLE is very
+/// limited, so most schema changes must be made this way.
+fn rebuild_table(
+    current: &mut ADB,
+    tbl_name: &str,
+    modify: impl FnOnce(&mut ATable),
+) -> Result<String> {
+    let old_table = match current.get_table(tbl_name) {
+        Some(table) => table,
+        None => {
+            crate::warn!("Cannot alter table {} that does not exist", tbl_name);
+            return Ok("".to_string());
+        }
+    };
     let mut new_table = old_table.clone();
     new_table.name = tmp_table_name(&new_table.name);
-    match new {
-        Some(col) => new_table.replace_column(col.clone()),
-        None => new_table.remove_column(old.name()),
-    }
-    let stmts: [&str; 4] = [
-        &create_table(&new_table),
-        &copy_table(&old_table, &new_table),
-        &drop_table(&old_table.name),
-        &format!("ALTER TABLE {} RENAME TO {};", &new_table.name, tbl_name),
+    modify(&mut new_table);
+    // Dropping the old table does not affect the rows referring to
+    // it, as migrations are applied with foreign key enforcement
+    // turned off (see migration_transaction).
+    let mut stmts: Vec<String> = vec![
+        create_table(&new_table),
+        copy_table(&old_table, &new_table)?,
+        drop_table(&old_table.name),
+        rename_table(&new_table.name, tbl_name),
     ];
+    // Indexes are dropped along with the old table
+    stmts.extend(helper::sql_create_indexes(tbl_name, &new_table));
     let result = stmts.join("\n");
     new_table.name = old_table.name.clone();
     current.replace_table(new_table);
-    result
-}
-
-pub fn sql_insert_or_update(table: &str, columns: &[Column], w: &mut impl Write) {
-    write!(w, "INSERT OR REPLACE ").unwrap();
-    write!(w, "INTO {} (", table).unwrap();
-    helper::list_columns(columns, w);
-    write!(w, ") VALUES (").unwrap();
-    columns.iter().fold("", |sep, _| {
-        write!(w, "{}?", sep).unwrap();
-        ", "
-    });
-    write!(w, ")").unwrap();
+    Ok(result)
 }
 
 struct SQLitePlaceholderSource {}
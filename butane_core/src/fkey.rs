@@ -1,4 +1,5 @@
 use crate::db::ConnectionMethods;
+use crate::query::{column_indexes, load_rows_in, row_has_key, Prefetch};
 use crate::*;
 use once_cell::unsync::OnceCell;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -7,7 +8,15 @@ use std::fmt::{Debug, Formatter};
 
 /// Used to implement a relationship between models.
 ///
-/// Initialize using `From` or `from_pk`
+/// Initialize using `From` or `from_pk`.
+///
+/// If the referenced model has a composite primary key, the foreign
+/// key has a column for each column of the key, named after the field
+/// and the key column, e.g. `order_tenant_id` and `order_id` for a
+/// field `order` referring to a key of `tenant_id` and `id`. The
+/// field must name the key columns, in order, with the
+/// `#[references(tenant_id, id)]` attribute. Such a field cannot be
+/// used in a `filter!` expression.
 ///
 /// # Examples
 /// ```ignore
@@ -20,7 +29,7 @@ use std::fmt::{Debug, Formatter};
 ///   blog: ForeignKey<Blog>,
 ///   ...
 /// }
-///
+/// ```
 pub struct ForeignKey<T>
 where
     T: DataObject,
@@ -28,12 +37,15 @@ where
     // At least one must be initialized (enforced internally by this
     // type), but both need not be
     val: OnceCell<T>,
-    valpk: OnceCell<SqlVal>,
+    valpk: OnceCell<Vec<SqlVal>>,
 }
-impl<T: DataObject> ForeignKey<T> {
+impl<T> ForeignKey<T>
+where
+    T: DataObject,
+{
     pub fn from_pk(pk: T::PKType) -> Self {
         let ret = Self::new_raw();
-        ret.valpk.set(pk.into_sql()).unwrap();
+        ret.valpk.set(pk.to_sql_values()).unwrap();
         ret
     }
     /// Returns a reference to the value. It must have already been loaded. If not, returns Error::ValueNotLoaded
@@ -44,9 +56,9 @@ impl<T: DataObject> ForeignKey<T> {
     /// Returns a reference to the primary key of the value.
     pub fn pk(&self) -> T::PKType {
         match self.val.get() {
-            Some(v) => v.pk().clone(),
+            Some(v) => v.pk().into_owned(),
             None => match self.valpk.get() {
-                Some(pk) => T::PKType::from_sql_ref(pk.as_ref()).unwrap(),
+                Some(pk) => T::PKType::from_sql_values(pk).unwrap(),
                 None => panic!("Invalid foreign key state"),
             },
         }
@@ -57,7 +69,7 @@ impl<T: DataObject> ForeignKey<T> {
     pub fn load(&self, conn: &impl ConnectionMethods) -> Result<&T> {
         self.val.get_or_try_init(|| {
             let pk = self.valpk.get().unwrap();
-            T::get(conn, &T::PKType::from_sql_ref(pk.as_ref())?)
+            T::get(conn, &T::PKType::from_sql_values(pk)?)
         })
     }
 
@@ -68,11 +80,11 @@ impl<T: DataObject> ForeignKey<T> {
         }
     }
 
-    fn ensure_valpk(&self) -> &SqlVal {
+    fn ensure_valpk(&self) -> &[SqlVal] {
         match self.valpk.get() {
-            Some(sqlval) => return &sqlval,
+            Some(sqlvals) => return &sqlvals,
             None => match self.val.get() {
-                Some(val) => self.valpk.set(val.pk().to_sql()).unwrap(),
+                Some(val) => self.valpk.set(val.pk().to_sql_values()).unwrap(),
                 None => panic!("Invalid foreign key state"),
             },
         }
@@ -80,6 +92,42 @@ impl<T: DataObject> ForeignKey<T> {
     }
 }
 
+impl<T> Prefetch for ForeignKey<T>
+where
+    T: DataObject,
+{
+    fn prefetch(fkeys: &[&Self], conn: &dyn ConnectionMethods) -> Result<()> {
+        let mut pks: Vec<Vec<SqlVal>> = Vec::new();
+        for fkey in fkeys.iter().filter(|fkey| fkey.val.get().is_none()) {
+            let pk = fkey.ensure_valpk();
+            if !pks.iter().any(|other| other.as_slice() == pk) {
+                pks.push(pk.to_vec());
+            }
+        }
+        if pks.is_empty() {
+            return Ok(());
+        }
+        let pkidxs = column_indexes(T::COLUMNS, T::PKCOLS)?;
+        let rows = load_rows_in(
+            conn,
+            T::TABLE,
+            T::COLUMNS,
+            T::PKCOLS,
+            &pks,
+            T::SOFT_DELETE_COL,
+        )?;
+        for fkey in fkeys.iter().filter(|fkey| fkey.val.get().is_none()) {
+            // Each foreign key needs its own object, so the object is
+            // constructed from the row afresh for each one
+            let pk = fkey.ensure_valpk();
+            if let Some(row) = rows.iter().find(|row| row_has_key(row, &pkidxs, pk)) {
+                fkey.val.set(T::from_row(row)?).ok();
+            }
+        }
+        Ok(())
+    }
+}
+
 impl<T: DataObject> From<T> for ForeignKey<T> {
     fn from(obj: T) -> Self {
         let ret = Self::new_raw();
@@ -87,12 +135,18 @@ impl<T: DataObject> From<T> for ForeignKey<T> {
         ret
     }
 }
-impl<T: DataObject> From<&T> for ForeignKey<T> {
+impl<T> From<&T> for ForeignKey<T>
+where
+    T: DataObject,
+{
     fn from(obj: &T) -> Self {
-        Self::from_pk(obj.pk().clone())
+        Self::from_pk(obj.pk().into_owned())
     }
 }
-impl<T: DataObject> Clone for ForeignKey<T> {
+impl<T> Clone for ForeignKey<T>
+where
+    T: DataObject,
+{
     fn clone(&self) -> Self {
         // Once specialization lands, it would be nice to clone val if
         // it's cloneable. Then we wouldn't have to ensure the pk
@@ -113,31 +167,39 @@ where
     }
 }
 
-impl<T: DataObject> Eq for ForeignKey<T> {}
-impl<T: DataObject> Debug for ForeignKey<T> {
+impl<T> Eq for ForeignKey<T> where T: DataObject {}
+impl<T> Debug for ForeignKey<T>
+where
+    T: DataObject,
+{
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        self.ensure_valpk().fmt(f)
+        match self.ensure_valpk() {
+            [pk] => pk.fmt(f),
+            pks => pks.fmt(f),
+        }
     }
 }
 
 impl<T> ToSql for ForeignKey<T>
 where
     T: DataObject,
+    T::PKType: PrimaryKeyType,
 {
     fn to_sql(&self) -> SqlVal {
-        self.ensure_valpk().clone()
+        self.ensure_valpk()[0].clone()
     }
     fn to_sql_ref(&self) -> SqlValRef<'_> {
-        self.ensure_valpk().as_ref()
+        self.ensure_valpk()[0].as_ref()
     }
     fn into_sql(self) -> SqlVal {
         self.ensure_valpk();
-        self.valpk.into_inner().unwrap()
+        self.valpk.into_inner().unwrap().swap_remove(0)
     }
 }
 impl<T> FieldType for ForeignKey<T>
 where
     T: DataObject,
+    T::PKType: PrimaryKeyType,
 {
     const SQLTYPE: SqlType = <T as DataObject>::PKType::SQLTYPE;
     type RefType = <<T as DataObject>::PKType as FieldType>::RefType;
@@ -148,7 +210,7 @@ where
 {
     fn from_sql_ref(valref: SqlValRef) -> Result<Self> {
         Ok(ForeignKey {
-            valpk: SqlVal::from(valref).into(),
+            valpk: vec![SqlVal::from(valref)].into(),
             val: OnceCell::new(),
         })
     }
@@ -162,7 +224,7 @@ where
         match self.val.get() {
             Some(t) => t.pk().eq(&other.as_pk()),
             None => match self.valpk.get() {
-                Some(valpk) => valpk.eq(&other.as_pk().to_sql()),
+                Some(valpk) => valpk.eq(&other.as_pk().to_sql_values()),
                 None => panic!("Invalid foreign key state"),
             },
         }
@@ -194,3 +256,46 @@ where
         Ok(Self::from_pk(T::PKType::deserialize(deserializer)?))
     }
 }
+
+/// A `ForeignKey` or `Option<ForeignKey>` field referring to a model
+/// with a composite primary key, whose value occupies a column for
+/// each column of the key. Used by macro-generated code. You do not
+/// need to use this directly.
+pub trait ForeignKeyColumns: Sized {
+    /// The referenced model.
+    type Model: DataObject;
+    /// The values of each of the columns, in the order of the key.
+    fn to_sql_refs(&self) -> Vec<SqlValRef<'_>>;
+    /// Reads the field from the values of each of its columns.
+    fn from_sql_values(vals: Vec<SqlVal>) -> Result<Self>;
+}
+
+impl<T: DataObject> ForeignKeyColumns for ForeignKey<T> {
+    type Model = T;
+    fn to_sql_refs(&self) -> Vec<SqlValRef<'_>> {
+        self.ensure_valpk().iter().map(SqlVal::as_ref).collect()
+    }
+    fn from_sql_values(vals: Vec<SqlVal>) -> Result<Self> {
+        Ok(ForeignKey {
+            valpk: vals.into(),
+            val: OnceCell::new(),
+        })
+    }
+}
+
+impl<T: DataObject> ForeignKeyColumns for Option<ForeignKey<T>> {
+    type Model = T;
+    fn to_sql_refs(&self) -> Vec<SqlValRef<'_>> {
+        match self {
+            Some(fkey) => fkey.to_sql_refs(),
+            None => T::PKCOLS.iter().map(|_| SqlValRef::Null).collect(),
+        }
+    }
+    fn from_sql_values(vals: Vec<SqlVal>) -> Result<Self> {
+        // As in SQL, a key with any null column refers to nothing
+        if vals.iter().any(|val| matches!(val, SqlVal::Null)) {
+            return Ok(None);
+        }
+        ForeignKey::from_sql_values(vals).map(Some)
+    }
+}
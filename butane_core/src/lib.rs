@@ -1,11 +1,12 @@
 #![allow(clippy::iter_nth_zero)]
 #![allow(clippy::upper_case_acronyms)] //grandfathered, not going to break API to rename
 use serde::{Deserialize, Serialize};
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::cmp::{Eq, PartialEq};
 use std::default::Default;
 use thiserror::Error as ThisError;
 
+pub mod backref;
 pub mod codegen;
 pub mod custom;
 pub mod db;
@@ -18,7 +19,9 @@ pub mod sqlval;
 #[cfg(feature = "uuid")]
 pub mod uuid;
 
-use db::{BackendRow, Column, ConnectionMethods};
+#[cfg(feature = "async")]
+use db::{AsyncConnectionMethods, BoxFuture};
+use db::{BackendConnection, BackendRow, Column, ConnectionMethods, Transaction};
 
 use custom::SqlTypeCustom;
 pub use query::Query;
@@ -35,6 +38,26 @@ pub type Result<T> = std::result::Result<T, crate::Error>;
 #[derive(Clone, Default, Debug)]
 pub struct ObjectState {
     pub saved: bool,
+    /// Column values as last loaded from or saved to the database,
+    /// used to determine which columns need to be saved.
+    snapshot: Option<Vec<SqlVal>>,
+}
+impl ObjectState {
+    /// Records the values of all the object's columns as they now
+    /// exist in the database. Used by macro-generated code. You do
+    /// not need to call this directly.
+    pub fn set_snapshot(&mut self, values: Vec<SqlVal>) {
+        self.snapshot = Some(values);
+    }
+    /// Tests if the column at index `idx` may have changed since the
+    /// object was last loaded or saved. Used by macro-generated
+    /// code. You do not need to call this directly.
+    pub fn is_column_dirty(&self, idx: usize, val: &SqlValRef) -> bool {
+        match self.snapshot.as_ref().and_then(|s| s.get(idx)) {
+            Some(stored) => stored != val,
+            None => true,
+        }
+    }
 }
 /// Two `ObjectState`s always compare as equal. This effectively
 /// removes `ObjectState` from participating in equality tests between
@@ -69,35 +92,323 @@ pub trait DataResult: Sized {
 /// Rather than implementing this type manually, use the
 /// `#[model]` attribute.
 pub trait DataObject: DataResult<DBO = Self> {
-    /// The type of the primary key field.
-    type PKType: PrimaryKeyType;
+    /// The type of the primary key field, or a tuple of the types of
+    /// the primary key fields for a composite primary key.
+    type PKType: PrimaryKey;
     type Fields: Default;
-    /// The name of the primary key column.
+    /// The name of the primary key column. For a composite primary
+    /// key, the name of its first column.
     const PKCOL: &'static str;
+    /// The names of the primary key columns, in the order of the
+    /// values of `PKType`.
+    const PKCOLS: &'static [&'static str] = &[Self::PKCOL];
     /// The name of the table.
     const TABLE: &'static str;
-    /// Get the primary key
-    fn pk(&self) -> &Self::PKType;
-    /// Find this object in the database based on primary key.
+    /// The column recording when each object was soft deleted, if the
+    /// model was declared with `#[model(soft_delete)]`.
+    const SOFT_DELETE_COL: Option<&'static str> = None;
+    /// Get the primary key. This is a `Cow` since a composite primary
+    /// key is assembled from its fields; it was previously a
+    /// `&Self::PKType`, which callers may still get with `&*obj.pk()`.
+    fn pk(&self) -> Cow<'_, Self::PKType>;
+    /// The name of the table holding the links of the [`Many`](many::Many)
+    /// field named `field`, or `None` if there is no such field.
+    fn many_table(field: &str) -> Option<&'static str>
+    where
+        Self: Sized,
+    {
+        let _ = field;
+        None
+    }
+    /// Find this object in the database based on primary key. An
+    /// object which has been soft deleted is not found.
     fn get(conn: &impl ConnectionMethods, id: impl Borrow<Self::PKType>) -> Result<Self>
     where
         Self: Sized,
     {
         <Self as DataResult>::query()
-            .filter(query::BoolExpr::Eq(
-                Self::PKCOL,
-                query::Expr::Val(id.borrow().to_sql()),
-            ))
+            .filter(query::pk_expr::<Self>(id.borrow()))
             .limit(1)
             .load(conn)?
             .into_iter()
             .nth(0)
             .ok_or(Error::NoSuchObject)
     }
-    /// Save the object to the database.
+    /// Save the object to the database. If the object already exists
+    /// in the database, only columns which have changed since it was
+    /// loaded or last saved are written.
     fn save(&mut self, conn: &impl ConnectionMethods) -> Result<()>;
-    /// Delete the object from the database.
+    /// Save all of `objects` to the database. Objects which have not
+    /// been saved before are inserted together, which is much faster
+    /// than saving each in turn.
+    fn save_all(objects: &mut [Self], conn: &impl ConnectionMethods) -> Result<()>
+    where
+        Self: Sized,
+    {
+        for obj in objects {
+            obj.save(conn)?;
+        }
+        Ok(())
+    }
+    /// Delete the object from the database. If the model uses soft
+    /// deletion, the object is instead marked as deleted.
     fn delete(&self, conn: &impl ConnectionMethods) -> Result<()>;
+    /// Reload the object's columns from the database, discarding any
+    /// changes to them which have not been saved. Values cached by
+    /// its `ForeignKey`, `Many` and `BackRef` fields are cleared. Fails
+    /// with [`Error::NoSuchObject`] if the object is no longer in the
+    /// database.
+    fn reload(&mut self, conn: &impl ConnectionMethods) -> Result<()>;
+    /// Insert the object, unless it conflicts with an existing object
+    /// on the `conflict` columns (which must have a unique constraint),
+    /// in which case `on_conflict` is applied to the existing object.
+    /// Returns true if an object was inserted or updated. `Many` fields
+    /// are not saved, and the object is not marked as saved, as it may
+    /// not be the object now in the database.
+    ///
+    /// `conflict` may only be empty with `OnConflict::DoNothing`, in
+    /// which case a conflict on any unique constraint leaves the
+    /// existing object unchanged. Otherwise this fails with
+    /// [`Error::MissingConflictTarget`].
+    ///
+    /// There is no `Query` counterpart, since an upsert writes a
+    /// particular object and has no use for a filter. To change the
+    /// objects a query matches, use
+    /// [`Query::update`](query::Query::update).
+    fn upsert(
+        &mut self,
+        conn: &impl ConnectionMethods,
+        conflict: &[&str],
+        on_conflict: query::OnConflict,
+    ) -> Result<bool>;
+    /// Find the first object matching `filter`, or if there is none,
+    /// insert the object returned by `defaults`, which must match
+    /// `filter`. Returns the object and whether it was created. Runs
+    /// within a transaction. If another connection creates a matching
+    /// object at the same time, a unique constraint covering the
+    /// columns of `filter` ensures only one is inserted and both
+    /// connections get that object. If inserting fails for any other
+    /// reason, such as a conflict with an object which does not match
+    /// `filter`, the error is returned.
+    fn get_or_create(
+        conn: &mut impl BackendConnection,
+        filter: query::BoolExpr,
+        defaults: impl FnOnce() -> Self,
+    ) -> Result<(Self, bool)>
+    where
+        Self: Sized,
+    {
+        conn.with_transaction(|trans| get_or_create_in(trans, filter, defaults))
+    }
+    /// Like [`get_or_create`](DataObject::get_or_create), but an
+    /// object which already exists is changed by `update` and saved,
+    /// within the same transaction.
+    fn update_or_create(
+        conn: &mut impl BackendConnection,
+        filter: query::BoolExpr,
+        defaults: impl FnOnce() -> Self,
+        update: impl FnOnce(&mut Self),
+    ) -> Result<(Self, bool)>
+    where
+        Self: Sized,
+    {
+        conn.with_transaction(|trans| {
+            let (mut obj, created) = get_or_create_in(trans, filter, defaults)?;
+            if !created {
+                update(&mut obj);
+                obj.save(trans)?;
+            }
+            Ok((obj, created))
+        })
+    }
+    /// Like [`get`](DataObject::get), for use with an async connection.
+    #[cfg(feature = "async")]
+    fn get_async<'a>(
+        conn: &'a impl AsyncConnectionMethods,
+        id: Self::PKType,
+    ) -> BoxFuture<'a, Result<Self>>
+    where
+        Self: Send + 'a,
+    {
+        let filter = query::pk_expr::<Self>(&id);
+        Box::pin(async move {
+            <Self as DataResult>::query()
+                .filter(filter)
+                .limit(1)
+                .load_async(conn)
+                .await?
+                .into_iter()
+                .nth(0)
+                .ok_or(Error::NoSuchObject)
+        })
+    }
+    /// Like [`save`](DataObject::save), for use with an async connection.
+    ///
+    /// This and the other async methods which depend on the model's
+    /// fields are generated by the `model` macro. If the macro was
+    /// built without the `async` feature they are not, and fail with
+    /// [`Error::AsyncUnsupported`].
+    #[cfg(feature = "async")]
+    fn save_async<'a>(
+        &'a mut self,
+        _conn: &'a impl AsyncConnectionMethods,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Err(Error::AsyncUnsupported("save_async")) })
+    }
+    /// Like [`reload`](DataObject::reload), for use with an async connection.
+    #[cfg(feature = "async")]
+    fn reload_async<'a>(
+        &'a mut self,
+        _conn: &'a impl AsyncConnectionMethods,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Err(Error::AsyncUnsupported("reload_async")) })
+    }
+    /// Like [`upsert`](DataObject::upsert), for use with an async connection.
+    #[cfg(feature = "async")]
+    fn upsert_async<'a>(
+        &'a mut self,
+        _conn: &'a impl AsyncConnectionMethods,
+        _conflict: &'a [&'a str],
+        _on_conflict: query::OnConflict,
+    ) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async { Err(Error::AsyncUnsupported("upsert_async")) })
+    }
+    /// Like [`get_or_create`](DataObject::get_or_create), for use with
+    /// an async connection. Async connections do not support
+    /// transactions, so this does not run within one, and relies on
+    /// the unique constraint alone to detect a matching object created
+    /// at the same time.
+    #[cfg(feature = "async")]
+    fn get_or_create_async<'a>(
+        conn: &'a impl AsyncConnectionMethods,
+        filter: query::BoolExpr,
+        defaults: impl FnOnce() -> Self + Send + 'a,
+    ) -> BoxFuture<'a, Result<(Self, bool)>>
+    where
+        Self: Send + 'a,
+    {
+        Box::pin(async move {
+            let existing = Self::query()
+                .filter(filter.clone())
+                .load_first_async(conn)
+                .await?;
+            if let Some(obj) = existing {
+                return Ok((obj, false));
+            }
+            let mut obj = defaults();
+            match obj.save_async(conn).await {
+                Ok(()) => Ok((obj, true)),
+                Err(e @ Error::UniqueViolation(_)) => Self::query()
+                    .filter(filter)
+                    .load_first_async(conn)
+                    .await?
+                    .map(|obj| (obj, false))
+                    .ok_or(e),
+                Err(e) => Err(e),
+            }
+        })
+    }
+    /// Like [`update_or_create`](DataObject::update_or_create), for use
+    /// with an async connection.
+    #[cfg(feature = "async")]
+    fn update_or_create_async<'a>(
+        conn: &'a impl AsyncConnectionMethods,
+        filter: query::BoolExpr,
+        defaults: impl FnOnce() -> Self + Send + 'a,
+        update: impl FnOnce(&mut Self) + Send + 'a,
+    ) -> BoxFuture<'a, Result<(Self, bool)>>
+    where
+        Self: Send + 'a,
+    {
+        Box::pin(async move {
+            let (mut obj, created) = Self::get_or_create_async(conn, filter, defaults).await?;
+            if !created {
+                update(&mut obj);
+                obj.save_async(conn).await?;
+            }
+            Ok((obj, created))
+        })
+    }
+    /// Like [`delete`](DataObject::delete), for use with an async connection.
+    #[cfg(feature = "async")]
+    fn delete_async<'a>(
+        &'a self,
+        conn: &'a impl AsyncConnectionMethods,
+    ) -> BoxFuture<'a, Result<()>> {
+        let expr = query::pk_expr::<Self>(&self.pk());
+        Box::pin(async move {
+            query::delete_where_async::<Self>(conn, Self::TABLE, expr).await?;
+            Ok(())
+        })
+    }
+}
+
+/// An object whose model was declared with `#[model(soft_delete)]`.
+/// Deleting such an object records when it was deleted rather than
+/// removing it from the database, and queries do not match it unless
+/// asked to with [`Query::with_deleted`](query::Query::with_deleted)
+/// or [`Query::only_deleted`](query::Query::only_deleted).
+pub trait SoftDelete: DataObject {
+    /// Restores the object if it has been soft deleted.
+    fn restore(&self, conn: &impl ConnectionMethods) -> Result<()> {
+        let assignment = query::Assignment {
+            column: soft_delete_col::<Self>()?,
+            value: SqlVal::Null,
+        };
+        let expr = query::pk_expr::<Self>(&self.pk());
+        match conn.update_where(Self::TABLE, vec![assignment], expr)? {
+            0 => Err(Error::NoSuchObject),
+            _ => Ok(()),
+        }
+    }
+    /// Like [`restore`](SoftDelete::restore), for use with an async connection.
+    #[cfg(feature = "async")]
+    fn restore_async<'a>(
+        &'a self,
+        conn: &'a impl AsyncConnectionMethods,
+    ) -> BoxFuture<'a, Result<()>> {
+        let expr = query::pk_expr::<Self>(&self.pk());
+        Box::pin(async move {
+            let assignment = query::Assignment {
+                column: soft_delete_col::<Self>()?,
+                value: SqlVal::Null,
+            };
+            let updated = conn.update_where(Self::TABLE, vec![assignment], expr);
+            match updated.await? {
+                0 => Err(Error::NoSuchObject),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// The body of `get_or_create`, run within `trans`.
+fn get_or_create_in<T: DataObject>(
+    trans: &mut Transaction,
+    filter: query::BoolExpr,
+    defaults: impl FnOnce() -> T,
+) -> Result<(T, bool)> {
+    if let Some(obj) = T::query().filter(filter.clone()).load_first(trans)? {
+        return Ok((obj, false));
+    }
+    // The insert is made within a savepoint, so that the transaction
+    // remains usable if it fails
+    let mut obj = defaults();
+    match trans.with_transaction(|savepoint| obj.save(savepoint)) {
+        Ok(()) => Ok((obj, true)),
+        // Another connection may have inserted a matching object since
+        // the query. If not, the conflict is with some other object.
+        Err(e @ Error::UniqueViolation(_)) => T::query()
+            .filter(filter)
+            .load_first(trans)?
+            .map(|obj| (obj, false))
+            .ok_or(e),
+        Err(e) => Err(e),
+    }
+}
+
+fn soft_delete_col<T: DataObject>() -> Result<&'static str> {
+    T::SOFT_DELETE_COL.ok_or_else(|| Error::Internal(format!("{} is not soft deleted", T::TABLE)))
 }
 
 pub trait ModelTyped {
@@ -145,19 +456,59 @@ pub enum Error {
     IncompatibleCustomT(custom::SqlTypeCustom, &'static str),
     #[error("Literal values for custom types are currently unsupported.")]
     LiteralForCustomUnsupported(custom::SqlValCustom),
+    #[error("Query was prepared for backend {0} and cannot be run by backend {1}")]
+    IncompatiblePreparedQuery(&'static str, &'static str),
+    #[error("Query expects {expected} parameters, found {found}")]
+    WrongParameterCount { expected: usize, found: usize },
+    #[error("Unique constraint violation {0}")]
+    UniqueViolation(ConstraintDetails),
+    #[error("Foreign key constraint violation {0}")]
+    ForeignKeyViolation(ConstraintDetails),
+    #[error("Not null constraint violation {0}")]
+    NotNullViolation(ConstraintDetails),
+    #[error("Check constraint violation {0}")]
+    CheckViolation(ConstraintDetails),
+    /// A violation of a constraint not covered by the more specific variants.
+    #[error("Constraint violation {0}")]
+    ConstraintViolation(ConstraintDetails),
+    #[error("Serialization failure {0}")]
+    SerializationFailure(String),
+    #[error("Deadlock detected {0}")]
+    Deadlock(String),
+    /// The object could not be saved because it has been changed or
+    /// deleted since it was loaded, as detected by its `#[version]` field.
+    #[error("Object has been modified since it was loaded")]
+    StaleObject,
+    /// A query with [`prefetch`](query::Query::prefetch) was executed
+    /// by a method which does not support prefetching.
+    #[error("Prefetching is not supported by {0}")]
+    PrefetchUnsupported(&'static str),
+    /// An aggregate other than [`count`](query::Query::count) was
+    /// computed for a query with a limit or offset.
+    #[error("Limit and offset are not supported by {0}")]
+    LimitUnsupported(&'static str),
+    /// An upsert which updates the existing row did not name the
+    /// columns on which a conflict with it is detected.
+    #[error("Upsert with OnConflict::Update requires conflict columns")]
+    MissingConflictTarget,
+    /// An async method of a model was called which the `model` macro
+    /// did not generate, because it was built without the `async`
+    /// feature.
+    #[error("{0} is not supported by a model generated without the async feature")]
+    AsyncUnsupported(&'static str),
     #[error("(De)serialization error {0}")]
     SerdeJson(#[from] serde_json::Error),
     #[error("IO error {0}")]
     IO(#[from] std::io::Error),
     #[cfg(feature = "sqlite")]
     #[error("Sqlite error {0}")]
-    SQLite(#[from] rusqlite::Error),
+    SQLite(rusqlite::Error),
     #[cfg(feature = "sqlite")]
     #[error("Sqlite error {0}")]
     SQLiteFromSQL(rusqlite::types::FromSqlError),
     #[cfg(feature = "pg")]
     #[error("Postgres error {0}")]
-    Postgres(#[from] postgres::Error),
+    Postgres(postgres::Error),
     #[cfg(feature = "datetime")]
     #[error("Chrono error {0}")]
     Chrono(#[from] chrono::ParseError),
@@ -170,6 +521,51 @@ pub enum Error {
     Generic(#[from] Box<dyn std::error::Error + Sync + Send>),
 }
 
+impl Error {
+    /// Tests if the error was caused by a conflict with a concurrent
+    /// transaction, such that retrying the transaction may succeed:
+    /// a serialization failure or deadlock, or `SQLITE_BUSY` in SQLite.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::SerializationFailure(_) | Error::Deadlock(_) => true,
+            #[cfg(feature = "sqlite")]
+            Error::SQLite(rusqlite::Error::SqliteFailure(e, _)) => {
+                e.code == rusqlite::ErrorCode::DatabaseBusy
+            }
+            _ => false,
+        }
+    }
+    /// The details of the violated constraint, if the error is a
+    /// constraint violation of any kind.
+    pub fn constraint_details(&self) -> Option<&ConstraintDetails> {
+        match self {
+            Error::UniqueViolation(details)
+            | Error::ForeignKeyViolation(details)
+            | Error::NotNullViolation(details)
+            | Error::CheckViolation(details)
+            | Error::ConstraintViolation(details) => Some(details),
+            _ => None,
+        }
+    }
+}
+
+/// Describes a constraint violation reported by the database. The
+/// table, column and constraint are only known if the backend reports
+/// them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConstraintDetails {
+    pub table: Option<String>,
+    pub column: Option<String>,
+    pub constraint: Option<String>,
+    /// The message from the backend.
+    pub message: String,
+}
+impl std::fmt::Display for ConstraintDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
 #[cfg(feature = "sqlite")]
 impl From<rusqlite::types::FromSqlError> for Error {
     fn from(e: rusqlite::types::FromSqlError) -> Self {
@@ -1,36 +1,80 @@
-use crate::db::{Column, ConnectionMethods};
-use crate::query::{BoolExpr, Expr};
-use crate::{DataObject, Error, FieldType, Result, SqlType, SqlVal, ToSql};
+#[cfg(feature = "async")]
+use crate::db::AsyncConnectionMethods;
+use crate::db::{BackendRows, Column, ConnectionMethods};
+use crate::query::{
+    column_indexes, key_expr, load_rows_in, not_deleted, row_has_key, Aggregate, BoolExpr, Prefetch,
+};
+use crate::{DataObject, Error, FromSql, PrimaryKey, Result, SqlType, SqlVal, SqlValRef};
 use once_cell::unsync::OnceCell;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::marker::PhantomData;
 
 fn default_oc<T>() -> OnceCell<Vec<T>> {
     OnceCell::default()
 }
 
+fn default_owner_columns() -> &'static [&'static str] {
+    &["owner"]
+}
+
+fn default_has_columns() -> &'static [&'static str] {
+    &["has"]
+}
+
 /// Used to implement a many-to-many relationship between models.
 ///
 /// Creates a new table with columns "owner" and "has" If type T has a
 /// many-to-many relationship with U, owner type is T::PKType, has is
 /// U::PKType. Table name is T_ManyToMany_foo where foo is the name of
 /// the Many field
+///
+/// If T has a composite primary key, there is instead an owner column
+/// for each column of the key, named `owner_{column}`, and likewise
+/// `has_{column}` if U does. The field must then name the columns of
+/// U's key, in order, with the `#[references(tenant_id, id)]`
+/// attribute. Such a field cannot be used in a `filter!` expression.
+///
+/// To store additional data with each link, name a through model as
+/// the second type argument, e.g. `Many<Tag, PostTag>`. The through
+/// model is a `#[model]` with `owner` and `has` fields (typically
+/// `ForeignKey`s to the two models) along with any others, and its
+/// table is used in place of the generated one. Links with additional
+/// data are added by saving the through model directly, and may be
+/// loaded with [`load_links`](Many::load_links).
 //
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Many<T>
+pub struct Many<T, L = ()>
 where
     T: DataObject,
 {
     item_table: Cow<'static, str>,
-    owner: Option<SqlVal>,
-    owner_type: SqlType,
+    owner: Option<Vec<SqlVal>>,
+    // The types of the owner's primary key columns
+    owner_types: Vec<SqlType>,
+    // The columns of the many table holding the owner's primary key
+    // and the value's. These aren't serialized, being set again by
+    // ensure_init.
     #[serde(skip)]
-    new_values: Vec<SqlVal>,
+    #[serde(default = "default_owner_columns")]
+    owner_columns: &'static [&'static str],
+    #[serde(skip)]
+    #[serde(default = "default_has_columns")]
+    has_columns: &'static [&'static str],
+    #[serde(skip)]
+    new_values: Vec<Vec<SqlVal>>,
+    #[serde(skip)]
+    removed_values: Vec<Vec<SqlVal>>,
+    // Whether all values saved to the db are to be removed
+    #[serde(skip)]
+    cleared: bool,
     #[serde(skip)]
     #[serde(default = "default_oc")]
     all_values: OnceCell<Vec<T>>,
+    #[serde(skip)]
+    through: PhantomData<L>,
 }
-impl<T> Many<T>
+impl<T, L> Many<T, L>
 where
     T: DataObject,
 {
@@ -44,20 +88,34 @@ where
         Many {
             item_table: Cow::Borrowed("not_initialized"),
             owner: None,
-            owner_type: SqlType::Int,
+            owner_types: Vec::new(),
+            owner_columns: default_owner_columns(),
+            has_columns: default_has_columns(),
             new_values: Vec::new(),
+            removed_values: Vec::new(),
+            cleared: false,
             all_values: OnceCell::new(),
+            through: PhantomData,
         }
     }
 
     /// Used by macro-generated code. You do not need to call this directly.
-    pub fn ensure_init(&mut self, item_table: &'static str, owner: SqlVal, owner_type: SqlType) {
+    pub fn ensure_init(
+        &mut self,
+        item_table: &'static str,
+        owner: Vec<SqlVal>,
+        owner_columns: &'static [&'static str],
+        has_columns: &'static [&'static str],
+        owner_types: Vec<SqlType>,
+    ) {
+        self.owner_columns = owner_columns;
+        self.has_columns = has_columns;
         if self.owner.is_some() {
             return;
         }
         self.item_table = Cow::Borrowed(item_table);
         self.owner = Some(owner);
-        self.owner_type = owner_type;
+        self.owner_types = owner_types;
         self.all_values = OnceCell::new();
     }
 
@@ -65,7 +123,120 @@ where
     pub fn add(&mut self, new_val: &T) {
         // all_values is now out of date, so clear it
         self.all_values = OnceCell::new();
-        self.new_values.push(new_val.pk().to_sql())
+        let pk = new_val.pk().to_sql_values();
+        match self.removed_values.iter().position(|v| *v == pk) {
+            // Still saved in the db, so just don't remove it
+            Some(idx) => {
+                self.removed_values.remove(idx);
+            }
+            None => self.new_values.push(pk),
+        }
+    }
+
+    /// Removes a value. The removal takes effect in the database
+    /// when the `Many` is saved.
+    pub fn remove(&mut self, val: &T) {
+        let pk = val.pk().to_sql_values();
+        if let Some(vals) = self.all_values.get_mut() {
+            vals.retain(|v| v.pk().to_sql_values() != pk);
+        }
+        match self.new_values.iter().position(|v| *v == pk) {
+            Some(idx) => {
+                self.new_values.remove(idx);
+            }
+            None if !self.cleared && !self.removed_values.contains(&pk) => {
+                self.removed_values.push(pk)
+            }
+            None => (),
+        }
+    }
+
+    /// Removes all values. The removal takes effect in the database
+    /// when the `Many` is saved.
+    pub fn clear(&mut self) {
+        self.new_values.clear();
+        self.removed_values.clear();
+        self.cleared = true;
+        self.all_values = OnceCell::new();
+        self.all_values.set(Vec::new()).ok();
+    }
+
+    /// Replaces all values with `vals`. Equivalent to `clear`
+    /// followed by `add` for each value.
+    pub fn set<'a>(&mut self, vals: impl IntoIterator<Item = &'a T>)
+    where
+        T: 'a,
+    {
+        self.clear();
+        for val in vals {
+            self.add(val);
+        }
+    }
+
+    /// Tests whether `val` is one of the values saved in the
+    /// database. Changes which have not been saved are not taken into
+    /// account, and neither are values which have been soft deleted.
+    pub fn contains(&self, conn: &impl ConnectionMethods, val: &T) -> Result<bool> {
+        let owner: &[SqlVal] = match &self.owner {
+            Some(o) => o,
+            None => return Ok(false),
+        };
+        let expr = BoolExpr::And(
+            Box::new(self.saved_links(owner)),
+            Box::new(key_expr(self.has_columns, val.pk().to_sql_values())),
+        );
+        let mut rows = conn.query(
+            &self.item_table,
+            &self.columns()[..1],
+            Some(expr),
+            Some(1),
+            None,
+            None,
+        )?;
+        let found = rows.next()?.is_some();
+        // The rows may borrow the connection, which T::get needs
+        drop(rows);
+        if !found {
+            return Ok(false);
+        }
+        if T::SOFT_DELETE_COL.is_some() && T::PKCOLS.len() > 1 {
+            // saved_links cannot leave these out, so check the value
+            // itself
+            return match T::get(conn, val.pk()) {
+                Ok(_) => Ok(true),
+                Err(Error::NoSuchObject) => Ok(false),
+                Err(e) => Err(e),
+            };
+        }
+        Ok(true)
+    }
+
+    /// Returns the number of values saved in the database. Changes
+    /// which have not been saved are not taken into account, and
+    /// neither are values which have been soft deleted.
+    pub fn count(&self, conn: &impl ConnectionMethods) -> Result<i64> {
+        let owner: &[SqlVal] = match &self.owner {
+            Some(o) => o,
+            None => return Ok(0),
+        };
+        if T::SOFT_DELETE_COL.is_some() && T::PKCOLS.len() > 1 {
+            // saved_links cannot leave these out, so the values are
+            // loaded to count them
+            return Ok(self.load_saved(conn, owner)?.len() as i64);
+        }
+        let mut rows = conn.query_aggregate(
+            &self.item_table,
+            &[],
+            &[Aggregate::count()],
+            Some(self.saved_links(owner)),
+        )?;
+        match rows.next()? {
+            None => Ok(0),
+            Some(row) => match row.get(0, SqlType::BigInt)? {
+                SqlValRef::Null => Ok(0),
+                val => i64::from_sql_ref(val),
+            },
+        }
     }
 
     /// Returns a reference to the value. It must have already been loaded. If not, returns Error::ValueNotLoaded
@@ -76,20 +247,43 @@ where
             .map(|v| v.iter())
     }
 
+    /// Clears any loaded values, so they will be reloaded by the next
+    /// call to `load`. Changes which have not been saved are kept.
+    pub fn reset(&mut self) {
+        self.all_values = OnceCell::new();
+    }
+
     /// Used by macro-generated code. You do not need to call this directly.
     pub fn save(&mut self, conn: &impl ConnectionMethods) -> Result<()> {
         let owner = self.owner.as_ref().ok_or(Error::NotInitialized)?;
-        while !self.new_values.is_empty() {
-            conn.insert_only(
-                &self.item_table,
-                &self.columns(),
-                &[
-                    owner.as_ref(),
-                    self.new_values.pop().unwrap().as_ref().clone(),
-                ],
-            )?;
+        for expr in self.deletions(owner) {
+            conn.delete_where(&self.item_table, expr)?;
+        }
+        self.cleared = false;
+        self.removed_values.clear();
+        let columns = self.columns();
+        while let Some(val) = self.new_values.pop() {
+            let values: Vec<SqlValRef> = owner.iter().chain(&val).map(SqlVal::as_ref).collect();
+            conn.insert_only(&self.item_table, &columns, &values)?;
+        }
+        Ok(())
+    }
+
+    /// Used by macro-generated code. You do not need to call this directly.
+    #[cfg(feature = "async")]
+    pub async fn save_async(&mut self, conn: &impl AsyncConnectionMethods) -> Result<()> {
+        let owner = self.owner.as_ref().ok_or(Error::NotInitialized)?;
+        for expr in self.deletions(owner) {
+            conn.delete_where(&self.item_table, expr).await?;
+        }
+        self.cleared = false;
+        self.removed_values.clear();
+        let columns = self.columns();
+        while let Some(val) = self.new_values.pop() {
+            let values: Vec<SqlValRef> = owner.iter().chain(&val).map(SqlVal::as_ref).collect();
+            conn.insert_only(&self.item_table, &columns, &values)
+                .await?;
         }
-        self.new_values.clear();
         Ok(())
     }
 
@@ -98,44 +292,258 @@ where
     pub fn load(&self, conn: &impl ConnectionMethods) -> Result<impl Iterator<Item = &T>> {
         let vals: Result<&Vec<T>> = self.all_values.get_or_try_init(|| {
             //if we don't have an owner then there are no values
-            let owner: &SqlVal = match &self.owner {
+            let owner: &[SqlVal] = match &self.owner {
                 Some(o) => o,
                 None => return Ok(Vec::new()),
             };
-            let mut vals = T::query()
-                .filter(BoolExpr::Subquery {
-                    col: T::PKCOL,
-                    tbl2: self.item_table.clone(),
-                    tbl2_col: "has",
-                    expr: Box::new(BoolExpr::Eq("owner", Expr::Val(owner.clone()))),
-                })
-                .load(conn)?;
+            let mut vals = if self.cleared {
+                Vec::new()
+            } else {
+                self.load_saved(conn, owner)?
+            };
+            // Leave out the values removed but not yet saved
+            if !self.removed_values.is_empty() {
+                vals.retain(|v| !self.removed_values.contains(&v.pk().to_sql_values()));
+            }
             // Now add in the values for things not saved to the db yet
             if !self.new_values.is_empty() {
-                vals.append(
-                    &mut T::query()
-                        .filter(BoolExpr::In(T::PKCOL, self.new_values.clone()))
-                        .load(conn)?,
-                );
+                for row in load_rows_in(
+                    conn,
+                    T::TABLE,
+                    T::COLUMNS,
+                    T::PKCOLS,
+                    &self.new_values,
+                    T::SOFT_DELETE_COL,
+                )? {
+                    vals.push(T::from_row(&row)?);
+                }
             }
             Ok(vals)
         });
         vals.map(|v| v.iter())
     }
-    pub fn columns(&self) -> [Column; 2] {
-        [
-            Column::new("owner", self.owner_type.clone()),
-            Column::new("has", <T::PKType as FieldType>::SQLTYPE),
-        ]
+    // Loads the values of `owner` saved in the database
+    fn load_saved(&self, conn: &impl ConnectionMethods, owner: &[SqlVal]) -> Result<Vec<T>> {
+        if let [has] = self.has_columns {
+            let owned = key_expr(self.owner_columns, owner.to_vec());
+            return T::query()
+                .filter(BoolExpr::Subquery {
+                    col: T::PKCOL,
+                    tbl2: self.item_table.clone(),
+                    tbl2_col: *has,
+                    expr: Box::new(owned),
+                })
+                .load(conn);
+        }
+        // A composite key cannot be matched by a subquery, so the
+        // links are loaded first
+        let links = load_rows_in(
+            conn,
+            &self.item_table,
+            &self.columns(),
+            self.owner_columns,
+            &[owner.to_vec()],
+            None,
+        )?;
+        let keys: Vec<Vec<SqlVal>> = links
+            .into_iter()
+            .map(|link| link[owner.len()..].to_vec())
+            .collect();
+        load_rows_in(
+            conn,
+            T::TABLE,
+            T::COLUMNS,
+            T::PKCOLS,
+            &keys,
+            T::SOFT_DELETE_COL,
+        )?
+        .iter()
+        .map(|row| T::from_row(row))
+        .collect()
+    }
+    // Matches the links of `owner` in the many table, leaving out
+    // those to values which have been soft deleted if their key is a
+    // single column
+    fn saved_links(&self, owner: &[SqlVal]) -> BoolExpr {
+        let owned = key_expr(self.owner_columns, owner.to_vec());
+        match (T::SOFT_DELETE_COL, self.has_columns) {
+            (Some(col), [has]) => BoolExpr::And(
+                Box::new(owned),
+                Box::new(BoolExpr::Subquery {
+                    col: *has,
+                    tbl2: Cow::Borrowed(T::TABLE),
+                    tbl2_col: T::PKCOL,
+                    expr: Box::new(not_deleted(col)),
+                }),
+            ),
+            _ => owned,
+        }
+    }
+    // Deletions from the many table needed to save the pending removals
+    fn deletions(&self, owner: &[SqlVal]) -> Vec<BoolExpr> {
+        let owned = key_expr(self.owner_columns, owner.to_vec());
+        let mut exprs = Vec::new();
+        if self.cleared {
+            exprs.push(owned.clone());
+        }
+        if self.removed_values.is_empty() {
+            return exprs;
+        }
+        match self.has_columns {
+            [has] => exprs.push(BoolExpr::And(
+                Box::new(owned),
+                Box::new(BoolExpr::In(
+                    *has,
+                    self.removed_values.iter().map(|v| v[0].clone()).collect(),
+                )),
+            )),
+            has => exprs.extend(self.removed_values.iter().map(|v| {
+                BoolExpr::And(Box::new(owned.clone()), Box::new(key_expr(has, v.clone())))
+            })),
+        }
+        exprs
+    }
+    /// The columns of the many table: those holding the owner's
+    /// primary key, followed by those holding the value's.
+    pub fn columns(&self) -> Vec<Column> {
+        self.owner_columns
+            .iter()
+            .copied()
+            .zip(self.owner_types.iter().cloned())
+            .chain(self.has_columns.iter().copied().zip(T::PKType::sql_types()))
+            .map(|(name, ty)| Column::new(name, ty))
+            .collect()
     }
 }
-impl<T: DataObject> PartialEq<Many<T>> for Many<T> {
-    fn eq(&self, other: &Many<T>) -> bool {
+impl<T, L> Many<T, L>
+where
+    T: DataObject,
+    L: DataObject,
+{
+    /// Loads the through model for each link saved in the database,
+    /// paired with the value it links to. Changes which have not been
+    /// saved are not taken into account.
+    pub fn load_links(&self, conn: &impl ConnectionMethods) -> Result<Vec<(L, T)>> {
+        let owner: &[SqlVal] = match &self.owner {
+            Some(o) => o,
+            None => return Ok(Vec::new()),
+        };
+        let hasidxs = column_indexes(L::COLUMNS, self.has_columns)?;
+        let links = load_rows_in(
+            conn,
+            L::TABLE,
+            L::COLUMNS,
+            self.owner_columns,
+            &[owner.to_vec()],
+            L::SOFT_DELETE_COL,
+        )?;
+        let mut pks: Vec<Vec<SqlVal>> = Vec::new();
+        for link in &links {
+            let pk: Vec<SqlVal> = hasidxs.iter().map(|idx| link[*idx].clone()).collect();
+            if !pks.contains(&pk) {
+                pks.push(pk);
+            }
+        }
+        let pkidxs = column_indexes(T::COLUMNS, T::PKCOLS)?;
+        let rows = load_rows_in(
+            conn,
+            T::TABLE,
+            T::COLUMNS,
+            T::PKCOLS,
+            &pks,
+            T::SOFT_DELETE_COL,
+        )?;
+        links
+            .iter()
+            .filter_map(|link| {
+                let pk: Vec<SqlVal> = hasidxs.iter().map(|idx| link[*idx].clone()).collect();
+                rows.iter()
+                    .find(|row| row_has_key(row, &pkidxs, &pk))
+                    .map(|row| Ok((L::from_row(link)?, T::from_row(row)?)))
+            })
+            .collect()
+    }
+}
+impl<T, L> Prefetch for Many<T, L>
+where
+    T: DataObject,
+{
+    fn prefetch(manys: &[&Self], conn: &dyn ConnectionMethods) -> Result<()> {
+        // Unsaved changes aren't reflected in the many table, so those
+        // with any are left to load
+        let manys: Vec<&Self> = manys
+            .iter()
+            .copied()
+            .filter(|m| {
+                m.all_values.get().is_none()
+                    && m.new_values.is_empty()
+                    && m.removed_values.is_empty()
+                    && !m.cleared
+            })
+            .collect();
+        let owners: Vec<Vec<SqlVal>> = manys.iter().filter_map(|m| m.owner.clone()).collect();
+        // Every Many being prefetched is the same field, so all share
+        // one many table
+        let first = match manys.iter().find(|m| m.owner.is_some()) {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        let owner_columns = first.owner_columns;
+        let links = load_rows_in(
+            conn,
+            &first.item_table,
+            &first.columns(),
+            owner_columns,
+            &owners,
+            None,
+        )?;
+        // Each link is the owner's key followed by the value's
+        let split = owner_columns.len();
+        let mut pks: Vec<Vec<SqlVal>> = Vec::new();
+        for link in &links {
+            if !pks.iter().any(|pk| pk.as_slice() == &link[split..]) {
+                pks.push(link[split..].to_vec());
+            }
+        }
+        let pkidxs = column_indexes(T::COLUMNS, T::PKCOLS)?;
+        let rows = load_rows_in(
+            conn,
+            T::TABLE,
+            T::COLUMNS,
+            T::PKCOLS,
+            &pks,
+            T::SOFT_DELETE_COL,
+        )?;
+        for m in manys {
+            let owner = match &m.owner {
+                Some(owner) => owner,
+                None => continue,
+            };
+            let vals: Result<Vec<T>> = links
+                .iter()
+                .filter(|link| &link[..split] == owner.as_slice())
+                .filter_map(|link| {
+                    rows.iter()
+                        .find(|row| row_has_key(row, &pkidxs, &link[split..]))
+                })
+                .map(|row| T::from_row(row))
+                .collect();
+            m.all_values.set(vals?).ok();
+        }
+        Ok(())
+    }
+}
+
+impl<T: DataObject, L> PartialEq<Many<T, L>> for Many<T, L> {
+    fn eq(&self, other: &Many<T, L>) -> bool {
         (self.owner == other.owner) && (self.item_table == other.item_table)
     }
 }
-impl<T: DataObject> Eq for Many<T> {}
-impl<T: DataObject> Default for Many<T> {
+impl<T: DataObject, L> Eq for Many<T, L> {}
+impl<T, L> Default for Many<T, L>
+where
+    T: DataObject,
+{
     fn default() -> Self {
         Self::new()
     }
@@ -30,12 +30,16 @@ pub enum TypeKey {
     /// Represents a type which is not natively known to butane but
     /// which butane will be made aware of with the `#\[butane_type\]` macro
     CustomType(String),
+    /// Represents the type of the given column of the composite
+    /// primary key of the model type with the given name
+    PKColumn(String, String),
 }
 impl std::fmt::Display for TypeKey {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::result::Result<(), std::fmt::Error> {
         match self {
             TypeKey::PK(name) => write!(f, "PK({})", name),
             TypeKey::CustomType(name) => write!(f, "CustomType({})", name),
+            TypeKey::PKColumn(name, col) => write!(f, "PKColumn({}.{})", name, col),
         }
     }
 }
@@ -49,6 +53,7 @@ impl serde::ser::Serialize for TypeKey {
         serializer.serialize_str(&match self {
             TypeKey::PK(s) => format!("PK:{}", s),
             TypeKey::CustomType(s) => format!("CT:{}", s),
+            TypeKey::PKColumn(s, col) => format!("PC:{}.{}", s, col),
         })
     }
 }
@@ -75,6 +80,13 @@ impl<'de> Visitor<'de> for TypeKeyVisitor {
             Ok(TypeKey::PK(rest))
         } else if v.starts_with("CT:") {
             Ok(TypeKey::CustomType(rest))
+        } else if v.starts_with("PC:") {
+            match rest.split_once('.') {
+                Some((name, col)) => Ok(TypeKey::PKColumn(name.to_string(), col.to_string())),
+                None => Err(E::custom(
+                    "Malformed primary key column type key".to_string(),
+                )),
+            }
         } else {
             Err(E::custom("Unkown type key string".to_string()))
         }
@@ -91,11 +103,16 @@ impl Ord for TypeKey {
         match self {
             PK(s) => match other {
                 PK(other_s) => s.cmp(other_s),
-                CustomType(_) => Ordering::Less,
+                CustomType(_) | PKColumn(..) => Ordering::Less,
             },
             CustomType(s) => match other {
                 PK(_) => Ordering::Greater,
                 CustomType(other_s) => s.cmp(other_s),
+                PKColumn(..) => Ordering::Less,
+            },
+            PKColumn(s, col) => match other {
+                PK(_) | CustomType(_) => Ordering::Greater,
+                PKColumn(other_s, other_col) => (s, col).cmp(&(other_s, other_col)),
             },
         }
     }
@@ -181,7 +198,8 @@ impl ADB {
                     changed |= col.resolve_type(&resolver);
                 }
             }
-            for (key, ty) in self.extra_types.iter_mut() {
+            changed |= self.resolve_pk_column_types();
+            for (key, ty) in self.extra_types.iter() {
                 match ty {
                     DeferredSqlType::Known(ty) => {
                         changed |= resolver.insert(key.clone(), ty.clone().into()) || changed;
@@ -190,14 +208,18 @@ impl ADB {
                         changed |= resolver.insert(key.clone(), ty.clone()) || changed;
                     }
                     DeferredSqlType::Deferred(tykey) => {
+                        // Leave the entry itself deferred. Entries
+                        // mapping one primary key to another record a
+                        // custom table name, which is needed again to
+                        // resolve foreign key references.
                         if let Some(sqltype) = resolver.find_type(tykey) {
-                            *ty = sqltype.into();
-                            changed = true;
+                            changed |= resolver.insert(key.clone(), sqltype);
                         }
                     }
                 }
             }
         }
+        self.resolve_references();
 
         // Now do a verification pass to ensure nothing is unresolved
         for table in &mut self.tables.values() {
@@ -205,11 +227,128 @@ impl ADB {
                 if let DeferredSqlType::Deferred(key) = &col.sqltype {
                     return Err(Error::CannotResolveType(key.to_string()));
                 }
+                if let Some(ARef::Deferred(key)) = col.reference() {
+                    return Err(Error::CannotResolveType(key.to_string()));
+                }
+            }
+            for constraint in &table.constraints {
+                if let AConstraintKind::ForeignKey(_, fk) = &constraint.kind {
+                    if let ARef::Deferred(key) = &fk.reference {
+                        return Err(Error::CannotResolveType(key.to_string()));
+                    }
+                }
             }
         }
         Ok(())
     }
 
+    /// Resolves the types of columns holding a column of a composite
+    /// primary key, from the column in the referenced table. Returns
+    /// true if any were resolved.
+    fn resolve_pk_column_types(&mut self) -> bool {
+        let mut resolved: Vec<(String, String, TypeIdentifier)> = Vec::new();
+        for table in self.tables.values() {
+            for col in &table.columns {
+                if let DeferredSqlType::Deferred(TypeKey::PKColumn(tyname, pkcol)) = &col.sqltype {
+                    let ty = self
+                        .table_for_type(tyname)
+                        .and_then(|target| target.column(pkcol))
+                        .and_then(|target_col| target_col.typeid().ok());
+                    if let Some(ty) = ty {
+                        resolved.push((table.name.clone(), col.name.clone(), ty));
+                    }
+                }
+            }
+        }
+        let changed = !resolved.is_empty();
+        for (table, col, ty) in resolved {
+            if let Some(col) = self
+                .tables
+                .get_mut(&table)
+                .and_then(|t| t.columns.iter_mut().find(|c| c.name == col))
+            {
+                col.sqltype = DeferredSqlType::KnownId(ty);
+            }
+        }
+        changed
+    }
+
+    /// Fixup as many ARef::Deferred references as possible into ARef::Literal
+    fn resolve_references(&mut self) {
+        let mut resolved: Vec<(String, String, ARef)> = Vec::new();
+        for table in self.tables.values() {
+            for col in &table.columns {
+                if let Some(ARef::Deferred(TypeKey::PK(tyname))) = col.reference() {
+                    if let Some(target) = self.table_for_type(tyname) {
+                        if let Some(pk) = target.pk() {
+                            resolved.push((
+                                table.name.clone(),
+                                col.name.clone(),
+                                ARef::Literal {
+                                    table: target.name.clone(),
+                                    column: pk.name.clone(),
+                                },
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        for (table, col, reference) in resolved {
+            if let Some(fk) = self
+                .tables
+                .get_mut(&table)
+                .and_then(|t| t.columns.iter_mut().find(|c| c.name == col))
+                .and_then(|c| c.foreign_key.as_mut())
+            {
+                fk.reference = reference;
+            }
+        }
+        // Foreign keys to composite primary keys are table constraints
+        let mut resolved: Vec<(String, String, ARef)> = Vec::new();
+        for table in self.tables.values() {
+            for constraint in &table.constraints {
+                if let AConstraintKind::ForeignKey(_, fk) = &constraint.kind {
+                    if let ARef::Deferred(TypeKey::PK(tyname)) = &fk.reference {
+                        if let Some(target) = self.table_for_type(tyname) {
+                            resolved.push((
+                                table.name.clone(),
+                                constraint.name.clone(),
+                                ARef::Columns {
+                                    table: target.name.clone(),
+                                    columns: target
+                                        .pk_columns()
+                                        .iter()
+                                        .map(|col| col.name.clone())
+                                        .collect(),
+                                },
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        for (table, name, reference) in resolved {
+            if let Some(AConstraintKind::ForeignKey(_, fk)) = self
+                .tables
+                .get_mut(&table)
+                .and_then(|t| t.constraints.iter_mut().find(|c| c.name == name))
+                .map(|c| &mut c.kind)
+            {
+                fk.reference = reference;
+            }
+        }
+    }
+
+    /// Find the table for the model type with the given name, taking
+    /// custom table names into account.
+    fn table_for_type(&self, tyname: &str) -> Option<&ATable> {
+        match self.extra_types.get(&TypeKey::PK(tyname.to_string())) {
+            Some(DeferredSqlType::Deferred(TypeKey::PK(name))) => self.tables.get(name),
+            _ => self.tables.get(tyname),
+        }
+    }
+
     pub fn transform_with(&mut self, op: Operation) {
         use Operation::*;
         match op {
@@ -232,6 +371,77 @@ impl ADB {
                     t.replace_column(new);
                 }
             }
+            AddIndex(table, index) => {
+                if let Some(t) = self.tables.get_mut(&table) {
+                    t.add_index(index);
+                }
+            }
+            RemoveIndex(table, name) => {
+                if let Some(t) = self.tables.get_mut(&table) {
+                    t.remove_index(&name);
+                }
+            }
+            AddConstraint(table, constraint) => {
+                if let Some(t) = self.tables.get_mut(&table) {
+                    t.add_constraint(constraint);
+                }
+            }
+            RemoveConstraint(table, name) => {
+                if let Some(t) = self.tables.get_mut(&table) {
+                    t.remove_constraint(&name);
+                }
+            }
+            RenameTable(old, new) => {
+                if let Some(mut t) = self.tables.remove(&old) {
+                    t.name = new.clone();
+                    self.tables.insert(new.clone(), t);
+                }
+                self.update_references(|table, _| {
+                    if table == &old {
+                        *table = new.clone();
+                    }
+                });
+            }
+            RenameColumn(table, old, new) => {
+                if let Some(t) = self.tables.get_mut(&table) {
+                    t.rename_column(&old, &new);
+                }
+                self.update_references(|ref_table, column| {
+                    if ref_table == &table && column == &old {
+                        *column = new.clone();
+                    }
+                });
+            }
+        }
+    }
+
+    /// Apply `f` to the table and column of every resolved foreign
+    /// key reference, once for each column of a reference to several.
+    fn update_references(&mut self, mut f: impl FnMut(&mut String, &mut String)) {
+        for table in self.tables.values_mut() {
+            let constraint_fks = table
+                .constraints
+                .iter_mut()
+                .filter_map(|c| match &mut c.kind {
+                    AConstraintKind::ForeignKey(_, fk) => Some(fk),
+                    _ => None,
+                });
+            for fk in table
+                .columns
+                .iter_mut()
+                .filter_map(|col| col.foreign_key.as_mut())
+                .chain(constraint_fks)
+            {
+                match &mut fk.reference {
+                    ARef::Literal { table, column } => f(table, column),
+                    ARef::Columns { table, columns } => {
+                        for column in columns {
+                            f(table, column);
+                        }
+                    }
+                    ARef::Deferred(_) => (),
+                }
+            }
         }
     }
 }
@@ -241,12 +451,23 @@ impl ADB {
 pub struct ATable {
     pub name: String,
     pub columns: Vec<AColumn>,
+    #[serde(default)]
+    pub indexes: Vec<AIndex>,
+    /// Table-level constraints, in addition to those on individual columns.
+    #[serde(default)]
+    pub constraints: Vec<AConstraint>,
+    /// The name this table had before it was renamed, if it was.
+    #[serde(default)]
+    pub renamed_from: Option<String>,
 }
 impl ATable {
     pub fn new(name: String) -> ATable {
         ATable {
             name,
             columns: Vec::new(),
+            indexes: Vec::new(),
+            constraints: Vec::new(),
+            renamed_from: None,
         }
     }
     pub fn add_column(&mut self, col: AColumn) {
@@ -265,9 +486,173 @@ impl ATable {
     pub fn remove_column(&mut self, name: &str) {
         self.columns.retain(|c| c.name != name);
     }
+    /// Renames the column `old` to `new`, including in any indexes
+    /// and unique or foreign key constraints covering it. Check
+    /// constraints are arbitrary SQL and are left as they are.
+    pub fn rename_column(&mut self, old: &str, new: &str) {
+        if let Some(col) = self.columns.iter_mut().find(|c| c.name == old) {
+            col.name = new.to_string();
+        }
+        let constraint_columns = self
+            .constraints
+            .iter_mut()
+            .filter_map(|c| match &mut c.kind {
+                AConstraintKind::Unique(columns) => Some(columns),
+                AConstraintKind::ForeignKey(columns, _) => Some(columns),
+                AConstraintKind::Check(_) => None,
+            });
+        for columns in self
+            .indexes
+            .iter_mut()
+            .map(|i| &mut i.columns)
+            .chain(constraint_columns)
+        {
+            for col in columns {
+                if col == old {
+                    *col = new.to_string();
+                }
+            }
+        }
+    }
+    /// The primary key column. For a composite primary key, the
+    /// first of its columns.
     pub fn pk(&self) -> Option<&AColumn> {
         self.columns.iter().find(|c| c.is_pk())
     }
+    /// All of the primary key columns, in order.
+    pub fn pk_columns(&self) -> Vec<&AColumn> {
+        self.columns.iter().filter(|c| c.is_pk()).collect()
+    }
+    pub fn add_index(&mut self, index: AIndex) {
+        if let Some(existing) = self.indexes.iter_mut().find(|i| i.name == index.name) {
+            *existing = index;
+        } else {
+            self.indexes.push(index);
+        }
+    }
+    pub fn index<'a>(&'a self, name: &str) -> Option<&'a AIndex> {
+        self.indexes.iter().find(|i| i.name == name)
+    }
+    pub fn remove_index(&mut self, name: &str) {
+        self.indexes.retain(|i| i.name != name);
+    }
+    pub fn add_constraint(&mut self, constraint: AConstraint) {
+        if let Some(existing) = self
+            .constraints
+            .iter_mut()
+            .find(|c| c.name == constraint.name)
+        {
+            *existing = constraint;
+        } else {
+            self.constraints.push(constraint);
+        }
+    }
+    pub fn constraint<'a>(&'a self, name: &str) -> Option<&'a AConstraint> {
+        self.constraints.iter().find(|c| c.name == name)
+    }
+    pub fn remove_constraint(&mut self, name: &str) {
+        self.constraints.retain(|c| c.name != name);
+    }
+    /// Names of the tables referenced by foreign keys in this table.
+    pub fn referenced_tables(&self) -> impl Iterator<Item = &str> {
+        let constraint_refs = self.constraints.iter().filter_map(|c| match &c.kind {
+            AConstraintKind::ForeignKey(_, fk) => Some(&fk.reference),
+            _ => None,
+        });
+        self.columns
+            .iter()
+            .filter_map(|c| c.reference())
+            .chain(constraint_refs)
+            .filter_map(|reference| match reference {
+                ARef::Literal { table, .. } | ARef::Columns { table, .. } => Some(table.as_str()),
+                ARef::Deferred(_) => None,
+            })
+    }
+}
+
+/// Abstract representation of a secondary index on a table.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct AIndex {
+    name: String,
+    columns: Vec<String>,
+}
+impl AIndex {
+    pub fn new(name: impl Into<String>, columns: Vec<String>) -> Self {
+        AIndex {
+            name: name.into(),
+            columns,
+        }
+    }
+    /// Create an index with the default name for the given table and columns.
+    pub fn new_for_columns(table: &str, columns: Vec<String>) -> Self {
+        let name = format!("{}_{}_idx", table, columns.join("_"));
+        AIndex::new(name, columns)
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+/// Abstract representation of a table-level constraint.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct AConstraint {
+    name: String,
+    kind: AConstraintKind,
+}
+impl AConstraint {
+    pub fn new(name: impl Into<String>, kind: AConstraintKind) -> Self {
+        AConstraint {
+            name: name.into(),
+            kind,
+        }
+    }
+    /// Create a unique constraint with the default name for the given
+    /// table and columns.
+    pub fn new_unique(table: &str, columns: Vec<String>) -> Self {
+        let name = format!("{}_{}_key", table, columns.join("_"));
+        AConstraint::new(name, AConstraintKind::Unique(columns))
+    }
+    /// Create a check constraint with the default name for the `n`th
+    /// check on the given table.
+    pub fn new_check(table: &str, n: usize, expr: impl Into<String>) -> Self {
+        let name = format!("{}_check{}", table, n);
+        AConstraint::new(name, AConstraintKind::Check(expr.into()))
+    }
+    /// Create a foreign key constraint with the default name for the
+    /// given table and columns.
+    pub fn new_foreign_key(table: &str, columns: Vec<String>, fk: AForeignKey) -> Self {
+        let name = format!("{}_{}_fkey", table, columns.join("_"));
+        AConstraint::new(name, AConstraintKind::ForeignKey(columns, fk))
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn kind(&self) -> &AConstraintKind {
+        &self.kind
+    }
+    /// The columns the constraint covers, if they are known.
+    pub fn columns(&self) -> Option<&[String]> {
+        match &self.kind {
+            AConstraintKind::Unique(columns) => Some(columns),
+            AConstraintKind::ForeignKey(columns, _) => Some(columns),
+            AConstraintKind::Check(_) => None,
+        }
+    }
+}
+
+/// The kinds of table-level constraint.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum AConstraintKind {
+    /// No two rows may have the same values for all of the columns.
+    Unique(Vec<String>),
+    /// The SQL boolean expression must hold for every row.
+    Check(String),
+    /// The columns together refer to a row of another table, used for
+    /// references to a composite primary key.
+    ForeignKey(Vec<String>, AForeignKey),
 }
 
 /// SqlType which may not yet be known.
@@ -301,8 +686,74 @@ impl From<TypeIdentifier> for DeferredSqlType {
     }
 }
 
+/// Reference to a column in another table.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum ARef {
+    /// Reference to the primary key of the table for the given
+    /// type, which may not yet be known.
+    Deferred(TypeKey),
+    /// Reference to a known table and column.
+    Literal { table: String, column: String },
+    /// Reference to several columns of a known table, such as a
+    /// composite primary key.
+    Columns { table: String, columns: Vec<String> },
+}
+
+/// Action taken on referencing rows when the row referenced by a
+/// foreign key is deleted or updated.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub enum ReferentialAction {
+    NoAction,
+    Restrict,
+    Cascade,
+    SetNull,
+}
+impl ReferentialAction {
+    /// The SQL clause for this action, e.g. `CASCADE`.
+    pub fn sql(&self) -> &'static str {
+        match self {
+            ReferentialAction::NoAction => "NO ACTION",
+            ReferentialAction::Restrict => "RESTRICT",
+            ReferentialAction::Cascade => "CASCADE",
+            ReferentialAction::SetNull => "SET NULL",
+        }
+    }
+}
+
+/// Abstract representation of a foreign key constraint on a column.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct AForeignKey {
+    reference: ARef,
+    #[serde(default)]
+    on_delete: Option<ReferentialAction>,
+    #[serde(default)]
+    on_update: Option<ReferentialAction>,
+}
+impl AForeignKey {
+    pub fn new(
+        reference: ARef,
+        on_delete: Option<ReferentialAction>,
+        on_update: Option<ReferentialAction>,
+    ) -> Self {
+        AForeignKey {
+            reference,
+            on_delete,
+            on_update,
+        }
+    }
+    pub fn reference(&self) -> &ARef {
+        &self.reference
+    }
+    pub fn on_delete(&self) -> Option<ReferentialAction> {
+        self.on_delete
+    }
+    pub fn on_update(&self) -> Option<ReferentialAction> {
+        self.on_update
+    }
+}
+
 /// Abstract representation of a database column schema.
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AColumn {
     name: String,
     sqltype: DeferredSqlType,
@@ -312,6 +763,12 @@ pub struct AColumn {
     #[serde(default)]
     unique: bool,
     default: Option<SqlVal>,
+    #[serde(default)]
+    foreign_key: Option<AForeignKey>,
+    #[serde(default)]
+    renamed_from: Option<String>,
+    #[serde(default)]
+    default_now: bool,
 }
 impl AColumn {
     pub fn new(
@@ -331,6 +788,9 @@ impl AColumn {
             auto,
             unique,
             default,
+            foreign_key: None,
+            renamed_from: None,
+            default_now: false,
         }
     }
     /// Simple column that is non-null, non-auto, non-pk, non-unique with no default
@@ -352,6 +812,32 @@ impl AColumn {
     pub fn default(&self) -> &Option<SqlVal> {
         &self.default
     }
+    /// Whether the column's default is the current time rather than a
+    /// fixed value, as for the timestamps of `#[auto_now]` and
+    /// `#[auto_now_add]` fields.
+    pub fn default_now(&self) -> bool {
+        self.default_now
+    }
+    pub fn set_default_now(&mut self, default_now: bool) {
+        self.default_now = default_now;
+    }
+    pub fn foreign_key(&self) -> Option<&AForeignKey> {
+        self.foreign_key.as_ref()
+    }
+    pub fn set_foreign_key(&mut self, fk: Option<AForeignKey>) {
+        self.foreign_key = fk;
+    }
+    /// The name this column had before it was renamed, if it was.
+    pub fn renamed_from(&self) -> Option<&str> {
+        self.renamed_from.as_deref()
+    }
+    pub fn set_renamed_from(&mut self, name: Option<String>) {
+        self.renamed_from = name;
+    }
+    /// The column referenced by this column's foreign key, if any.
+    pub fn reference(&self) -> Option<&ARef> {
+        self.foreign_key.as_ref().map(|fk| &fk.reference)
+    }
     pub fn typeid(&self) -> Result<TypeIdentifier> {
         match &self.sqltype {
             DeferredSqlType::KnownId(t) => Ok(t.clone()),
@@ -375,32 +861,70 @@ impl AColumn {
         self.auto
     }
 }
+// The rename hint is not part of the column's schema, so it is
+// ignored when comparing columns.
+impl PartialEq for AColumn {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.sqltype == other.sqltype
+            && self.nullable == other.nullable
+            && self.pk == other.pk
+            && self.auto == other.auto
+            && self.unique == other.unique
+            && self.default == other.default
+            && self.default_now == other.default_now
+            && self.foreign_key == other.foreign_key
+    }
+}
 
 /// Individual operation use to apply a migration.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Operation {
-    //future improvement: support column renames
     AddTable(ATable),
     RemoveTable(String),
     AddColumn(String, AColumn),
     RemoveColumn(String, String),
     ChangeColumn(String, AColumn, AColumn),
+    AddIndex(String, AIndex),
+    RemoveIndex(String, String),
+    AddConstraint(String, AConstraint),
+    RemoveConstraint(String, String),
+    RenameTable(String, String),
+    RenameColumn(String, String, String),
 }
 
 /// Determine the operations necessary to move the database schema from `old` to `new`.
 pub fn diff(old: &ADB, new: &ADB) -> Vec<Operation> {
     let mut ops: Vec<Operation> = Vec::new();
+    // Apply renames first, so the remaining differences can be found by name
+    let mut old = old.clone();
+    let renames = find_renames(
+        old.tables.values().map(|t| (&t.name, &t.renamed_from)),
+        new.tables.values().map(|t| (&t.name, &t.renamed_from)),
+    );
+    for (from, to) in renames {
+        let op = Operation::RenameTable(from, to);
+        old.transform_with(op.clone());
+        ops.push(op);
+    }
+    let old = &old;
     let new_names: HashSet<&String> = new.tables.keys().collect();
     let old_names: HashSet<&String> = old.tables.keys().collect();
-    let new_tables = new_names.difference(&old_names);
-    for added in new_tables {
-        let added: &str = added.as_ref();
-        ops.push(Operation::AddTable(
-            new.tables.get(added).expect("no table").clone(),
-        ));
+    let new_tables: Vec<&ATable> = new_names
+        .difference(&old_names)
+        .map(|added| new.tables.get(*added).expect("no table"))
+        .collect();
+    // Referenced tables must be created before the tables referencing them
+    for added in sort_by_references(new_tables) {
+        ops.push(Operation::AddTable(added.clone()));
     }
-    for removed in old_names.difference(&new_names) {
-        ops.push(Operation::RemoveTable((*removed).to_string()));
+    let removed_tables: Vec<&ATable> = old_names
+        .difference(&new_names)
+        .map(|removed| old.tables.get(*removed).expect("no table"))
+        .collect();
+    // and dropped after them
+    for removed in sort_by_references(removed_tables).into_iter().rev() {
+        ops.push(Operation::RemoveTable(removed.name.clone()));
     }
     for table in new_names.intersection(&old_names) {
         let table: &str = table.as_ref();
@@ -412,12 +936,80 @@ pub fn diff(old: &ADB, new: &ADB) -> Vec<Operation> {
     ops
 }
 
+/// Orders `tables` such that each table comes after any other tables
+/// in the set it references. Reference cycles are broken arbitrarily.
+fn sort_by_references(mut remaining: Vec<&ATable>) -> Vec<&ATable> {
+    let mut sorted: Vec<&ATable> = Vec::with_capacity(remaining.len());
+    while !remaining.is_empty() {
+        let next = remaining
+            .iter()
+            .position(|t| {
+                t.referenced_tables()
+                    .all(|r| r == t.name || !remaining.iter().any(|other| other.name == r))
+            })
+            .unwrap_or(0);
+        sorted.push(remaining.remove(next));
+    }
+    sorted
+}
+
+/// Finds renames between two sets of items, given as pairs of each
+/// item's name and the name it was renamed from, if any. Renames
+/// recorded on `old` items are reversed, as for a down migration.
+/// Returns pairs of (from, to) names.
+fn find_renames<'a>(
+    old: impl Iterator<Item = (&'a String, &'a Option<String>)>,
+    new: impl Iterator<Item = (&'a String, &'a Option<String>)>,
+) -> Vec<(String, String)> {
+    let old: Vec<(&String, &Option<String>)> = old.collect();
+    let new: Vec<(&String, &Option<String>)> = new.collect();
+    fn has(items: &[(&String, &Option<String>)], name: &str) -> bool {
+        items.iter().any(|(item, _)| item.as_str() == name)
+    }
+    let mut renames: Vec<(String, String)> = Vec::new();
+    for (name, from) in &new {
+        if let Some(from) = from {
+            if has(&old, from) && !has(&new, from) && !has(&old, name) {
+                renames.push((from.clone(), (*name).clone()));
+            }
+        }
+    }
+    for (name, to) in &old {
+        if let Some(to) = to {
+            if has(&new, to) && !has(&old, to) && !has(&new, name) {
+                renames.push(((*name).clone(), to.clone()));
+            }
+        }
+    }
+    renames
+}
+
 fn col_by_name<'a>(columns: &'a [AColumn], name: &str) -> Option<&'a AColumn> {
     columns.iter().find(|c| c.name == name)
 }
 
 fn diff_table(old: &ATable, new: &ATable) -> Vec<Operation> {
     let mut ops: Vec<Operation> = Vec::new();
+    let mut old = old.clone();
+    let renames = find_renames(
+        old.columns.iter().map(|c| (&c.name, &c.renamed_from)),
+        new.columns.iter().map(|c| (&c.name, &c.renamed_from)),
+    );
+    // Drop indexes and constraints before any columns they cover are
+    // changed, and create them only after
+    let (removed_indexes, added_indexes) = diff_indexes(&old, new);
+    for removed in removed_indexes {
+        ops.push(Operation::RemoveIndex(old.name.clone(), removed));
+    }
+    let (removed_constraints, added_constraints) = diff_constraints(&old, new);
+    for removed in removed_constraints {
+        ops.push(Operation::RemoveConstraint(old.name.clone(), removed));
+    }
+    for (from, to) in renames {
+        old.rename_column(&from, &to);
+        ops.push(Operation::RenameColumn(new.name.clone(), from, to));
+    }
+    let old = &old;
     let new_names: HashSet<&String> = new.columns.iter().map(|c| &c.name).collect();
     let old_names: HashSet<&String> = old.columns.iter().map(|c| &c.name).collect();
     let added_names = new_names.difference(&old_names);
@@ -447,5 +1039,46 @@ fn diff_table(old: &ATable, new: &ATable) -> Vec<Operation> {
             col.clone(),
         ));
     }
+    for added in added_indexes {
+        ops.push(Operation::AddIndex(new.name.clone(), added));
+    }
+    for added in added_constraints {
+        ops.push(Operation::AddConstraint(new.name.clone(), added));
+    }
     ops
 }
+
+/// Returns the names of indexes to remove and the indexes to add to
+/// move from `old` to `new`. Changed indexes are both removed and added.
+fn diff_indexes(old: &ATable, new: &ATable) -> (Vec<String>, Vec<AIndex>) {
+    let removed = old
+        .indexes
+        .iter()
+        .filter(|index| new.index(&index.name) != Some(index))
+        .map(|index| index.name.clone())
+        .collect();
+    let added = new
+        .indexes
+        .iter()
+        .filter(|index| old.index(&index.name) != Some(index))
+        .cloned()
+        .collect();
+    (removed, added)
+}
+
+/// Like `diff_indexes`, but for table-level constraints.
+fn diff_constraints(old: &ATable, new: &ATable) -> (Vec<String>, Vec<AConstraint>) {
+    let removed = old
+        .constraints
+        .iter()
+        .filter(|constraint| new.constraint(&constraint.name) != Some(constraint))
+        .map(|constraint| constraint.name.clone())
+        .collect();
+    let added = new
+        .constraints
+        .iter()
+        .filter(|constraint| old.constraint(&constraint.name) != Some(constraint))
+        .cloned()
+        .collect();
+    (removed, added)
+}
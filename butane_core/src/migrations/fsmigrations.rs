@@ -130,8 +130,11 @@ impl MigrationMut for FsMigration {
         let fname = format!("{}.table", table);
         self.ensure_dir()?;
         let path = self.root.join(fname);
-        std::fs::remove_file(&path)?;
-        Ok(())
+        match std::fs::remove_file(&path) {
+            // Like MemMigration, deleting a table which doesn't exist is a no-op
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
     }
 
     fn add_sql(&mut self, backend_name: &str, up_sql: &str, down_sql: &str) -> Result<()> {
@@ -39,7 +39,7 @@ pub trait Migration: PartialEq {
     /// must be in the state of the migration prior to this one
     fn apply(&self, conn: &mut impl db::BackendConnection) -> Result<()> {
         let backend_name = conn.backend_name();
-        let tx = conn.transaction()?;
+        let tx = conn.migration_transaction()?;
         let sql = self
             .up_sql(backend_name)?
             .ok_or_else(|| Error::UnknownBackend(backend_name.to_string()))?;
@@ -58,7 +58,7 @@ pub trait Migration: PartialEq {
     /// to the database.
     fn downgrade(&self, conn: &mut impl db::BackendConnection) -> Result<()> {
         let backend_name = conn.backend_name();
-        let tx = conn.transaction()?;
+        let tx = conn.migration_transaction()?;
         let sql = self
             .down_sql(backend_name)?
             .ok_or_else(|| Error::UnknownBackend(backend_name.to_string()))?;
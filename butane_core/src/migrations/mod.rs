@@ -1,11 +1,14 @@
 //! For working with migrations. If using the butane CLI tool, it is
 //! not necessary to use these types directly.
 use crate::db::BackendRows;
+#[cfg(feature = "async")]
+use crate::db::{AsyncConnectionMethods, BoxFuture};
 use crate::db::{Column, ConnectionMethods};
 use crate::sqlval::{FromSql, SqlValRef, ToSql};
 use crate::{db, query, DataObject, DataResult, Error, Result, SqlType};
 
 use fallible_iterator::FallibleIterator;
+use std::borrow::Cow;
 use std::path::Path;
 
 pub mod adb;
@@ -229,8 +232,8 @@ impl DataObject for ButaneMigration {
     type Fields = (); // we don't need Fields as we never filter
     const PKCOL: &'static str = "name";
     const TABLE: &'static str = "butane_migrations";
-    fn pk(&self) -> &String {
-        &self.name
+    fn pk(&self) -> Cow<'_, String> {
+        Cow::Borrowed(&self.name)
     }
     fn save(&mut self, conn: &impl ConnectionMethods) -> Result<()> {
         let mut values: Vec<SqlValRef<'_>> = Vec::with_capacity(2usize);
@@ -242,7 +245,74 @@ impl DataObject for ButaneMigration {
             &values,
         )
     }
+    #[cfg(feature = "async")]
+    fn save_async<'a>(
+        &'a mut self,
+        conn: &'a impl AsyncConnectionMethods,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let values = [self.name.to_sql_ref()];
+            conn.insert_or_replace(
+                Self::TABLE,
+                <Self as DataResult>::COLUMNS,
+                &Column::new(Self::PKCOL, SqlType::Text),
+                &values,
+            )
+            .await
+        })
+    }
     fn delete(&self, conn: &impl ConnectionMethods) -> Result<()> {
         conn.delete(Self::TABLE, Self::PKCOL, self.pk().to_sql())
     }
+    fn upsert(
+        &mut self,
+        conn: &impl ConnectionMethods,
+        conflict: &[&str],
+        on_conflict: query::OnConflict,
+    ) -> Result<bool> {
+        let values = [self.name.to_sql_ref()];
+        let cnt = conn.upsert(
+            Self::TABLE,
+            <Self as DataResult>::COLUMNS,
+            conflict,
+            &on_conflict,
+            &values,
+        )?;
+        Ok(cnt > 0)
+    }
+    #[cfg(feature = "async")]
+    fn upsert_async<'a>(
+        &'a mut self,
+        conn: &'a impl AsyncConnectionMethods,
+        conflict: &'a [&'a str],
+        on_conflict: query::OnConflict,
+    ) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let values = [self.name.to_sql_ref()];
+            let cnt = conn
+                .upsert(
+                    Self::TABLE,
+                    <Self as DataResult>::COLUMNS,
+                    conflict,
+                    &on_conflict,
+                    &values,
+                )
+                .await?;
+            Ok(cnt > 0)
+        })
+    }
+    fn reload(&mut self, conn: &impl ConnectionMethods) -> Result<()> {
+        *self = Self::get(conn, &self.name)?;
+        Ok(())
+    }
+    #[cfg(feature = "async")]
+    fn reload_async<'a>(
+        &'a mut self,
+        conn: &'a impl AsyncConnectionMethods,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            *self = Self::get_async(conn, self.name.clone()).await?;
+            Ok(())
+        })
+    }
 }
@@ -1,8 +1,8 @@
 //! Not expected to be used directly.
 
 use crate::fkey::ForeignKey;
-use crate::query::{BoolExpr, Column, Expr, Join};
-use crate::sqlval::{FieldType, SqlVal, ToSql};
+use crate::query::{Assignment, BoolExpr, Column, Expr, Join};
+use crate::sqlval::{FieldType, PrimaryKeyType, SqlVal, ToSql};
 use crate::DataObject;
 use std::borrow::{Borrow, Cow};
 use std::cmp::{PartialEq, PartialOrd};
@@ -73,8 +73,25 @@ where
     {
         BoolExpr::Like(self.name, Expr::Val(val.to_sql()))
     }
+
+    /// Assigns `val` to this field, for use with [`Query::update`].
+    ///
+    /// [`Query::update`]: crate::query::Query::update
+    pub fn set<U>(&self, val: U) -> Assignment
+    where
+        U: Into<T>,
+    {
+        Assignment {
+            column: self.name,
+            value: val.into().into(),
+        }
+    }
 }
-impl<F: DataObject> FieldExpr<ForeignKey<F>> {
+impl<F> FieldExpr<ForeignKey<F>>
+where
+    F: DataObject,
+    F::PKType: PrimaryKeyType,
+{
     pub fn subfilter(&self, q: BoolExpr) -> BoolExpr {
         BoolExpr::Subquery {
             col: self.name,
@@ -107,6 +124,7 @@ impl<O, T> ManyFieldExpr<O, T>
 where
     O: DataObject,
     T: DataObject,
+    T::PKType: PrimaryKeyType,
 {
     pub fn new(many_table: &'static str) -> Self {
         ManyFieldExpr {
@@ -2,18 +2,30 @@
 //! the `query!`, `filter!`, and `find!` macros instead of using this
 //! module directly.
 
-use crate::db::{BackendRows, ConnectionMethods, QueryResult};
-use crate::{DataResult, Result, SqlVal};
-use fallible_iterator::FallibleIterator;
+#[cfg(feature = "async")]
+use crate::db::AsyncConnectionMethods;
+use crate::db::{owned_row, BackendRows, Column, ConnectionMethods, PreparedSql, QueryResult};
+use crate::{
+    DataObject, DataResult, FieldType, FromSql, PrimaryKey, Result, SqlType, SqlVal, SqlValRef,
+};
 use std::borrow::Cow;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
 mod fieldexpr;
 
+pub use fallible_iterator::FallibleIterator;
 pub use fieldexpr::{DataOrd, FieldExpr, ManyFieldExpr};
 
 type TblName = Cow<'static, str>;
 
+type PrefetchFn<T> = Arc<dyn Fn(&[T], &dyn ConnectionMethods) -> Result<()> + Send + Sync>;
+
+// Keeps the number of values in the IN clauses of each prefetch query
+// below the backends' limits on parameters. A composite key has an IN
+// clause per column, so fewer keys are taken at a time.
+const PREFETCH_BATCH: usize = 500;
+
 /// Abstract representation of a database expression.
 #[derive(Clone)]
 pub enum Expr {
@@ -64,6 +76,115 @@ pub enum BoolExpr {
     },
 }
 
+/// Builds an expression matching the object of type `T` with primary
+/// key `pk`. Used by macro-generated code. You do not need to call
+/// this directly.
+pub fn pk_expr<T: DataObject>(pk: &T::PKType) -> BoolExpr {
+    key_expr(T::PKCOLS, pk.to_sql_values())
+}
+
+/// Builds an expression matching rows in which the columns `cols`
+/// have the values `vals`.
+pub(crate) fn key_expr(cols: &[&'static str], vals: Vec<SqlVal>) -> BoolExpr {
+    let mut exprs: Vec<BoolExpr> = cols
+        .iter()
+        .zip(vals)
+        .map(|(col, val)| BoolExpr::Eq(*col, Expr::Val(val)))
+        .collect();
+    if exprs.len() == 1 {
+        exprs.pop().unwrap()
+    } else {
+        BoolExpr::AllOf(exprs)
+    }
+}
+
+/// Tests whether the column names `a` and `b` are the same, in the
+/// same order. Used by macro-generated code to check at compile time
+/// that a `#[references]` attribute names the primary key columns of
+/// the model it refers to. You do not need to call this directly.
+pub const fn columns_match(a: &[&str], b: &[&str]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        let (x, y) = (a[i].as_bytes(), b[i].as_bytes());
+        if x.len() != y.len() {
+            return false;
+        }
+        let mut j = 0;
+        while j < x.len() {
+            if x[j] != y[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Deletes the objects of type `T` in `table` matching `expr`. If `T`
+/// uses soft deletion, they are instead marked as deleted now, and any
+/// already marked are unaffected. Used by macro-generated code. You do
+/// not need to call this directly.
+pub fn delete_where<T: DataObject>(
+    conn: &impl ConnectionMethods,
+    table: &str,
+    expr: BoolExpr,
+) -> Result<usize> {
+    match T::SOFT_DELETE_COL {
+        Some(col) => conn.update_where(
+            table,
+            vec![deleted_now(col)?],
+            BoolExpr::And(Box::new(expr), Box::new(not_deleted(col))),
+        ),
+        None => conn.delete_where(table, expr),
+    }
+}
+
+/// Like [`delete_where`], for use with an async connection.
+#[cfg(feature = "async")]
+pub async fn delete_where_async<T: DataObject>(
+    conn: &impl AsyncConnectionMethods,
+    table: &str,
+    expr: BoolExpr,
+) -> Result<usize> {
+    match T::SOFT_DELETE_COL {
+        Some(col) => {
+            conn.update_where(
+                table,
+                vec![deleted_now(col)?],
+                BoolExpr::And(Box::new(expr), Box::new(not_deleted(col))),
+            )
+            .await
+        }
+        None => conn.delete_where(table, expr).await,
+    }
+}
+
+/// Builds an expression matching objects which have not been soft
+/// deleted, given the column recording when they were.
+pub(crate) fn not_deleted(col: &'static str) -> BoolExpr {
+    BoolExpr::Eq(col, Expr::Val(SqlVal::Null))
+}
+
+/// The assignment marking an object as soft deleted now.
+#[cfg(feature = "datetime")]
+fn deleted_now(col: &'static str) -> Result<Assignment> {
+    Ok(Assignment {
+        column: col,
+        value: SqlVal::Timestamp(crate::now()),
+    })
+}
+
+#[cfg(not(feature = "datetime"))]
+fn deleted_now(_col: &'static str) -> Result<Assignment> {
+    Err(crate::Error::Internal(
+        "soft deletion requires the datetime feature".to_string(),
+    ))
+}
+
 /// Represents the direction of a sort.
 #[derive(Clone)]
 pub enum OrderDirection {
@@ -78,6 +199,94 @@ pub struct Order {
     pub column: &'static str,
 }
 
+/// Assignment of a value to a column (SET in an SQL UPDATE). Usually
+/// constructed with [`FieldExpr::set`].
+#[derive(Clone)]
+pub struct Assignment {
+    pub column: &'static str,
+    pub value: SqlVal,
+}
+
+/// What an upsert does when the row it inserts conflicts with an
+/// existing row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Leave the existing row unchanged.
+    DoNothing,
+    /// Set the given columns of the existing row to the values which
+    /// were to be inserted. With no columns, the same as `DoNothing`.
+    Update(Vec<&'static str>),
+}
+
+/// An aggregate function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregateFunc {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// An aggregate function applied to a column. `Count` with no column
+/// counts all rows (`COUNT(*)` in SQL).
+#[derive(Clone)]
+pub struct Aggregate {
+    pub func: AggregateFunc,
+    pub column: Option<&'static str>,
+    /// The type of the aggregated value.
+    pub ty: SqlType,
+}
+impl Aggregate {
+    pub fn count() -> Self {
+        Aggregate {
+            func: AggregateFunc::Count,
+            column: None,
+            ty: SqlType::BigInt,
+        }
+    }
+    pub fn new(func: AggregateFunc, column: &'static str, ty: SqlType) -> Self {
+        Aggregate {
+            func,
+            column: Some(column),
+            ty,
+        }
+    }
+}
+
+/// A field type which can be summed with [`Query::sum`]. Integers are
+/// summed as `i64` and reals as `f64`, so that sums of small integer
+/// types do not overflow.
+pub trait Summable {
+    /// The type of the sum.
+    type Sum: FromSql;
+    /// The SQL type of the sum.
+    const SUM_SQLTYPE: SqlType;
+}
+
+macro_rules! impl_summable {
+    ($ty:ty, $sum:ty, $sqltype:ident) => {
+        impl Summable for $ty {
+            type Sum = $sum;
+            const SUM_SQLTYPE: SqlType = SqlType::$sqltype;
+        }
+    };
+}
+impl_summable!(i8, i64, BigInt);
+impl_summable!(u8, i64, BigInt);
+impl_summable!(i16, i64, BigInt);
+impl_summable!(u16, i64, BigInt);
+impl_summable!(i32, i64, BigInt);
+impl_summable!(u32, i64, BigInt);
+impl_summable!(i64, i64, BigInt);
+impl_summable!(f32, f64, Real);
+impl_summable!(f64, f64, Real);
+
+impl<T: Summable> Summable for Option<T> {
+    type Sum = T::Sum;
+    const SUM_SQLTYPE: SqlType = T::SUM_SQLTYPE;
+}
+
 #[derive(Clone)]
 pub enum Join {
     /// Inner join `join_table` where `col1` is equal to
@@ -112,27 +321,42 @@ impl Column {
     }
 }
 
+/// Which soft deleted objects a query matches.
+#[derive(Clone, Copy)]
+enum Deleted {
+    Excluded,
+    Included,
+    Only,
+}
+
 /// Representation of a database query.
 #[derive(Clone)]
 pub struct Query<T: DataResult> {
     table: TblName,
     filter: Option<BoolExpr>,
+    deleted: Deleted,
     limit: Option<i32>,
     offset: Option<i32>,
     sort: Vec<Order>,
+    prefetch: Vec<PrefetchFn<T>>,
     phantom: PhantomData<T>,
 }
 impl<T: DataResult> Query<T> {
     /// Creates a query which matches all objects in `table`. The set
     /// of matched objects can be restricted with `filter` and
-    /// `limit`.
+    /// `limit`. If the model uses soft deletion, objects which have
+    /// been soft deleted are not matched unless
+    /// [`with_deleted`](Query::with_deleted) or
+    /// [`only_deleted`](Query::only_deleted) is used.
     pub fn new(table: &'static str) -> Query<T> {
         Query {
             table: Cow::Borrowed(table),
             filter: None,
+            deleted: Deleted::Excluded,
             limit: None,
             offset: None,
             sort: Vec::new(),
+            prefetch: Vec::new(),
             phantom: PhantomData,
         }
     }
@@ -157,6 +381,22 @@ impl<T: DataResult> Query<T> {
         self
     }
 
+    /// Matches soft deleted objects as well as the others. Has no
+    /// effect unless the model uses soft deletion. Returns `self` as
+    /// this method is expected to be chained.
+    pub fn with_deleted(mut self) -> Query<T> {
+        self.deleted = Deleted::Included;
+        self
+    }
+
+    /// Matches only soft deleted objects. Has no effect unless the
+    /// model uses soft deletion. Returns `self` as this method is
+    /// expected to be chained.
+    pub fn only_deleted(mut self) -> Query<T> {
+        self.deleted = Deleted::Only;
+        self
+    }
+
     /// Order the query results by the given column. Multiple calls to
     /// this method may be made, with earlier calls taking precedence.
     /// It is recommended to use the `colname!`
@@ -176,27 +416,583 @@ impl<T: DataResult> Query<T> {
         self.order(column, OrderDirection::Descending)
     }
 
+    /// Loads the objects referred to by a [`ForeignKey`] or [`Many`]
+    /// field of the matching objects, so that they are available with
+    /// `get` rather than each having to be loaded separately.
+    /// `field` returns the field of an object, e.g. `|post| &post.blog`.
+    /// All the referred-to objects are loaded with one additional
+    /// query (two for a `Many`) when the query is executed with
+    /// `load` or `load_first`. Returns `self` as this method is
+    /// expected to be chained.
+    ///
+    /// Prefetching is not supported by `iter`, which would have to
+    /// query the database while the iterator holds the connection, nor
+    /// by the async methods. They fail with
+    /// [`Error::PrefetchUnsupported`](crate::Error::PrefetchUnsupported).
+    ///
+    /// [`ForeignKey`]: crate::ForeignKey
+    /// [`Many`]: crate::many::Many
+    pub fn prefetch<P, F>(mut self, field: F) -> Query<T>
+    where
+        T: 'static,
+        P: Prefetch + 'static,
+        F: Fn(&T) -> &P + Send + Sync + 'static,
+    {
+        self.prefetch
+            .push(Arc::new(move |objs: &[T], conn: &dyn ConnectionMethods| {
+                let fields: Vec<&P> = objs.iter().map(&field).collect();
+                P::prefetch(&fields, conn)
+            }));
+        self
+    }
+
     /// Executes the query against `conn` and returns the first result (if any).
-    pub fn load_first(self, conn: &impl ConnectionMethods) -> Result<Option<T>> {
-        conn.query(&self.table, T::COLUMNS, self.filter, Some(1), None, None)?
+    pub fn load_first(mut self, conn: &impl ConnectionMethods) -> Result<Option<T>> {
+        let filter = self.take_filter();
+        let obj: Option<T> = conn
+            .query(&self.table, T::COLUMNS, filter, Some(1), None, None)?
             .mapped(T::from_row)
-            .nth(0)
+            .nth(0)?;
+        if let Some(obj) = &obj {
+            prefetch_all(&self.prefetch, std::slice::from_ref(obj), conn)?;
+        }
+        Ok(obj)
     }
 
     /// Executes the query against `conn`.
-    pub fn load(self, conn: &impl ConnectionMethods) -> Result<QueryResult<T>> {
+    pub fn load(mut self, conn: &impl ConnectionMethods) -> Result<QueryResult<T>> {
+        let filter = self.take_filter();
         let sort = if self.sort.is_empty() {
             None
         } else {
             Some(self.sort.as_slice())
         };
-        conn.query(&self.table, T::COLUMNS, self.filter, self.limit, self.offset, sort)?
+        let objs: QueryResult<T> = conn
+            .query(
+                &self.table,
+                T::COLUMNS,
+                filter,
+                self.limit,
+                self.offset,
+                sort,
+            )?
             .mapped(T::from_row)
-            .collect()
+            .collect()?;
+        prefetch_all(&self.prefetch, &objs, conn)?;
+        Ok(objs)
+    }
+
+    /// Executes the query against `conn`, returning an iterator which
+    /// reads the matching objects from the database as they are
+    /// requested rather than loading all of them at once. With some
+    /// backends, `conn` cannot be used for anything else until the
+    /// iterator has been dropped. Fails if the query uses
+    /// [`prefetch`](Query::prefetch).
+    pub fn iter<'c>(
+        mut self,
+        conn: &'c impl ConnectionMethods,
+    ) -> Result<impl FallibleIterator<Item = T, Error = crate::Error> + 'c>
+    where
+        T: 'c,
+    {
+        self.check_no_prefetch("Query::iter")?;
+        let filter = self.take_filter();
+        let sort = if self.sort.is_empty() {
+            None
+        } else {
+            Some(self.sort.as_slice())
+        };
+        Ok(conn
+            .query_streaming(
+                &self.table,
+                T::COLUMNS,
+                filter,
+                self.limit,
+                self.offset,
+                sort,
+            )?
+            .mapped(T::from_row))
+    }
+
+    /// Prepares the query for `conn` so that it can be run many times
+    /// without generating its SQL again. Each [`Expr::Placeholder`]
+    /// in the query's filter (written `placeholder!()` in `query!`
+    /// and `filter!`) becomes a parameter which is bound when the
+    /// prepared query is run.
+    pub fn prepare(mut self, conn: &impl ConnectionMethods) -> Result<PreparedQuery<T>> {
+        let filter = self.take_filter();
+        let sort = if self.sort.is_empty() {
+            None
+        } else {
+            Some(self.sort.as_slice())
+        };
+        let sql = conn.prepare_query(
+            &self.table,
+            T::COLUMNS,
+            filter,
+            self.limit,
+            self.offset,
+            sort,
+        )?;
+        Ok(PreparedQuery {
+            sql,
+            prefetch: self.prefetch,
+        })
     }
 
-    /// Executes the query against `conn` and deletes all matching objects.
+    /// Executes the query against `conn` and deletes all matching
+    /// objects. If the model uses soft deletion, they are instead
+    /// marked as deleted, and any already marked are unaffected.
     pub fn delete(self, conn: &impl ConnectionMethods) -> Result<usize> {
-        conn.delete_where(&self.table, self.filter.unwrap_or(BoolExpr::True))
+        delete_where::<T::DBO>(conn, &self.table, self.filter.unwrap_or(BoolExpr::True))
+    }
+
+    /// Like [`load_first`](Query::load_first), for use with an async
+    /// connection. Fails if the query uses [`prefetch`](Query::prefetch).
+    #[cfg(feature = "async")]
+    pub async fn load_first_async(
+        mut self,
+        conn: &impl AsyncConnectionMethods,
+    ) -> Result<Option<T>> {
+        self.check_no_prefetch("Query::load_first_async")?;
+        let filter = self.take_filter();
+        conn.query(&self.table, T::COLUMNS, filter, Some(1), None, None)
+            .await?
+            .first()
+            .map(|row| T::from_row(row))
+            .transpose()
+    }
+
+    /// Like [`load`](Query::load), for use with an async connection.
+    /// Fails if the query uses [`prefetch`](Query::prefetch).
+    #[cfg(feature = "async")]
+    pub async fn load_async(
+        mut self,
+        conn: &impl AsyncConnectionMethods,
+    ) -> Result<QueryResult<T>> {
+        self.check_no_prefetch("Query::load_async")?;
+        let filter = self.take_filter();
+        let sort = if self.sort.is_empty() {
+            None
+        } else {
+            Some(self.sort.as_slice())
+        };
+        conn.query(
+            &self.table,
+            T::COLUMNS,
+            filter,
+            self.limit,
+            self.offset,
+            sort,
+        )
+        .await?
+        .iter()
+        .map(|row| T::from_row(row))
+        .collect()
+    }
+
+    /// Like [`delete`](Query::delete), for use with an async connection.
+    #[cfg(feature = "async")]
+    pub async fn delete_async(self, conn: &impl AsyncConnectionMethods) -> Result<usize> {
+        delete_where_async::<T::DBO>(conn, &self.table, self.filter.unwrap_or(BoolExpr::True)).await
+    }
+
+    /// Executes the query against `conn` and applies `assignments` to
+    /// all matching objects, returning the number of objects
+    /// updated. Objects already loaded are not affected.
+    pub fn update(
+        mut self,
+        conn: &impl ConnectionMethods,
+        assignments: Vec<Assignment>,
+    ) -> Result<usize> {
+        if assignments.is_empty() {
+            return Ok(0);
+        }
+        let filter = self.take_filter();
+        conn.update_where(&self.table, assignments, filter.unwrap_or(BoolExpr::True))
+    }
+
+    /// Executes the query against `conn` and returns the number of
+    /// matching objects. The [`offset`](Query::offset) and
+    /// [`limit`](Query::limit) are taken into account, so the result
+    /// is the number of objects [`load`](Query::load) would return.
+    pub fn count(mut self, conn: &impl ConnectionMethods) -> Result<i64> {
+        let limit = self.limit.take();
+        let offset = self.offset.take().unwrap_or(0);
+        let count: i64 = self.aggregate(Aggregate::count(), conn)?.unwrap_or(0);
+        let count = (count - i64::from(offset)).max(0);
+        Ok(match limit {
+            Some(limit) => count.min(i64::from(limit)),
+            None => count,
+        })
+    }
+
+    /// Executes the query against `conn` and returns whether there
+    /// are any matching objects.
+    pub fn exists(mut self, conn: &impl ConnectionMethods) -> Result<bool> {
+        let filter = self.take_filter();
+        let mut rows = conn.query(&self.table, &T::COLUMNS[..1], filter, Some(1), None, None)?;
+        Ok(rows.next()?.is_some())
+    }
+
+    /// Executes the query against `conn` and returns the sum of
+    /// `field` over the matching objects, or `None` if there are none.
+    /// Like the other aggregates except `count`, fails with
+    /// [`Error::LimitUnsupported`](crate::Error::LimitUnsupported) if
+    /// the query has a limit or offset.
+    pub fn sum<F>(
+        self,
+        field: FieldExpr<F>,
+        conn: &impl ConnectionMethods,
+    ) -> Result<Option<F::Sum>>
+    where
+        F: Summable + Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Sum, field.name(), F::SUM_SQLTYPE),
+            conn,
+        )
+    }
+
+    /// Executes the query against `conn` and returns the average of
+    /// `field` over the matching objects, or `None` if there are none.
+    pub fn avg<F>(self, field: FieldExpr<F>, conn: &impl ConnectionMethods) -> Result<Option<f64>>
+    where
+        F: Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Avg, field.name(), SqlType::Real),
+            conn,
+        )
+    }
+
+    /// Executes the query against `conn` and returns the smallest
+    /// value of `field` among the matching objects, or `None` if
+    /// there are none.
+    pub fn min<F>(self, field: FieldExpr<F>, conn: &impl ConnectionMethods) -> Result<Option<F>>
+    where
+        F: FieldType + Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Min, field.name(), F::SQLTYPE),
+            conn,
+        )
+    }
+
+    /// Executes the query against `conn` and returns the largest
+    /// value of `field` among the matching objects, or `None` if
+    /// there are none.
+    pub fn max<F>(self, field: FieldExpr<F>, conn: &impl ConnectionMethods) -> Result<Option<F>>
+    where
+        F: FieldType + Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Max, field.name(), F::SQLTYPE),
+            conn,
+        )
+    }
+
+    /// Groups the matching objects by the value of `field`. Aggregates
+    /// computed on the returned [`GroupedQuery`] produce one value
+    /// per group.
+    pub fn group_by<K>(self, field: FieldExpr<K>) -> GroupedQuery<T, K>
+    where
+        K: FieldType + Into<SqlVal>,
+    {
+        GroupedQuery {
+            query: self,
+            key: field.name(),
+            phantom: PhantomData,
+        }
+    }
+
+    fn aggregate<U: FromSql>(
+        mut self,
+        aggregate: Aggregate,
+        conn: &impl ConnectionMethods,
+    ) -> Result<Option<U>> {
+        self.check_no_limit("aggregates")?;
+        let ty = aggregate.ty.clone();
+        let filter = self.take_filter();
+        let mut rows = conn.query_aggregate(&self.table, &[], &[aggregate], filter)?;
+        let row = match rows.next()? {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        let val = match row.get(0, ty)? {
+            SqlValRef::Null => None,
+            val => Some(U::from_sql_ref(val)?),
+        };
+        Ok(val)
+    }
+
+    // An aggregate is computed over all of the matching rows, which
+    // would be misleading if the query limits them
+    fn check_no_limit(&self, method: &'static str) -> Result<()> {
+        if self.limit.is_none() && self.offset.is_none() {
+            Ok(())
+        } else {
+            Err(crate::Error::LimitUnsupported(method))
+        }
+    }
+
+    fn check_no_prefetch(&self, method: &'static str) -> Result<()> {
+        if self.prefetch.is_empty() {
+            Ok(())
+        } else {
+            Err(crate::Error::PrefetchUnsupported(method))
+        }
+    }
+
+    /// Takes the query's filter, combined with the condition selecting
+    /// soft deleted objects or excluding them.
+    fn take_filter(&mut self) -> Option<BoolExpr> {
+        let filter = self.filter.take();
+        let col = match <T::DBO as DataObject>::SOFT_DELETE_COL {
+            Some(col) => col,
+            None => return filter,
+        };
+        let deleted = match self.deleted {
+            Deleted::Excluded => not_deleted(col),
+            Deleted::Included => return filter,
+            Deleted::Only => BoolExpr::Ne(col, Expr::Val(SqlVal::Null)),
+        };
+        Some(match filter {
+            Some(filter) => BoolExpr::And(Box::new(filter), Box::new(deleted)),
+            None => deleted,
+        })
+    }
+}
+
+/// A field which refers to other objects, which can be loaded for many
+/// objects at once with [`Query::prefetch`].
+pub trait Prefetch {
+    /// Loads the objects referred to by each of `fields` which has not
+    /// already been loaded.
+    fn prefetch(fields: &[&Self], conn: &dyn ConnectionMethods) -> Result<()>;
+}
+
+fn prefetch_all<T>(
+    prefetch: &[PrefetchFn<T>],
+    objs: &[T],
+    conn: &dyn ConnectionMethods,
+) -> Result<()> {
+    for prefetch in prefetch {
+        prefetch(objs, conn)?;
+    }
+    Ok(())
+}
+
+/// Finds the index in `columns` of each of the columns `names`.
+pub(crate) fn column_indexes(columns: &[Column], names: &[&str]) -> Result<Vec<usize>> {
+    names
+        .iter()
+        .map(|name| {
+            columns
+                .iter()
+                .position(|col| col.name() == *name)
+                .ok_or_else(|| crate::Error::Internal(format!("no column {}", name)))
+        })
+        .collect()
+}
+
+/// Tests whether the columns of `row` at `indexes` have the values
+/// of `key`.
+pub(crate) fn row_has_key(row: &[SqlVal], indexes: &[usize], key: &[SqlVal]) -> bool {
+    indexes.iter().zip(key).all(|(idx, val)| &row[*idx] == val)
+}
+
+/// Loads the rows of `table` for which the columns `cols` have the
+/// values of one of `keys`. The rows are not converted to objects, so
+/// that a row may be used to construct more than one object. Rows
+/// which have been soft deleted, according to `soft_delete_col`, are
+/// left out.
+pub(crate) fn load_rows_in(
+    conn: &dyn ConnectionMethods,
+    table: &str,
+    columns: &[Column],
+    cols: &[&'static str],
+    keys: &[Vec<SqlVal>],
+    soft_delete_col: Option<&'static str>,
+) -> Result<Vec<Vec<SqlVal>>> {
+    let idxs = column_indexes(columns, cols)?;
+    let mut rows = Vec::new();
+    let batch_size = (PREFETCH_BATCH / cols.len().max(1)).max(1);
+    for batch in keys.chunks(batch_size) {
+        // Each column is matched against the values it has in any of
+        // the keys, which for a composite key may also match rows
+        // with other combinations of them. Those are left out below.
+        let mut exprs: Vec<BoolExpr> = cols
+            .iter()
+            .enumerate()
+            .map(|(i, col)| {
+                let mut vals: Vec<SqlVal> = Vec::new();
+                for key in batch {
+                    if !vals.contains(&key[i]) {
+                        vals.push(key[i].clone());
+                    }
+                }
+                BoolExpr::In(*col, vals)
+            })
+            .collect();
+        if let Some(deleted_col) = soft_delete_col {
+            exprs.push(not_deleted(deleted_col));
+        }
+        let mut batch_rows: Vec<Vec<SqlVal>> = conn
+            .query(
+                table,
+                columns,
+                Some(BoolExpr::AllOf(exprs)),
+                None,
+                None,
+                None,
+            )?
+            .mapped(|row| owned_row(row, columns))
+            .collect()?;
+        if cols.len() > 1 {
+            batch_rows.retain(|row| batch.iter().any(|key| row_has_key(row, &idxs, key)));
+        }
+        rows.append(&mut batch_rows);
+    }
+    Ok(rows)
+}
+
+/// A [`Query`] which has been prepared for repeated execution, created
+/// with [`Query::prepare`]. Backends cache the underlying statement on
+/// each connection the query is run with.
+#[derive(Clone)]
+pub struct PreparedQuery<T: DataResult> {
+    sql: PreparedSql,
+    prefetch: Vec<PrefetchFn<T>>,
+}
+impl<T: DataResult> PreparedQuery<T> {
+    /// Executes the query against `conn`, binding `params` to the
+    /// query's placeholders in the order they appear in its filter.
+    /// `conn` must be a connection to the same backend the query was
+    /// prepared for.
+    pub fn load(&self, conn: &impl ConnectionMethods, params: &[SqlVal]) -> Result<QueryResult<T>> {
+        let objs: QueryResult<T> = conn
+            .query_prepared(&self.sql, params)?
+            .mapped(T::from_row)
+            .collect()?;
+        prefetch_all(&self.prefetch, &objs, conn)?;
+        Ok(objs)
+    }
+
+    /// Like [`load`](PreparedQuery::load), but returns only the first
+    /// result (if any).
+    pub fn load_first(
+        &self,
+        conn: &impl ConnectionMethods,
+        params: &[SqlVal],
+    ) -> Result<Option<T>> {
+        let obj: Option<T> = conn
+            .query_prepared(&self.sql, params)?
+            .mapped(T::from_row)
+            .nth(0)?;
+        if let Some(obj) = &obj {
+            prefetch_all(&self.prefetch, std::slice::from_ref(obj), conn)?;
+        }
+        Ok(obj)
+    }
+}
+
+/// A [`Query`] whose matching objects are grouped by the value of a
+/// field, created with [`Query::group_by`]. Each aggregate returns a
+/// `(key, value)` pair for each group, ordered by key.
+pub struct GroupedQuery<T: DataResult, K: FieldType> {
+    query: Query<T>,
+    key: &'static str,
+    phantom: PhantomData<K>,
+}
+impl<T: DataResult, K: FieldType> GroupedQuery<T, K> {
+    /// Returns the number of objects in each group.
+    pub fn count(self, conn: &impl ConnectionMethods) -> Result<Vec<(K, i64)>> {
+        let grouped: Vec<(K, Option<i64>)> = self.aggregate(Aggregate::count(), conn)?;
+        Ok(grouped
+            .into_iter()
+            .map(|(key, count)| (key, count.unwrap_or(0)))
+            .collect())
+    }
+
+    /// Returns the sum of `field` in each group.
+    pub fn sum<F>(
+        self,
+        field: FieldExpr<F>,
+        conn: &impl ConnectionMethods,
+    ) -> Result<Vec<(K, Option<F::Sum>)>>
+    where
+        F: Summable + Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Sum, field.name(), F::SUM_SQLTYPE),
+            conn,
+        )
+    }
+
+    /// Returns the average of `field` in each group.
+    pub fn avg<F>(
+        self,
+        field: FieldExpr<F>,
+        conn: &impl ConnectionMethods,
+    ) -> Result<Vec<(K, Option<f64>)>>
+    where
+        F: Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Avg, field.name(), SqlType::Real),
+            conn,
+        )
+    }
+
+    /// Returns the smallest value of `field` in each group.
+    pub fn min<F>(
+        self,
+        field: FieldExpr<F>,
+        conn: &impl ConnectionMethods,
+    ) -> Result<Vec<(K, Option<F>)>>
+    where
+        F: FieldType + Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Min, field.name(), F::SQLTYPE),
+            conn,
+        )
+    }
+
+    /// Returns the largest value of `field` in each group.
+    pub fn max<F>(
+        self,
+        field: FieldExpr<F>,
+        conn: &impl ConnectionMethods,
+    ) -> Result<Vec<(K, Option<F>)>>
+    where
+        F: FieldType + Into<SqlVal>,
+    {
+        self.aggregate(
+            Aggregate::new(AggregateFunc::Max, field.name(), F::SQLTYPE),
+            conn,
+        )
+    }
+
+    fn aggregate<U: FromSql>(
+        mut self,
+        aggregate: Aggregate,
+        conn: &impl ConnectionMethods,
+    ) -> Result<Vec<(K, Option<U>)>> {
+        self.query.check_no_limit("grouped aggregates")?;
+        let ty = aggregate.ty.clone();
+        let key_col = crate::db::Column::new(self.key, K::SQLTYPE);
+        let filter = self.query.take_filter();
+        conn.query_aggregate(&self.query.table, &[key_col], &[aggregate], filter)?
+            .mapped(|row| {
+                let key = K::from_sql_ref(row.get(0, K::SQLTYPE)?)?;
+                let val = match row.get(1, ty.clone())? {
+                    SqlValRef::Null => None,
+                    val => Some(U::from_sql_ref(val)?),
+                };
+                Ok((key, val))
+            })
+            .collect()
     }
 }
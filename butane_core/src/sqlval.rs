@@ -160,6 +160,24 @@ impl SqlVal {
         }
     }
 }
+impl PartialEq<SqlValRef<'_>> for SqlVal {
+    fn eq(&self, other: &SqlValRef<'_>) -> bool {
+        match (self, other) {
+            (SqlVal::Null, SqlValRef::Null) => true,
+            (SqlVal::Bool(a), SqlValRef::Bool(b)) => a == b,
+            (SqlVal::Int(a), SqlValRef::Int(b)) => a == b,
+            (SqlVal::BigInt(a), SqlValRef::BigInt(b)) => a == b,
+            (SqlVal::Real(a), SqlValRef::Real(b)) => a == b,
+            (SqlVal::Text(a), SqlValRef::Text(b)) => a == b,
+            (SqlVal::Blob(a), SqlValRef::Blob(b)) => a == b,
+            #[cfg(feature = "datetime")]
+            (SqlVal::Timestamp(a), SqlValRef::Timestamp(b)) => a == b,
+            (SqlVal::Custom(_), SqlValRef::Custom(_)) => *self == SqlVal::from(other.clone()),
+            (_, _) => false,
+        }
+    }
+}
+
 impl fmt::Display for SqlVal {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use SqlVal::*;
@@ -271,6 +289,93 @@ pub trait FieldType: ToSql + FromSql {
 /// Marker trait for a type suitable for being a primary key
 pub trait PrimaryKeyType: FieldType + Clone + PartialEq {}
 
+/// The primary key of a model. Either a single [PrimaryKeyType] or,
+/// for a composite primary key, a tuple of them.
+pub trait PrimaryKey: Clone + PartialEq {
+    /// The types of each of the key's columns, in order.
+    fn sql_types() -> Vec<SqlType>;
+    /// The values of each of the key's columns, in order.
+    fn to_sql_values(&self) -> Vec<SqlVal>;
+    /// Reads a key from the values of each of its columns, in order.
+    fn from_sql_values(vals: &[SqlVal]) -> Result<Self>;
+}
+
+impl<P: PrimaryKeyType> PrimaryKey for P {
+    fn sql_types() -> Vec<SqlType> {
+        vec![P::SQLTYPE]
+    }
+    fn to_sql_values(&self) -> Vec<SqlVal> {
+        vec![self.to_sql()]
+    }
+    fn from_sql_values(vals: &[SqlVal]) -> Result<Self> {
+        match vals {
+            [val] => P::from_sql_ref(val.as_ref()),
+            _ => Err(key_len_err(1, vals)),
+        }
+    }
+}
+
+fn key_len_err(expected: usize, vals: &[SqlVal]) -> crate::Error {
+    crate::Error::BoundsError(format!(
+        "Expected {} primary key values, found {}",
+        expected,
+        vals.len()
+    ))
+}
+
+macro_rules! impl_composite_pk {
+    ($($ty:ident $idx:tt),+) => {
+        impl<$($ty: PrimaryKeyType),+> PrimaryKey for ($($ty,)+) {
+            fn sql_types() -> Vec<SqlType> {
+                vec![$($ty::SQLTYPE),+]
+            }
+            fn to_sql_values(&self) -> Vec<SqlVal> {
+                vec![$(self.$idx.to_sql()),+]
+            }
+            fn from_sql_values(vals: &[SqlVal]) -> Result<Self> {
+                let len = [$($idx),+].len();
+                if vals.len() != len {
+                    return Err(key_len_err(len, vals));
+                }
+                Ok(($($ty::from_sql_ref(vals[$idx].as_ref())?,)+))
+            }
+        }
+    };
+}
+
+impl_composite_pk!(A 0, B 1);
+impl_composite_pk!(A 0, B 1, C 2);
+impl_composite_pk!(A 0, B 1, C 2, D 3);
+
+/// The type of the `I`th column of a [PrimaryKey]. Used by
+/// macro-generated code for the columns of a foreign key to a model
+/// with a composite primary key.
+pub trait PrimaryKeyColumn<const I: usize>: PrimaryKey {
+    type Type: PrimaryKeyType;
+}
+
+impl<P: PrimaryKeyType> PrimaryKeyColumn<0> for P {
+    type Type = P;
+}
+
+macro_rules! impl_pk_column {
+    ($idx:tt, $col:ident; $($ty:ident),+) => {
+        impl<$($ty: PrimaryKeyType),+> PrimaryKeyColumn<$idx> for ($($ty,)+) {
+            type Type = $col;
+        }
+    };
+}
+
+impl_pk_column!(0, A; A, B);
+impl_pk_column!(1, B; A, B);
+impl_pk_column!(0, A; A, B, C);
+impl_pk_column!(1, B; A, B, C);
+impl_pk_column!(2, C; A, B, C);
+impl_pk_column!(0, A; A, B, C, D);
+impl_pk_column!(1, B; A, B, C, D);
+impl_pk_column!(2, C; A, B, C, D);
+impl_pk_column!(3, D; A, B, C, D);
+
 /// Trait for referencing the primary key for a given model. Used to
 /// implement ForeignKey equality tests.
 pub trait AsPrimaryKey<T: DataObject> {
@@ -443,6 +548,13 @@ impl FieldType for NaiveDateTime {
 #[cfg(feature = "datetime")]
 impl PrimaryKeyType for NaiveDateTime {}
 
+/// The current time in UTC. Timestamps which butane fills in itself,
+/// such as those of `#[auto_now]` fields, are set to this.
+#[cfg(feature = "datetime")]
+pub fn now() -> NaiveDateTime {
+    chrono::Utc::now().naive_utc()
+}
+
 impl ToSql for &str {
     fn to_sql(&self) -> SqlVal {
         SqlVal::Text((*self).to_string())